@@ -0,0 +1,74 @@
+use adpcm2aiff::adpcm_encoder::{encode_xa_adpcm, encode_xa_adpcm_parallel, EncodeOptions};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+
+// 10 seconds at 18900 Hz
+const SAMPLES_COUNT: usize = 189_000;
+
+/// A mix of tones, a decaying envelope and some noise, so every filter and a spread of ranges get
+/// picked.
+fn test_pcm() -> Vec<u8> {
+  let mut noise: u32 = 0x1234_5678;
+  (0..SAMPLES_COUNT)
+    .map(|n| {
+      noise ^= noise << 13;
+      noise ^= noise >> 17;
+      noise ^= noise << 5;
+
+      let t = n as f64 / 18900.0;
+      let envelope = 1.0 - (t % 1.0);
+      let tone = (t * 440.0 * std::f64::consts::TAU).sin() * 9000.0
+        + (t * 1250.0 * std::f64::consts::TAU).sin() * 3000.0;
+      let hiss = f64::from((noise >> 20) as i32 - 2048);
+      (tone * envelope + hiss) as i16
+    })
+    .flat_map(|sample| sample.to_le_bytes())
+    .collect()
+}
+
+fn bench_encode(c: &mut Criterion) {
+  let pcm = test_pcm();
+  let mut out = Vec::with_capacity(SAMPLES_COUNT);
+
+  let mut group = c.benchmark_group("encode");
+  group.throughput(Throughput::Elements(SAMPLES_COUNT as u64));
+  group.bench_function("xa_10s", |b| {
+    b.iter(|| {
+      out.clear();
+      encode_xa_adpcm(SAMPLES_COUNT, &mut black_box(pcm.as_slice()), &mut out).unwrap();
+    })
+  });
+  group.finish();
+}
+
+/// Serial against parallel encoding of the same input, with the parallel encoder on 1, 2, 4 and 8
+/// threads. Thread counts past the machine's core count only measure scheduling overhead, so read
+/// the speedup off the counts the machine actually has.
+fn bench_parallel(c: &mut Criterion) {
+  let pcm = test_pcm();
+  let mut out = Vec::with_capacity(SAMPLES_COUNT);
+
+  let mut group = c.benchmark_group("encode_parallel");
+  group.throughput(Throughput::Elements(SAMPLES_COUNT as u64));
+  group.bench_function("serial", |b| {
+    b.iter(|| {
+      out.clear();
+      encode_xa_adpcm(SAMPLES_COUNT, &mut black_box(pcm.as_slice()), &mut out).unwrap();
+    })
+  });
+  for threads in [1, 2, 4, 8] {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+    group.bench_with_input(BenchmarkId::new("parallel", threads), &threads, |b, _| {
+      b.iter(|| {
+        out.clear();
+        pool.install(|| {
+          encode_xa_adpcm_parallel(SAMPLES_COUNT, EncodeOptions::default(), &mut black_box(pcm.as_slice()), &mut out, &mut |_, _| {})
+        }).unwrap();
+      })
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, bench_encode, bench_parallel);
+criterion_main!(benches);
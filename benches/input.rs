@@ -0,0 +1,77 @@
+use adpcm2aiff::{
+  adpcm_encoder::encode_xa_adpcm,
+  input::open_input,
+  preprocess::{DecimateReader, ResampleReader},
+  wav::write_wav,
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::{
+  hint::black_box,
+  io::{self, Write},
+};
+
+// 10 minutes at 37800 Hz, about 45 MB of WAV
+const SAMPLES_COUNT: usize = 37_800 * 600;
+
+fn test_wav() -> tempfile::NamedTempFile {
+  let samples: Vec<i16> = (0..SAMPLES_COUNT)
+    .map(|n| ((n as f64 * 0.01).sin() * 10_000.0) as i16)
+    .collect();
+  let mut file = tempfile::NamedTempFile::new().unwrap();
+  write_wav(&samples, 37_800, &mut file).unwrap();
+  file.flush().unwrap();
+  file
+}
+
+/// Reads and encodes a WAV file from disk. Run with and without `--features mmap` to compare the
+/// mapped and buffered input paths.
+fn bench_input(c: &mut Criterion) {
+  let wav = test_wav();
+
+  let mut group = c.benchmark_group("input");
+  group.throughput(Throughput::Elements(SAMPLES_COUNT as u64));
+  group.sample_size(10);
+  group.bench_function("read_wav", |b| {
+    b.iter(|| {
+      let (_, _, mut rdr) = open_input(wav.path(), None).unwrap();
+      io::copy(&mut rdr, &mut io::sink()).unwrap();
+    })
+  });
+  group.bench_function("encode_wav", |b| {
+    b.iter(|| {
+      let (samples_count, _, mut rdr) = open_input(wav.path(), None).unwrap();
+      encode_xa_adpcm(samples_count, &mut rdr, &mut io::sink()).unwrap();
+    })
+  });
+  group.finish();
+}
+
+/// 37800 Hz to 18900 Hz through the general resampler and through the whole-ratio decimator,
+/// which give the same samples.
+fn bench_decimate(c: &mut Criterion) {
+  // 10 seconds at 37800 Hz
+  let samples_count = 378_000;
+  let pcm: Vec<u8> = (0..samples_count)
+    .map(|n| ((n as f64 * 0.01).sin() * 10_000.0) as i16)
+    .flat_map(|sample| sample.to_le_bytes())
+    .collect();
+
+  let mut group = c.benchmark_group("resample_2x");
+  group.throughput(Throughput::Elements(samples_count as u64));
+  group.bench_function("resample", |b| {
+    b.iter(|| {
+      let mut rdr = ResampleReader::new(black_box(pcm.as_slice()), samples_count, 37_800, 18_900);
+      io::copy(&mut rdr, &mut io::sink()).unwrap();
+    })
+  });
+  group.bench_function("decimate", |b| {
+    b.iter(|| {
+      let mut rdr = DecimateReader::new(black_box(pcm.as_slice()), samples_count, 2);
+      io::copy(&mut rdr, &mut io::sink()).unwrap();
+    })
+  });
+  group.finish();
+}
+
+criterion_group!(benches, bench_input, bench_decimate);
+criterion_main!(benches);
@@ -0,0 +1,902 @@
+use alloc::{vec, vec::Vec};
+
+// The XA ADPCM specification defines four pairs of filter values (K0, K1) as:
+// Filter 0 = (0,         0)
+// Filter 1 = (0.9375,    0)
+// Filter 2 = (1.796875, -0.8125)
+// Filter 3 = (1.53125,  -0.859375)
+//
+// 16-bit WAV PCM samples use a fixed-point encoding with the 6 least significant bits representing
+// the decimal portion. Filter values for this encoding are derived via
+//   fixed_point_filter = xa_adpcm_filter * 2^6
+// and stored in these tables:
+pub const XA_ADPCM_FILTER_COUNT: usize = 4;
+pub const FILTER_K0: [i32; XA_ADPCM_FILTER_COUNT] = [0, 60, 115, 98];
+pub const FILTER_K1: [i32; XA_ADPCM_FILTER_COUNT] = [0, 0, -52, -55];
+
+/// Filter coefficients the encoder predicts with, in the same 2^6 fixed point as `FILTER_K0` and
+/// `FILTER_K1`: a filter value of 0.9375 is 60. Decoders that use other coefficients need the
+/// encoder to use them too, or every unit's prediction drifts from what they play back.
+///
+/// Each coefficient must be within `FILTER_COEFFICIENT_RANGE`, -2.0 to just under 2.0, and filter
+/// 0 must stay `(0, 0)`: the encoder falls back to it for silence and after state resets, where the
+/// decoder's history has to be ignored. `adpcm_decoder` always decodes with the standard tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterTables {
+  pub k0: [i32; XA_ADPCM_FILTER_COUNT],
+  pub k1: [i32; XA_ADPCM_FILTER_COUNT],
+}
+
+/// Coefficients that fit in a signed byte, as 2^6 fixed-point filter values do. Anything outside it
+/// is most likely a table given in some other scale.
+pub const FILTER_COEFFICIENT_RANGE: core::ops::RangeInclusive<i32> = -128..=127;
+
+impl FilterTables {
+  /// The four filters of the XA ADPCM specification
+  pub const STANDARD: FilterTables = FilterTables { k0: FILTER_K0, k1: FILTER_K1 };
+
+  /// Whether every coefficient is in `FILTER_COEFFICIENT_RANGE` and filter 0 is `(0, 0)`.
+  pub fn is_valid(&self) -> bool {
+    self.k0[0] == 0 && self.k1[0] == 0
+      && self.k0.iter().chain(&self.k1).all(|coefficient| FILTER_COEFFICIENT_RANGE.contains(coefficient))
+  }
+}
+
+impl Default for FilterTables {
+  fn default() -> Self {
+    FilterTables::STANDARD
+  }
+}
+
+// XA ADPCM samples are stored as 4-bits, and the decoder expands them to 16-bit samples by left
+// shifting by the number of bits specified in sample unit's sound parameter. The maximum number
+// of bits a sample can be shifted by is (16 - 4) = 12.
+pub(crate) const MAX_SHIFT: usize = 12;
+
+
+pub(crate) const SOUND_UNIT_SIZE: usize = 28;
+
+pub const SOUND_UNIT_SAMPLES: usize = 28;
+pub const SOUND_GROUP_SAMPLES: usize = SOUND_UNIT_SAMPLES * 8;
+pub const ADPCM_SECTOR_SAMPLES: usize = SOUND_GROUP_SAMPLES * 18;
+pub const XA_ADPCM_SECTOR_SIZE: usize = 0x914;
+/// Size of the 18 sound groups at the start of each sector, without the trailing 0x14 zero bytes
+pub const XA_ADPCM_SECTOR_DATA_SIZE: usize = 0x900;
+
+// Each sound group is a 16-byte sound parameter header followed by 28 bytes for each of the
+// 28 sample positions, holding one nibble from each of the 8 sound units.
+pub(crate) const SOUND_PARAMETERS_SIZE: usize = 16;
+pub(crate) const SOUND_GROUP_SIZE: usize = SOUND_PARAMETERS_SIZE + SOUND_UNIT_SAMPLES * 4;
+pub(crate) const SOUND_GROUPS_PER_SECTOR: usize = ADPCM_SECTOR_SAMPLES / SOUND_GROUP_SAMPLES;
+
+/// Size of a PlayStation SPU ADPCM block: 1 sound parameter byte, 1 flags byte and 14 data bytes
+/// holding the 28 samples of one sound unit.
+pub const SPU_ADPCM_BLOCK_SIZE: usize = 16;
+
+/// Number of XA sectors needed to hold `samples_count` samples; the final sector is zero padded.
+pub fn sectors_for_samples(samples_count: usize) -> usize {
+  samples_count.div_ceil(ADPCM_SECTOR_SAMPLES)
+}
+
+/// Filter, noise shaper and dither state carried from one sound unit to the next.
+#[derive(Clone)]
+pub struct EncoderState {
+  // Every filter sees the same input, so the predictors share one delay chain
+  predictor_delayed_1: i32,
+  predictor_delayed_2: i32,
+
+  encoder_delayed_1: i32,
+  encoder_delayed_2: i32,
+
+  noise_shaper_delayed_1: i32,
+  noise_shaper_delayed_2: i32,
+  noise_shaper_output: i32,
+
+  quantizer_input: i64,
+  quantizer_output: i32,
+
+  dither: Option<Dither>,
+
+  pub(crate) tail_mode: TailMode,
+  pub(crate) last_sample: i16,
+
+  peak_headroom_pct: u32,
+
+  sector_padding: SectorPadding,
+
+  sound_group_layout: SoundGroupLayout,
+
+  allowed_filters: FilterSet,
+
+  force_params: Option<(u8, u8)>,
+
+  rounding: RoundingMode,
+
+  filter_tables: FilterTables,
+
+  // Sample positions where the filter state starts over, in order, and the next one still to come
+  state_resets: Vec<usize>,
+  next_reset: usize,
+  // Samples encoded so far, in whole sound units
+  position: usize,
+
+  clipped_samples: usize,
+}
+
+impl EncoderState {
+  pub fn new() -> Self {
+    EncoderState {
+      predictor_delayed_1: 0,
+      predictor_delayed_2: 0,
+
+      encoder_delayed_1: 0,
+      encoder_delayed_2: 0,
+
+      noise_shaper_delayed_1: 0,
+      noise_shaper_delayed_2: 0,
+      noise_shaper_output: 0,
+
+      quantizer_input: 0,
+      quantizer_output: 0,
+
+      dither: None,
+
+      tail_mode: TailMode::Zero,
+      last_sample: 0,
+
+      peak_headroom_pct: 0,
+
+      sector_padding: SectorPadding::Padded,
+      sound_group_layout: SoundGroupLayout::CdXaRedundant,
+
+      allowed_filters: FilterSet::ALL,
+
+      force_params: None,
+
+      rounding: RoundingMode::Nearest,
+
+      filter_tables: FilterTables::STANDARD,
+
+      state_resets: Vec::new(),
+      next_reset: 0,
+      position: 0,
+
+      clipped_samples: 0,
+    }
+  }
+
+  /// Fresh state for encoding a stream from its first sample.
+  pub fn with_options(options: EncodeOptions) -> Self {
+    let mut state_resets = options.state_resets;
+    state_resets.sort_unstable();
+
+    EncoderState {
+      dither: options.dither_seed.map(Dither::new),
+      tail_mode: options.tail_mode,
+      peak_headroom_pct: options.peak_headroom_pct,
+      sector_padding: options.sector_padding,
+      sound_group_layout: options.sound_group_layout,
+      allowed_filters: options.allowed_filters,
+      force_params: options.force_params,
+      rounding: options.rounding,
+      filter_tables: options.filter_tables,
+      state_resets,
+      ..Self::new()
+    }
+  }
+
+  /// Clears the predictor, filter, noise shaper and quantizer history, as if encoding started over
+  /// at the next sound unit. Settings, dither and counters are kept.
+  fn reset_filters(&mut self) {
+    self.predictor_delayed_1 = 0;
+    self.predictor_delayed_2 = 0;
+    self.encoder_delayed_1 = 0;
+    self.encoder_delayed_2 = 0;
+    self.noise_shaper_delayed_1 = 0;
+    self.noise_shaper_delayed_2 = 0;
+    self.noise_shaper_output = 0;
+    self.quantizer_input = 0;
+    self.quantizer_output = 0;
+  }
+
+  /// Samples so far that the quantizer had to clamp to the 4-bit range by more than one step.
+  /// Overshooting by a single step is left out, since range selection allows for it as rounding.
+  /// Larger overshoots happen when noise shaper feedback pushes a sample past the unit's peak,
+  /// typically on loud low-frequency or full-scale input.
+  pub fn clipped_samples(&self) -> usize {
+    self.clipped_samples
+  }
+}
+
+impl Default for EncoderState {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Encoder settings that apply to every sound unit and sector.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncodeOptions {
+  /// Seed for TPDF dither added ahead of the quantizer. The same seed always gives the same output;
+  /// `None` encodes without dither.
+  pub dither_seed: Option<u64>,
+  /// How samples past the end of the input are filled in
+  pub tail_mode: TailMode,
+  /// Margin added to each unit's predicted peak before its range is picked, in percent. The encode
+  /// pass also subtracts noise shaper feedback, so it can overshoot the predicted peak and clip;
+  /// headroom trades a little resolution for fewer clipped samples. 0 sizes the range exactly.
+  pub peak_headroom_pct: u32,
+  /// What follows the sound groups of each XA sector
+  pub sector_padding: SectorPadding,
+  /// How each XA sound group's header holds its sound parameters. Only the default layout decodes
+  /// with `adpcm_decoder`.
+  pub sound_group_layout: SoundGroupLayout,
+  /// Filters the encoder may pick for each sound unit
+  pub allowed_filters: FilterSet,
+  /// Debugging aid: `(filter, range)` to write for every sound unit instead of searching for the
+  /// best fit. The quantizer and noise shaper still run with them, so the output shows how a
+  /// decoder handles each parameter pair. Values past filter 3 or range 12 are clamped; not meant
+  /// for real encodes.
+  pub force_params: Option<(u8, u8)>,
+  /// How fixed-point filter and quantizer results are rounded
+  pub rounding: RoundingMode,
+  /// Filter coefficients for both the peak prediction and the encode itself
+  pub filter_tables: FilterTables,
+  /// Sample positions at which the filter and noise shaper state starts over, taking effect at the
+  /// first sound unit that starts at or after each one. That unit always uses filter 0, even if
+  /// `allowed_filters` leaves it out, so the decoder starts over too: digital silence from there on
+  /// decodes to exact zeros whatever came before. Positions count from the first sample encoded, so
+  /// they only line up for a single sequential pass over the input.
+  pub state_resets: Vec<usize>,
+}
+
+/// Set of XA ADPCM filters, one bit per filter index. Some simple decoders only handle filters 0
+/// and 1 reliably.
+///
+/// `EncoderConfig` rejects an empty set; the encoder itself falls back to filter 0 if given one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterSet(u8);
+
+impl FilterSet {
+  pub const ALL: FilterSet = FilterSet((1 << XA_ADPCM_FILTER_COUNT) - 1);
+
+  /// Builds a set from a bitmask with bit `n` set for filter `n`. Bits past the last filter are
+  /// ignored.
+  pub const fn from_bits(bits: u8) -> Self {
+    FilterSet(bits & Self::ALL.0)
+  }
+
+  pub const fn bits(self) -> u8 {
+    self.0
+  }
+
+  pub const fn contains(self, filter: usize) -> bool {
+    filter < XA_ADPCM_FILTER_COUNT && self.0 & (1 << filter) != 0
+  }
+
+  pub const fn is_empty(self) -> bool {
+    self.0 == 0
+  }
+}
+
+impl Default for FilterSet {
+  fn default() -> Self {
+    Self::ALL
+  }
+}
+
+/// How samples past the end of the input are filled in, both in a final partial sound unit and in
+/// the rest of the final sector or block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TailMode {
+  /// Pad with zeros. The predictor carries real audio into the padding, so a stream that ends away
+  /// from zero leaves a step and a faint transient tail.
+  #[default]
+  Zero,
+  /// Repeat the last real sample (DC hold), so the filters see no step where the input ends
+  Hold,
+}
+
+/// How the fixed-point math rounds when it shifts a result back down: the filter products in the
+/// predictors and noise shaper, and the quantizer. PSX tools differ here, so matching another
+/// encoder byte for byte can need either.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoundingMode {
+  /// Add half a step before shifting, rounding to nearest (halves round up)
+  #[default]
+  Nearest,
+  /// Shift with no bias, which floors (rounds toward negative infinity)
+  Truncate,
+}
+
+impl RoundingMode {
+  /// Added before a right shift by `shift`
+  fn bias(self, shift: usize) -> i32 {
+    match self {
+      RoundingMode::Nearest => 1 << (shift - 1),
+      RoundingMode::Truncate => 0,
+    }
+  }
+}
+
+/// What follows the 18 sound groups of each XA sector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SectorPadding {
+  /// 0x14 zero bytes, filling the sector out to the `XA_ADPCM_SECTOR_SIZE` bytes of a CD-ROM XA
+  /// Form 2 payload
+  #[default]
+  Padded,
+  /// Nothing, for tools that expect bare `XA_ADPCM_SECTOR_DATA_SIZE`-byte blocks
+  Bare,
+}
+
+impl SectorPadding {
+  /// Bytes written per sector
+  pub const fn sector_size(self) -> usize {
+    match self {
+      SectorPadding::Padded => XA_ADPCM_SECTOR_SIZE,
+      SectorPadding::Bare => XA_ADPCM_SECTOR_DATA_SIZE,
+    }
+  }
+}
+
+/// How the 8 sound parameter bytes of an XA sound group fill its 16-byte header, for decoders that
+/// read them from somewhere other than where CD-XA puts them. `p0`-`p7` are the parameters of
+/// sound units 0-7.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SoundGroupLayout {
+  /// `p0 p1 p2 p3 p0 p1 p2 p3 p4 p5 p6 p7 p4 p5 p6 p7`, the redundant layout of the CD-XA spec
+  /// that the PlayStation's own decoder reads
+  #[default]
+  CdXaRedundant,
+  /// `p0 p1 p2 p3 p4 p5 p6 p7 p0 p1 p2 p3 p4 p5 p6 p7`: all 8 parameters in order, then again
+  Repeated,
+  /// `p0 p1 p2 p3 p4 p5 p6 p7` followed by 8 zero bytes, each parameter written once
+  Single,
+}
+
+impl SoundGroupLayout {
+  /// The 16-byte sound group header holding `p`, the parameters of units 0-7
+  pub fn sound_parameters(self, p: [u8; 8]) -> [u8; SOUND_PARAMETERS_SIZE] {
+    match self {
+      SoundGroupLayout::CdXaRedundant => [
+        p[0], p[1], p[2], p[3], p[0], p[1], p[2], p[3], p[4], p[5], p[6], p[7], p[4], p[5], p[6], p[7]
+      ],
+      SoundGroupLayout::Repeated => [
+        p[0], p[1], p[2], p[3], p[4], p[5], p[6], p[7], p[0], p[1], p[2], p[3], p[4], p[5], p[6], p[7]
+      ],
+      SoundGroupLayout::Single => [
+        p[0], p[1], p[2], p[3], p[4], p[5], p[6], p[7], 0, 0, 0, 0, 0, 0, 0, 0
+      ],
+    }
+  }
+}
+
+/// TPDF dither source: a xorshift64* generator, seeded through splitmix64 so that any seed
+/// (including 0) gives a usable state.
+#[derive(Clone)]
+pub(crate) struct Dither {
+  state: u64,
+}
+
+impl Dither {
+  pub(crate) fn new(seed: u64) -> Self {
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    Dither { state: (z ^ (z >> 31)) | 1 }
+  }
+
+  pub(crate) fn next_u64(&mut self) -> u64 {
+    self.state ^= self.state >> 12;
+    self.state ^= self.state << 25;
+    self.state ^= self.state >> 27;
+    self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+  }
+
+  /// Uniform noise in [-1/2, 1/2) quantizer LSB
+  fn rectangular(&mut self) -> i32 {
+    (self.next_u64() >> (64 - MAX_SHIFT)) as i32 - (1 << (MAX_SHIFT - 1))
+  }
+
+  /// Triangular noise in (-1, 1) quantizer LSB, the sum of two independent uniform noises
+  fn triangular(&mut self) -> i32 {
+    self.rectangular() + self.rectangular()
+  }
+}
+
+/// Filter and range chosen for one sound unit, and the peak that decided them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnitAnalysis {
+  pub filter: u8,
+  pub range: u8,
+  /// Largest residue, with its sign, left by the chosen filter across the unit
+  pub peak: i32,
+}
+
+/// Picks the filter and range for the first `SOUND_UNIT_SAMPLES` of `samples`, as the first half
+/// of `encode_sound_unit`. Advances the state's position and predictor history, and resets the
+/// filters at a state reset, but leaves the encoding filter and noise shaper alone otherwise.
+fn select_parameters(encoder_state: &mut EncoderState, samples: &[i16]) -> UnitAnalysis {
+  let mut reset = false;
+  while encoder_state.state_resets.get(encoder_state.next_reset).is_some_and(|&at| at <= encoder_state.position) {
+    encoder_state.next_reset += 1;
+    reset = true;
+  }
+  if reset {
+    encoder_state.reset_filters();
+  }
+  encoder_state.position += SOUND_UNIT_SIZE;
+
+  // ---------------------------
+  // Predictors
+
+  // The predictors determine the peak value produced by each filter pair across the sound unit.
+  // All four run in one pass over the shared delay chain.
+  let mut peaks = [0_i32; XA_ADPCM_FILTER_COUNT];
+  let mut delayed_1 = encoder_state.predictor_delayed_1;
+  let mut delayed_2 = encoder_state.predictor_delayed_2;
+  let filter_bias = encoder_state.rounding.bias(6);
+  let FilterTables { k0: filter_k0, k1: filter_k1 } = encoder_state.filter_tables;
+
+  for &sample in samples.iter().take(SOUND_UNIT_SIZE) {
+    let dry_sample = i32::from(sample);
+
+    // Filter 0 has no feedback, so its output is the dry sample
+    if dry_sample.abs() > peaks[0].abs() { peaks[0] = dry_sample; }
+
+    for filter in 1..XA_ADPCM_FILTER_COUNT {
+      // Sample and filter values are fixed-point, so we need to shift right by 6 after multiplication
+      // to renormalize the values. With `RoundingMode::Nearest`, add (1 << 5) before normalization
+      // so the normalized value is rounded to nearest rather than down.
+      let feedback = (
+        filter_k0[filter] * delayed_1 +
+        filter_k1[filter] * delayed_2 +
+        filter_bias
+      ) >> 6;
+      let sample = dry_sample - feedback;
+
+      if sample.abs() > peaks[filter].abs() { peaks[filter] = sample; }
+    }
+
+    delayed_2 = delayed_1;
+    delayed_1 = dry_sample;
+  }
+
+  encoder_state.predictor_delayed_1 = delayed_1;
+  encoder_state.predictor_delayed_2 = delayed_2;
+
+  // ---------------------------
+  // Filter and range selection
+
+  // The selected filter is the allowed one that produced the lowest peak value across the sound
+  // unit. Ties go to the lower filter index.
+  let allowed_filters = encoder_state.allowed_filters;
+  let filter = match encoder_state.force_params {
+    Some((forced, _)) => usize::from(forced).min(XA_ADPCM_FILTER_COUNT - 1),
+    // The decoder's history still holds whatever came before the reset, and filter 0 is the only
+    // one that ignores it
+    None if reset => 0,
+    None => {
+      let mut filter = (0..XA_ADPCM_FILTER_COUNT).find(|&n| allowed_filters.contains(n)).unwrap_or(0);
+      for n in filter + 1..XA_ADPCM_FILTER_COUNT {
+        if allowed_filters.contains(n) && peaks[n].abs() < peaks[filter].abs() {
+          filter = n;
+        }
+      }
+      filter
+    },
+  };
+  let lowest_peak = peaks[filter];
+
+  // Find the number of right shifts required to fit `highest_peak`, plus any headroom, in the 4-bit
+  // ADPCM sample range.
+  // A silent unit gets the maximum shift (range 0) so that residue from the noise shaper quantizes
+  // to zero instead of being amplified into audible noise.
+  let sized_peak = saturate_i32(
+    i64::from(lowest_peak) + i64::from(lowest_peak) * i64::from(encoder_state.peak_headroom_pct) / 100
+  );
+  let mut shift = 0;
+  if let Some((_, forced)) = encoder_state.force_params {
+    shift = MAX_SHIFT - usize::from(forced).min(MAX_SHIFT);
+  } else if lowest_peak == 0 {
+    shift = MAX_SHIFT;
+  } else if sized_peak > 0 { 
+    let max_peak_adpcm = i32::from(i16::MAX) >> MAX_SHIFT;
+    while shift < MAX_SHIFT && (sized_peak >> shift) > max_peak_adpcm { shift += 1; }
+  } else {
+    let min_peak_adpcm = i32::from(i16::MIN) >> MAX_SHIFT;
+    while shift < MAX_SHIFT && (sized_peak >> shift) < min_peak_adpcm { shift += 1; }
+  }
+
+  // Sample expansion algorithm for the decoder is
+  //   word_value = adpcm_value * 2^(12 - R)
+  // so range needs to be (12 - shift)
+  let range = MAX_SHIFT - shift;
+
+  UnitAnalysis {
+    filter: u8::try_from(filter).unwrap(),
+    range: u8::try_from(range).unwrap(),
+    peak: lowest_peak,
+  }
+}
+
+/// Encodes the first `SOUND_UNIT_SAMPLES` of `samples` as one sound unit, writing one 4-bit sample
+/// per byte to the low nibble of `output`. Returns the unit's sound parameter: filter in the high
+/// nibble, range in the low nibble.
+pub fn encode_sound_unit(encoder_state: &mut EncoderState, samples: &[i16], output: &mut [u8]) -> u8 {
+  let UnitAnalysis { filter, range, peak: lowest_peak } = select_parameters(encoder_state, samples);
+  let (filter, range) = (usize::from(filter), usize::from(range));
+  let filter_bias = encoder_state.rounding.bias(6);
+  let FilterTables { k0: filter_k0, k1: filter_k1 } = encoder_state.filter_tables;
+
+  // ---------------------------
+  // Encoding
+
+  let k0 = filter_k0[filter];
+  let k1 = filter_k1[filter];
+  for n in 0..SOUND_UNIT_SIZE {
+    // Process sample with selected filter
+    let dry_sample = i32::from(samples[n]);
+    let feedback = (
+      k0 * encoder_state.encoder_delayed_1 +
+      k1 * encoder_state.encoder_delayed_2 +
+      filter_bias
+    ) >> 6;
+    
+    encoder_state.encoder_delayed_2 = encoder_state.encoder_delayed_1;
+    encoder_state.encoder_delayed_1 = dry_sample;
+    let filtered_sample = dry_sample - feedback;
+
+    // Gain control. At range 12 a full 17-bit filtered sample plus noise shaper feedback no
+    // longer fits in an i32 once shifted, so gain control, quantization and noise shaping are done
+    // in i64, and only the noise shaper state is narrowed back down.
+    let gain_control_input = filtered_sample.saturating_sub(encoder_state.noise_shaper_output);
+    let gain_controlled_sample = i64::from(gain_control_input) << range;
+
+    // Dither. The quantizer LSB is (1 << MAX_SHIFT) after gain control, whatever the range. Silent
+    // units are left alone, since at range 0 a single LSB of dither would decode as loud noise.
+    let dither = match encoder_state.dither.as_mut() {
+      Some(dither) if lowest_peak != 0 => dither.triangular(),
+      _ => 0,
+    };
+    encoder_state.quantizer_input = gain_controlled_sample + i64::from(dither);
+
+    // Quantizer
+    let rounded = (encoder_state.quantizer_input + i64::from(encoder_state.rounding.bias(MAX_SHIFT))) >> MAX_SHIFT;
+    let quantizer_output = rounded.clamp(i64::from(i16::MIN) >> MAX_SHIFT, i64::from(i16::MAX) >> MAX_SHIFT);
+    // Only overshoots of more than one step count as clipping. The range is picked so the peak just
+    // fits, so rounding and noise shaper feedback push the odd sample one step past the clamp even
+    // on quiet material; that's an error of at most a step and a half, not an audible clip.
+    if (rounded - quantizer_output).abs() > 1 {
+      encoder_state.clipped_samples += 1;
+    }
+    encoder_state.quantizer_output = i32::try_from(quantizer_output).unwrap();
+    let encoded_sample = i8::try_from( encoder_state.quantizer_output ).unwrap();
+
+    // Noise shaper
+    let noise_shaper_input = ((quantizer_output << MAX_SHIFT) - encoder_state.quantizer_input) >> range;
+    encoder_state.noise_shaper_delayed_2 = encoder_state.noise_shaper_delayed_1;
+    encoder_state.noise_shaper_delayed_1 = saturate_i32(noise_shaper_input);
+    encoder_state.noise_shaper_output = saturate_i32((
+      i64::from(k0) * i64::from(encoder_state.noise_shaper_delayed_1) +
+      i64::from(k1) * i64::from(encoder_state.noise_shaper_delayed_1) +
+      i64::from(filter_bias)
+    ) >> 6);
+
+    // Write sample to output buffer
+    let encoded_byte = encoded_sample.to_be_bytes()[0];
+    output[n] = encoded_byte;
+  }
+
+  // Encode sound parameter
+  let filter_byte = u8::try_from(filter).unwrap();
+  let range_byte = u8::try_from(range).unwrap();
+
+  ((filter_byte << 4) & 0xF0) + (range_byte & 0x0F)
+}
+
+/// Picks a filter and range for each `SOUND_UNIT_SAMPLES` of `samples` as the encoder would,
+/// starting from `encoder_state`, without quantizing anything. A short final unit is zero padded.
+///
+/// Only the predictors run, so this is much lighter than an encode. `encoder_state` itself isn't
+/// changed: the units are analyzed from a copy of it, so the same state can go on to encode them
+/// and will pick the same parameters.
+pub fn analyze_units(encoder_state: &EncoderState, samples: &[i16]) -> Vec<UnitAnalysis> {
+  let mut encoder_state = encoder_state.clone();
+  samples.chunks(SOUND_UNIT_SAMPLES)
+    .map(|chunk| {
+      let mut unit = [0_i16; SOUND_UNIT_SAMPLES];
+      unit[..chunk.len()].copy_from_slice(chunk);
+      select_parameters(&mut encoder_state, &unit)
+    })
+    .collect()
+}
+
+/// Encodes one sound unit, returning its 4-bit samples (one per byte, in the low nibble) and its
+/// sound parameter, with the filter in the high nibble and the range in the low nibble. Like
+/// `encode_sound_unit`, this advances `encoder_state`, so consecutive calls encode consecutive units.
+pub fn encode_unit(encoder_state: &mut EncoderState, samples: &[i16; SOUND_UNIT_SAMPLES]) -> ([u8; SOUND_UNIT_SAMPLES], u8) {
+  let mut nibbles = [0_u8; SOUND_UNIT_SAMPLES];
+  let sound_parameter = encode_sound_unit(encoder_state, samples, &mut nibbles);
+  (nibbles, sound_parameter)
+}
+
+fn saturate_i32(value: i64) -> i32 {
+  value.clamp(i64::from(i32::MIN), i64::from(i32::MAX)) as i32
+}
+
+
+fn combine_nibbles(low: u8, high: u8) -> u8 {
+  (low & 0x0F) + ((high & 0x0F) << 4)
+}
+
+/// Encodes `SOUND_GROUP_SAMPLES` samples as one 128-byte XA sound group: 16 bytes of sound
+/// parameters, laid out as the state's `SoundGroupLayout` says, followed by the 8 sound units
+/// interleaved a nibble at a time. See `XaEncoder::encode_one_sector` for the byte layout.
+pub fn encode_sound_group(encoder_state: &mut EncoderState, samples: &[i16], output: &mut [u8]) {
+  let mut sound_units = [[0_u8; SOUND_UNIT_SAMPLES]; 8];
+  let mut p = [0_u8; 8];
+  for (unit, unit_samples) in samples[..SOUND_GROUP_SAMPLES].chunks_exact(SOUND_UNIT_SAMPLES).enumerate() {
+    (sound_units[unit], p[unit]) = encode_unit(encoder_state, unit_samples.try_into().unwrap());
+  }
+
+  output[..SOUND_PARAMETERS_SIZE].copy_from_slice(&encoder_state.sound_group_layout.sound_parameters(p));
+  for k in 0..SOUND_UNIT_SIZE {
+    for m in 0..4 {
+      output[SOUND_PARAMETERS_SIZE + 4 * k + m] = combine_nibbles(sound_units[2 * m][k], sound_units[2 * m + 1][k]);
+    }
+  }
+}
+
+/// Encodes `ADPCM_SECTOR_SAMPLES` samples as one sector: 18 sound groups, followed by 0x14 zero
+/// bytes unless the state was set up for `SectorPadding::Bare`. Returns the number of bytes written
+/// to `output`.
+pub fn encode_sector(encoder_state: &mut EncoderState, samples: &[i16], output: &mut [u8]) -> usize {
+  let groups = samples[..ADPCM_SECTOR_SAMPLES].chunks(SOUND_GROUP_SAMPLES);
+  for (group_samples, group_output) in groups.zip(output[..XA_ADPCM_SECTOR_DATA_SIZE].chunks_mut(SOUND_GROUP_SIZE)) {
+    encode_sound_group(encoder_state, group_samples, group_output);
+  }
+
+  let sector_size = encoder_state.sector_padding.sector_size();
+  output[XA_ADPCM_SECTOR_DATA_SIZE..sector_size].fill(0);
+  sector_size
+}
+
+/// Encodes `SOUND_UNIT_SAMPLES` samples as one `SPU_ADPCM_BLOCK_SIZE`-byte SPU ADPCM block with
+/// the given flags byte.
+pub fn encode_spu_block(encoder_state: &mut EncoderState, flags: u8, samples: &[i16], output: &mut [u8]) {
+  let (sound_unit, sound_parameter) = encode_unit(encoder_state, samples[..SOUND_UNIT_SAMPLES].try_into().unwrap());
+
+  output[0] = sound_parameter;
+  output[1] = flags;
+  for (byte, pair) in output[2..SPU_ADPCM_BLOCK_SIZE].iter_mut().zip(sound_unit.chunks(2)) {
+    *byte = combine_nibbles(pair[0], pair[1]);
+  }
+}
+
+/// Encodes a whole buffer of samples as XA sectors, padding the final sector as
+/// `options.tail_mode` says. Gives the same bytes as `encode_xa_adpcm` without needing `std`.
+pub fn encode_xa_adpcm_samples(samples: &[i16], options: EncodeOptions) -> Vec<u8> {
+  let sector_size = options.sector_padding.sector_size();
+  let mut encoder_state = EncoderState::with_options(options);
+  let num_sectors = sectors_for_samples(samples.len());
+  let mut output = vec![0_u8; num_sectors * sector_size];
+
+  let mut sector_samples = [0_i16; ADPCM_SECTOR_SAMPLES];
+  for (chunk, sector) in samples.chunks(ADPCM_SECTOR_SAMPLES).zip(output.chunks_mut(sector_size)) {
+    let input = padded_sector(&mut encoder_state, chunk, &mut sector_samples);
+    encode_sector(&mut encoder_state, input, sector);
+  }
+
+  output
+}
+
+/// Returns `chunk` if it's a whole sector of samples, or else `chunk` copied into `buffer` and
+/// padded out as `encoder_state.tail_mode` says.
+pub(crate) fn padded_sector<'a>(
+  encoder_state: &mut EncoderState,
+  chunk: &'a [i16],
+  buffer: &'a mut [i16; ADPCM_SECTOR_SAMPLES],
+) -> &'a [i16] {
+  if chunk.len() == ADPCM_SECTOR_SAMPLES {
+    return chunk
+  }
+
+  if let Some(&last) = chunk.last() { encoder_state.last_sample = last; }
+  let pad = match encoder_state.tail_mode {
+    TailMode::Zero => 0,
+    TailMode::Hold => encoder_state.last_sample,
+  };
+  buffer[..chunk.len()].copy_from_slice(chunk);
+  buffer[chunk.len()..].fill(pad);
+  &buffer[..]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encode_unit_picks_a_slope_filter_for_a_ramp() {
+    let ramp = |unit: i16| -> [i16; SOUND_UNIT_SAMPLES] { core::array::from_fn(|n| (unit * 28 + n as i16) * 40) };
+    let mut encoder_state = EncoderState::new();
+
+    // The first unit has no history to predict from; after it, a constant slope is best predicted
+    // by the two-tap filters, which extrapolate from the last two samples
+    encode_unit(&mut encoder_state, &ramp(0));
+    let (nibbles, sound_parameter) = encode_unit(&mut encoder_state, &ramp(1));
+    let (filter, range) = (sound_parameter >> 4, sound_parameter & 0x0F);
+    assert!(filter == 2 || filter == 3, "filter {}", filter);
+    assert!(usize::from(range) <= MAX_SHIFT);
+    assert!(nibbles.iter().all(|&nibble| nibble <= 0x0F));
+
+    let options = EncodeOptions { force_params: Some((3, 7)), ..Default::default() };
+    let (_, sound_parameter) = encode_unit(&mut EncoderState::with_options(options), &ramp(1));
+    assert_eq!((sound_parameter >> 4, sound_parameter & 0x0F), (3, 7));
+  }
+
+  #[test]
+  fn unit_analysis_matches_a_full_encode() {
+    // A decaying tone with a silent stretch, two sectors long, and a state reset partway in
+    let samples: Vec<i16> = (0..2 * ADPCM_SECTOR_SAMPLES)
+      .map(|n| if (3000..3500).contains(&n) { 0 } else { ((n as f64 * 0.07).sin() * 20_000.0 / (1.0 + n as f64 / 2000.0)) as i16 })
+      .collect();
+    let options = EncodeOptions { state_resets: vec![5000], ..Default::default() };
+    let encoder_state = EncoderState::with_options(options.clone());
+
+    let analysis = analyze_units(&encoder_state, &samples);
+    assert_eq!(analysis.len(), 2 * ADPCM_SECTOR_SAMPLES / SOUND_UNIT_SAMPLES);
+    assert!(analysis.iter().any(|unit| unit.filter > 0));
+    assert!(analysis.iter().any(|unit| unit.peak == 0 && usize::from(unit.range) == 0));
+
+    let encoded = encode_xa_adpcm_samples(&samples, options);
+    let encoded_params: Vec<u8> = encoded.chunks(XA_ADPCM_SECTOR_SIZE)
+      .flat_map(|sector| sector[..XA_ADPCM_SECTOR_DATA_SIZE].chunks(SOUND_GROUP_SIZE))
+      .flat_map(|group| (0..8).map(|unit| crate::adpcm_decoder::sound_parameter(group, unit)))
+      .collect();
+    let analyzed_params: Vec<u8> = analysis.iter().map(|unit| (unit.filter << 4) | unit.range).collect();
+    assert_eq!(analyzed_params, encoded_params);
+
+    // The state analyzed from is left as it was, so it goes on to pick the same parameters
+    let mut encoder_state = encoder_state;
+    let (_, sound_parameter) = encode_unit(&mut encoder_state, samples[..SOUND_UNIT_SAMPLES].try_into().unwrap());
+    assert_eq!(sound_parameter, analyzed_params[0]);
+  }
+
+  #[test]
+  fn sound_parameters_are_repeated() {
+    let samples: Vec<i16> = (0..SOUND_GROUP_SAMPLES).map(|n| ((n % 50) as i16 - 25) * (n as i16 / 8)).collect();
+    let mut group = [0_u8; SOUND_GROUP_SIZE];
+    encode_sound_group(&mut EncoderState::new(), &samples, &mut group);
+
+    assert_eq!(group[0..4], group[4..8]);
+    assert_eq!(group[8..12], group[12..16]);
+    assert_ne!(group[4..8], group[8..12]);
+  }
+
+  #[test]
+  fn sound_group_layouts_place_parameters() {
+    let p = [0x01, 0x12, 0x23, 0x34, 0x05, 0x16, 0x27, 0x38];
+    assert_eq!(
+      SoundGroupLayout::CdXaRedundant.sound_parameters(p),
+      [0x01, 0x12, 0x23, 0x34, 0x01, 0x12, 0x23, 0x34, 0x05, 0x16, 0x27, 0x38, 0x05, 0x16, 0x27, 0x38]
+    );
+    assert_eq!(
+      SoundGroupLayout::Repeated.sound_parameters(p),
+      [0x01, 0x12, 0x23, 0x34, 0x05, 0x16, 0x27, 0x38, 0x01, 0x12, 0x23, 0x34, 0x05, 0x16, 0x27, 0x38]
+    );
+    assert_eq!(
+      SoundGroupLayout::Single.sound_parameters(p),
+      [0x01, 0x12, 0x23, 0x34, 0x05, 0x16, 0x27, 0x38, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+
+    // Only the header changes; the sound units are encoded the same
+    let samples: Vec<i16> = (0..SOUND_GROUP_SAMPLES).map(|n| ((n % 50) as i16 - 25) * (n as i16 / 8)).collect();
+    let mut redundant = [0_u8; SOUND_GROUP_SIZE];
+    encode_sound_group(&mut EncoderState::new(), &samples, &mut redundant);
+    let options = EncodeOptions { sound_group_layout: SoundGroupLayout::Single, ..Default::default() };
+    let mut single = [0_u8; SOUND_GROUP_SIZE];
+    encode_sound_group(&mut EncoderState::with_options(options), &samples, &mut single);
+    assert_eq!(single[..8], redundant[4..12]);
+    assert_eq!(single[8..SOUND_PARAMETERS_SIZE], [0; 8]);
+    assert_eq!(single[SOUND_PARAMETERS_SIZE..], redundant[SOUND_PARAMETERS_SIZE..]);
+  }
+
+  #[test]
+  fn buffer_encode_pads_final_sector() {
+    let samples = vec![1000_i16; ADPCM_SECTOR_SAMPLES + 10];
+
+    let zero = encode_xa_adpcm_samples(&samples, EncodeOptions::default());
+    let hold = encode_xa_adpcm_samples(&samples, EncodeOptions { tail_mode: TailMode::Hold, ..Default::default() });
+    assert_eq!(zero.len(), 2 * XA_ADPCM_SECTOR_SIZE);
+    assert_eq!(zero[..XA_ADPCM_SECTOR_SIZE], hold[..XA_ADPCM_SECTOR_SIZE]);
+    assert_ne!(zero[XA_ADPCM_SECTOR_SIZE..], hold[XA_ADPCM_SECTOR_SIZE..]);
+    assert!(zero[2 * XA_ADPCM_SECTOR_SIZE - 0x14..].iter().all(|&byte| byte == 0));
+  }
+
+  #[test]
+  fn standard_filter_tables_match_the_built_in_encode() {
+    let samples: Vec<i16> = (0..ADPCM_SECTOR_SAMPLES).map(|n| ((n % 300) as i16 - 150) * 60).collect();
+
+    let built_in = encode_xa_adpcm_samples(&samples, EncodeOptions::default());
+    let standard = FilterTables { k0: FILTER_K0, k1: FILTER_K1 };
+    let overridden = encode_xa_adpcm_samples(&samples, EncodeOptions { filter_tables: standard, ..Default::default() });
+    assert_eq!(built_in, overridden);
+
+    let tweaked = FilterTables { k0: [0, 60, 112, 98], k1: [0, 0, -50, -55] };
+    assert_ne!(built_in, encode_xa_adpcm_samples(&samples, EncodeOptions { filter_tables: tweaked, ..Default::default() }));
+  }
+
+  #[test]
+  fn forced_filter_0_is_written_to_every_unit() {
+    let samples: Vec<i16> = (0..ADPCM_SECTOR_SAMPLES * 2).map(|n| ((n % 300) as i16 - 150) * 60).collect();
+
+    let all = encode_xa_adpcm_samples(&samples, EncodeOptions::default());
+    let only_0 = encode_xa_adpcm_samples(&samples, EncodeOptions { allowed_filters: FilterSet::from_bits(0b0001), ..Default::default() });
+    let filters = |encoded: &[u8]| -> Vec<u8> {
+      encoded.chunks(XA_ADPCM_SECTOR_SIZE)
+        .flat_map(|sector| sector[..XA_ADPCM_SECTOR_DATA_SIZE].chunks(SOUND_GROUP_SIZE))
+        .flat_map(|group| group[..SOUND_PARAMETERS_SIZE].iter().map(|&parameter| parameter >> 4).collect::<Vec<_>>())
+        .collect()
+    };
+
+    assert!(filters(&all).iter().any(|&filter| filter != 0));
+    assert!(filters(&only_0).iter().all(|&filter| filter == 0));
+
+    // Filters 2 and 3 only: the smooth ramps still pick one of them for every unit
+    let only_2_3 = encode_xa_adpcm_samples(&samples, EncodeOptions { allowed_filters: FilterSet::from_bits(0b1100), ..Default::default() });
+    assert!(filters(&only_2_3).iter().all(|&filter| filter == 2 || filter == 3));
+  }
+
+  #[test]
+  fn forced_params_are_written_to_every_unit() {
+    // Loud ramps with a silent stretch, which the search would give a mix of filters and ranges
+    let samples: Vec<i16> = (0..ADPCM_SECTOR_SAMPLES * 2)
+      .map(|n| if n % 1000 < 200 { 0 } else { ((n % 300) as i16 - 150) * 60 })
+      .collect();
+
+    let encoded = encode_xa_adpcm_samples(&samples, EncodeOptions { force_params: Some((2, 9)), ..Default::default() });
+    let parameters: Vec<u8> = encoded.chunks(XA_ADPCM_SECTOR_SIZE)
+      .flat_map(|sector| sector[..XA_ADPCM_SECTOR_DATA_SIZE].chunks(SOUND_GROUP_SIZE))
+      .flat_map(|group| group[..SOUND_PARAMETERS_SIZE].to_vec())
+      .collect();
+    assert!(parameters.iter().all(|&parameter| parameter == (2 << 4) | 9));
+  }
+
+  #[test]
+  fn rounding_mode_changes_the_encode() {
+    use crate::adpcm_decoder::{decode_sector, DecoderState};
+
+    // Triangle wave with a little wobble, so plenty of values land between quantizer steps
+    let samples: Vec<i16> = (0..ADPCM_SECTOR_SAMPLES)
+      .map(|n| ((n % 200) as i16 - 100).abs() * 80 - 4000 + (n % 7) as i16)
+      .collect();
+    let encode = |rounding| encode_xa_adpcm_samples(&samples, EncodeOptions { rounding, ..Default::default() });
+
+    let nearest = encode(RoundingMode::Nearest);
+    let truncate = encode(RoundingMode::Truncate);
+    assert_eq!(nearest, encode_xa_adpcm_samples(&samples, EncodeOptions::default()));
+    assert_ne!(nearest, truncate);
+
+    // Either way the encode decodes close to the source
+    for encoded in [nearest, truncate] {
+      let mut decoded = vec![0_i16; ADPCM_SECTOR_SAMPLES];
+      decode_sector(&mut DecoderState::new(), &encoded, &mut decoded);
+      let max_error = samples.iter().zip(&decoded).map(|(&a, &b)| (i32::from(a) - i32::from(b)).abs()).max().unwrap();
+      assert!(max_error < 1000, "max error {}", max_error);
+    }
+  }
+
+  #[test]
+  fn bare_sectors_drop_only_the_pad() {
+    let samples: Vec<i16> = (0..ADPCM_SECTOR_SAMPLES * 3 - 100).map(|n| ((n % 300) as i16 - 150) * 60).collect();
+
+    let padded = encode_xa_adpcm_samples(&samples, EncodeOptions::default());
+    let bare = encode_xa_adpcm_samples(&samples, EncodeOptions { sector_padding: SectorPadding::Bare, ..Default::default() });
+    assert_eq!(padded.len() - bare.len(), 0x14 * 3);
+    for (padded, bare) in padded.chunks(XA_ADPCM_SECTOR_SIZE).zip(bare.chunks(XA_ADPCM_SECTOR_DATA_SIZE)) {
+      assert_eq!(&padded[..XA_ADPCM_SECTOR_DATA_SIZE], bare);
+    }
+  }
+}
@@ -0,0 +1,351 @@
+use crate::adpcm_core::{
+  FILTER_K0,
+  FILTER_K1,
+  SOUND_GROUP_SAMPLES,
+  SOUND_GROUP_SIZE,
+  SOUND_GROUPS_PER_SECTOR,
+  SOUND_PARAMETERS_SIZE,
+  SOUND_UNIT_SAMPLES,
+};
+#[cfg(feature = "std")]
+use crate::{adpcm_core::{ADPCM_SECTOR_SAMPLES, XA_ADPCM_SECTOR_SIZE}, error::XaError};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub struct DecoderState {
+  delayed_1: i32,
+  delayed_2: i32,
+}
+
+impl DecoderState {
+  pub fn new() -> Self {
+    DecoderState { delayed_1: 0, delayed_2: 0 }
+  }
+}
+
+impl Default for DecoderState {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Sound parameter byte of `unit` (0-7) of a sound group: filter in the high nibble, range in the
+/// low nibble. The 16-byte header holds units 0-3, 0-3 again, 4-7 and 4-7 again; this reads the
+/// middle 8 bytes, which cover each unit once.
+pub fn sound_parameter(group: &[u8], unit: usize) -> u8 {
+  group[4 + unit]
+}
+
+/// Encoded 4-bit sample `n` of `unit` (0-7) of a sound group. The samples follow the header as 28
+/// 4-byte words, one per sample index; byte `m` of word `n` holds unit `2 * m` in its low nibble
+/// and unit `2 * m + 1` in its high nibble.
+pub fn sound_unit_nibble(group: &[u8], unit: usize, n: usize) -> u8 {
+  let byte = group[SOUND_PARAMETERS_SIZE + n * 4 + unit / 2];
+  if unit.is_multiple_of(2) { byte & 0x0F } else { byte >> 4 }
+}
+
+fn decode_sound_unit(decoder_state: &mut DecoderState, group: &[u8], unit: usize, output: &mut [i16]) {
+  let sound_parameter = sound_parameter(group, unit);
+  let filter = usize::from(sound_parameter >> 4) % FILTER_K0.len();
+  let range = u32::from(sound_parameter & 0x0F);
+
+  let k0 = FILTER_K0[filter];
+  let k1 = FILTER_K1[filter];
+
+  for (n, sample) in output.iter_mut().enumerate().take(SOUND_UNIT_SAMPLES) {
+    let nibble = sound_unit_nibble(group, unit, n);
+
+    // Sign-extend the 4-bit sample into the top of a 16-bit word, then shift it back down by range
+    let expanded = (i32::from((nibble << 4) as i8) << 8) >> range;
+    let feedback = (
+      k0 * decoder_state.delayed_1 +
+      k1 * decoder_state.delayed_2 +
+      (1 << 5)
+    ) >> 6;
+    let decoded = (expanded + feedback).clamp(i32::from(i16::MIN), i32::from(i16::MAX));
+
+    decoder_state.delayed_2 = decoder_state.delayed_1;
+    decoder_state.delayed_1 = decoded;
+    *sample = decoded as i16;
+  }
+}
+
+fn decode_sound_group(decoder_state: &mut DecoderState, group: &[u8], output: &mut [i16]) {
+  for (unit, unit_output) in output.chunks_mut(SOUND_UNIT_SAMPLES).enumerate() {
+    decode_sound_unit(decoder_state, group, unit, unit_output);
+  }
+}
+
+/// Decodes the 18 sound groups at the start of `sector` into `ADPCM_SECTOR_SAMPLES` samples.
+///
+/// Any bytes after the sound groups (e.g. the trailing 0x14 zero pad) are ignored.
+pub fn decode_sector(decoder_state: &mut DecoderState, sector: &[u8], output: &mut [i16]) {
+  let groups = sector.chunks(SOUND_GROUP_SIZE).take(SOUND_GROUPS_PER_SECTOR);
+  for (group, group_output) in groups.zip(output.chunks_mut(SOUND_GROUP_SAMPLES)) {
+    decode_sound_group(decoder_state, group, group_output);
+  }
+}
+
+/// Decodes XA sectors from a `Read` source one at a time, so long streams needn't be held in
+/// memory. Decoder state carries over from sector to sector, so the samples match decoding the
+/// whole stream at once.
+#[cfg(feature = "std")]
+pub struct XaDecoder<R: Read> {
+  decoder_state: DecoderState,
+  input: R,
+  sector: Vec<u8>,
+  skip_blank_sectors: bool,
+  sectors_read: usize,
+  leading_blank_sectors: usize,
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> XaDecoder<R> {
+  /// Decodes `XA_ADPCM_SECTOR_SIZE`-byte sectors from `input`.
+  pub fn new(input: R) -> Self {
+    Self::with_sector_size(input, XA_ADPCM_SECTOR_SIZE)
+  }
+
+  /// Decodes `sector_size`-byte sectors from `input`, e.g. 0x900 for sectors without the trailing
+  /// pad. Anything after the 18 sound groups of each sector is ignored.
+  pub fn with_sector_size(input: R, sector_size: usize) -> Self {
+    XaDecoder {
+      decoder_state: DecoderState::new(),
+      input,
+      sector: vec![0; sector_size],
+      skip_blank_sectors: false,
+      sectors_read: 0,
+      leading_blank_sectors: 0,
+    }
+  }
+
+  /// Skip sectors that are entirely zero at the start of the stream, such as blank lead-in sectors
+  /// added by another tool. Blank sectors after the first non-blank one are always decoded.
+  pub fn skip_blank_sectors(mut self, skip: bool) -> Self {
+    self.skip_blank_sectors = skip;
+    self
+  }
+
+  /// Whole sectors read from the input so far, including skipped ones.
+  pub fn sectors_read(&self) -> usize {
+    self.sectors_read
+  }
+
+  /// All-zero sectors found at the start of the stream so far, whether or not they were skipped.
+  pub fn leading_blank_sectors(&self) -> usize {
+    self.leading_blank_sectors
+  }
+
+  /// Reads the next sector and decodes it into the first `ADPCM_SECTOR_SAMPLES` samples of
+  /// `output`. Returns `false`, leaving `output` alone, once the input ends on a sector boundary.
+  pub fn decode_one_sector(&mut self, output: &mut [i16]) -> Result<bool, XaError> {
+    loop {
+      if !self.read_sector()? {
+        return Ok(false)
+      }
+
+      let is_blank = self.sector.iter().all(|&byte| byte == 0);
+      if is_blank && self.leading_blank_sectors == self.sectors_read - 1 {
+        self.leading_blank_sectors += 1;
+        if self.skip_blank_sectors { continue; }
+      }
+
+      decode_sector(&mut self.decoder_state, &self.sector, output);
+      return Ok(true)
+    }
+  }
+
+  /// Fills `sector` from the input. Returns `false` if the input had already ended.
+  fn read_sector(&mut self) -> Result<bool, XaError> {
+    let mut filled = 0;
+    while filled < self.sector.len() {
+      match self.input.read(&mut self.sector[filled..]) {
+        Ok(0) => break,
+        Ok(count) => filled += count,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+        Err(e) => return Err(e.into()),
+      }
+    }
+
+    match filled {
+      0 => Ok(false),
+      n if n == self.sector.len() => {
+        self.sectors_read += 1;
+        Ok(true)
+      },
+      read => Err(XaError::TruncatedSector { read, sector_size: self.sector.len() }),
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read + Seek> XaDecoder<R> {
+  /// Moves to the sector holding `sample`, counted from the first sector of the stream (skipped
+  /// blank sectors included), so the next `decode_one_sector` decodes that sector. Returns where
+  /// `sample` falls in its output.
+  ///
+  /// The stream is taken to start where the input was when the decoder was created. Decoder state
+  /// starts over at the sector, as it does at the start of the stream, rather than carrying on from
+  /// the sector before. Units using filters 1-3 at the start of the sector then decode slightly
+  /// differently from a decode of the whole stream, until the difference dies away in the filters.
+  pub fn seek_to_sample(&mut self, sample: usize) -> Result<usize, XaError> {
+    let sector_size = self.sector.len() as u64;
+    let start = self.input.stream_position()? - self.sectors_read as u64 * sector_size;
+    let sector = sample / ADPCM_SECTOR_SAMPLES;
+    self.input.seek(SeekFrom::Start(start + sector as u64 * sector_size))?;
+
+    self.decoder_state = DecoderState::new();
+    self.sectors_read = sector;
+    // Only blank sectors from the start of the stream count, so seeking back into them counts them
+    // again from there
+    self.leading_blank_sectors = self.leading_blank_sectors.min(sector);
+
+    Ok(sample % ADPCM_SECTOR_SAMPLES)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::adpcm_core::{encode_xa_adpcm_samples, ADPCM_SECTOR_SAMPLES, XA_ADPCM_SECTOR_SIZE};
+
+  #[test]
+  fn sound_group_interleave_is_pinned() {
+    use crate::adpcm_core::{encode_sound_group, EncodeOptions, EncoderState, FilterSet};
+
+    // With only filter 0, unit `u` of samples that are multiples of `1 << (11 - u)` reaching -8 of
+    // them gets range `u + 1` and quantizes exactly, so every nibble is known: sample `k` of unit
+    // `u` encodes `(3u + k) % 16 - 8`
+    let nibble = |unit: usize, k: usize| ((3 * unit + k) % 16) as i16 - 8;
+    let samples: Vec<i16> = (0..SOUND_GROUP_SAMPLES)
+      .map(|n| nibble(n / SOUND_UNIT_SAMPLES, n % SOUND_UNIT_SAMPLES) << (11 - n / SOUND_UNIT_SAMPLES))
+      .collect();
+    let mut state = EncoderState::with_options(EncodeOptions { allowed_filters: FilterSet::from_bits(0b0001), ..Default::default() });
+    let mut group = [0_u8; SOUND_GROUP_SIZE];
+    encode_sound_group(&mut state, &samples, &mut group);
+
+    assert_eq!(group[..SOUND_PARAMETERS_SIZE], [1, 2, 3, 4, 1, 2, 3, 4, 5, 6, 7, 8, 5, 6, 7, 8]);
+    for unit in 0..8 {
+      assert_eq!(sound_parameter(&group, unit), unit as u8 + 1);
+      for k in 0..SOUND_UNIT_SAMPLES {
+        assert_eq!(sound_unit_nibble(&group, unit, k), (nibble(unit, k) & 0x0F) as u8, "unit {} sample {}", unit, k);
+      }
+    }
+    // Sample 1 of units 0-7 encodes 9, C, F, 2, 5, 8, B, E, odd units in the high nibbles
+    assert_eq!(group[SOUND_PARAMETERS_SIZE + 4..SOUND_PARAMETERS_SIZE + 8], [0xC9, 0x2F, 0x85, 0xEB]);
+
+    let mut decoded = [0_i16; SOUND_GROUP_SAMPLES];
+    decode_sound_group(&mut DecoderState::new(), &group, &mut decoded);
+    assert_eq!(decoded[..], samples[..]);
+  }
+
+  #[test]
+  fn decode_encoded_sine() {
+    let samples: Vec<i16> = (0..ADPCM_SECTOR_SAMPLES)
+      .map(|n| (((n as f64) * 0.05).sin() * 12_000.0) as i16)
+      .collect();
+
+    let sector = encode_xa_adpcm_samples(&samples, Default::default());
+    assert_eq!(sector.len(), XA_ADPCM_SECTOR_SIZE);
+
+    let mut decoded = vec![0_i16; ADPCM_SECTOR_SAMPLES];
+    decode_sector(&mut DecoderState::new(), &sector, &mut decoded);
+
+    let max_error = samples.iter()
+      .zip(decoded.iter())
+      .map(|(&a, &b)| (i32::from(a) - i32::from(b)).abs())
+      .max()
+      .unwrap();
+    assert!(max_error < 1000, "max error {}", max_error);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn stream_decode_of_encoded_ramp() {
+    use crate::adpcm_encoder::XaEncoder;
+    use std::io::Write;
+
+    // A sawtooth of rising ramps
+    let samples: Vec<i16> = (0..3 * ADPCM_SECTOR_SAMPLES - 500).map(|n| ((n % 1008) * 16) as i16 - 8000).collect();
+    let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    let mut encoder = XaEncoder::new(vec![0; 2 * XA_ADPCM_SECTOR_SIZE]);
+    encoder.write_all(&pcm).unwrap();
+    let stream = encoder.finish().unwrap();
+
+    let mut decoder = XaDecoder::new(stream.as_slice()).skip_blank_sectors(true);
+    let mut decoded = Vec::new();
+    let mut sector = [0_i16; ADPCM_SECTOR_SAMPLES];
+    while decoder.decode_one_sector(&mut sector).unwrap() {
+      decoded.extend_from_slice(&sector);
+    }
+    assert_eq!(decoder.sectors_read(), 5);
+    assert_eq!(decoder.leading_blank_sectors(), 2);
+    assert_eq!(decoded.len(), 3 * ADPCM_SECTOR_SAMPLES);
+
+    // Same as decoding the sectors in one go with a single decoder state
+    let mut whole = vec![0_i16; 3 * ADPCM_SECTOR_SAMPLES];
+    let mut state = DecoderState::new();
+    for (sector, output) in stream[2 * XA_ADPCM_SECTOR_SIZE..].chunks(XA_ADPCM_SECTOR_SIZE).zip(whole.chunks_mut(ADPCM_SECTOR_SAMPLES)) {
+      decode_sector(&mut state, sector, output);
+    }
+    assert_eq!(decoded, whole);
+
+    let max_error = samples.iter()
+      .zip(&decoded)
+      .map(|(&a, &b)| (i32::from(a) - i32::from(b)).abs())
+      .max()
+      .unwrap();
+    assert!(max_error < 1000, "max error {}", max_error);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn stream_decode_rejects_partial_sector() {
+    let stream = vec![0_u8; XA_ADPCM_SECTOR_SIZE + 100];
+    let mut decoder = XaDecoder::new(stream.as_slice());
+    let mut sector = [0_i16; ADPCM_SECTOR_SAMPLES];
+
+    assert!(decoder.decode_one_sector(&mut sector).unwrap());
+    let err = decoder.decode_one_sector(&mut sector).unwrap_err();
+    assert!(matches!(err, XaError::TruncatedSector { read: 100, sector_size: XA_ADPCM_SECTOR_SIZE }));
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn seek_to_sample_matches_full_decode() {
+    use std::io::Cursor;
+
+    let samples: Vec<i16> = (0..3 * ADPCM_SECTOR_SAMPLES)
+      .map(|n| (((n as f64) * 0.05).sin() * 12_000.0) as i16)
+      .collect();
+    // Behind a header, as in an APCM AIFF file
+    let mut stream = vec![0xAA; 54];
+    stream.extend(encode_xa_adpcm_samples(&samples, Default::default()));
+
+    let mut whole = vec![0_i16; 3 * ADPCM_SECTOR_SAMPLES];
+    let mut state = DecoderState::new();
+    for (sector, output) in stream[54..].chunks(XA_ADPCM_SECTOR_SIZE).zip(whole.chunks_mut(ADPCM_SECTOR_SAMPLES)) {
+      decode_sector(&mut state, sector, output);
+    }
+
+    let mut rdr = Cursor::new(stream);
+    rdr.set_position(54);
+    let mut decoder = XaDecoder::new(rdr);
+    let mut sector = [0_i16; ADPCM_SECTOR_SAMPLES];
+    // Read ahead first, so the seek goes back
+    while decoder.decode_one_sector(&mut sector).unwrap() {}
+
+    let index = decoder.seek_to_sample(5000).unwrap();
+    assert_eq!(index, 5000 - ADPCM_SECTOR_SAMPLES);
+    assert!(decoder.decode_one_sector(&mut sector).unwrap());
+    assert_eq!(decoder.sectors_read(), 2);
+    assert!((i32::from(sector[index]) - i32::from(whole[5000])).abs() < 64);
+    // The predictor restart only disturbs the start of the sector
+    assert_eq!(sector[ADPCM_SECTOR_SAMPLES - 100..], whole[2 * ADPCM_SECTOR_SAMPLES - 100..2 * ADPCM_SECTOR_SAMPLES]);
+
+    // Seeking to the start is exact
+    assert_eq!(decoder.seek_to_sample(0).unwrap(), 0);
+    assert!(decoder.decode_one_sector(&mut sector).unwrap());
+    assert_eq!(sector[..], whole[..ADPCM_SECTOR_SAMPLES]);
+  }
+}
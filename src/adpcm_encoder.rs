@@ -1,281 +1,1162 @@
-use anyhow::Result;
-use byteorder::{LE, ReadBytesExt, WriteBytesExt};
-use std::io::{Read, Write};
-
-// The XA ADPCM specification defines four pairs of filter values (K0, K1) as:
-// Filter 0 = (0,         0)
-// Filter 1 = (0.9375,    0)
-// Filter 2 = (1.796875, -0.8125)
-// Filter 3 = (1.53125,  -0.859375)
-//
-// 16-bit WAV PCM samples use a fixed-point encoding with the 6 least significant bits representing
-// the decimal portion. Filter values for this encoding are derived via
-//   fixed_point_filter = xa_adpcm_filter * 2^6
-// and stored in these tables:
-const XA_ADPCM_FILTER_COUNT: usize = 4;
-const FILTER_K0: [i32; XA_ADPCM_FILTER_COUNT] = [0, 60, 115, 98];
-const FILTER_K1: [i32; XA_ADPCM_FILTER_COUNT] = [0, 0, -52, -55];
+use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use std::{
+  cmp::min,
+  io::{self, Read, Write},
+  path::Path,
+  str::FromStr,
+};
 
-// XA ADPCM samples are stored as 4-bits, and the decoder expands them to 16-bit samples by left
-// shifting by the number of bits specified in sample unit's sound parameter. The maximum number
-// of bits a sample can be shifted by is (16 - 4) = 12.
-const MAX_SHIFT: usize = 12;
+use crate::{
+  adpcm_core,
+  adpcm_decoder::{decode_sector, DecoderState},
+  stats::{snr_db_of, EncodeStats, SnrWeighting, WeightingFilter},
+};
 
+pub use crate::adpcm_core::{
+  analyze_units,
+  encode_xa_adpcm_samples,
+  sectors_for_samples,
+  EncodeOptions,
+  EncoderState,
+  FilterSet,
+  FilterTables,
+  RoundingMode,
+  SectorPadding,
+  SoundGroupLayout,
+  TailMode,
+  UnitAnalysis,
+  ADPCM_SECTOR_SAMPLES,
+  FILTER_COEFFICIENT_RANGE,
+  FILTER_K0,
+  FILTER_K1,
+  SOUND_GROUP_SAMPLES,
+  SOUND_UNIT_SAMPLES,
+  SPU_ADPCM_BLOCK_SIZE,
+  XA_ADPCM_SECTOR_DATA_SIZE,
+  XA_ADPCM_SECTOR_SIZE,
+};
 
-const SOUND_UNIT_SIZE: usize = 28;
+impl FromStr for TailMode {
+  type Err = anyhow::Error;
 
-const SAMPLE_MASK: i32 = 0xF;
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "zero" => Ok(TailMode::Zero),
+      "hold" => Ok(TailMode::Hold),
+      _ => Err(anyhow!("Unknown tail mode `{}`, expected `zero` or `hold`", s)),
+    }
+  }
+}
 
-pub(crate) const SOUND_UNIT_SAMPLES: usize = 28;
-pub(crate) const SOUND_GROUP_SAMPLES: usize = SOUND_UNIT_SAMPLES * 8;
-pub(crate) const ADPCM_SECTOR_SAMPLES: usize = SOUND_GROUP_SAMPLES * 18;
-pub(crate) const XA_ADPCM_SECTOR_SIZE: usize = 0x914;
+impl FromStr for RoundingMode {
+  type Err = anyhow::Error;
 
-pub struct EncoderState {
-  predictor_delayed_1: [i32; XA_ADPCM_FILTER_COUNT],
-  predictor_delayed_2: [i32; XA_ADPCM_FILTER_COUNT],
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "nearest" => Ok(RoundingMode::Nearest),
+      "truncate" => Ok(RoundingMode::Truncate),
+      _ => Err(anyhow!("Unknown rounding mode `{}`, expected `nearest` or `truncate`", s)),
+    }
+  }
+}
 
-  encoder_delayed_1: i32,
-  encoder_delayed_2: i32,
+impl FromStr for FilterSet {
+  type Err = anyhow::Error;
 
-  noise_shaper_delayed_1: i32,
-  noise_shaper_delayed_2: i32,
-  noise_shaper_output: i32,
+  /// Parses a comma-separated list of filter indices, e.g. `0,1`
+  fn from_str(s: &str) -> Result<Self> {
+    let mut bits = 0;
+    for index in s.split(',') {
+      match index.trim().parse::<u8>() {
+        Ok(filter) if filter < 4 => bits |= 1 << filter,
+        _ => return Err(anyhow!("Invalid filter `{}` in `{}`, expected indices 0-3 separated by commas", index, s)),
+      }
+    }
 
-  quantizer_input: i32,
-  quantizer_output: i32,
+    Ok(FilterSet::from_bits(bits))
+  }
 }
 
-impl EncoderState {
-  fn new() -> Self {
-    EncoderState {
-      predictor_delayed_1: [0; XA_ADPCM_FILTER_COUNT],
-      predictor_delayed_2: [0; XA_ADPCM_FILTER_COUNT],
+impl FromStr for SectorPadding {
+  type Err = anyhow::Error;
 
-      encoder_delayed_1: 0,
-      encoder_delayed_2: 0,
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "padded" => Ok(SectorPadding::Padded),
+      "bare" => Ok(SectorPadding::Bare),
+      _ => Err(anyhow!("Unknown sector padding `{}`, expected `padded` or `bare`", s)),
+    }
+  }
+}
 
-      noise_shaper_delayed_1: 0,
-      noise_shaper_delayed_2: 0,
-      noise_shaper_output: 0,
+impl FromStr for SoundGroupLayout {
+  type Err = anyhow::Error;
 
-      quantizer_input: 0,
-      quantizer_output: 0,
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "cd-xa" => Ok(SoundGroupLayout::CdXaRedundant),
+      "repeated" => Ok(SoundGroupLayout::Repeated),
+      "single" => Ok(SoundGroupLayout::Single),
+      _ => Err(anyhow!("Unknown sound group layout `{}`, expected `cd-xa`, `repeated` or `single`", s)),
     }
   }
 }
 
-fn encode_sound_unit(encoder_state: &mut EncoderState, samples: &[i16], output: &mut [u8]) -> u8 {
-  // ---------------------------
-  // Predictors
-
-  // The predictors determine the peak value produced by each filter pair across the sound unit
-  let mut peaks = [0_i32; 4];
-  for filter in 0..XA_ADPCM_FILTER_COUNT {
-    let k0 = FILTER_K0[filter];
-    let k1 = FILTER_K1[filter];
-
-    let mut delayed_1 = encoder_state.predictor_delayed_1[filter];
-    let mut delayed_2 = encoder_state.predictor_delayed_2[filter];
-
-    let mut peak: i32 = 0;
-    
-    for n in 0..SOUND_UNIT_SIZE {
-      let dry_sample = i32::from(samples[n]);
-      // Sample and filter values are fixed-point, so we need to shift right by 6 after multiplication
-      // to renormalize the values. Add (1 << 5) before normalization to ensure normalized value is
-      // rounded up rather than down.
-      let feedback = (
-        k0 * delayed_1 +
-        k1 * delayed_2 +
-        (1 << 5)
-      ) >> 6;
-      let sample = dry_sample - feedback;
-
-      if sample.abs() > peak.abs() { peak = sample; }
-      delayed_2 = delayed_1;
-      delayed_1 = dry_sample;
+
+// Fills `samples` from `rdr`, padding as `tail_mode` says once the input runs out. `last_sample`
+// tracks the most recent real sample across calls, for `TailMode::Hold`. Returns the number of
+// padding samples added; read errors other than EOF are propagated.
+//
+// Samples are read a sector's worth of bytes at a time rather than one `read_i16` each, which
+// matters once `rdr` is a boxed reader, and lets in-memory and mapped input be copied out directly.
+// A partial sample at the end of the input is dropped.
+fn fill_sample_buffer<R: Read>(samples: &mut[i16], rdr: &mut R, tail_mode: TailMode, last_sample: &mut i16) -> io::Result<usize> {
+  let mut bytes = [0_u8; ADPCM_SECTOR_SAMPLES * 2];
+  let mut padding = 0;
+  for chunk in samples.chunks_mut(ADPCM_SECTOR_SAMPLES) {
+    let bytes = &mut bytes[..chunk.len() * 2];
+    let mut filled = 0;
+    while filled < bytes.len() {
+      match rdr.read(&mut bytes[filled..]) {
+        Ok(0) => break,
+        Ok(n) => filled += n,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+        Err(e) => return Err(e),
+      }
     }
 
-    encoder_state.predictor_delayed_1[filter] = delayed_1;
-    encoder_state.predictor_delayed_2[filter] = delayed_2;
-    peaks[filter] = peak;
+    let read_samples = filled / 2;
+    for (sample, pair) in chunk.iter_mut().zip(bytes.chunks_exact(2)).take(read_samples) {
+      *sample = i16::from_le_bytes([pair[0], pair[1]]);
+    }
+    if read_samples > 0 {
+      *last_sample = chunk[read_samples - 1];
+    }
+    for sample in &mut chunk[read_samples..] {
+      padding += 1;
+      *sample = match tail_mode {
+        TailMode::Zero => 0,
+        TailMode::Hold => *last_sample,
+      };
+    }
   }
 
-  // ---------------------------
-  // Filter and range selection
+  Ok(padding)
+}
+
+fn fill_samples<R: Read>(encoder_state: &mut EncoderState, samples: &mut [i16], input: &mut R) -> io::Result<usize> {
+  fill_sample_buffer(samples, input, encoder_state.tail_mode, &mut encoder_state.last_sample)
+}
+
+// Returns the number of zero samples added and the number of bytes written
+fn encode_sector<R: Read, W: Write>(encoder_state: &mut EncoderState, input: &mut R, output: &mut W) -> Result<(usize, usize)> {
+  let mut samples = [0_i16; ADPCM_SECTOR_SAMPLES];
+  let mut sector = [0_u8; XA_ADPCM_SECTOR_SIZE];
+
+  let padding = fill_samples(encoder_state, &mut samples, input)?;
+  let sector_size = adpcm_core::encode_sector(encoder_state, &samples, &mut sector);
+  output.write_all(&sector[..sector_size])?;
+
+  Ok((padding, sector_size))
+}
+
+/// Encodes `samples_count` samples from `input` as XA sectors. Returns the number of zero samples
+/// added after `input` ran out, which includes the padding that fills out the final sector.
+pub fn encode_xa_adpcm<R: Read, W: Write>(samples_count: usize, input: &mut R, output: &mut W) -> Result<usize> {
+  let stats = encode_xa_adpcm_with_progress(samples_count, EncodeOptions::default(), input, output, &mut |_, _| {})?;
+  Ok(stats.padding_samples)
+}
+
+/// Encodes like `encode_xa_adpcm`, calling `progress(sectors_done, sectors_total)` after each
+/// sector is written. `progress` is always called at least once, on completion.
+///
+/// `options` sets up dither, padding of the final partial sector and range headroom. Returns the
+/// padding and clipped sample counts.
+pub fn encode_xa_adpcm_with_progress<R: Read, W: Write>(
+  samples_count: usize,
+  options: EncodeOptions,
+  input: &mut R,
+  output: &mut W,
+  progress: &mut dyn FnMut(usize, usize),
+) -> Result<EncodeStats> {
+  let mut encoder_state = EncoderState::with_options(options);
+  
+  let num_sectors = sectors_for_samples(samples_count);
+
+  let mut stats = EncodeStats::default();
+  for n in 0..num_sectors {
+    let (padding, bytes) = encode_sector(&mut encoder_state, input, output)?;
+    stats.padding_samples += padding;
+    stats.bytes_written += bytes;
+    progress(n + 1, num_sectors);
+  }
+  if num_sectors == 0 { progress(0, 0); }
+  stats.clipped_samples = encoder_state.clipped_samples();
 
-  // The selected filter is the one that produced the lowest peak value across the sound unit
-  let mut filter = 0;
-  let mut lowest_peak = i32::from(i16::MIN);
-  for n in 0..XA_ADPCM_FILTER_COUNT {
-    let peak = peaks[n];
-    if peak.abs() < lowest_peak.abs() {
-      filter = n;
-      lowest_peak = peak;
+  Ok(stats)
+}
+
+/// Encodes a slice of samples as XA sectors, writing each sector to `output` as it's encoded. Gives
+/// the same bytes as `encode_xa_adpcm_with_progress` reading the same samples, without going through
+/// little-endian bytes, and unlike `encode_xa_adpcm_samples` never holds the whole output in memory.
+pub fn write_xa_adpcm_samples<W: Write>(samples: &[i16], options: EncodeOptions, output: &mut W) -> Result<EncodeStats> {
+  let mut encoder_state = EncoderState::with_options(options);
+  let mut sector_samples = [0_i16; ADPCM_SECTOR_SAMPLES];
+  let mut sector = [0_u8; XA_ADPCM_SECTOR_SIZE];
+
+  let mut stats = EncodeStats::default();
+  for chunk in samples.chunks(ADPCM_SECTOR_SAMPLES) {
+    let input = adpcm_core::padded_sector(&mut encoder_state, chunk, &mut sector_samples);
+    let sector_size = adpcm_core::encode_sector(&mut encoder_state, input, &mut sector);
+    output.write_all(&sector[..sector_size])?;
+    stats.padding_samples += ADPCM_SECTOR_SAMPLES - chunk.len();
+    stats.bytes_written += sector_size;
+  }
+  stats.clipped_samples = encoder_state.clipped_samples();
+
+  Ok(stats)
+}
+
+/// Encodes like `encode_xa_adpcm_with_progress`, but for audio that loops back to its start.
+///
+/// The input is buffered and encoded twice. The first pass is discarded and only warms up the
+/// encoder state, so the second pass starts from the state the stream ends in. A decoder wrapping
+/// from the last sector back to the first then sees no transient. Progress counts both passes, but
+/// clipped samples are only counted in the second.
+pub fn encode_xa_adpcm_looped<R: Read, W: Write>(
+  samples_count: usize,
+  options: EncodeOptions,
+  input: &mut R,
+  output: &mut W,
+  progress: &mut dyn FnMut(usize, usize),
+) -> Result<EncodeStats> {
+  let mut pcm = Vec::with_capacity(samples_count * 2);
+  input.take((samples_count * 2) as u64).read_to_end(&mut pcm)?;
+
+  let mut encoder_state = EncoderState::with_options(options);
+  let num_sectors = sectors_for_samples(samples_count);
+
+  let mut padding = 0;
+  let mut warm_up_input = pcm.as_slice();
+  for n in 0..num_sectors {
+    padding += encode_sector(&mut encoder_state, &mut warm_up_input, &mut io::sink())?.0;
+    progress(n + 1, num_sectors * 2);
+  }
+
+  let warm_up_clipped = encoder_state.clipped_samples();
+  let mut loop_input = pcm.as_slice();
+  let mut bytes_written = 0;
+  for n in 0..num_sectors {
+    bytes_written += encode_sector(&mut encoder_state, &mut loop_input, output)?.1;
+    progress(num_sectors + n + 1, num_sectors * 2);
+  }
+  if num_sectors == 0 { progress(0, 0); }
+
+  let clipped_samples = encoder_state.clipped_samples() - warm_up_clipped;
+  Ok(EncodeStats { padding_samples: padding, clipped_samples, bytes_written, ..Default::default() })
+}
+
+/// Layout of the encoded output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+  /// 0x914-byte CD-ROM XA sectors, as written by `encode_xa_adpcm`
+  #[default]
+  Xa,
+  /// Flat stream of 16-byte SPU ADPCM blocks (`.vag` body), as written by `encode_spu_adpcm`
+  Spu,
+}
+
+impl FromStr for OutputFormat {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "xa" => Ok(OutputFormat::Xa),
+      "spu" | "vag" => Ok(OutputFormat::Spu),
+      _ => Err(anyhow!("Unknown output format `{}`, expected `xa` or `spu`", s)),
     }
   }
-  let filter = filter;
-
-  // Find the number of right shifts required to fit `highest_peak` in the 4-bit ADPCM sample range
-  let mut shift = 0;
-  if lowest_peak > 0 { 
-    let max_peak_adpcm = i32::from(i16::MAX) >> MAX_SHIFT;
-    while shift < MAX_SHIFT && (lowest_peak >> shift) > max_peak_adpcm { shift += 1; }
-  } else {
-    let min_peak_adpcm = i32::from(i16::MIN) >> MAX_SHIFT;
-    while shift < MAX_SHIFT && (lowest_peak >> shift) < min_peak_adpcm { shift += 1; }
-  }
-
-  // Sample expansion algorithm for the decoder is
-  //   word_value = adpcm_value * 2^(12 - R)
-  // so range needs to be (12 - shift)
-  let range = MAX_SHIFT - shift;
-
-  // ---------------------------
-  // Encoding
-
-  let k0 = FILTER_K0[filter];
-  let k1 = FILTER_K1[filter];
-  for n in 0..SOUND_UNIT_SIZE {
-    // Process sample with selected filter
-    let dry_sample = i32::from(samples[n]);
-    let feedback = (
-      k0 * encoder_state.encoder_delayed_1 +
-      k1 * encoder_state.encoder_delayed_2 +
-      (1 << 5)
-    ) >> 6;
-    
-    encoder_state.encoder_delayed_2 = encoder_state.encoder_delayed_1;
-    encoder_state.encoder_delayed_1 = dry_sample;
-    let filtered_sample = dry_sample - feedback;
-
-    // Gain control
-    let gain_control_input = filtered_sample - encoder_state.noise_shaper_output;
-    let gain_controlled_sample = gain_control_input << range;
-    encoder_state.quantizer_input = gain_controlled_sample;
-
-    // Quantizer
-    encoder_state.quantizer_output = (
-      (encoder_state.quantizer_input + (1 << (MAX_SHIFT - 1))) >> MAX_SHIFT
-    ).clamp(i32::from(i16::MIN) >> MAX_SHIFT, i32::from(i16::MAX) >> MAX_SHIFT);
-    let encoded_sample = i8::try_from( encoder_state.quantizer_output ).unwrap();
-
-    // Noise shaper
-    let noise_shaper_input = ((encoder_state.quantizer_output << MAX_SHIFT) - encoder_state.quantizer_input) >> range;
-    encoder_state.noise_shaper_delayed_2 = encoder_state.noise_shaper_delayed_1;
-    encoder_state.noise_shaper_delayed_1 = noise_shaper_input;
-    encoder_state.noise_shaper_output =  (
-      k0 * encoder_state.noise_shaper_delayed_1 +
-      k1 * encoder_state.noise_shaper_delayed_1 +
-      (1 << 5)
-    ) >> 6;
-
-    // Write sample to output buffer
-    let encoded_byte = encoded_sample.to_be_bytes()[0];
-    output[n] = encoded_byte;
-  }
-
-  // Encode sound parameter
-  let filter_byte = u8::try_from(filter).unwrap();
-  let range_byte = u8::try_from(range).unwrap();
-
-  ((filter_byte << 4) & 0xF0) + (range_byte & 0x0F)
 }
 
-fn fill_sample_buffer<R: Read>(samples: &mut[i16], rdr: &mut R) {
-    for n in 0..samples.len() {
-    match rdr.read_i16::<LE>() {
-      Ok(sample) => samples[n] = sample,
-      Err(_) => samples[n] = 0,
+impl OutputFormat {
+  /// Format implied by an output file's extension: `.acm`, `.aif`, `.aiff` and `.xa` for XA, `.spu`
+  /// and `.vag` for SPU. `None` for any other extension, or none.
+  pub fn from_extension(path: &Path) -> Option<Self> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+      "acm" | "aif" | "aiff" | "xa" => Some(OutputFormat::Xa),
+      "spu" | "vag" => Some(OutputFormat::Spu),
+      _ => None,
     }
   }
 }
 
-fn write_combined_bytes<W: Write>(b1: u8, b2: u8, output: &mut W) -> Result<()> {
-  let out_byte = (b1 & 0x0F) + ((b2 & 0x0F) << 4);
+// SPU ADPCM block flags
+const SPU_FLAG_LOOP_START: u8 = 0x06;
+const SPU_FLAG_LOOP_END: u8 = 0x03;
+const SPU_FLAG_END: u8 = 0x07;
+
+/// Sample range played repeatedly once playback reaches `end`. Both ends are inclusive, matching
+/// the loop points stored in WAV `smpl` chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopRegion {
+  pub start: usize,
+  pub end: usize,
+}
+
+fn spu_block_flags(block: usize, num_blocks: usize, loop_region: Option<LoopRegion>) -> u8 {
+  match loop_region {
+    Some(LoopRegion { start, end }) => {
+      let mut flags = 0;
+      if block == start / SOUND_UNIT_SAMPLES { flags |= SPU_FLAG_LOOP_START; }
+      if block == end / SOUND_UNIT_SAMPLES { flags |= SPU_FLAG_LOOP_END; }
+      flags
+    },
+    None if block == num_blocks - 1 => SPU_FLAG_END,
+    None => 0,
+  }
+}
+
+fn encode_spu_block<R: Read, W: Write>(encoder_state: &mut EncoderState, flags: u8, input: &mut R, output: &mut W) -> Result<usize> {
+  let mut samples = [0_i16; SOUND_UNIT_SAMPLES];
+  let mut block = [0_u8; SPU_ADPCM_BLOCK_SIZE];
 
-  output.write_u8(out_byte)?;
+  let padding = fill_samples(encoder_state, &mut samples, input)?;
+  adpcm_core::encode_spu_block(encoder_state, flags, &samples, &mut block);
+  output.write_all(&block)?;
 
-  Ok(())
+  Ok(padding)
 }
 
-fn encode_sound_group<R: Read, W: Write>(encoder_state: &mut EncoderState, input: &mut R, output: &mut W) -> Result<()> {
-  let mut pcm_samples = [0_i16; 28];
-  let mut sound_unit_0 = vec![0_u8; 28];
-  let mut sound_unit_1 = vec![0_u8; 28];
-  let mut sound_unit_2 = vec![0_u8; 28];
-  let mut sound_unit_3 = vec![0_u8; 28];
-  let mut sound_unit_4 = vec![0_u8; 28];
-  let mut sound_unit_5 = vec![0_u8; 28];
-  let mut sound_unit_6 = vec![0_u8; 28];
-  let mut sound_unit_7 = vec![0_u8; 28];
+/// Encodes `samples_count` samples as a flat stream of SPU ADPCM blocks, one per sound unit. Uses
+/// the same filter/range selection and quantization as the XA encoder.
+///
+/// With a `loop_region`, the block containing its start is flagged loop-start (0x06) and the block
+/// containing its end is flagged loop-end + repeat (0x03). Without one, the final block is flagged
+/// as the end of a non-looping sound (0x07).
+///
+/// Returns the padding and clipped sample counts, as `encode_xa_adpcm_with_progress` does.
+/// `progress(blocks_done, blocks_total)` is called as blocks are written, and at least once.
+pub fn encode_spu_adpcm<R: Read, W: Write>(
+  samples_count: usize,
+  loop_region: Option<LoopRegion>,
+  options: EncodeOptions,
+  input: &mut R,
+  output: &mut W,
+  progress: &mut dyn FnMut(usize, usize),
+) -> Result<EncodeStats> {
+  if let Some(LoopRegion { start, end }) = loop_region {
+    if start > end || end >= samples_count {
+      return Err(anyhow!("Invalid loop region {}..={} for {} samples", start, end, samples_count))
+    }
+  }
 
-  fill_sample_buffer(&mut pcm_samples, input);
-  let p0 = encode_sound_unit(encoder_state, &pcm_samples, &mut sound_unit_0);
+  let mut encoder_state = EncoderState::with_options(options);
 
-  fill_sample_buffer(&mut pcm_samples, input);
-  let p1 = encode_sound_unit(encoder_state, &pcm_samples, &mut sound_unit_1);
+  let num_blocks = samples_count.div_ceil(SOUND_UNIT_SAMPLES);
 
-  fill_sample_buffer(&mut pcm_samples, input);
-  let p2 = encode_sound_unit(encoder_state, &pcm_samples, &mut sound_unit_2);
+  let mut padding = 0;
+  for block in 0..num_blocks {
+    let flags = spu_block_flags(block, num_blocks, loop_region);
+    padding += encode_spu_block(&mut encoder_state, flags, input, output)?;
+    // Reporting every block would cost more than the encode itself, so report per sector's worth
+    if (block + 1) % (ADPCM_SECTOR_SAMPLES / SOUND_UNIT_SAMPLES) == 0 { progress(block + 1, num_blocks); }
+  }
+  progress(num_blocks, num_blocks);
 
-  fill_sample_buffer(&mut pcm_samples, input);
-  let p3 = encode_sound_unit(encoder_state, &pcm_samples, &mut sound_unit_3);
+  Ok(EncodeStats {
+    padding_samples: padding,
+    clipped_samples: encoder_state.clipped_samples(),
+    bytes_written: num_blocks * SPU_ADPCM_BLOCK_SIZE,
+    ..Default::default()
+  })
+}
 
-  fill_sample_buffer(&mut pcm_samples, input);
-  let p4 = encode_sound_unit(encoder_state, &pcm_samples, &mut sound_unit_4);
+/// Encodes like `encode_xa_adpcm`, additionally decoding each sector as it's written and measuring
+/// its SNR against the source samples. Only the `samples_count` real samples are measured, not the
+/// zero padding at the end of the final sector. Reports progress and dithers like
+/// `encode_xa_adpcm_with_progress`.
+pub fn encode_xa_adpcm_with_stats<R: Read, W: Write>(
+  samples_count: usize,
+  options: EncodeOptions,
+  input: &mut R,
+  output: &mut W,
+  progress: &mut dyn FnMut(usize, usize),
+) -> Result<EncodeStats> {
+  encode_xa_adpcm_with_weighted_stats(samples_count, options, SnrWeighting::Flat, 0, input, output, progress)
+}
 
-  fill_sample_buffer(&mut pcm_samples, input);
-  let p5 = encode_sound_unit(encoder_state, &pcm_samples, &mut sound_unit_5);
+/// Encodes like `encode_xa_adpcm_with_stats`, running both the source and the decoded samples
+/// through `weighting` before measuring each sector's SNR. The filters carry on from one sector to
+/// the next, so sector boundaries don't add transients. `sample_rate` places the filters' corners
+/// and is ignored for flat weighting.
+pub fn encode_xa_adpcm_with_weighted_stats<R: Read, W: Write>(
+  samples_count: usize,
+  options: EncodeOptions,
+  weighting: SnrWeighting,
+  sample_rate: u32,
+  input: &mut R,
+  output: &mut W,
+  progress: &mut dyn FnMut(usize, usize),
+) -> Result<EncodeStats> {
+  let mut encoder_state = EncoderState::with_options(options);
+  let mut decoder_state = DecoderState::new();
+  let mut stats = EncodeStats { snr_weighting: weighting, ..Default::default() };
+  let mut source_filter = WeightingFilter::new(weighting, sample_rate);
+  let mut decoded_filter = WeightingFilter::new(weighting, sample_rate);
 
-  fill_sample_buffer(&mut pcm_samples, input);
-  let p6 = encode_sound_unit(encoder_state, &pcm_samples, &mut sound_unit_6);
+  let num_sectors = sectors_for_samples(samples_count);
 
-  fill_sample_buffer(&mut pcm_samples, input);
-  let p7 = encode_sound_unit(encoder_state, &pcm_samples, &mut sound_unit_7);
+  let mut pcm = Vec::with_capacity(ADPCM_SECTOR_SAMPLES * 2);
+  let mut sector = Vec::with_capacity(XA_ADPCM_SECTOR_SIZE);
+  let mut source = vec![0_i16; ADPCM_SECTOR_SAMPLES];
+  let mut decoded = vec![0_i16; ADPCM_SECTOR_SAMPLES];
 
-  let sound_parameters = [
-    p0, p1, p2, p3, p0, p1, p2, p3, p4, p5, p6, p7, p4, p5, p6, p7
-  ];
+  for n in 0..num_sectors {
+    pcm.clear();
+    input.by_ref().take((ADPCM_SECTOR_SAMPLES * 2) as u64).read_to_end(&mut pcm)?;
+    fill_sample_buffer(&mut source, &mut pcm.as_slice(), TailMode::Zero, &mut 0)?;
 
-  output.write_all(&sound_parameters)?;
-  for k in 0..28 {
-    write_combined_bytes(sound_unit_0[k], sound_unit_1[k], output)?;
-    write_combined_bytes(sound_unit_2[k], sound_unit_3[k], output)?;
-    write_combined_bytes(sound_unit_4[k], sound_unit_5[k], output)?;
-    write_combined_bytes(sound_unit_6[k], sound_unit_7[k], output)?;
+    sector.clear();
+    let (padding, bytes) = encode_sector(&mut encoder_state, &mut pcm.as_slice(), &mut sector)?;
+    stats.padding_samples += padding;
+    stats.bytes_written += bytes;
+    output.write_all(&sector)?;
+
+    decode_sector(&mut decoder_state, &sector, &mut decoded);
+    let real_samples = min(ADPCM_SECTOR_SAMPLES, samples_count - n * ADPCM_SECTOR_SAMPLES);
+    let weighted_source = source_filter.process(&source[..real_samples]);
+    let weighted_decoded = decoded_filter.process(&decoded[..real_samples]);
+    stats.sector_snr_db.push(snr_db_of(weighted_source.into_iter().zip(weighted_decoded)));
+    progress(n + 1, num_sectors);
   }
+  if num_sectors == 0 { progress(0, 0); }
+  stats.clipped_samples = encoder_state.clipped_samples();
 
-  Ok(())
+  Ok(stats)
 }
 
-fn encode_sound_block<R: Read, W: Write>(encoder_state: &mut EncoderState, input: &mut R, output: &mut W) -> Result<()> {
-  for _ in 0..18 {
-    encode_sound_group(encoder_state, input, output)?;
-  }  
+// Number of sectors handed to the thread pool at once by `encode_xa_adpcm_parallel`, per thread.
+// Bounds memory use for long inputs while giving each thread enough work to stay busy.
+const PARALLEL_SECTORS_PER_THREAD: usize = 16;
+
+/// Encodes like `encode_xa_adpcm`, but spreads sectors across all CPU cores.
+///
+/// Encoder state is reset at the start of every sector so sectors can be encoded independently.
+/// Each sector still decodes correctly on its own, but the restart introduces a small
+/// discontinuity at every sector boundary, so the output is not identical to `encode_xa_adpcm`.
+/// Progress is reported after each batch of sectors is written, and at least once. Dither would
+/// restart with every sector too, so `options.dither_seed` should be left unset.
+pub fn encode_xa_adpcm_parallel<R: Read, W: Write>(
+  samples_count: usize,
+  options: EncodeOptions,
+  input: &mut R,
+  output: &mut W,
+  progress: &mut dyn FnMut(usize, usize),
+) -> Result<EncodeStats> {
+  let sector_bytes = ADPCM_SECTOR_SAMPLES * 2;
+  let num_sectors = sectors_for_samples(samples_count);
+  let batch_size = rayon::current_num_threads() * PARALLEL_SECTORS_PER_THREAD;
+
+  let mut sectors_done = 0;
+  let mut stats = EncodeStats::default();
+  while sectors_done < num_sectors {
+    let batch_sectors = min(batch_size, num_sectors - sectors_done);
+
+    let mut pcm_batch = Vec::with_capacity(batch_sectors);
+    for _ in 0..batch_sectors {
+      let mut pcm = Vec::with_capacity(sector_bytes);
+      input.by_ref().take(sector_bytes as u64).read_to_end(&mut pcm)?;
+      pcm_batch.push(pcm);
+    }
+
+    let encoded_batch = pcm_batch
+      .par_iter()
+      .map(|pcm| {
+        let mut encoder_state = EncoderState::with_options(options.clone());
+        let mut sector = Vec::with_capacity(XA_ADPCM_SECTOR_SIZE);
+        let (padding, _) = encode_sector(&mut encoder_state, &mut pcm.as_slice(), &mut sector)?;
+        Ok((sector, padding, encoder_state.clipped_samples()))
+      })
+      .collect::<Result<Vec<_>>>()?;
 
-  Ok(())
+    for (sector, padding, clipped) in encoded_batch {
+      output.write_all(&sector)?;
+      stats.padding_samples += padding;
+      stats.clipped_samples += clipped;
+      stats.bytes_written += sector.len();
+    }
+    sectors_done += batch_sectors;
+    progress(sectors_done, num_sectors);
+  }
+  if num_sectors == 0 { progress(0, 0); }
+
+  Ok(stats)
+}
+
+/// Streaming XA ADPCM encoder.
+///
+/// Accepts little-endian 16-bit PCM bytes through `Write` and emits a complete XA sector to the
+/// wrapped writer every time `ADPCM_SECTOR_SAMPLES` samples have accumulated. Encoder state is
+/// carried across writes, so the output is identical to encoding the whole stream at once with
+/// `encode_xa_adpcm`. Call `finish` to flush the final partial sector.
+pub struct XaEncoder<W: Write> {
+  encoder_state: EncoderState,
+  output: W,
+  // Raw PCM bytes waiting to be encoded. May end with the first half of a sample if a write split
+  // an i16 across two buffers.
+  pending: Vec<u8>,
 }
 
-fn encode_sector<R: Read, W: Write>(encoder_state: &mut EncoderState, input: &mut R, output: &mut W) -> Result<()> {
-  encode_sound_block(encoder_state, input, output)?;
+impl<W: Write> XaEncoder<W> {
+  const SECTOR_BYTES: usize = ADPCM_SECTOR_SAMPLES * 2;
 
-  let zero_pad = [0_u8; 0x14];
-  output.write_all(&zero_pad)?;
+  pub fn new(output: W) -> Self {
+    XaEncoder {
+      encoder_state: EncoderState::new(),
+      output,
+      pending: Vec::with_capacity(Self::SECTOR_BYTES),
+    }
+  }
+
+  /// Encodes exactly one `XA_ADPCM_SECTOR_SIZE`-byte sector from the next `ADPCM_SECTOR_SAMPLES`
+  /// samples of `input` and writes it to `output` rather than the wrapped writer, for containers
+  /// that add their own sector headers. Encoder state carries over from sector to sector, so
+  /// successive calls give the same bytes as `encode_xa_adpcm`. Returns the number of zero samples
+  /// added if `input` runs out.
+  ///
+  /// A sector is 18 sound groups of 128 bytes followed by 0x14 zero bytes. Each group holds 8 sound
+  /// units of 28 samples, in order:
+  ///
+  /// - 16 bytes of sound parameters, one per unit (filter in the high nibble, range in the low
+  ///   nibble), laid out as units 0-3, 0-3 again, 4-7, 4-7 again.
+  /// - 112 bytes of samples. Byte `4 * k + m` holds sample `k` of unit `2 * m` in its low nibble
+  ///   and sample `k` of unit `2 * m + 1` in its high nibble.
+  ///
+  /// Errors if bytes written through `Write` are still waiting to be encoded, since they would
+  /// otherwise end up out of order.
+  pub fn encode_one_sector<R: Read, O: Write>(&mut self, input: &mut R, output: &mut O) -> Result<usize> {
+    if !self.pending.is_empty() {
+      return Err(anyhow!("{} bytes written to the encoder haven't been encoded yet", self.pending.len()))
+    }
 
-  Ok(())
+    Ok(encode_sector(&mut self.encoder_state, input, output)?.0)
+  }
+
+  /// Zero-pads and encodes any buffered samples as a final sector, flushes the wrapped writer and
+  /// returns it.
+  pub fn finish(mut self) -> Result<W> {
+    if !self.pending.len().is_multiple_of(2) {
+      return Err(anyhow!("Input ended with half of a 16-bit sample"))
+    }
+
+    if !self.pending.is_empty() {
+      encode_sector(&mut self.encoder_state, &mut self.pending.as_slice(), &mut self.output)?;
+      self.pending.clear();
+    }
+    self.output.flush()?;
+
+    Ok(self.output)
+  }
 }
 
-pub(crate) fn encode_xa_adpcm<R: Read, W: Write>(samples_count: usize, input: &mut R, output: &mut W) -> Result<()> {
-  let mut encoder_state = EncoderState::new();
-  
-  let mut num_sectors = samples_count / ADPCM_SECTOR_SAMPLES;
-  if samples_count % ADPCM_SECTOR_SAMPLES != 0 { num_sectors += 1 }
+impl<W: Write> Write for XaEncoder<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let take = min(buf.len(), Self::SECTOR_BYTES - self.pending.len());
+    self.pending.extend_from_slice(&buf[..take]);
+
+    if self.pending.len() == Self::SECTOR_BYTES {
+      encode_sector(&mut self.encoder_state, &mut self.pending.as_slice(), &mut self.output)
+        .map_err(io::Error::other)?;
+      self.pending.clear();
+    }
+
+    Ok(take)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::adpcm_core::{encode_sound_unit, Dither, MAX_SHIFT, SOUND_GROUP_SIZE, SOUND_UNIT_SIZE};
+  use crate::adpcm_decoder::{sound_parameter, sound_unit_nibble};
+  use crate::stats::snr_db;
+
+  fn test_samples(count: usize) -> Vec<u8> {
+    (0..count)
+      .map(|n| (((n as f64) * 0.05).sin() * 12_000.0) as i16)
+      .flat_map(|sample| sample.to_le_bytes())
+      .collect()
+  }
+
+  fn encode_whole(pcm: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_xa_adpcm(pcm.len() / 2, &mut &pcm[..], &mut out).unwrap();
+    out
+  }
+
+  #[test]
+  fn buffer_encode_matches_stream_encode() {
+    let pcm = test_samples(ADPCM_SECTOR_SAMPLES * 2 + 1000);
+    let samples: Vec<i16> = pcm.chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+
+    for tail_mode in [TailMode::Zero, TailMode::Hold] {
+      let options = EncodeOptions { tail_mode, dither_seed: Some(3), ..Default::default() };
+      let mut out = Vec::new();
+      encode_xa_adpcm_with_progress(samples.len(), options.clone(), &mut &pcm[..], &mut out, &mut |_, _| {}).unwrap();
+      assert_eq!(encode_xa_adpcm_samples(&samples, options), out);
+    }
+  }
+
+  #[test]
+  fn slice_encode_matches_stream_encode() {
+    let pcm = test_samples(ADPCM_SECTOR_SAMPLES * 2 + 1000);
+    let samples: Vec<i16> = pcm.chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+
+    for (tail_mode, sector_padding) in [(TailMode::Zero, SectorPadding::Padded), (TailMode::Hold, SectorPadding::Bare)] {
+      let options = EncodeOptions { tail_mode, sector_padding, ..Default::default() };
+      let mut from_bytes = Vec::new();
+      let expected = encode_xa_adpcm_with_progress(samples.len(), options.clone(), &mut &pcm[..], &mut from_bytes, &mut |_, _| {}).unwrap();
+      let mut from_slice = Vec::new();
+      let stats = write_xa_adpcm_samples(&samples, options, &mut from_slice).unwrap();
+
+      assert_eq!(from_slice, from_bytes);
+      assert_eq!(stats, expected);
+    }
+
+    let mut out = Vec::new();
+    assert_eq!(write_xa_adpcm_samples(&[], EncodeOptions::default(), &mut out).unwrap(), EncodeStats::default());
+    assert!(out.is_empty());
+  }
+
+  #[test]
+  fn streaming_matches_whole_stream_encode() {
+    let pcm = test_samples(ADPCM_SECTOR_SAMPLES * 2 + 1000);
+
+    let mut encoder = XaEncoder::new(Vec::new());
+    for chunk in pcm.chunks(4096) {
+      encoder.write_all(chunk).unwrap();
+    }
+    let out = encoder.finish().unwrap();
+
+    assert_eq!(out.len(), 3 * XA_ADPCM_SECTOR_SIZE);
+    assert_eq!(out, encode_whole(&pcm));
+  }
+
+  #[test]
+  fn short_reads_encode_like_whole_reads() {
+    // Hands out one byte per read, so samples arrive split across reads
+    struct ByteAtATime<'a>(&'a [u8]);
+    impl Read for ByteAtATime<'_> {
+      fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.0.len()).min(1);
+        buf[..n].copy_from_slice(&self.0[..n]);
+        self.0 = &self.0[n..];
+        Ok(n)
+      }
+    }
+
+    // An odd byte count leaves a partial sample at the end, which is dropped
+    let pcm = test_samples(ADPCM_SECTOR_SAMPLES + 300);
+    let pcm = &pcm[..pcm.len() - 1];
+    let mut out = Vec::new();
+    let padding = encode_xa_adpcm(ADPCM_SECTOR_SAMPLES + 300, &mut ByteAtATime(pcm), &mut out).unwrap();
+
+    assert_eq!(out, encode_whole(pcm));
+    assert_eq!(padding, 2 * ADPCM_SECTOR_SAMPLES - (ADPCM_SECTOR_SAMPLES + 299));
+  }
+
+  #[test]
+  fn stats_count_bytes_written() {
+    let samples_count = ADPCM_SECTOR_SAMPLES * 3 + ADPCM_SECTOR_SAMPLES / 2;
+    let pcm = test_samples(samples_count);
+    let no_progress = &mut |_, _| {};
+
+    for sector_padding in [SectorPadding::Padded, SectorPadding::Bare] {
+      let options = EncodeOptions { sector_padding, ..Default::default() };
+      let encodes = [
+        encode_xa_adpcm_with_progress(samples_count, options.clone(), &mut &pcm[..], &mut Vec::new(), no_progress),
+        encode_xa_adpcm_with_stats(samples_count, options.clone(), &mut &pcm[..], &mut Vec::new(), no_progress),
+        encode_xa_adpcm_looped(samples_count, options.clone(), &mut &pcm[..], &mut Vec::new(), no_progress),
+        encode_xa_adpcm_parallel(samples_count, options.clone(), &mut &pcm[..], &mut Vec::new(), no_progress),
+      ];
+      for stats in encodes {
+        assert_eq!(stats.unwrap().bytes_written, 4 * sector_padding.sector_size());
+      }
+    }
 
-  for _ in 0..num_sectors {
-    encode_sector(&mut encoder_state, input, output)?;
+    let stats = encode_spu_adpcm(samples_count, None, EncodeOptions::default(), &mut &pcm[..], &mut Vec::new(), no_progress).unwrap();
+    assert_eq!(stats.bytes_written, samples_count.div_ceil(SOUND_UNIT_SAMPLES) * SPU_ADPCM_BLOCK_SIZE);
   }
 
-  Ok(())
+  #[test]
+  fn one_sector_at_a_time_matches_whole_stream_encode() {
+    let pcm = test_samples(ADPCM_SECTOR_SAMPLES + 100);
+
+    let mut encoder = XaEncoder::new(io::sink());
+    let mut input = pcm.as_slice();
+    let mut out = Vec::new();
+    for _ in 0..2 {
+      let mut sector = Vec::new();
+      encoder.encode_one_sector(&mut input, &mut sector).unwrap();
+      assert_eq!(sector.len(), XA_ADPCM_SECTOR_SIZE);
+      out.extend_from_slice(&sector);
+    }
+
+    assert_eq!(out, encode_whole(&pcm));
+
+    encoder.write_all(&[0x12]).unwrap();
+    assert!(encoder.encode_one_sector(&mut &pcm[..], &mut Vec::new()).is_err());
+  }
+
+  #[test]
+  fn sample_split_across_writes() {
+    let pcm = test_samples(ADPCM_SECTOR_SAMPLES + 100);
+
+    // Odd-sized chunks put half of an i16 at the end of every other write
+    let mut encoder = XaEncoder::new(Vec::new());
+    for chunk in pcm.chunks(333) {
+      encoder.write_all(chunk).unwrap();
+    }
+    let out = encoder.finish().unwrap();
+
+    assert_eq!(out, encode_whole(&pcm));
+  }
+
+  #[test]
+  fn stats_encode_matches_plain_encode() {
+    let pcm = test_samples(ADPCM_SECTOR_SAMPLES * 2 + 1000);
+
+    let mut out = Vec::new();
+    let stats = encode_xa_adpcm_with_stats(pcm.len() / 2, EncodeOptions::default(), &mut &pcm[..], &mut out, &mut |_, _| {}).unwrap();
+
+    assert_eq!(out, encode_whole(&pcm));
+    assert_eq!(stats.sector_snr_db.len(), 3);
+    assert!(stats.min_snr_db() > 20.0, "min SNR {}", stats.min_snr_db());
+  }
+
+  #[test]
+  fn spu_blocks_match_xa_sound_units() {
+    let pcm = test_samples(SOUND_GROUP_SAMPLES);
+
+    let mut spu = Vec::new();
+    encode_spu_adpcm(SOUND_GROUP_SAMPLES, None, EncodeOptions::default(), &mut &pcm[..], &mut spu, &mut |_, _| {}).unwrap();
+    assert_eq!(spu.len(), 8 * SPU_ADPCM_BLOCK_SIZE);
+
+    // Units are encoded in the same order with the same state, so each block should carry the same
+    // sound parameter and nibbles as the matching unit of the first XA sound group
+    let xa = encode_whole(&pcm);
+    for (unit, block) in spu.chunks(SPU_ADPCM_BLOCK_SIZE).enumerate() {
+      assert_eq!(block[0], xa[4 + unit]);
+      for k in 0..SOUND_UNIT_SAMPLES {
+        let spu_nibble = (block[2 + k / 2] >> (4 * (k % 2))) & 0x0F;
+        let xa_nibble = (xa[16 + k * 4 + unit / 2] >> (4 * (unit % 2))) & 0x0F;
+        assert_eq!(spu_nibble, xa_nibble, "unit {} sample {}", unit, k);
+      }
+    }
+  }
+
+  fn spu_flags(samples_count: usize, loop_region: Option<LoopRegion>) -> Vec<u8> {
+    let pcm = test_samples(samples_count);
+    let mut spu = Vec::new();
+    encode_spu_adpcm(samples_count, loop_region, EncodeOptions::default(), &mut &pcm[..], &mut spu, &mut |_, _| {}).unwrap();
+
+    spu.chunks(SPU_ADPCM_BLOCK_SIZE).map(|block| block[1]).collect()
+  }
+
+  #[test]
+  fn spu_end_flag_without_loop() {
+    assert_eq!(spu_flags(100, None), [0, 0, 0, 0x07]);
+  }
+
+  #[test]
+  fn spu_loop_flags_spanning_two_blocks() {
+    // Sample 20 is in block 0, sample 40 in block 1
+    let flags = spu_flags(100, Some(LoopRegion { start: 20, end: 40 }));
+    assert_eq!(flags, [0x06, 0x03, 0, 0]);
+
+    // Loop starting and ending inside one block carries both flags
+    let flags = spu_flags(100, Some(LoopRegion { start: 60, end: 70 }));
+    assert_eq!(flags, [0, 0, 0x07, 0]);
+
+    let pcm = test_samples(100);
+    let region = Some(LoopRegion { start: 20, end: 100 });
+    assert!(encode_spu_adpcm(100, region, EncodeOptions::default(), &mut &pcm[..], &mut Vec::new(), &mut |_, _| {}).is_err());
+  }
+
+  #[test]
+  fn sector_count_boundaries() {
+    assert_eq!(sectors_for_samples(0), 0);
+    assert_eq!(sectors_for_samples(1), 1);
+    assert_eq!(sectors_for_samples(ADPCM_SECTOR_SAMPLES), 1);
+    assert_eq!(sectors_for_samples(ADPCM_SECTOR_SAMPLES + 1), 2);
+  }
+
+  /// Largest difference between `samples` and the second of two back-to-back plays of `xa`, over
+  /// the first sound unit after the wrap
+  fn wrap_error(xa: &[u8], samples: &[i16]) -> i32 {
+    let mut looped = xa.to_vec();
+    looped.extend_from_slice(xa);
+    let decoded = decode_all(&looped);
+
+    let wrapped = &decoded[xa.len() / XA_ADPCM_SECTOR_SIZE * ADPCM_SECTOR_SAMPLES..];
+    samples.iter()
+      .zip(wrapped)
+      .take(SOUND_UNIT_SAMPLES)
+      .map(|(&a, &b)| (i32::from(a) - i32::from(b)).abs())
+      .max()
+      .unwrap()
+  }
+
+  #[test]
+  fn looped_encode_removes_wrap_transient() {
+    // A loud, slow wave that fills its sectors exactly and ends where it began, a long way from zero
+    let samples: Vec<i16> = (0..ADPCM_SECTOR_SAMPLES * 2)
+      .map(|n| (((n as f64) * std::f64::consts::TAU / 1008.0).cos() * 20_000.0) as i16)
+      .collect();
+    let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+    let single_pass = encode_whole(&pcm);
+    let mut two_pass = Vec::new();
+    encode_xa_adpcm_looped(samples.len(), EncodeOptions::default(), &mut &pcm[..], &mut two_pass, &mut |_, _| {}).unwrap();
+    assert_eq!(two_pass.len(), single_pass.len());
+
+    let single_pass_error = wrap_error(&single_pass, &samples);
+    let two_pass_error = wrap_error(&two_pass, &samples);
+    assert!(
+      two_pass_error * 4 < single_pass_error,
+      "wrap error {} with two passes, {} with one", two_pass_error, single_pass_error
+    );
+  }
+
+  fn encode_dithered(pcm: &[u8], seed: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_xa_adpcm_with_progress(pcm.len() / 2, EncodeOptions { dither_seed: Some(seed), ..Default::default() }, &mut &pcm[..], &mut out, &mut |_, _| {}).unwrap();
+    out
+  }
+
+  #[test]
+  fn dither_is_reproducible() {
+    let pcm = test_samples(ADPCM_SECTOR_SAMPLES);
+
+    assert_eq!(encode_dithered(&pcm, 1), encode_dithered(&pcm, 1));
+    assert_ne!(encode_dithered(&pcm, 1), encode_dithered(&pcm, 2));
+    assert_ne!(encode_dithered(&pcm, 1), encode_whole(&pcm));
+  }
+
+  #[test]
+  fn dither_keeps_silence_silent() {
+    // A quiet tone that fades to digital silence halfway through
+    let mut samples: Vec<i16> = (0..ADPCM_SECTOR_SAMPLES / 2)
+      .map(|n| (((n as f64) * 0.05).sin() * 40.0) as i16)
+      .collect();
+    samples.resize(ADPCM_SECTOR_SAMPLES * 2, 0);
+    let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+    let decoded = decode_all(&encode_dithered(&pcm, 7));
+    let tail = &decoded[ADPCM_SECTOR_SAMPLES..];
+    let max_hiss = tail.iter().map(|&sample| i32::from(sample).abs()).max().unwrap();
+    assert!(max_hiss <= 2, "silence decoded with peaks of {}", max_hiss);
+  }
+
+  #[test]
+  fn progress_reports_every_sector() {
+    let pcm = test_samples(ADPCM_SECTOR_SAMPLES * 2 + 1000);
+
+    let mut calls = Vec::new();
+    encode_xa_adpcm_with_progress(pcm.len() / 2, EncodeOptions::default(), &mut &pcm[..], &mut Vec::new(), &mut |done, total| {
+      calls.push((done, total));
+    }).unwrap();
+
+    assert_eq!(calls, [(1, 3), (2, 3), (3, 3)]);
+  }
+
+  #[test]
+  fn progress_fires_on_completion() {
+    let pcm = test_samples(100);
+
+    let mut calls = Vec::new();
+    encode_xa_adpcm_with_progress(100, EncodeOptions::default(), &mut &pcm[..], &mut Vec::new(), &mut |done, total| {
+      calls.push((done, total));
+    }).unwrap();
+    assert_eq!(calls, [(1, 1)]);
+
+    let mut calls = Vec::new();
+    encode_xa_adpcm_with_progress(0, EncodeOptions::default(), &mut &pcm[..], &mut Vec::new(), &mut |done, total| {
+      calls.push((done, total));
+    }).unwrap();
+    assert_eq!(calls, [(0, 0)]);
+
+    let mut calls = Vec::new();
+    encode_spu_adpcm(100, None, EncodeOptions::default(), &mut &pcm[..], &mut Vec::new(), &mut |done, total| {
+      calls.push((done, total));
+    }).unwrap();
+    assert_eq!(calls, [(4, 4)]);
+  }
+
+  #[test]
+  fn parallel_encodes_sectors_independently() {
+    let pcm = test_samples(ADPCM_SECTOR_SAMPLES * 40 + 1000);
+
+    let mut out = Vec::new();
+    encode_xa_adpcm_parallel(pcm.len() / 2, EncodeOptions::default(), &mut &pcm[..], &mut out, &mut |_, _| {}).unwrap();
+
+    // Every sector matches a standalone encode of its own samples from fresh state
+    let expected: Vec<u8> = pcm
+      .chunks(ADPCM_SECTOR_SAMPLES * 2)
+      .flat_map(encode_whole)
+      .collect();
+    assert_eq!(out.len(), 41 * XA_ADPCM_SECTOR_SIZE);
+    assert_eq!(out, expected);
+  }
+
+  fn decode_all(xa: &[u8]) -> Vec<i16> {
+    let mut decoder_state = DecoderState::new();
+    let mut decoded = vec![0_i16; xa.len() / XA_ADPCM_SECTOR_SIZE * ADPCM_SECTOR_SAMPLES];
+    for (sector, output) in xa.chunks(XA_ADPCM_SECTOR_SIZE).zip(decoded.chunks_mut(ADPCM_SECTOR_SAMPLES)) {
+      decode_sector(&mut decoder_state, sector, output);
+    }
+    decoded
+  }
+
+  #[test]
+  fn silent_unit_decodes_to_silence() {
+    let mut encoder_state = EncoderState::new();
+    let mut sound_unit = [0_u8; SOUND_UNIT_SAMPLES];
+    let sound_parameter = encode_sound_unit(&mut encoder_state, &[0; SOUND_UNIT_SAMPLES], &mut sound_unit);
+
+    assert_eq!(sound_parameter, 0x00);
+    assert!(sound_unit.iter().all(|&nibble| nibble == 0));
+
+    let pcm = vec![0_u8; SOUND_UNIT_SAMPLES * 2];
+    assert!(decode_all(&encode_whole(&pcm)).iter().all(|&sample| sample == 0));
+  }
+
+  #[test]
+  fn silence_after_audio_decodes_to_silence() {
+    // One loud unit leaves residue in the noise shaper; the silent units after it must not turn
+    // that residue into noise
+    let mut pcm = test_samples(SOUND_UNIT_SAMPLES);
+    pcm.resize(SOUND_GROUP_SAMPLES * 2, 0);
+
+    let decoded = decode_all(&encode_whole(&pcm));
+    assert!(decoded[SOUND_UNIT_SAMPLES..SOUND_GROUP_SAMPLES].iter().all(|&sample| sample == 0));
+  }
+
+  // Yields `data` in small reads, failing once with `error_kind` after `fail_at` bytes
+  struct FlakyReader {
+    data: Vec<u8>,
+    position: usize,
+    fail_at: usize,
+    error_kind: Option<io::ErrorKind>,
+  }
+
+  impl Read for FlakyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      if self.position == self.fail_at {
+        if let Some(kind) = self.error_kind.take() {
+          return Err(io::Error::new(kind, "flaky"))
+        }
+      }
+
+      let end = min(self.data.len(), self.position + min(buf.len(), 7));
+      let n = end - self.position;
+      buf[..n].copy_from_slice(&self.data[self.position..end]);
+      self.position = end;
+      Ok(n)
+    }
+  }
+
+  #[test]
+  fn padding_count_covers_final_sector() {
+    let pcm = test_samples(1000);
+
+    let padding = encode_xa_adpcm(1000, &mut &pcm[..], &mut Vec::new()).unwrap();
+    assert_eq!(padding, ADPCM_SECTOR_SAMPLES - 1000);
+
+    // Input shorter than the declared sample count is padded too
+    let padding = encode_xa_adpcm(1000, &mut &pcm[..1000], &mut Vec::new()).unwrap();
+    assert_eq!(padding, ADPCM_SECTOR_SAMPLES - 500);
+  }
+
+  #[test]
+  fn interrupted_read_is_retried() {
+    let pcm = test_samples(1000);
+    let mut rdr = FlakyReader {
+      data: pcm.clone(),
+      position: 0,
+      fail_at: 701,
+      error_kind: Some(io::ErrorKind::Interrupted),
+    };
+
+    let mut out = Vec::new();
+    let padding = encode_xa_adpcm(1000, &mut rdr, &mut out).unwrap();
+
+    assert_eq!(padding, ADPCM_SECTOR_SAMPLES - 1000);
+    assert_eq!(out, encode_whole(&pcm));
+  }
+
+  #[test]
+  fn read_error_is_propagated() {
+    let mut rdr = FlakyReader {
+      data: test_samples(1000),
+      position: 0,
+      fail_at: 700,
+      error_kind: Some(io::ErrorKind::BrokenPipe),
+    };
+
+    let err = encode_xa_adpcm(1000, &mut rdr, &mut Vec::new()).unwrap_err();
+    assert_eq!(err.downcast::<io::Error>().unwrap().kind(), io::ErrorKind::BrokenPipe);
+  }
+
+  #[test]
+  fn finish_rejects_dangling_byte() {
+    let mut encoder = XaEncoder::new(Vec::new());
+    encoder.write_all(&[0x12, 0x34, 0x56]).unwrap();
+
+    assert!(encoder.finish().is_err());
+  }
+
+  #[test]
+  fn finish_without_input_writes_nothing() {
+    let encoder = XaEncoder::new(Vec::new());
+
+    assert!(encoder.finish().unwrap().is_empty());
+  }
+
+  #[test]
+  fn hold_tail_is_quieter_than_zero_tail() {
+    // A stream that ends well away from zero, partway through a sound unit
+    let samples_count = ADPCM_SECTOR_SAMPLES / 2 + 13;
+    let pcm: Vec<u8> = (0..samples_count)
+      .map(|n| (8_000.0 + ((n as f64) * 0.05).sin() * 3_000.0) as i16)
+      .flat_map(|sample| sample.to_le_bytes())
+      .collect();
+
+    // Energy of the sample-to-sample steps from the last real sample to the end of the sector
+    let tail_energy = |tail_mode| {
+      let mut sector = Vec::new();
+      encode_xa_adpcm_with_progress(samples_count, EncodeOptions { tail_mode, ..Default::default() }, &mut &pcm[..], &mut sector, &mut |_, _| {}).unwrap();
+      let mut decoded = vec![0_i16; ADPCM_SECTOR_SAMPLES];
+      decode_sector(&mut DecoderState::new(), &sector, &mut decoded);
+
+      decoded[samples_count - 1..].windows(2)
+        .map(|w| (f64::from(w[1]) - f64::from(w[0])).powi(2))
+        .sum::<f64>()
+    };
+
+    let zero = tail_energy(TailMode::Zero);
+    let hold = tail_energy(TailMode::Hold);
+    assert!(hold * 100.0 < zero, "hold {} vs zero {}", hold, zero);
+  }
+
+  #[test]
+  fn hold_pads_with_last_sample() {
+    let pcm = [1_i16, 2, 3].iter().flat_map(|sample| sample.to_le_bytes()).collect::<Vec<u8>>();
+    let mut samples = [0_i16; 6];
+    let mut last_sample = 0;
+
+    assert_eq!(fill_sample_buffer(&mut samples, &mut &pcm[..], TailMode::Hold, &mut last_sample).unwrap(), 3);
+    assert_eq!(samples, [1, 2, 3, 3, 3, 3]);
+    fill_sample_buffer(&mut samples, &mut &pcm[..0], TailMode::Hold, &mut last_sample).unwrap();
+    assert_eq!(samples, [3; 6]);
+    fill_sample_buffer(&mut samples, &mut &pcm[..], TailMode::Zero, &mut last_sample).unwrap();
+    assert_eq!(samples, [1, 2, 3, 0, 0, 0]);
+  }
+
+  /// Counts decoded samples where the quantizer ran out of range: the stored nibble is at either
+  /// extreme and the decoded sample still misses the input by more than one step.
+  fn clipped_samples(samples: &[i16], encoded: &[u8], decoded: &[i16]) -> usize {
+    samples.iter().zip(decoded).enumerate()
+      .filter(|&(n, (&sample, &decoded))| {
+        let sector = &encoded[(n / ADPCM_SECTOR_SAMPLES) * XA_ADPCM_SECTOR_SIZE..];
+        let group = &sector[((n % ADPCM_SECTOR_SAMPLES) / SOUND_GROUP_SAMPLES) * SOUND_GROUP_SIZE..];
+        let unit = (n % SOUND_GROUP_SAMPLES) / SOUND_UNIT_SAMPLES;
+        let range = sound_parameter(group, unit) & 0x0F;
+        let nibble = sound_unit_nibble(group, unit, n % SOUND_UNIT_SAMPLES);
+        let step = 1_i32 << (12 - range);
+        (nibble == 0x7 || nibble == 0x8) && (i32::from(sample) - i32::from(decoded)).abs() > step
+      })
+      .count()
+  }
+
+  #[test]
+  fn peak_headroom_reduces_clipping() {
+    // Sharp full-scale steps: the noise shaper's feedback pushes the quantizer input past the
+    // filtered peak the range was picked for
+    let samples: Vec<i16> = (0..ADPCM_SECTOR_SAMPLES * 4)
+      .map(|n| if (n / 37) % 2 == 0 { 0 } else { 30_000 })
+      .collect();
+    let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+    let clips_with_headroom = |peak_headroom_pct| {
+      let mut encoded = Vec::new();
+      let options = EncodeOptions { peak_headroom_pct, ..Default::default() };
+      encode_xa_adpcm_with_progress(samples.len(), options, &mut &pcm[..], &mut encoded, &mut |_, _| {}).unwrap();
+
+      let mut decoded = vec![0_i16; samples.len()];
+      let mut state = DecoderState::new();
+      for (sector, output) in encoded.chunks(XA_ADPCM_SECTOR_SIZE).zip(decoded.chunks_mut(ADPCM_SECTOR_SAMPLES)) {
+        decode_sector(&mut state, sector, output);
+      }
+      (clipped_samples(&samples, &encoded, &decoded), snr_db(&samples, &decoded))
+    };
+
+    let (clips, snr) = clips_with_headroom(0);
+    let (clips_10, snr_10) = clips_with_headroom(10);
+    assert!(clips > 2000, "{clips}");
+    assert_eq!(clips_10, 0);
+    assert!(snr_10 > snr, "{snr_10} <= {snr}");
+  }
+
+  fn sine_clips(amplitude: f64, sectors: usize) -> usize {
+    let pcm: Vec<u8> = (0..ADPCM_SECTOR_SAMPLES * sectors)
+      .map(|n| ((n as f64) * std::f64::consts::TAU * 50.0 / 18900.0).sin() * amplitude)
+      .flat_map(|sample| (sample as i16).to_le_bytes())
+      .collect();
+    let stats = encode_xa_adpcm_with_progress(pcm.len() / 2, EncodeOptions::default(), &mut &pcm[..], &mut Vec::new(), &mut |_, _| {}).unwrap();
+    stats.clipped_samples
+  }
+
+  #[test]
+  fn hot_sine_counts_clipped_samples() {
+    assert_eq!(sine_clips(1000.0, 4), 0);
+
+    // A full-scale low tone leaves filters 2 and 3 a tiny residue, and noise shaper feedback
+    // overshoots the range picked for it
+    let first_sector = sine_clips(32767.0, 1);
+    let all_sectors = sine_clips(32767.0, 4);
+    assert!(first_sector > 0);
+    assert!(all_sectors > first_sector, "{} <= {}", all_sectors, first_sector);
+  }
+
+  #[test]
+  fn random_units_at_every_range_dont_overflow() {
+    // Alternates full-scale units, which leave the noise shaper holding large values, with random
+    // units scaled to land on each range in turn. Overflow checks are on in test builds, so any
+    // intermediate that leaves i32 panics.
+    let mut rng = Dither::new(550);
+    let mut state = EncoderState::with_options(EncodeOptions { dither_seed: Some(1), ..Default::default() });
+    let mut output = [0_u8; SOUND_UNIT_SIZE];
+    let mut ranges_seen = [false; MAX_SHIFT + 1];
+
+    for round in 0..4000 {
+      let samples: Vec<i16> = if round % 2 == 0 {
+        (0..SOUND_UNIT_SIZE).map(|n| if (n + round / 2) % 3 == 0 { i16::MAX } else { i16::MIN }).collect()
+      } else {
+        let bits = (round / 2) % 16;
+        (0..SOUND_UNIT_SIZE).map(|_| (rng.next_u64() as i16) >> (15 - bits)).collect()
+      };
+
+      let sound_parameter = encode_sound_unit(&mut state, &samples, &mut output);
+      ranges_seen[usize::from(sound_parameter & 0x0F)] = true;
+    }
+
+    assert_eq!(ranges_seen, [true; MAX_SHIFT + 1]);
+  }
 }
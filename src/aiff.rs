@@ -1,21 +1,46 @@
 use anyhow::{anyhow, Result};
 use byteorder::{BE, ReadBytesExt, WriteBytesExt};
 use extended::Extended;
+use log::warn;
 use std::{
   fmt,
-  io::{Read, Write},
+  io::{self, Read, Write},
 };
 
-use crate::adpcm_encoder::{
-  ADPCM_SECTOR_SAMPLES,
-  XA_ADPCM_SECTOR_SIZE,
+use crate::{
+  adpcm_decoder::XaDecoder,
+  error::XaError,
+  adpcm_encoder::{
+    sectors_for_samples,
+    ADPCM_SECTOR_SAMPLES,
+    XA_ADPCM_SECTOR_SIZE,
+  },
 };
 
+/// Size of the FORM, COMM and APCM headers written by `write_apcm_aiff_header`, which the XA
+/// sectors follow
+pub const APCM_AIFF_HEADER_SIZE: usize = 12 + (8 + 18) + (8 + 8);
+
+/// COMM `sampleSize` written to APCM AIFF files. Standard AIFF gives the bits per uncompressed
+/// sample here, but the game's APCM files hold 4, the bits per ADPCM nibble, so that's what gets
+/// written by default. Generic AIFF readers may reject it; they can't play XA sectors anyway.
+pub const APCM_SAMPLE_SIZE: i16 = 4;
+
+/// Most XA sectors an APCM AIFF file can hold. The FORM chunk size is an `i32` covering the rest of
+/// the headers and all of the sectors, so files top out just under 2 GiB: about 54.7 hours of audio
+/// at 18900 Hz, or 27.4 hours at 37800 Hz.
+pub const MAX_APCM_SECTORS: usize = max_apcm_sectors(XA_ADPCM_SECTOR_SIZE);
+
+/// Most `sector_size`-byte sectors an APCM AIFF file can hold; see `MAX_APCM_SECTORS`.
+pub const fn max_apcm_sectors(sector_size: usize) -> usize {
+  (i32::MAX as usize - (APCM_AIFF_HEADER_SIZE - 8)) / sector_size
+}
+
 #[derive(Debug)]
-pub(crate) struct AIFF {
+pub struct AIFF {
   chunk_id:   [u8; 4], // FourCC 'FORM' header
   chunk_size: i32,     // 4 (form type) + [8 + 18 (common chunk)] + [8 + 8 + audio_data_length bytes (ADPCM chunk)]
-  form_type:  [u8; 4], // 'AIFF'
+  form_type:  [u8; 4], // 'AIFF', or 'AIFC' when read from an AIFF-C file
 }
 
 #[allow(non_snake_case)]
@@ -28,24 +53,29 @@ impl AIFF {
     }
   }
 
-  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self> {
+  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self, XaError> {
     let mut chunk_id: [u8; 4] = [0; 4];
     rdr.read_exact(&mut chunk_id)?;
     if chunk_id != [0x46, 0x4F, 0x52, 0x4D] {
-      return Err(anyhow!("Not a FORM chunk: {:?}", chunk_id))
+      return Err(XaError::MissingChunk { expected: *b"FORM", found: chunk_id })
     }
     
     let chunk_size = rdr.read_i32::<BE>()?;
 
     let mut form_type: [u8; 4] = [0; 4];
     rdr.read_exact(&mut form_type)?;
-    if form_type != [0x41, 0x49, 0x46, 0x46] {
-      return Err(anyhow!("Not an AIFF form type: {:?}", form_type))
+    if &form_type != b"AIFF" && &form_type != b"AIFC" {
+      return Err(XaError::NotAiff(form_type))
     }
 
     Ok(AIFF { chunk_id, chunk_size, form_type })
   }
 
+  /// Whether this is an AIFF-C form, whose COMM chunk carries a compression type and name
+  pub fn is_aifc(&self) -> bool {
+    &self.form_type == b"AIFC"
+  }
+
   fn to_writer<W: Write>(&self, wtr: &mut W) -> Result<()> {
     wtr.write_all(&self.chunk_id)?;
     wtr.write_i32::<BE>(self.chunk_size)?;
@@ -66,7 +96,7 @@ impl fmt::Display for AIFF {
 }
 
 #[derive(Debug)]
-pub(crate) struct CommonChunk {
+pub struct CommonChunk {
   chunk_id: [u8; 4], // 'COMM'
   chunk_size: i32,
 
@@ -74,28 +104,44 @@ pub(crate) struct CommonChunk {
   num_sample_frames: u32,
   sample_size: i16,
   sample_rate: Extended,
+
+  // AIFF-C only
+  compression_type: Option<[u8; 4]>,
+  compression_name: Option<String>,
 }
 
 impl CommonChunk {
-  fn new(samples_count: u32) -> Self {
+  fn new(samples_count: u32, sample_rate: u32, sample_size: i16) -> Self {
     CommonChunk {
       chunk_id: [0x43, 0x4F, 0x4D, 0x4D],
       chunk_size: 18,
 
       num_channels: 1,
       num_sample_frames: samples_count,
-      sample_size: 4,
-      sample_rate: Extended::try_from(18900).unwrap(),
+      sample_size,
+      sample_rate: Extended::from(sample_rate),
+
+      compression_type: None,
+      compression_name: None,
     }
   }
 
-  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self> {
+  /// Reads the COMM chunk of `form`. In an AIFF-C form the chunk is followed by a compression type
+  /// FourCC and a pascal string naming it, padded to an even length.
+  pub fn from_reader<R: Read>(rdr: &mut R, form: &AIFF) -> Result<Self, XaError> {
     let mut chunk_id: [u8; 4] = [0; 4];
     rdr.read_exact(&mut chunk_id)?;
     if chunk_id != [0x43, 0x4F, 0x4D, 0x4D] {
-      return Err(anyhow!("Not a COMM chunk: {:?}", chunk_id))
+      return Err(XaError::MissingChunk { expected: *b"COMM", found: chunk_id })
     }
     let chunk_size = rdr.read_i32::<BE>()?;
+    if !form.is_aifc() && chunk_size != 18 {
+      return Err(XaError::ChunkSize { chunk: chunk_id, expected: 18, found: i64::from(chunk_size) })
+    }
+    // Compression type plus at least the pascal string's length byte and its pad byte
+    if form.is_aifc() && chunk_size < 18 + 4 + 2 {
+      return Err(XaError::ChunkSize { chunk: chunk_id, expected: 18 + 4 + 2, found: i64::from(chunk_size) })
+    }
 
     let num_channels = rdr.read_i16::<BE>()?;
     let num_sample_frames = rdr.read_u32::<BE>()?;
@@ -104,6 +150,26 @@ impl CommonChunk {
     rdr.read_exact(&mut sample_rate_bytes)?;
     let sample_rate = Extended::from_be_bytes(sample_rate_bytes);
 
+    let (compression_type, compression_name) = if form.is_aifc() {
+      let mut compression_type: [u8; 4] = [0; 4];
+      rdr.read_exact(&mut compression_type)?;
+
+      let name_len = usize::from(rdr.read_u8()?);
+      let padded_len = (1 + name_len).next_multiple_of(2);
+      let mut name = vec![0; padded_len - 1];
+      rdr.read_exact(&mut name)?;
+      name.truncate(name_len);
+
+      let expected = 18 + 4 + padded_len as i64;
+      if i64::from(chunk_size) != expected {
+        return Err(XaError::ChunkSize { chunk: chunk_id, expected, found: i64::from(chunk_size) })
+      }
+
+      (Some(compression_type), Some(String::from_utf8_lossy(&name).into_owned()))
+    } else {
+      (None, None)
+    };
+
     Ok(CommonChunk{
       chunk_id,
       chunk_size,
@@ -112,6 +178,9 @@ impl CommonChunk {
       num_sample_frames,
       sample_size,
       sample_rate,
+
+      compression_type,
+      compression_name,
     })
   }
 
@@ -128,6 +197,27 @@ impl CommonChunk {
   }
 }
 
+impl CommonChunk {
+  pub fn num_sample_frames(&self) -> u32 {
+    self.num_sample_frames
+  }
+
+  /// Replaces the declared frame count, e.g. to match an original file that declares its exact
+  /// decoded length. Nothing else in the headers depends on it.
+  pub fn set_num_sample_frames(&mut self, num_sample_frames: u32) {
+    self.num_sample_frames = num_sample_frames;
+  }
+
+  pub fn sample_rate(&self) -> f64 {
+    self.sample_rate.to_f64()
+  }
+
+  /// Bits per sample as declared by the COMM chunk; `APCM_SAMPLE_SIZE` in the game's files
+  pub fn sample_size(&self) -> i16 {
+    self.sample_size
+  }
+}
+
 impl fmt::Display for CommonChunk {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     write!(f, "CommonChunk {{ ")?;
@@ -138,41 +228,60 @@ impl fmt::Display for CommonChunk {
     write!(f, "num_sample_frames: {}, ", self.num_sample_frames)?;
     write!(f, "sample_size: {}, ", self.sample_size)?;
     write!(f, "sample_rate: {} ", self.sample_rate.to_f64())?;
+    if let (Some(compression_type), Some(compression_name)) = (&self.compression_type, &self.compression_name) {
+      write!(f, "compression_type: {}, ", String::from_utf8_lossy(compression_type))?;
+      write!(f, "compression_name: {:?} ", compression_name)?;
+    }
     write!(f, "}}") 
   }
 }
 
 #[derive(Debug)]
-pub(crate) struct APCMChunk {
+pub struct APCMChunk {
   chunk_id: [u8; 4], // "APCM"
   chunk_size: i32,
 
-  unknown: i32,
+  unknown: i32, // Purpose unknown; 0 in most files, but some game files have other values
   sector_size: i32,
   // XA-ADPCM sectors
 }
 
 impl APCMChunk {
-  fn new(adpcm_data_size: i32) -> Self {
+  fn new(adpcm_data_size: i32, sector_size: i32, unknown: i32) -> Self {
     APCMChunk {
       chunk_id: [0x41, 0x50, 0x43, 0x4D],
       chunk_size: 8 + adpcm_data_size,
 
-      unknown: 0,
-      sector_size: 0x914,
+      unknown,
+      sector_size,
     }
   }
 
-  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self> {
+  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self, XaError> {
     let mut chunk_id: [u8; 4] = [0; 4];
     rdr.read_exact(&mut chunk_id)?;
     if chunk_id != [0x41, 0x50, 0x43, 0x4D] {
-      return Err(anyhow!("Not an APCM chunk: {:?}", chunk_id))
+      return Err(XaError::MissingChunk { expected: *b"APCM", found: chunk_id })
     }
     let chunk_size = rdr.read_i32::<BE>()?;
     
     let unknown = rdr.read_i32::<BE>()?;
     let sector_size = rdr.read_i32::<BE>()?;
+    if sector_size <= 0 {
+      return Err(XaError::InvalidSectorSize(sector_size))
+    }
+
+    // The chunk holds the two header fields followed by a whole number of sectors
+    let data_size = i64::from(chunk_size) - 8;
+    let sector_size_i64 = i64::from(sector_size);
+    if data_size < 0 || data_size % sector_size_i64 != 0 {
+      let num_sectors = data_size.max(0) / sector_size_i64;
+      return Err(XaError::PartialSector {
+        sector_size,
+        nearest: (8 + num_sectors * sector_size_i64, 8 + (num_sectors + 1) * sector_size_i64),
+        found: chunk_size,
+      })
+    }
 
     Ok(APCMChunk { chunk_id, chunk_size, unknown, sector_size })
   }
@@ -188,39 +297,899 @@ impl APCMChunk {
   }
 }
 
+impl APCMChunk {
+  /// The reserved field ahead of the sector size, whose meaning is unknown
+  pub fn unknown(&self) -> i32 {
+    self.unknown
+  }
+
+  pub fn sector_size(&self) -> Result<usize, XaError> {
+    match usize::try_from(self.sector_size) {
+      Ok(sector_size) if sector_size > 0 => Ok(sector_size),
+      _ => Err(XaError::InvalidSectorSize(self.sector_size)),
+    }
+  }
+
+  /// Number of whole sectors in the chunk's sample data
+  pub fn num_sectors(&self) -> Result<usize, XaError> {
+    let data_size = usize::try_from(i64::from(self.chunk_size) - 8)
+      .map_err(|_| XaError::ChunkSize { chunk: self.chunk_id, expected: 8, found: i64::from(self.chunk_size) })?;
+
+    Ok(data_size / self.sector_size()?)
+  }
+}
+
 impl fmt::Display for APCMChunk {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     write!(f, "APCMChunk {{ ")?;
     write!(f, "chunk_id: {}, ", String::from_utf8_lossy(&self.chunk_id))?;
     write!(f, "chunk_size: {} ", self.chunk_size)?;
-    write!(f, "unknown: {} ", self.unknown)?;
+    write!(f, "reserved (unknown): {} ", self.unknown)?;
     write!(f, "sector_size: {} ", self.sector_size)?;
     write!(f, "}}") 
   }
 }
 
-pub(crate) fn write_apcm_aiff_header<W: Write>(num_samples: usize, wtr: &mut W) -> Result<()> {
-  let mut num_sectors = num_samples / ADPCM_SECTOR_SAMPLES;
-  if num_samples % ADPCM_SECTOR_SAMPLES != 0 { num_sectors += 1 }
-  let num_sectors = num_sectors;
+/// Bits per sample of the uncompressed PCM written by `PcmAiffWriter`
+pub const PCM_SAMPLE_SIZE: i16 = 16;
+
+/// Size of the FORM, COMM and SSND headers written by `PcmAiffWriter`, which the samples follow.
+/// The same as `APCM_AIFF_HEADER_SIZE`, since the SSND chunk's offset and block size fields take
+/// the place of the APCM chunk's reserved field and sector size.
+pub const PCM_AIFF_HEADER_SIZE: usize = APCM_AIFF_HEADER_SIZE;
+
+/// Header of a standard AIFF `SSND` chunk, which big-endian PCM sample frames follow.
+#[derive(Debug)]
+pub struct SoundDataChunk {
+  chunk_id: [u8; 4], // "SSND"
+  chunk_size: i32,
+
+  offset: u32,     // Bytes of padding ahead of the first sample frame; 0 here
+  block_size: u32, // Alignment of the sample frames; 0 for none
+}
+
+impl SoundDataChunk {
+  fn new(sound_data_size: i32) -> Self {
+    SoundDataChunk { chunk_id: *b"SSND", chunk_size: 8 + sound_data_size, offset: 0, block_size: 0 }
+  }
+
+  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self, XaError> {
+    let mut chunk_id: [u8; 4] = [0; 4];
+    rdr.read_exact(&mut chunk_id)?;
+    if &chunk_id != b"SSND" {
+      return Err(XaError::MissingChunk { expected: *b"SSND", found: chunk_id })
+    }
+    let chunk_size = rdr.read_i32::<BE>()?;
+    if chunk_size < 8 {
+      return Err(XaError::ChunkSize { chunk: chunk_id, expected: 8, found: i64::from(chunk_size) })
+    }
+    let offset = rdr.read_u32::<BE>()?;
+    let block_size = rdr.read_u32::<BE>()?;
+
+    Ok(SoundDataChunk { chunk_id, chunk_size, offset, block_size })
+  }
+
+  fn to_writer<W: Write>(&self, wtr: &mut W) -> Result<()> {
+    wtr.write_all(&self.chunk_id)?;
+    wtr.write_i32::<BE>(self.chunk_size)?;
+
+    wtr.write_u32::<BE>(self.offset)?;
+    wtr.write_u32::<BE>(self.block_size)?;
+
+    Ok(())
+  }
 
-  let num_samples = num_samples;
-  
-  let data_size = i32::try_from(num_sectors * XA_ADPCM_SECTOR_SIZE)?;
+  /// Bytes of sample data in the chunk, after any `offset` padding
+  pub fn sound_data_size(&self) -> usize {
+    (self.chunk_size as usize - 8).saturating_sub(self.offset as usize)
+  }
+}
+
+/// Writes a standard 16-bit mono PCM AIFF file of `samples_count` samples, for tools that read
+/// AIFF but not XA: FORM, COMM and SSND chunks, then the samples big-endian. Takes little-endian
+/// sample bytes through `Write`, like `WavWriter`, and swaps them as they arrive. The headers come
+/// first, so the length has to be known up front.
+pub struct PcmAiffWriter<W: Write> {
+  inner: W,
+  // Sample bytes still to come
+  remaining: u64,
+  // Low byte of a sample whose high byte hasn't been written yet
+  pending: Option<u8>,
+}
+
+impl<W: Write> PcmAiffWriter<W> {
+  pub fn new(samples_count: usize, sample_rate: u32, mut inner: W) -> Result<Self> {
+    let data_size = samples_count
+      .checked_mul(2)
+      .and_then(|size| i32::try_from(size).ok())
+      .filter(|&size| size <= i32::MAX - (PCM_AIFF_HEADER_SIZE as i32 - 8))
+      .ok_or_else(|| anyhow!("Too many samples for an AIFF file: {}", samples_count))?;
+
+    AIFF::new(data_size).to_writer(&mut inner)?;
+    CommonChunk::new(u32::try_from(samples_count)?, sample_rate, PCM_SAMPLE_SIZE).to_writer(&mut inner)?;
+    SoundDataChunk::new(data_size).to_writer(&mut inner)?;
+
+    Ok(PcmAiffWriter { inner, remaining: data_size as u64, pending: None })
+  }
+
+  /// Pads the data out to the declared length with silence if fewer samples were written, then
+  /// flushes and returns the wrapped writer.
+  pub fn finish(mut self) -> Result<W> {
+    if let Some(low) = self.pending.take() {
+      self.inner.write_all(&[0, low])?;
+      self.remaining -= 2;
+    }
+    io::copy(&mut io::repeat(0).take(self.remaining), &mut self.inner)?;
+    self.inner.flush()?;
+
+    Ok(self.inner)
+  }
+}
+
+impl<W: Write> Write for PcmAiffWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let pending = u64::from(self.pending.is_some());
+    if self.remaining <= pending && !buf.is_empty() {
+      return Err(io::Error::other("More sample data than the AIFF header declares"))
+    }
+
+    let take = buf.len().min(usize::try_from(self.remaining - pending).unwrap_or(usize::MAX));
+    let mut swapped = Vec::with_capacity(take + 1);
+    let mut bytes = buf[..take].iter().copied();
+    if let Some(low) = self.pending.take() {
+      match bytes.next() {
+        Some(high) => swapped.extend_from_slice(&[high, low]),
+        None => self.pending = Some(low),
+      }
+    }
+    let rest: Vec<u8> = bytes.collect();
+    let mut pairs = rest.chunks_exact(2);
+    for pair in pairs.by_ref() {
+      swapped.extend_from_slice(&[pair[1], pair[0]]);
+    }
+    if let [low] = pairs.remainder() {
+      self.pending = Some(*low);
+    }
+
+    self.inner.write_all(&swapped)?;
+    self.remaining -= swapped.len() as u64;
+    Ok(take)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Reads a standard 16-bit mono PCM AIFF file with its sound data in an `SSND` chunk straight after
+/// the COMM chunk, as `PcmAiffWriter` writes. Returns the COMM chunk and the samples.
+pub fn read_pcm_aiff<R: Read>(rdr: &mut R) -> Result<(CommonChunk, Vec<i16>), XaError> {
+  let aiff = AIFF::from_reader(rdr)?;
+  let comm = CommonChunk::from_reader(rdr, &aiff)?;
+  if comm.num_channels != 1 {
+    return Err(XaError::UnsupportedChannels(comm.num_channels as u16))
+  }
+  if comm.sample_size != PCM_SAMPLE_SIZE {
+    return Err(XaError::UnsupportedBitDepth(comm.sample_size as u16))
+  }
+  let ssnd = SoundDataChunk::from_reader(rdr)?;
+  io::copy(&mut rdr.take(u64::from(ssnd.offset)), &mut io::sink())?;
+
+  let num_samples = (comm.num_sample_frames as usize).min(ssnd.sound_data_size() / 2);
+  let mut samples = vec![0_i16; num_samples];
+  rdr.read_i16_into::<BE>(&mut samples)?;
+
+  Ok((comm, samples))
+}
+
+/// Builds the FORM, COMM and APCM headers for `num_samples` samples of `sector_size`-byte XA
+/// sectors played at `sample_rate`. `sample_size` is the COMM chunk's bits per sample; pass
+/// `APCM_SAMPLE_SIZE` unless experimenting. `apcm_unknown` fills the APCM chunk's reserved field;
+/// pass 0 unless matching an existing file.
+///
+/// Fails if the sectors wouldn't fit in an AIFF file (see `max_apcm_sectors`).
+pub fn apcm_aiff_headers(
+  num_samples: usize,
+  sample_rate: u32,
+  sample_size: i16,
+  sector_size: usize,
+  apcm_unknown: i32,
+) -> Result<(AIFF, CommonChunk, APCMChunk)> {
+  let num_sectors = sectors_for_samples(num_samples);
+  let max_sectors = max_apcm_sectors(sector_size);
+  if num_sectors > max_sectors {
+    return Err(XaError::OutputTooLarge { num_sectors, max_sectors }.into())
+  }
+
+  let data_size = i32::try_from(num_sectors * sector_size)?;
   let num_samples = u32::try_from(num_samples)?;
 
   let aiff = AIFF::new(data_size);
-  let comm = CommonChunk::new(num_samples);
-  let apcm = APCMChunk::new(data_size);
+  let comm = CommonChunk::new(num_samples, sample_rate, sample_size);
+  let apcm = APCMChunk::new(data_size, i32::try_from(sector_size)?, apcm_unknown);
 
+  Ok((aiff, comm, apcm))
+}
+
+/// Writes the headers built by `apcm_aiff_headers`. Fails before writing anything if the sectors
+/// wouldn't fit in an AIFF file.
+pub fn write_apcm_aiff_header<W: Write>(
+  num_samples: usize,
+  sample_rate: u32,
+  sample_size: i16,
+  sector_size: usize,
+  apcm_unknown: i32,
+  wtr: &mut W,
+) -> Result<()> {
+  let (aiff, comm, apcm) = apcm_aiff_headers(num_samples, sample_rate, sample_size, sector_size, apcm_unknown)?;
+  write_apcm_aiff_headers(&aiff, &comm, &apcm, wtr)
+}
+
+/// Writes headers from `apcm_aiff_headers`, after any changes made to them.
+pub fn write_apcm_aiff_headers<W: Write>(aiff: &AIFF, comm: &CommonChunk, apcm: &APCMChunk, wtr: &mut W) -> Result<()> {
   aiff.to_writer(wtr)?;
   comm.to_writer(wtr)?;
   apcm.to_writer(wtr)?;
 
-  println!("");
-  println!("{}", aiff);
-  println!("{}", comm);
-  println!("{}", apcm);
-
   Ok(())
 }
+
+/// Sizes derived from the FORM, COMM and APCM headers of an APCM AIFF file, for checking that the
+/// headers agree with each other and with the file.
+#[derive(Debug, PartialEq)]
+pub struct HeaderReport {
+  pub num_sectors: usize,
+  /// Sample frames the sectors hold, including the zero padding in the final sector
+  pub implied_sample_frames: usize,
+  /// Sample frames declared by the COMM chunk
+  pub declared_sample_frames: u32,
+  /// Sample rate declared by the COMM chunk, in Hz
+  pub sample_rate: f64,
+  /// Bits per sample declared by the COMM chunk
+  pub sample_size: i16,
+  /// FORM chunk size implied by the COMM and APCM chunk sizes, and the size found
+  pub form_size: (i64, i64),
+  /// File size implied by the FORM chunk size, and the actual size if it's known
+  pub file_size: Option<(i64, u64)>,
+}
+
+impl HeaderReport {
+  pub fn new(aiff: &AIFF, comm: &CommonChunk, apcm: &APCMChunk, file_size: Option<u64>) -> Result<Self, XaError> {
+    let num_sectors = apcm.num_sectors()?;
+    let expected_form_size = 4 + (8 + i64::from(comm.chunk_size)) + (8 + i64::from(apcm.chunk_size));
+
+    Ok(HeaderReport {
+      num_sectors,
+      implied_sample_frames: num_sectors * ADPCM_SECTOR_SAMPLES,
+      declared_sample_frames: comm.num_sample_frames,
+      sample_rate: comm.sample_rate(),
+      sample_size: comm.sample_size,
+      form_size: (expected_form_size, i64::from(aiff.chunk_size)),
+      file_size: file_size.map(|file_size| (8 + i64::from(aiff.chunk_size), file_size)),
+    })
+  }
+
+  /// Length of the declared sample frames at the declared sample rate
+  pub fn duration_secs(&self) -> f64 {
+    f64::from(self.declared_sample_frames) / self.sample_rate
+  }
+
+  /// Whether the declared sample frames fill the last sector, without needing another
+  pub fn sample_frames_consistent(&self) -> bool {
+    sectors_for_samples(self.declared_sample_frames as usize) == self.num_sectors
+  }
+
+  pub fn form_size_consistent(&self) -> bool {
+    self.form_size.0 == self.form_size.1
+  }
+
+  pub fn file_size_consistent(&self) -> bool {
+    self.file_size.is_none_or(|(expected, found)| i64::try_from(found) == Ok(expected))
+  }
+
+  pub fn is_consistent(&self) -> bool {
+    self.sample_frames_consistent() && self.form_size_consistent() && self.file_size_consistent()
+  }
+}
+
+impl fmt::Display for HeaderReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    fn check(ok: bool) -> &'static str {
+      if ok { "ok" } else { "MISMATCH" }
+    }
+
+    writeln!(f, "sectors: {}", self.num_sectors)?;
+    writeln!(
+      f,
+      "sample frames: {} declared, {} held by sectors: {}",
+      self.declared_sample_frames, self.implied_sample_frames, check(self.sample_frames_consistent())
+    )?;
+    writeln!(f, "duration: {:.3} s at {} Hz", self.duration_secs(), self.sample_rate)?;
+    if self.sample_size == APCM_SAMPLE_SIZE {
+      writeln!(f, "sample size: {} bits", self.sample_size)?;
+    } else {
+      writeln!(f, "sample size: {} bits, not the usual {}", self.sample_size, APCM_SAMPLE_SIZE)?;
+    }
+    write!(
+      f,
+      "FORM size: {} expected, {} found: {}",
+      self.form_size.0, self.form_size.1, check(self.form_size_consistent())
+    )?;
+    if let Some((expected, found)) = self.file_size {
+      write!(f, "\nfile size: {} expected, {} found: {}", expected, found, check(self.file_size_consistent()))?;
+    }
+
+    Ok(())
+  }
+}
+
+/// PCM samples decoded from an APCM chunk.
+#[derive(Debug)]
+pub struct DecodedAPCM {
+  pub samples: Vec<i16>,
+  /// Number of all-zero sectors found at the start of the data, whether or not they were skipped
+  pub leading_blank_sectors: usize,
+}
+
+/// Decodes the XA sectors following an APCM chunk header. `rdr` must be positioned directly after
+/// the header read by `APCMChunk::from_reader`.
+///
+/// Each `sector_size`-byte sector is decoded to `ADPCM_SECTOR_SAMPLES` samples; anything after the
+/// 18 sound groups (the trailing 0x14 zero pad) is ignored. Leading sectors that are entirely zero
+/// are counted, and dropped from the output when `skip_blank_sectors` is set.
+pub fn decode_apcm_sectors<R: Read>(apcm: &APCMChunk, rdr: &mut R, skip_blank_sectors: bool) -> Result<DecodedAPCM, XaError> {
+  let sector_size = apcm.sector_size()?;
+  let num_sectors = apcm.num_sectors()?;
+
+  // Bounded so that skipping blank sectors can't run past the APCM chunk
+  let sectors = rdr.take((num_sectors * sector_size) as u64);
+  let mut decoder = XaDecoder::with_sector_size(sectors, sector_size).skip_blank_sectors(skip_blank_sectors);
+  let mut samples = Vec::with_capacity(num_sectors * ADPCM_SECTOR_SAMPLES);
+
+  loop {
+    let start = samples.len();
+    samples.resize(start + ADPCM_SECTOR_SAMPLES, 0);
+    match decoder.decode_one_sector(&mut samples[start..]) {
+      Ok(true) => {},
+      Ok(false) | Err(XaError::TruncatedSector { .. }) => {
+        samples.truncate(start);
+        break
+      },
+      Err(e) => return Err(e),
+    }
+  }
+
+  if decoder.sectors_read() < num_sectors {
+    return Err(XaError::TruncatedSectors { read: decoder.sectors_read(), expected: num_sectors })
+  }
+
+  Ok(DecodedAPCM { samples, leading_blank_sectors: decoder.leading_blank_sectors() })
+}
+
+/// Reads the FORM, COMM and APCM headers of an APCM AIFF file, checking that the APCM chunk size
+/// agrees with the size declared by the FORM chunk.
+pub fn read_apcm_aiff_headers<R: Read>(rdr: &mut R) -> Result<(AIFF, CommonChunk, APCMChunk), XaError> {
+  let aiff = AIFF::from_reader(rdr)?;
+  let comm = CommonChunk::from_reader(rdr, &aiff)?;
+  let apcm = APCMChunk::from_reader(rdr)?;
+
+  // FORM size covers the form type, then each chunk's 8-byte header and body
+  let expected_apcm_size = i64::from(aiff.chunk_size) - 4 - (8 + i64::from(comm.chunk_size)) - 8;
+  if i64::from(apcm.chunk_size) != expected_apcm_size {
+    return Err(XaError::FormSizeMismatch { expected: expected_apcm_size, found: i64::from(apcm.chunk_size) })
+  }
+
+  Ok((aiff, comm, apcm))
+}
+
+/// Most bytes `ReferenceHeader::from_reader` reads looking for the end of the headers
+const MAX_REFERENCE_HEADER_SIZE: u64 = 4096;
+
+/// The FORM, COMM and APCM headers of an existing APCM AIFF file, kept byte for byte, to write
+/// ahead of newly encoded sectors so that every reserved and unknown field matches the original.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReferenceHeader {
+  bytes: Vec<u8>,
+  num_sectors: usize,
+  sector_size: usize,
+  sample_rate: u32,
+}
+
+impl ReferenceHeader {
+  /// Reads and checks the headers at the start of `rdr`, leaving it somewhere after them.
+  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self> {
+    let mut start = Vec::new();
+    rdr.take(MAX_REFERENCE_HEADER_SIZE).read_to_end(&mut start)?;
+    let mut cursor = io::Cursor::new(start);
+    let (_, comm, apcm) = read_apcm_aiff_headers(&mut cursor)?;
+
+    // Only the usual layout is taken, so sector offsets are the same as in files written from scratch
+    let header_size = cursor.position() as usize;
+    if header_size != APCM_AIFF_HEADER_SIZE {
+      return Err(anyhow!("Reference headers are {} bytes, not the usual {}", header_size, APCM_AIFF_HEADER_SIZE))
+    }
+    let mut bytes = cursor.into_inner();
+    bytes.truncate(header_size);
+    Ok(ReferenceHeader {
+      bytes,
+      num_sectors: apcm.num_sectors()?,
+      sector_size: apcm.sector_size()?,
+      sample_rate: comm.sample_rate().round() as u32,
+    })
+  }
+
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+
+  /// Sectors the APCM chunk declares; new sectors written after the header must match
+  pub fn num_sectors(&self) -> usize {
+    self.num_sectors
+  }
+
+  pub fn sector_size(&self) -> usize {
+    self.sector_size
+  }
+
+  /// Sample rate declared in the COMM chunk, rounded to a whole number of Hz
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  /// The headers, parsed again.
+  pub fn headers(&self) -> Result<(AIFF, CommonChunk, APCMChunk), XaError> {
+    read_apcm_aiff_headers(&mut self.bytes.as_slice())
+  }
+}
+
+/// Parses an APCM AIFF file and decodes its sample data, trimmed to the COMM chunk's sample frame
+/// count (less any skipped blank sectors).
+pub fn decode_apcm_aiff<R: Read>(rdr: &mut R, skip_blank_sectors: bool) -> Result<DecodedAPCM, XaError> {
+  let (_, comm, apcm) = read_apcm_aiff_headers(rdr)?;
+
+  let mut decoded = decode_apcm_sectors(&apcm, rdr, skip_blank_sectors)?;
+
+  let mut num_frames = comm.num_sample_frames() as usize;
+  if skip_blank_sectors {
+    num_frames = num_frames.saturating_sub(decoded.leading_blank_sectors * ADPCM_SECTOR_SAMPLES);
+  }
+  decoded.samples.truncate(num_frames);
+
+  Ok(decoded)
+}
+
+/// Joins APCM AIFF files into one without re-encoding, copying their sectors as they are.
+///
+/// Each file was encoded as if the decoder started from silence, but a decoder playing the joined
+/// stream carries its filter history over from one file to the next. A blank sector between them
+/// clears that history, so with `gap_sectors` of 0 the first sound unit of each file that doesn't
+/// start with a blank sector decodes against the end of the file before it, and may click. That
+/// case is logged as a warning.
+///
+/// `gap_sectors` blank sectors go between each pair of files, and `drop_leading_blank_sectors`
+/// leaves out the all-zero sectors each file starts with. The files have to share a sample rate
+/// and sector size, and declare sample frame counts that fit their sectors. Every file but the last
+/// counts as filling its final sector, since its padding now plays before the next file; the
+/// COMM chunk declares the last file's own sample frames after that. The COMM sample size and the
+/// APCM reserved field are taken from the first file.
+///
+/// Each file's sectors are held in memory until all of the headers have been read. They're read
+/// as they come rather than into a buffer sized from the header, so a file declaring far more
+/// sectors than it holds fails without a huge allocation.
+pub fn concat_apcm_aiff<R: Read, W: Write>(
+  inputs: Vec<R>,
+  gap_sectors: usize,
+  drop_leading_blank_sectors: bool,
+  wtr: &mut W,
+) -> Result<HeaderReport> {
+  let mut parts = Vec::with_capacity(inputs.len());
+  let mut format = None;
+  for (n, mut rdr) in inputs.into_iter().enumerate() {
+    let (aiff, comm, apcm) = read_apcm_aiff_headers(&mut rdr)?;
+    if !HeaderReport::new(&aiff, &comm, &apcm, None)?.sample_frames_consistent() {
+      return Err(anyhow!(
+        "File {} declares {} sample frames, which don't fit its {} sectors", n, comm.num_sample_frames, apcm.num_sectors()?
+      ))
+    }
+
+    let sector_size = apcm.sector_size()?;
+    let sample_rate = comm.sample_rate().round() as u32;
+    let (first_rate, first_size, ..) = *format.get_or_insert((sample_rate, sector_size, comm.sample_size, apcm.unknown));
+    if (sample_rate, sector_size) != (first_rate, first_size) {
+      return Err(anyhow!(
+        "File {} has {}-byte sectors at {} Hz, but the first has {}-byte sectors at {} Hz",
+        n, sector_size, sample_rate, first_size, first_rate
+      ))
+    }
+
+    let num_sectors = apcm.num_sectors()?;
+    let mut sectors = Vec::new();
+    rdr.by_ref().take((num_sectors * sector_size) as u64).read_to_end(&mut sectors)?;
+    if sectors.len() < num_sectors * sector_size {
+      return Err(anyhow!(
+        "File {} declares {} sectors, but ends after {}", n, num_sectors, sectors.len() / sector_size
+      ))
+    }
+    let blank_sectors = if drop_leading_blank_sectors {
+      sectors.chunks_exact(sector_size).take_while(|sector| sector.iter().all(|&byte| byte == 0)).count()
+    } else {
+      0
+    };
+    sectors.drain(..blank_sectors * sector_size);
+    let sample_frames = (comm.num_sample_frames as usize).saturating_sub(blank_sectors * ADPCM_SECTOR_SAMPLES);
+    parts.push((sectors, sample_frames));
+  }
+
+  let Some((sample_rate, sector_size, sample_size, apcm_unknown)) = format else {
+    return Err(anyhow!("No files to join"))
+  };
+  let gap = vec![0; gap_sectors * sector_size];
+  let last = parts.len() - 1;
+  let num_samples = parts.iter()
+    .enumerate()
+    .map(|(n, (sectors, sample_frames))| {
+      let gap_samples = if n < last { gap_sectors * ADPCM_SECTOR_SAMPLES } else { 0 };
+      let file_samples = if n < last { sectors.len() / sector_size * ADPCM_SECTOR_SAMPLES } else { *sample_frames };
+      file_samples + gap_samples
+    })
+    .sum();
+
+  let (aiff, comm, apcm) = apcm_aiff_headers(num_samples, sample_rate, sample_size, sector_size, apcm_unknown)?;
+  aiff.to_writer(wtr)?;
+  comm.to_writer(wtr)?;
+  apcm.to_writer(wtr)?;
+  for (n, (sectors, _)) in parts.iter().enumerate() {
+    let starts_blank = sectors.get(..sector_size).is_none_or(|sector| sector.iter().all(|&byte| byte == 0));
+    if n > 0 && gap_sectors == 0 && !starts_blank {
+      warn!(
+        "file {} follows file {} with no blank sector between them, so its first sound unit decodes \
+        against the end of file {} and may click",
+        n, n - 1, n - 1
+      );
+    }
+    if n > 0 {
+      wtr.write_all(&gap)?;
+    }
+    wtr.write_all(sectors)?;
+  }
+
+  Ok(HeaderReport::new(&aiff, &comm, &apcm, None)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::adpcm_encoder::encode_xa_adpcm;
+
+  fn encode_test_file(samples: &[i16], blank_sectors: usize) -> Vec<u8> {
+    let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    let num_samples = blank_sectors * ADPCM_SECTOR_SAMPLES + samples.len();
+
+    let mut out = Vec::new();
+    write_apcm_aiff_header(num_samples, 18900, APCM_SAMPLE_SIZE, XA_ADPCM_SECTOR_SIZE, 0, &mut out).unwrap();
+    out.resize(out.len() + blank_sectors * XA_ADPCM_SECTOR_SIZE, 0);
+    encode_xa_adpcm(samples.len(), &mut pcm.as_slice(), &mut out).unwrap();
+    out
+  }
+
+  fn test_samples(count: usize) -> Vec<i16> {
+    (0..count).map(|n| (((n as f64) * 0.03).sin() * 10_000.0) as i16).collect()
+  }
+
+  #[test]
+  fn decode_round_trip() {
+    let samples = test_samples(5000);
+    let file = encode_test_file(&samples, 0);
+
+    let decoded = decode_apcm_aiff(&mut file.as_slice(), true).unwrap();
+
+    assert_eq!(decoded.leading_blank_sectors, 0);
+    assert_eq!(decoded.samples.len(), samples.len());
+    let max_error = samples.iter()
+      .zip(decoded.samples.iter())
+      .map(|(&a, &b)| (i32::from(a) - i32::from(b)).abs())
+      .max()
+      .unwrap();
+    assert!(max_error < 1000, "max error {}", max_error);
+  }
+
+  // Overwrites the big-endian i32 at `offset`
+  fn patch_i32(file: &mut [u8], offset: usize, value: i32) {
+    file[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+  }
+
+  const FORM_SIZE_OFFSET: usize = 4;
+  const COMM_SIZE_OFFSET: usize = 12 + 4;
+  const APCM_SIZE_OFFSET: usize = 12 + 26 + 4;
+
+  #[test]
+  fn apcm_unknown_round_trips() {
+    let mut out = Vec::new();
+    APCMChunk::new(2 * 0x914, 0x914, 0x1234_5678).to_writer(&mut out).unwrap();
+
+    let apcm = APCMChunk::from_reader(&mut out.as_slice()).unwrap();
+    assert_eq!(apcm.unknown(), 0x1234_5678);
+    assert_eq!(apcm.num_sectors().unwrap(), 2);
+
+    let mut file = Vec::new();
+    write_apcm_aiff_header(5000, 18900, APCM_SAMPLE_SIZE, XA_ADPCM_SECTOR_SIZE, -1, &mut file).unwrap();
+    assert_eq!(file.len(), APCM_AIFF_HEADER_SIZE);
+    let (_, _, apcm) = read_apcm_aiff_headers(&mut file.as_slice()).unwrap();
+    assert_eq!(apcm.unknown(), -1);
+  }
+
+  #[test]
+  fn comm_sample_size_round_trips() {
+    let mut file = Vec::new();
+    write_apcm_aiff_header(5000, 18900, APCM_SAMPLE_SIZE, XA_ADPCM_SECTOR_SIZE, 0, &mut file).unwrap();
+    assert_eq!(file[12 + 8 + 6..12 + 8 + 8], [0, 4]);
+
+    let mut file = Vec::new();
+    write_apcm_aiff_header(5000, 18900, 16, XA_ADPCM_SECTOR_SIZE, 0, &mut file).unwrap();
+    let (aiff, comm, apcm) = read_apcm_aiff_headers(&mut file.as_slice()).unwrap();
+    assert_eq!(comm.sample_size(), 16);
+
+    let report = HeaderReport::new(&aiff, &comm, &apcm, None).unwrap();
+    assert_eq!(report.sample_size, 16);
+    assert!(report.to_string().contains("sample size: 16 bits, not the usual 4"));
+  }
+
+  #[test]
+  fn header_writer_prints_nothing() {
+    // The test harness only captures `print!` output, so the header is written in a child run of
+    // this test with capturing off, between markers
+    if std::env::var_os("HEADER_WRITER_CHILD").is_some() {
+      println!("<<");
+      eprintln!("<<");
+      write_apcm_aiff_header(5000, 18900, APCM_SAMPLE_SIZE, XA_ADPCM_SECTOR_SIZE, 0, &mut Vec::new()).unwrap();
+      println!(">>");
+      eprintln!(">>");
+      return
+    }
+
+    let child = std::process::Command::new(std::env::current_exe().unwrap())
+      .args(["--exact", "aiff::tests::header_writer_prints_nothing", "--nocapture", "--test-threads=1"])
+      .env("HEADER_WRITER_CHILD", "1")
+      .output()
+      .unwrap();
+    assert!(child.status.success());
+    assert!(String::from_utf8_lossy(&child.stdout).contains("<<\n>>"));
+    assert!(String::from_utf8_lossy(&child.stderr).contains("<<\n>>"));
+  }
+
+  #[test]
+  fn largest_output_fits_in_form_size() {
+    // The largest file's FORM size is as close to i32::MAX as whole sectors allow
+    let mut file = Vec::new();
+    write_apcm_aiff_header(MAX_APCM_SECTORS * ADPCM_SECTOR_SAMPLES, 18900, APCM_SAMPLE_SIZE, XA_ADPCM_SECTOR_SIZE, 0, &mut file).unwrap();
+    let (aiff, comm, apcm) = read_apcm_aiff_headers(&mut file.as_slice()).unwrap();
+    assert_eq!(apcm.num_sectors().unwrap(), MAX_APCM_SECTORS);
+    assert!(i64::from(aiff.chunk_size) + XA_ADPCM_SECTOR_SIZE as i64 > i64::from(i32::MAX));
+    assert_eq!(comm.num_sample_frames() as usize, MAX_APCM_SECTORS * ADPCM_SECTOR_SAMPLES);
+
+    // One more sample needs another sector, which doesn't fit
+    let mut file = Vec::new();
+    let err = write_apcm_aiff_header(MAX_APCM_SECTORS * ADPCM_SECTOR_SAMPLES + 1, 18900, APCM_SAMPLE_SIZE, XA_ADPCM_SECTOR_SIZE, 0, &mut file).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<XaError>(),
+      Some(XaError::OutputTooLarge { num_sectors, .. }) if *num_sectors == MAX_APCM_SECTORS + 1
+    ));
+    assert!(file.is_empty());
+  }
+
+  #[test]
+  fn reject_wrong_comm_size() {
+    let mut file = encode_test_file(&test_samples(100), 0);
+    patch_i32(&mut file, COMM_SIZE_OFFSET, 20);
+
+    let err = read_apcm_aiff_headers(&mut file.as_slice()).unwrap_err();
+    assert_eq!(err.to_string(), "Unexpected COMM chunk size: expected 18, found 20");
+    assert!(matches!(err, XaError::ChunkSize { expected: 18, found: 20, .. }));
+  }
+
+  #[test]
+  fn reject_short_apcm_chunk() {
+    let mut file = encode_test_file(&test_samples(100), 0);
+    patch_i32(&mut file, APCM_SIZE_OFFSET, 8 + 0x914 - 4);
+
+    let err = read_apcm_aiff_headers(&mut file.as_slice()).unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "Unexpected APCM chunk size: expected 8 + a whole number of 2324-byte sectors (nearest 8 or 2332), found 2328"
+    );
+  }
+
+  #[test]
+  fn reject_overlong_apcm_chunk() {
+    let mut file = encode_test_file(&test_samples(100), 0);
+    patch_i32(&mut file, APCM_SIZE_OFFSET, 8 + 2 * 0x914);
+
+    let err = read_apcm_aiff_headers(&mut file.as_slice()).unwrap_err();
+    assert_eq!(err.to_string(), "APCM chunk size doesn't match FORM size: expected 2332, found 4656");
+
+    // Consistent headers claiming more sectors than the file holds fail while decoding
+    patch_i32(&mut file, FORM_SIZE_OFFSET, 4 + 26 + 8 + 8 + 2 * 0x914);
+    let err = decode_apcm_aiff(&mut file.as_slice(), false).unwrap_err();
+    assert_eq!(err.to_string(), "APCM data ended after 1 of 2 sectors");
+  }
+
+  #[test]
+  fn header_report_checks_sizes() {
+    let mut file = encode_test_file(&test_samples(5000), 0);
+    let file_size = file.len() as u64;
+
+    let (aiff, comm, apcm) = read_apcm_aiff_headers(&mut file.as_slice()).unwrap();
+    let report = HeaderReport::new(&aiff, &comm, &apcm, Some(file_size)).unwrap();
+    assert_eq!(report, HeaderReport {
+      num_sectors: 2,
+      implied_sample_frames: 2 * ADPCM_SECTOR_SAMPLES,
+      declared_sample_frames: 5000,
+      sample_rate: 18900.0,
+      sample_size: APCM_SAMPLE_SIZE,
+      form_size: (file_size as i64 - 8, file_size as i64 - 8),
+      file_size: Some((file_size as i64, file_size)),
+    });
+    assert!(report.is_consistent());
+
+    // A FORM size that's off is reported rather than rejected
+    patch_i32(&mut file, FORM_SIZE_OFFSET, 100);
+    let mut rdr = file.as_slice();
+    let aiff = AIFF::from_reader(&mut rdr).unwrap();
+    let comm = CommonChunk::from_reader(&mut rdr, &aiff).unwrap();
+    let apcm = APCMChunk::from_reader(&mut rdr).unwrap();
+    let report = HeaderReport::new(&aiff, &comm, &apcm, Some(file_size + 1)).unwrap();
+    assert!(report.sample_frames_consistent());
+    assert!(!report.form_size_consistent());
+    assert!(!report.file_size_consistent());
+  }
+
+  /// Rewrites a file from `encode_test_file` as AIFF-C, with the given COMM compression name
+  fn to_aifc(file: &[u8], compression_name: &[u8]) -> Vec<u8> {
+    let mut extra = b"APCM".to_vec();
+    extra.push(compression_name.len() as u8);
+    extra.extend_from_slice(compression_name);
+    if !extra.len().is_multiple_of(2) {
+      extra.push(0);
+    }
+
+    let mut aifc = file.to_vec();
+    aifc[8..12].copy_from_slice(b"AIFC");
+    aifc.splice(12 + 26..12 + 26, extra.iter().copied());
+    patch_i32(&mut aifc, COMM_SIZE_OFFSET, 18 + extra.len() as i32);
+    let form_size = aifc.len() as i32 - 8;
+    patch_i32(&mut aifc, FORM_SIZE_OFFSET, form_size);
+    aifc
+  }
+
+  #[test]
+  fn read_aifc_comm_chunk() {
+    let samples = test_samples(5000);
+    let file = encode_test_file(&samples, 0);
+
+    for name in [&b"XA ADPCM"[..], b"XA ADPCM!", b""] {
+      let aifc = to_aifc(&file, name);
+
+      let (aiff, comm, _) = read_apcm_aiff_headers(&mut aifc.as_slice()).unwrap();
+      assert!(aiff.is_aifc());
+      assert_eq!(comm.compression_type, Some(*b"APCM"));
+      assert_eq!(comm.compression_name.as_deref(), Some(&*String::from_utf8_lossy(name)));
+      assert_eq!(comm.num_sample_frames(), 5000);
+
+      let decoded = decode_apcm_aiff(&mut aifc.as_slice(), false).unwrap();
+      assert_eq!(decoded.samples, decode_apcm_aiff(&mut file.as_slice(), false).unwrap().samples);
+    }
+
+    // The COMM size has to cover the padded compression name
+    let mut aifc = to_aifc(&file, b"XA ADPCM");
+    patch_i32(&mut aifc, COMM_SIZE_OFFSET, 18 + 4 + 9);
+    let err = read_apcm_aiff_headers(&mut aifc.as_slice()).unwrap_err();
+    assert!(matches!(err, XaError::ChunkSize { expected: 32, found: 31, .. }));
+  }
+
+  #[test]
+  fn detect_and_skip_leading_blank_sectors() {
+    let samples = test_samples(5000);
+    let file = encode_test_file(&samples, 3);
+
+    let kept = decode_apcm_aiff(&mut file.as_slice(), false).unwrap();
+    assert_eq!(kept.leading_blank_sectors, 3);
+    assert_eq!(kept.samples.len(), 3 * ADPCM_SECTOR_SAMPLES + samples.len());
+    assert!(kept.samples[..3 * ADPCM_SECTOR_SAMPLES].iter().all(|&sample| sample == 0));
+
+    let skipped = decode_apcm_aiff(&mut file.as_slice(), true).unwrap();
+    assert_eq!(skipped.leading_blank_sectors, 3);
+    assert_eq!(skipped.samples, kept.samples[3 * ADPCM_SECTOR_SAMPLES..]);
+  }
+
+  #[test]
+  fn concat_keeps_headers_consistent() {
+    let first = encode_test_file(&test_samples(5000), 2);
+    let second = encode_test_file(&test_samples(3000), 0);
+    let third = encode_test_file(&test_samples(100), 1);
+    let files = || vec![first.as_slice(), second.as_slice(), third.as_slice()];
+
+    let mut joined = Vec::new();
+    let report = concat_apcm_aiff(files(), 1, true, &mut joined).unwrap();
+    // 2 + 1 + 1 sectors of audio and a gap sector after each of the first two files
+    assert_eq!(report.num_sectors, 6);
+    assert_eq!(report.declared_sample_frames as usize, 5 * ADPCM_SECTOR_SAMPLES + 100);
+    let (aiff, comm, apcm) = read_apcm_aiff_headers(&mut joined.as_slice()).unwrap();
+    let reread = HeaderReport::new(&aiff, &comm, &apcm, Some(joined.len() as u64)).unwrap();
+    assert!(reread.is_consistent(), "{}", reread);
+
+    // The sectors are copied as they are, so each file decodes the same as it did on its own
+    let decoded = decode_apcm_aiff(&mut joined.as_slice(), false).unwrap().samples;
+    let second_decoded = decode_apcm_aiff(&mut second.as_slice(), false).unwrap().samples;
+    let second_start = 3 * ADPCM_SECTOR_SAMPLES;
+    assert_eq!(decoded[second_start..second_start + second_decoded.len()], second_decoded);
+
+    // Keeping the blank sectors and leaving out the gaps
+    let mut joined = Vec::new();
+    let report = concat_apcm_aiff(files(), 0, false, &mut joined).unwrap();
+    assert_eq!(report.num_sectors, 4 + 1 + 2);
+    assert_eq!(report.declared_sample_frames as usize, 6 * ADPCM_SECTOR_SAMPLES + 100);
+    let (aiff, comm, apcm) = read_apcm_aiff_headers(&mut joined.as_slice()).unwrap();
+    assert!(HeaderReport::new(&aiff, &comm, &apcm, Some(joined.len() as u64)).unwrap().is_consistent());
+  }
+
+  #[test]
+  fn concat_rejects_mismatched_files() {
+    let normal = encode_test_file(&test_samples(5000), 0);
+    let mut high = Vec::new();
+    write_apcm_aiff_header(0, 37800, APCM_SAMPLE_SIZE, XA_ADPCM_SECTOR_SIZE, 0, &mut high).unwrap();
+
+    let err = concat_apcm_aiff(vec![normal.as_slice(), high.as_slice()], 0, false, &mut Vec::new()).unwrap_err();
+    assert!(err.to_string().contains("37800 Hz"), "{}", err);
+    assert!(concat_apcm_aiff(Vec::<&[u8]>::new(), 0, false, &mut Vec::new()).is_err());
+  }
+
+  #[test]
+  fn concat_rejects_files_shorter_than_declared() {
+    // About 230 MB of sectors declared, and none there
+    let mut huge = Vec::new();
+    write_apcm_aiff_header(100_000 * ADPCM_SECTOR_SAMPLES, 18900, APCM_SAMPLE_SIZE, XA_ADPCM_SECTOR_SIZE, 0, &mut huge).unwrap();
+    huge.extend_from_slice(&[0; XA_ADPCM_SECTOR_SIZE]);
+
+    let err = concat_apcm_aiff(vec![huge.as_slice()], 0, false, &mut Vec::new()).unwrap_err();
+    assert!(err.to_string().contains("declares 100000 sectors, but ends after 1"), "{}", err);
+  }
+
+  #[test]
+  fn pcm_aiff_round_trips() {
+    let samples: Vec<i16> = (0..1001).map(|n| ((n * 37) % 2000 - 1000) as i16 * 30).collect();
+    let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+    // Written in odd-sized pieces, so samples are split between writes
+    let mut wtr = PcmAiffWriter::new(samples.len(), 37800, Vec::new()).unwrap();
+    for piece in pcm.chunks(7) {
+      wtr.write_all(piece).unwrap();
+    }
+    let file = wtr.finish().unwrap();
+    assert_eq!(file.len(), PCM_AIFF_HEADER_SIZE + 2 * samples.len());
+    assert_eq!(&file[..4], b"FORM");
+    assert_eq!(i32::from_be_bytes(file[4..8].try_into().unwrap()) as usize, file.len() - 8);
+    assert_eq!(&file[8..12], b"AIFF");
+    assert_eq!(&file[38..42], b"SSND");
+    assert_eq!(i32::from_be_bytes(file[42..46].try_into().unwrap()) as usize, 8 + 2 * samples.len());
+    // Offset and block size
+    assert_eq!(file[46..54], [0; 8]);
+    assert_eq!(file[54..56], samples[0].to_be_bytes());
+
+    let (comm, read) = read_pcm_aiff(&mut file.as_slice()).unwrap();
+    assert_eq!(comm.num_sample_frames() as usize, samples.len());
+    assert_eq!(comm.sample_size(), 16);
+    assert_eq!(comm.sample_rate(), 37800.0);
+    assert_eq!(read, samples);
+  }
+
+  #[test]
+  fn pcm_aiff_writer_pads_short_data() {
+    let mut wtr = PcmAiffWriter::new(3, 18900, Vec::new()).unwrap();
+    wtr.write_all(&[1, 2, 3]).unwrap();
+    let out = wtr.finish().unwrap();
+    assert_eq!(out[PCM_AIFF_HEADER_SIZE..], [2, 1, 0, 3, 0, 0]);
+
+    let mut wtr = PcmAiffWriter::new(1, 18900, Vec::new()).unwrap();
+    assert!(wtr.write_all(&[1, 0, 2, 0]).is_err());
+  }
+}
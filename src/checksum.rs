@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Result};
+use crc32fast::Hasher;
+use std::{
+  fmt,
+  io::{self, BufRead, Read, Write},
+};
+
+/// Passes writes through to the wrapped writer, computing the CRC32 of every `sector_size` bytes
+/// (sector data and any zero pad) after the first `header_size` bytes.
+pub struct SectorChecksumWriter<W: Write> {
+  inner: W,
+  header_remaining: usize,
+  sector_size: usize,
+  hasher: Hasher,
+  sector_filled: usize,
+  checksums: Vec<u32>,
+}
+
+impl<W: Write> SectorChecksumWriter<W> {
+  pub fn new(inner: W, header_size: usize, sector_size: usize) -> Self {
+    SectorChecksumWriter {
+      inner,
+      header_remaining: header_size,
+      sector_size,
+      hasher: Hasher::new(),
+      sector_filled: 0,
+      checksums: Vec::new(),
+    }
+  }
+
+  /// Returns the wrapped writer and one CRC32 per sector written. Errors if the last sector was
+  /// only partly written.
+  pub fn finish(self) -> Result<(W, Vec<u32>)> {
+    if self.sector_filled != 0 {
+      return Err(anyhow!("Output ended {} bytes into a sector", self.sector_filled))
+    }
+
+    Ok((self.inner, self.checksums))
+  }
+}
+
+impl<W: Write> Write for SectorChecksumWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+
+    let mut rest = &buf[..written];
+    let header = rest.len().min(self.header_remaining);
+    self.header_remaining -= header;
+    rest = &rest[header..];
+
+    while !rest.is_empty() {
+      let take = rest.len().min(self.sector_size - self.sector_filled);
+      self.hasher.update(&rest[..take]);
+      self.sector_filled += take;
+      rest = &rest[take..];
+
+      if self.sector_filled == self.sector_size {
+        let hasher = std::mem::take(&mut self.hasher);
+        self.checksums.push(hasher.finalize());
+        self.sector_filled = 0;
+      }
+    }
+
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Hash of every byte of an output file: BLAKE3 when built with the `blake3` feature, CRC32
+/// otherwise. Shown as lowercase hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutputHash([u8; OUTPUT_HASH_SIZE]);
+
+#[cfg(feature = "blake3")]
+const OUTPUT_HASH_SIZE: usize = 32;
+#[cfg(not(feature = "blake3"))]
+const OUTPUT_HASH_SIZE: usize = 4;
+
+impl OutputHash {
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+impl fmt::Display for OutputHash {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+  }
+}
+
+/// Passes writes through to the wrapped writer, hashing exactly the bytes it accepts.
+pub struct HashingWriter<W: Write> {
+  inner: W,
+  #[cfg(feature = "blake3")]
+  hasher: blake3::Hasher,
+  #[cfg(not(feature = "blake3"))]
+  hasher: Hasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+  pub fn new(inner: W) -> Self {
+    HashingWriter { inner, hasher: Default::default() }
+  }
+
+  /// Returns the wrapped writer and the hash of everything written through it.
+  pub fn finish(self) -> (W, OutputHash) {
+    #[cfg(feature = "blake3")]
+    let hash = OutputHash(*self.hasher.finalize().as_bytes());
+    #[cfg(not(feature = "blake3"))]
+    let hash = OutputHash(self.hasher.finalize().to_be_bytes());
+    (self.inner, hash)
+  }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+    self.hasher.update(&buf[..written]);
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Writes a checksum sidecar: one CRC32 per line, as 8 lowercase hex digits, in sector order.
+pub fn write_checksums<W: Write>(checksums: &[u32], wtr: &mut W) -> io::Result<()> {
+  for checksum in checksums {
+    writeln!(wtr, "{:08x}", checksum)?;
+  }
+
+  Ok(())
+}
+
+/// Reads a checksum sidecar written by `write_checksums`.
+pub fn read_checksums<R: BufRead>(rdr: R) -> Result<Vec<u32>> {
+  rdr.lines()
+    .map(|line| {
+      let line = line?;
+      u32::from_str_radix(line.trim(), 16).map_err(|_| anyhow!("Invalid checksum line `{}`", line))
+    })
+    .collect()
+}
+
+/// Computes the CRC32 of each `sector_size`-byte sector read from `rdr`, which must be positioned
+/// at the first sector.
+pub fn sector_checksums<R: Read>(rdr: &mut R, sector_size: usize) -> io::Result<Vec<u32>> {
+  let mut checksums = Vec::new();
+  let mut sector = vec![0; sector_size];
+  loop {
+    match rdr.read_exact(&mut sector) {
+      Ok(()) => checksums.push(crc32fast::hash(&sector)),
+      Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(checksums),
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{adpcm_encoder::XA_ADPCM_SECTOR_SIZE, aiff::APCM_AIFF_HEADER_SIZE, config::EncoderConfig, pipeline};
+
+  #[test]
+  fn sidecar_matches_reread_sectors() {
+    let pcm: Vec<u8> = (0..10_000)
+      .map(|n| (((n as f64) * 0.05).sin() * 12_000.0) as i16)
+      .flat_map(|sample| sample.to_le_bytes())
+      .collect();
+
+    // Small writes split sectors across calls
+    let mut wtr = SectorChecksumWriter::new(Vec::new(), APCM_AIFF_HEADER_SIZE, XA_ADPCM_SECTOR_SIZE);
+    pipeline::encode(&EncoderConfig::default(), 10_000, &mut pcm.as_slice(), &mut io::BufWriter::with_capacity(100, &mut wtr)).unwrap();
+    let (out, checksums) = wtr.finish().unwrap();
+    assert_eq!(checksums.len(), 3);
+
+    let mut sidecar = Vec::new();
+    write_checksums(&checksums, &mut sidecar).unwrap();
+    assert_eq!(sidecar.len(), 3 * 9);
+
+    let listed = read_checksums(sidecar.as_slice()).unwrap();
+    let reread = sector_checksums(&mut &out[APCM_AIFF_HEADER_SIZE..], XA_ADPCM_SECTOR_SIZE).unwrap();
+    assert_eq!(listed, reread);
+
+    // A corrupted sector no longer matches
+    let mut corrupt = out.clone();
+    corrupt[APCM_AIFF_HEADER_SIZE + XA_ADPCM_SECTOR_SIZE + 100] ^= 0xFF;
+    let reread = sector_checksums(&mut &corrupt[APCM_AIFF_HEADER_SIZE..], XA_ADPCM_SECTOR_SIZE).unwrap();
+    assert_eq!(reread[0], listed[0]);
+    assert_ne!(reread[1], listed[1]);
+  }
+
+  #[test]
+  fn output_hash_covers_the_whole_file() {
+    let pcm: Vec<u8> = (0..5000_i16).map(|n| n.wrapping_mul(7)).flat_map(|sample| sample.to_le_bytes()).collect();
+    let config = EncoderConfig::builder().hash_output(true).build().unwrap();
+    let encode = |pcm: &[u8]| {
+      let mut out = Vec::new();
+      let stats = pipeline::encode(&config, 5000, &mut &pcm[..], &mut out).unwrap();
+      (out, stats.output_hash.unwrap())
+    };
+
+    let (out, hash) = encode(&pcm);
+    let mut wtr = HashingWriter::new(Vec::new());
+    wtr.write_all(&out).unwrap();
+    assert_eq!(wtr.finish().1, hash);
+    assert_eq!(hash.to_string().len(), 2 * OUTPUT_HASH_SIZE);
+
+    assert_eq!(encode(&pcm).1, hash);
+    let mut changed = pcm.clone();
+    changed[2000] ^= 0x40;
+    assert_ne!(encode(&changed).1, hash);
+
+    let stats = pipeline::encode(&EncoderConfig::default(), 5000, &mut pcm.as_slice(), &mut Vec::new()).unwrap();
+    assert_eq!(stats.output_hash, None);
+  }
+}
@@ -0,0 +1,712 @@
+use anyhow::{anyhow, Result};
+use std::{
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use crate::{
+  adpcm_encoder::{
+    EncodeOptions,
+    FilterSet,
+    FilterTables,
+    LoopRegion,
+    OutputFormat,
+    RoundingMode,
+    SectorPadding,
+    SoundGroupLayout,
+    TailMode,
+    FILTER_COEFFICIENT_RANGE,
+  },
+  aiff::{ReferenceHeader, APCM_SAMPLE_SIZE},
+  preprocess::FadeCurve,
+  stats::SnrWeighting,
+};
+
+/// Sample rate of normal XA ADPCM, and the default
+pub const NORMAL_SAMPLE_RATE: u32 = 18_900;
+/// Sample rate of "high" XA ADPCM. The sectors are laid out the same; they're just played twice as
+/// fast.
+pub const HIGH_SAMPLE_RATE: u32 = 37_800;
+/// Sample rates XA ADPCM can be played back at
+pub const XA_SAMPLE_RATES: [u32; 2] = [NORMAL_SAMPLE_RATE, HIGH_SAMPLE_RATE];
+pub const DEFAULT_SAMPLE_RATE: u32 = NORMAL_SAMPLE_RATE;
+pub const MAX_PEAK_HEADROOM_PCT: u32 = 100;
+/// Highest range a sound parameter byte can hold
+pub const MAX_RANGE: usize = 12;
+pub const DEFAULT_INTERFILE_GAP_MS: usize = 500;
+pub const DEFAULT_TRIM_THRESHOLD_DBFS: f64 = -50.0;
+pub const DEFAULT_TRIM_MIN_RUN_MS: usize = 100;
+pub const DEFAULT_TRIM_PRE_ROLL_MS: usize = 20;
+pub const DEFAULT_PRE_EMPHASIS_HZ: f64 = 4000.0;
+pub const MAX_GAIN_DB: f64 = 24.0;
+pub const MAX_PRE_EMPHASIS_DB: f64 = 12.0;
+pub const MIN_SOFT_CLIP_DBFS: f64 = -24.0;
+
+/// XA ADPCM playback mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum XaRate {
+  /// `NORMAL_SAMPLE_RATE`
+  #[default]
+  Normal,
+  /// `HIGH_SAMPLE_RATE`
+  High,
+}
+
+impl XaRate {
+  pub fn sample_rate(self) -> u32 {
+    match self {
+      XaRate::Normal => NORMAL_SAMPLE_RATE,
+      XaRate::High => HIGH_SAMPLE_RATE,
+    }
+  }
+}
+
+impl FromStr for XaRate {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "normal" => Ok(XaRate::Normal),
+      "high" => Ok(XaRate::High),
+      _ => Err(anyhow!("Unknown rate `{}`, expected `normal` or `high`", s)),
+    }
+  }
+}
+
+/// How the command-line tool treats stereo WAV inputs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StereoMode {
+  /// Inputs must be mono, apart from formats `symphonia` decodes, which are downmixed
+  #[default]
+  Mono,
+  /// Each channel of a stereo input is encoded to its own output; see `StereoChannel`
+  Split,
+}
+
+impl FromStr for StereoMode {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "mono" => Ok(StereoMode::Mono),
+      "split" => Ok(StereoMode::Split),
+      _ => Err(anyhow!("Unknown stereo mode `{}`, expected `mono` or `split`", s)),
+    }
+  }
+}
+
+/// Order the command-line tool joins the inputs of each output in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputOrder {
+  /// Sorted by path with `input::natural_cmp`, so `part_2` comes before `part_10` whatever order
+  /// a shell glob or directory listing gave them in
+  #[default]
+  Natural,
+  /// As given on the command line or in the manifest
+  Listed,
+}
+
+impl FromStr for InputOrder {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "natural" => Ok(InputOrder::Natural),
+      "listed" => Ok(InputOrder::Listed),
+      _ => Err(anyhow!("Unknown input order `{}`, expected `natural` or `listed`", s)),
+    }
+  }
+}
+
+/// One channel of a stereo WAV input, encoded as a mono stream of its own. Games that play the two
+/// streams in sync need them frame-aligned: both channels of a file have the same number of
+/// samples, so with the same config their outputs have the same number of sectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StereoChannel {
+  Left,
+  Right,
+}
+
+impl StereoChannel {
+  /// Position of the channel within each stereo frame
+  pub fn index(self) -> usize {
+    match self {
+      StereoChannel::Left => 0,
+      StereoChannel::Right => 1,
+    }
+  }
+
+  /// `path` with `_L` or `_R` added to its file stem, e.g. `MUSIC_L.ACM`.
+  pub fn output_path(self, path: &Path) -> PathBuf {
+    let suffix = match self {
+      StereoChannel::Left => "_L",
+      StereoChannel::Right => "_R",
+    };
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    if let Some(extension) = path.extension() {
+      name.push(".");
+      name.push(extension);
+    }
+    path.with_file_name(name)
+  }
+}
+
+/// Options for `pipeline::encode`. Build one with `EncoderConfig::builder()`; the default config
+/// encodes XA sectors exactly as `encode_xa_adpcm` does.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncoderConfig {
+  /// Layout of the encoded output
+  pub output_format: OutputFormat,
+  /// Sample rate of the input, and of the output's COMM chunk; one of `XA_SAMPLE_RATES`
+  pub sample_rate: u32,
+  /// Accept input files at any sample rate, resampling each one to `sample_rate` on its own
+  pub resample: bool,
+  /// Silence inserted between concatenated input files, in milliseconds
+  pub interfile_gap_ms: usize,
+  /// Channel to encode from stereo WAV inputs, or `None` for mono inputs
+  pub stereo_channel: Option<StereoChannel>,
+  /// Start the encoder over at each gap between input files, so the gaps decode to true silence
+  /// instead of the tail of the file before
+  pub reset_at_gaps: bool,
+  /// Ramp at the start of each input file, in milliseconds
+  pub fade_in_ms: usize,
+  /// Ramp at the end of each input file, in milliseconds
+  pub fade_out_ms: usize,
+  /// Shape of the fade ramps
+  pub fade_curve: FadeCurve,
+  /// Trim silence from the start and end of each input file, before fading
+  pub trim_silence: bool,
+  /// Samples quieter than this, in dBFS, count as silence when trimming
+  pub trim_threshold_dbfs: f64,
+  /// Leading or trailing silence shorter than this many milliseconds isn't trimmed
+  pub trim_min_run_ms: usize,
+  /// Silence kept ahead of the first loud sample when trimming, in milliseconds
+  pub trim_pre_roll_ms: usize,
+  /// Encode XA sectors on all cores, restarting encoder state at every sector
+  pub parallel: bool,
+  /// Decode each XA sector after encoding it and record its SNR
+  pub stats: bool,
+  /// Frequency weighting applied before measuring SNR for `stats`; reporting only
+  pub snr_weighting: SnrWeighting,
+  /// Loop region flagged in SPU output
+  pub loop_region: Option<LoopRegion>,
+  /// Encode XA output twice so the stream loops back to its start without a transient
+  pub seamless_loop: bool,
+  /// Seed for TPDF dither added ahead of the quantizer, or `None` to encode without dither
+  pub dither_seed: Option<u64>,
+  /// Value of the reserved field in the APCM chunk header
+  pub apcm_unknown: i32,
+  /// Bits per sample written to the COMM chunk; see `APCM_SAMPLE_SIZE`
+  pub comm_sample_size: i16,
+  /// Frame count written to the COMM chunk instead of the number of input samples, to match an
+  /// existing file byte for byte
+  pub comm_sample_frames: Option<u32>,
+  /// Headers copied from an existing file and written in place of the ones built from the other
+  /// header settings, which are then ignored. The input must fill exactly the sectors they declare.
+  pub reference_header: Option<ReferenceHeader>,
+  /// Most sectors an XA output may have, for players that can only stream files up to some size.
+  /// Longer inputs fail before anything is written.
+  pub max_sectors: Option<usize>,
+  /// Hash the whole output as it's written, into `EncodeStats::output_hash`
+  pub hash_output: bool,
+  /// How the final partial sector or block is padded out
+  pub tail_mode: TailMode,
+  /// Margin added to each sound unit's predicted peak when picking its range, in percent
+  pub peak_headroom_pct: u32,
+  /// Whether each XA sector ends with its 0x14 zero bytes
+  pub sector_padding: SectorPadding,
+  /// How each XA sound group's header holds its sound parameters
+  pub sound_group_layout: SoundGroupLayout,
+  /// Gain applied to each input file ahead of pre-emphasis, in dB; 0 leaves the input alone
+  pub gain_db: f64,
+  /// High-shelf boost applied to each input file at Nyquist, in dB; 0 leaves the input alone
+  pub pre_emphasis_db: f64,
+  /// Midpoint of the pre-emphasis shelf, in Hz
+  pub pre_emphasis_hz: f64,
+  /// Level above which each input file is soft clipped rather than clamped, or `None` to leave it
+  /// alone
+  pub soft_clip_dbfs: Option<f64>,
+  /// Filters the encoder may pick for each sound unit
+  pub allowed_filters: FilterSet,
+  /// Fail the encode if the quantizer clamps more samples than this, or `None` to allow any number
+  pub max_clipped_samples: Option<usize>,
+  /// Debugging only: `(filter, range)` written for every sound unit instead of the best fit
+  pub force_params: Option<(u8, u8)>,
+  /// Rounding in the encoder's fixed-point math, for matching encoders that truncate
+  pub rounding: RoundingMode,
+  /// Filter coefficients to encode with, for decoders that don't use the standard ones
+  pub filter_tables: FilterTables,
+  /// Sample positions where the encoder's filter state starts over, e.g. the start of each gap
+  /// between joined files so the gaps decode to true silence
+  pub state_resets: Vec<usize>,
+}
+
+impl Default for EncoderConfig {
+  fn default() -> Self {
+    EncoderConfig {
+      output_format: OutputFormat::Xa,
+      sample_rate: DEFAULT_SAMPLE_RATE,
+      resample: false,
+      interfile_gap_ms: DEFAULT_INTERFILE_GAP_MS,
+      stereo_channel: None,
+      reset_at_gaps: false,
+      fade_in_ms: 0,
+      fade_out_ms: 0,
+      fade_curve: FadeCurve::Linear,
+      trim_silence: false,
+      trim_threshold_dbfs: DEFAULT_TRIM_THRESHOLD_DBFS,
+      trim_min_run_ms: DEFAULT_TRIM_MIN_RUN_MS,
+      trim_pre_roll_ms: DEFAULT_TRIM_PRE_ROLL_MS,
+      parallel: false,
+      stats: false,
+      snr_weighting: SnrWeighting::Flat,
+      loop_region: None,
+      seamless_loop: false,
+      dither_seed: None,
+      apcm_unknown: 0,
+      comm_sample_size: APCM_SAMPLE_SIZE,
+      comm_sample_frames: None,
+      reference_header: None,
+      max_sectors: None,
+      hash_output: false,
+      tail_mode: TailMode::Zero,
+      peak_headroom_pct: 0,
+      sector_padding: SectorPadding::Padded,
+      sound_group_layout: SoundGroupLayout::CdXaRedundant,
+      gain_db: 0.0,
+      pre_emphasis_db: 0.0,
+      pre_emphasis_hz: DEFAULT_PRE_EMPHASIS_HZ,
+      soft_clip_dbfs: None,
+      allowed_filters: FilterSet::ALL,
+      max_clipped_samples: None,
+      force_params: None,
+      rounding: RoundingMode::Nearest,
+      filter_tables: FilterTables::STANDARD,
+      state_resets: Vec::new(),
+    }
+  }
+}
+
+impl EncoderConfig {
+  pub fn builder() -> EncoderConfigBuilder {
+    EncoderConfigBuilder::new()
+  }
+
+  /// The settings applied to every sound unit and sector
+  pub fn encode_options(&self) -> EncodeOptions {
+    EncodeOptions {
+      dither_seed: self.dither_seed,
+      tail_mode: self.tail_mode,
+      peak_headroom_pct: self.peak_headroom_pct,
+      sector_padding: self.sector_padding,
+      sound_group_layout: self.sound_group_layout,
+      allowed_filters: self.allowed_filters,
+      force_params: self.force_params,
+      rounding: self.rounding,
+      filter_tables: self.filter_tables,
+      state_resets: self.state_resets.clone(),
+    }
+  }
+
+  /// Checks that the selected options can be combined.
+  pub fn validate(&self) -> Result<()> {
+    if !XA_SAMPLE_RATES.contains(&self.sample_rate) {
+      return Err(anyhow!(
+        "Unsupported sample rate {} Hz, expected {} or {}", self.sample_rate, NORMAL_SAMPLE_RATE, HIGH_SAMPLE_RATE
+      ))
+    }
+    if self.output_format == OutputFormat::Spu && (self.parallel || self.stats) {
+      return Err(anyhow!("Parallel encoding and stats are only supported for XA output"))
+    }
+    if self.parallel && self.stats {
+      return Err(anyhow!("Stats can't be collected while encoding in parallel"))
+    }
+    if let Some(reference) = &self.reference_header {
+      if self.output_format != OutputFormat::Xa {
+        return Err(anyhow!("A reference header only applies to XA output"))
+      }
+      if reference.sector_size() != self.sector_padding.sector_size() || reference.sample_rate() != self.sample_rate {
+        return Err(anyhow!(
+          "The reference header is for {}-byte sectors at {} Hz, but the output has {}-byte sectors at {} Hz",
+          reference.sector_size(), reference.sample_rate(), self.sector_padding.sector_size(), self.sample_rate
+        ))
+      }
+    }
+    if self.max_sectors.is_some() && self.output_format != OutputFormat::Xa {
+      return Err(anyhow!("A sector limit only applies to XA output"))
+    }
+    if self.loop_region.is_some() && self.output_format != OutputFormat::Spu {
+      return Err(anyhow!("Loop regions are only supported for SPU output"))
+    }
+    if self.seamless_loop && (self.output_format != OutputFormat::Xa || self.parallel || self.stats) {
+      return Err(anyhow!("Seamless looping is only supported for plain XA output"))
+    }
+    // Each channel would be trimmed on its own, leaving the two streams out of step
+    if self.stereo_channel.is_some() && self.trim_silence {
+      return Err(anyhow!("Silence trimming can't be used when encoding one channel of a stereo input"))
+    }
+    if self.trim_threshold_dbfs > 0.0 {
+      return Err(anyhow!("Silence threshold must be at most 0 dBFS, got {}", self.trim_threshold_dbfs))
+    }
+    if self.dither_seed.is_some() && self.parallel {
+      return Err(anyhow!("Dither isn't supported while encoding in parallel"))
+    }
+    if self.sector_padding != SectorPadding::Padded && self.output_format != OutputFormat::Xa {
+      return Err(anyhow!("Sector padding only applies to XA output"))
+    }
+    if self.sound_group_layout != SoundGroupLayout::CdXaRedundant {
+      if self.output_format != OutputFormat::Xa {
+        return Err(anyhow!("Sound group layouts only apply to XA output"))
+      }
+      // The stats decode each sector, and the decoder only reads the CD-XA layout
+      if self.stats {
+        return Err(anyhow!("Stats are only supported with the CD-XA sound group layout"))
+      }
+    }
+    if !(-MAX_GAIN_DB..=MAX_GAIN_DB).contains(&self.gain_db) {
+      return Err(anyhow!("Gain must be between -{} and {} dB, got {} dB", MAX_GAIN_DB, MAX_GAIN_DB, self.gain_db))
+    }
+    if !(0.0..=MAX_PRE_EMPHASIS_DB).contains(&self.pre_emphasis_db) {
+      return Err(anyhow!("Pre-emphasis must be between 0 and {} dB, got {} dB", MAX_PRE_EMPHASIS_DB, self.pre_emphasis_db))
+    }
+    let nyquist_hz = f64::from(self.sample_rate) / 2.0;
+    if !(self.pre_emphasis_hz > 0.0 && self.pre_emphasis_hz < nyquist_hz) {
+      return Err(anyhow!("Pre-emphasis corner must be between 0 and {} Hz, got {} Hz", nyquist_hz, self.pre_emphasis_hz))
+    }
+    if let Some(soft_clip_dbfs) = self.soft_clip_dbfs {
+      if !(MIN_SOFT_CLIP_DBFS..0.0).contains(&soft_clip_dbfs) {
+        return Err(anyhow!("Soft clip threshold must be at least {} dBFS and below 0 dBFS, got {} dBFS", MIN_SOFT_CLIP_DBFS, soft_clip_dbfs))
+      }
+    }
+    if self.allowed_filters.is_empty() {
+      return Err(anyhow!("At least one filter must be allowed"))
+    }
+    if self.peak_headroom_pct > MAX_PEAK_HEADROOM_PCT {
+      return Err(anyhow!("Peak headroom must be at most {}%, got {}%", MAX_PEAK_HEADROOM_PCT, self.peak_headroom_pct))
+    }
+    if !(1..=32).contains(&self.comm_sample_size) {
+      return Err(anyhow!("COMM sample size must be between 1 and 32 bits, got {}", self.comm_sample_size))
+    }
+    if (self.reset_at_gaps || !self.state_resets.is_empty()) && (self.parallel || self.seamless_loop) {
+      return Err(anyhow!("Filter state resets need a single sequential encode, not parallel or seamless loop encoding"))
+    }
+    if let Some((filter, range)) = self.force_params {
+      if !self.allowed_filters.contains(usize::from(filter)) {
+        return Err(anyhow!("Forced filter {} isn't one of the allowed filters", filter))
+      }
+      if usize::from(range) > MAX_RANGE {
+        return Err(anyhow!("Forced range must be at most {}, got {}", MAX_RANGE, range))
+      }
+    }
+    if !self.filter_tables.is_valid() {
+      return Err(anyhow!(
+        "Filter tables must be 2^6 fixed point, each coefficient from {} to {}, with filter 0 as (0, 0); got K0 {:?}, K1 {:?}",
+        FILTER_COEFFICIENT_RANGE.start(), FILTER_COEFFICIENT_RANGE.end(), self.filter_tables.k0, self.filter_tables.k1
+      ))
+    }
+
+    Ok(())
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EncoderConfigBuilder {
+  config: EncoderConfig,
+}
+
+impl EncoderConfigBuilder {
+  pub fn new() -> Self {
+    EncoderConfigBuilder { config: EncoderConfig::default() }
+  }
+
+  pub fn output_format(mut self, output_format: OutputFormat) -> Self {
+    self.config.output_format = output_format;
+    self
+  }
+
+  pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+    self.config.sample_rate = sample_rate;
+    self
+  }
+
+  /// Sets `sample_rate` to the rate of an XA mode
+  pub fn rate(self, rate: XaRate) -> Self {
+    self.sample_rate(rate.sample_rate())
+  }
+
+  pub fn resample(mut self, resample: bool) -> Self {
+    self.config.resample = resample;
+    self
+  }
+
+  pub fn interfile_gap_ms(mut self, interfile_gap_ms: usize) -> Self {
+    self.config.interfile_gap_ms = interfile_gap_ms;
+    self
+  }
+
+  pub fn reset_at_gaps(mut self, reset_at_gaps: bool) -> Self {
+    self.config.reset_at_gaps = reset_at_gaps;
+    self
+  }
+
+  pub fn fade_in_ms(mut self, fade_in_ms: usize) -> Self {
+    self.config.fade_in_ms = fade_in_ms;
+    self
+  }
+
+  pub fn fade_out_ms(mut self, fade_out_ms: usize) -> Self {
+    self.config.fade_out_ms = fade_out_ms;
+    self
+  }
+
+  pub fn fade_curve(mut self, fade_curve: FadeCurve) -> Self {
+    self.config.fade_curve = fade_curve;
+    self
+  }
+
+  pub fn stereo_channel(mut self, stereo_channel: Option<StereoChannel>) -> Self {
+    self.config.stereo_channel = stereo_channel;
+    self
+  }
+
+  pub fn trim_silence(mut self, trim_silence: bool) -> Self {
+    self.config.trim_silence = trim_silence;
+    self
+  }
+
+  pub fn trim_threshold_dbfs(mut self, trim_threshold_dbfs: f64) -> Self {
+    self.config.trim_threshold_dbfs = trim_threshold_dbfs;
+    self
+  }
+
+  pub fn trim_min_run_ms(mut self, trim_min_run_ms: usize) -> Self {
+    self.config.trim_min_run_ms = trim_min_run_ms;
+    self
+  }
+
+  pub fn trim_pre_roll_ms(mut self, trim_pre_roll_ms: usize) -> Self {
+    self.config.trim_pre_roll_ms = trim_pre_roll_ms;
+    self
+  }
+
+  pub fn parallel(mut self, parallel: bool) -> Self {
+    self.config.parallel = parallel;
+    self
+  }
+
+  pub fn stats(mut self, stats: bool) -> Self {
+    self.config.stats = stats;
+    self
+  }
+
+  pub fn snr_weighting(mut self, snr_weighting: SnrWeighting) -> Self {
+    self.config.snr_weighting = snr_weighting;
+    self
+  }
+
+  pub fn loop_region(mut self, loop_region: Option<LoopRegion>) -> Self {
+    self.config.loop_region = loop_region;
+    self
+  }
+
+  pub fn seamless_loop(mut self, seamless_loop: bool) -> Self {
+    self.config.seamless_loop = seamless_loop;
+    self
+  }
+
+  pub fn dither_seed(mut self, dither_seed: Option<u64>) -> Self {
+    self.config.dither_seed = dither_seed;
+    self
+  }
+
+  pub fn apcm_unknown(mut self, apcm_unknown: i32) -> Self {
+    self.config.apcm_unknown = apcm_unknown;
+    self
+  }
+
+  pub fn comm_sample_size(mut self, comm_sample_size: i16) -> Self {
+    self.config.comm_sample_size = comm_sample_size;
+    self
+  }
+
+  pub fn comm_sample_frames(mut self, comm_sample_frames: Option<u32>) -> Self {
+    self.config.comm_sample_frames = comm_sample_frames;
+    self
+  }
+
+  pub fn reference_header(mut self, reference_header: Option<ReferenceHeader>) -> Self {
+    self.config.reference_header = reference_header;
+    self
+  }
+
+  pub fn max_sectors(mut self, max_sectors: Option<usize>) -> Self {
+    self.config.max_sectors = max_sectors;
+    self
+  }
+
+  pub fn hash_output(mut self, hash_output: bool) -> Self {
+    self.config.hash_output = hash_output;
+    self
+  }
+
+  pub fn rounding(mut self, rounding: RoundingMode) -> Self {
+    self.config.rounding = rounding;
+    self
+  }
+
+  pub fn tail_mode(mut self, tail_mode: TailMode) -> Self {
+    self.config.tail_mode = tail_mode;
+    self
+  }
+
+  pub fn peak_headroom_pct(mut self, peak_headroom_pct: u32) -> Self {
+    self.config.peak_headroom_pct = peak_headroom_pct;
+    self
+  }
+
+  pub fn sector_padding(mut self, sector_padding: SectorPadding) -> Self {
+    self.config.sector_padding = sector_padding;
+    self
+  }
+
+  pub fn sound_group_layout(mut self, sound_group_layout: SoundGroupLayout) -> Self {
+    self.config.sound_group_layout = sound_group_layout;
+    self
+  }
+
+  pub fn gain_db(mut self, gain_db: f64) -> Self {
+    self.config.gain_db = gain_db;
+    self
+  }
+
+  pub fn pre_emphasis_db(mut self, pre_emphasis_db: f64) -> Self {
+    self.config.pre_emphasis_db = pre_emphasis_db;
+    self
+  }
+
+  pub fn pre_emphasis_hz(mut self, pre_emphasis_hz: f64) -> Self {
+    self.config.pre_emphasis_hz = pre_emphasis_hz;
+    self
+  }
+
+  pub fn soft_clip_dbfs(mut self, soft_clip_dbfs: Option<f64>) -> Self {
+    self.config.soft_clip_dbfs = soft_clip_dbfs;
+    self
+  }
+
+  pub fn allowed_filters(mut self, allowed_filters: FilterSet) -> Self {
+    self.config.allowed_filters = allowed_filters;
+    self
+  }
+
+  pub fn max_clipped_samples(mut self, max_clipped_samples: Option<usize>) -> Self {
+    self.config.max_clipped_samples = max_clipped_samples;
+    self
+  }
+
+  /// Debugging only: skips filter and range selection and writes `(filter, range)` for every
+  /// sound unit.
+  pub fn force_params(mut self, force_params: Option<(u8, u8)>) -> Self {
+    self.config.force_params = force_params;
+    self
+  }
+
+  /// Coefficients to encode with in place of `FilterTables::STANDARD`.
+  pub fn filter_tables(mut self, filter_tables: FilterTables) -> Self {
+    self.config.filter_tables = filter_tables;
+    self
+  }
+
+  pub fn state_resets(mut self, state_resets: Vec<usize>) -> Self {
+    self.config.state_resets = state_resets;
+    self
+  }
+
+  pub fn build(self) -> Result<EncoderConfig> {
+    self.config.validate()?;
+    Ok(self.config)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn builder_defaults_match_default() {
+    assert_eq!(EncoderConfig::builder().build().unwrap(), EncoderConfig::default());
+  }
+
+  #[test]
+  fn builder_rejects_invalid_combinations() {
+    assert!(EncoderConfig::builder().parallel(true).stats(true).build().is_err());
+    assert!(EncoderConfig::builder().output_format(OutputFormat::Spu).stats(true).build().is_err());
+    assert!(EncoderConfig::builder().output_format(OutputFormat::Spu).max_sectors(Some(100)).build().is_err());
+    assert!(EncoderConfig::builder().loop_region(Some(LoopRegion { start: 0, end: 10 })).build().is_err());
+    assert!(EncoderConfig::builder().seamless_loop(true).parallel(true).build().is_err());
+    assert!(EncoderConfig::builder().seamless_loop(true).output_format(OutputFormat::Spu).build().is_err());
+    assert!(EncoderConfig::builder().sound_group_layout(SoundGroupLayout::Single).stats(true).build().is_err());
+    assert!(EncoderConfig::builder().sound_group_layout(SoundGroupLayout::Repeated).output_format(OutputFormat::Spu).build().is_err());
+    assert!(EncoderConfig::builder().dither_seed(Some(1)).parallel(true).build().is_err());
+    assert!(EncoderConfig::builder().sample_rate(44_100).build().is_err());
+    assert!(EncoderConfig::builder().peak_headroom_pct(101).build().is_err());
+    assert!(EncoderConfig::builder().gain_db(-30.0).build().is_err());
+    assert!(EncoderConfig::builder().gain_db(-6.0).build().is_ok());
+    assert!(EncoderConfig::builder().pre_emphasis_db(-3.0).build().is_err());
+    assert!(EncoderConfig::builder().allowed_filters(FilterSet::from_bits(0)).build().is_err());
+    assert!(EncoderConfig::builder().allowed_filters(FilterSet::from_bits(0b0011)).build().is_ok());
+    assert!(EncoderConfig::builder().pre_emphasis_db(3.0).pre_emphasis_hz(9450.0).build().is_err());
+    assert!(EncoderConfig::builder().pre_emphasis_db(3.0).pre_emphasis_hz(9450.0).sample_rate(37_800).build().is_ok());
+    assert!(EncoderConfig::builder().soft_clip_dbfs(Some(-1.0)).build().is_ok());
+    assert!(EncoderConfig::builder().soft_clip_dbfs(Some(0.0)).build().is_err());
+    assert!(EncoderConfig::builder().soft_clip_dbfs(Some(-30.0)).build().is_err());
+    assert!(EncoderConfig::builder().sample_rate(37_800).build().is_ok());
+    assert!(EncoderConfig::builder().force_params(Some((4, 0))).build().is_err());
+    assert!(EncoderConfig::builder().force_params(Some((0, 13))).build().is_err());
+    assert!(EncoderConfig::builder().force_params(Some((2, 0))).allowed_filters(FilterSet::from_bits(0b0011)).build().is_err());
+    assert!(EncoderConfig::builder().force_params(Some((3, 12))).build().is_ok());
+    let scaled = FilterTables { k0: [0, 240, 460, 392], k1: [0, 0, -208, -220] };
+    assert!(EncoderConfig::builder().filter_tables(scaled).build().is_err());
+    let filter_0_moved = FilterTables { k0: [60, 60, 115, 98], ..FilterTables::STANDARD };
+    assert!(EncoderConfig::builder().filter_tables(filter_0_moved).build().is_err());
+    let tweaked = FilterTables { k0: [0, 61, 115, 98], ..FilterTables::STANDARD };
+    assert!(EncoderConfig::builder().filter_tables(tweaked).build().is_ok());
+    assert!(EncoderConfig::builder().state_resets(vec![100]).parallel(true).build().is_err());
+    assert!(EncoderConfig::builder().state_resets(vec![100]).seamless_loop(true).build().is_err());
+    assert!(EncoderConfig::builder().reset_at_gaps(true).parallel(true).build().is_err());
+    assert!(EncoderConfig::builder().comm_sample_size(0).build().is_err());
+    assert!(EncoderConfig::builder().comm_sample_size(16).build().is_ok());
+    assert!(EncoderConfig::builder().stereo_channel(Some(StereoChannel::Left)).trim_silence(true).build().is_err());
+
+    let config = EncoderConfig::builder()
+      .output_format(OutputFormat::Spu)
+      .loop_region(Some(LoopRegion { start: 0, end: 10 }))
+      .build()
+      .unwrap();
+    assert_eq!(config.loop_region, Some(LoopRegion { start: 0, end: 10 }));
+
+    let config = EncoderConfig::builder().rounding(RoundingMode::Truncate).build().unwrap();
+    assert_eq!(config.encode_options().rounding, RoundingMode::Truncate);
+  }
+
+  #[test]
+  fn stereo_channel_output_paths() {
+    assert_eq!(StereoChannel::Left.output_path(Path::new("out/MUSIC.ACM")), Path::new("out/MUSIC_L.ACM"));
+    assert_eq!(StereoChannel::Right.output_path(Path::new("out/MUSIC.ACM")), Path::new("out/MUSIC_R.ACM"));
+    assert_eq!(StereoChannel::Right.output_path(Path::new("music")), Path::new("music_R"));
+  }
+
+  #[test]
+  fn reference_header_must_match_the_output_layout() {
+    let mut file = Vec::new();
+    crate::aiff::write_apcm_aiff_header(5000, NORMAL_SAMPLE_RATE, APCM_SAMPLE_SIZE, 0x914, 0, &mut file).unwrap();
+    let reference = ReferenceHeader::from_reader(&mut file.as_slice()).unwrap();
+
+    let with_reference = || EncoderConfig::builder().reference_header(Some(reference.clone()));
+    assert!(with_reference().build().is_ok());
+    assert!(with_reference().sample_rate(HIGH_SAMPLE_RATE).build().is_err());
+    assert!(with_reference().sector_padding(SectorPadding::Bare).build().is_err());
+    assert!(with_reference().output_format(OutputFormat::Spu).build().is_err());
+  }
+}
@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Context, Result};
+use rayon::prelude::*;
+use std::path::PathBuf;
+
+use crate::{
+  adpcm_encoder::OutputFormat,
+  aiff::decode_apcm_aiff,
+  config::EncoderConfig,
+  input::{prep_input_reader, ClipProcessing},
+  offsets::sector_to_sample,
+  pipeline,
+  stats::{find_lag, spectral_distance_db, DEFAULT_ALIGN_WINDOW_SECTORS},
+};
+
+/// Pre-emphasis settings `match_emphasis` tries. Every gain is tried with every corner, except that
+/// a gain of 0 dB, which leaves the input alone, is only tried once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmphasisGrid {
+  pub gains_db: Vec<f64>,
+  pub corners_hz: Vec<f64>,
+}
+
+impl Default for EmphasisGrid {
+  fn default() -> Self {
+    EmphasisGrid {
+      gains_db: vec![0.0, 1.5, 3.0, 4.5, 6.0, 9.0],
+      corners_hz: vec![2000.0, 3000.0, 4000.0, 6000.0],
+    }
+  }
+}
+
+/// One point of the grid and how far its encode ended up from the reference.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmphasisCandidate {
+  pub gain_db: f64,
+  pub corner_hz: f64,
+  /// `spectral_distance_db` between the decoded candidate and the decoded reference
+  pub distance_db: f64,
+}
+
+/// Every candidate tried by `match_emphasis`, closest first, and the config of the closest.
+#[derive(Debug, Clone)]
+pub struct EmphasisMatch {
+  pub candidates: Vec<EmphasisCandidate>,
+  pub config: EncoderConfig,
+}
+
+/// Encodes `inputs` once for each point of `grid`, starting from `config`, and compares each
+/// decoded result with `reference`, the samples of an existing encode of the same audio. Leading
+/// blank sectors are left out of both, so `reference` should be decoded with them skipped too. The
+/// two are then lined up with `find_lag`, up to `DEFAULT_ALIGN_WINDOW_SECTORS` either way, since a
+/// reference from another tool rarely starts at the same sample: it may have been trimmed
+/// differently or have quiet but not blank sectors ahead of the audio.
+///
+/// The candidates are encoded in parallel, as XA with `config.sample_rate`, which should be the
+/// reference's rate. Any grid point that `config` can't take, such as a corner above Nyquist, is
+/// an error.
+pub fn match_emphasis(reference: &[i16], inputs: &[PathBuf], config: &EncoderConfig, grid: &EmphasisGrid) -> Result<EmphasisMatch> {
+  let mut points: Vec<(f64, f64)> = Vec::new();
+  for &gain_db in &grid.gains_db {
+    if gain_db == 0.0 {
+      points.push((0.0, config.pre_emphasis_hz));
+    } else {
+      points.extend(grid.corners_hz.iter().map(|&corner_hz| (gain_db, corner_hz)));
+    }
+  }
+  if points.is_empty() {
+    return Err(anyhow!("No pre-emphasis settings to try"))
+  }
+
+  let candidate_config = |gain_db: f64, corner_hz: f64| EncoderConfig {
+    output_format: OutputFormat::Xa,
+    stats: false,
+    pre_emphasis_db: gain_db,
+    pre_emphasis_hz: corner_hz,
+    ..config.clone()
+  };
+
+  let mut candidates = points.into_par_iter()
+    .map(|(gain_db, corner_hz)| {
+      let config = candidate_config(gain_db, corner_hz);
+      let (samples_count, mut rdr, _) = prep_input_reader(inputs.to_vec(), config.interfile_gap_ms, ClipProcessing::from_config(&config))?;
+      let mut encoded = Vec::new();
+      pipeline::encode(&config, samples_count, &mut rdr, &mut encoded)
+        .with_context(|| format!("Failed to encode with {} dB of pre-emphasis at {} Hz", gain_db, corner_hz))?;
+      let decoded = decode_apcm_aiff(&mut encoded.as_slice(), true)?;
+
+      let window = sector_to_sample(DEFAULT_ALIGN_WINDOW_SECTORS) as isize;
+      let lag = find_lag(&decoded.samples, reference, -window, window);
+      let reference = reference.get(lag.max(0) as usize..).unwrap_or_default();
+      let decoded = decoded.samples.get(lag.min(0).unsigned_abs()..).unwrap_or_default();
+
+      let distance_db = spectral_distance_db(reference, decoded, config.sample_rate);
+      Ok(EmphasisCandidate { gain_db, corner_hz, distance_db })
+    })
+    .collect::<Result<Vec<_>>>()?;
+  candidates.sort_by(|a, b| a.distance_db.total_cmp(&b.distance_db));
+
+  let config = candidate_config(candidates[0].gain_db, candidates[0].corner_hz);
+  Ok(EmphasisMatch { candidates, config })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::wav::write_wav;
+  use std::{f64::consts::TAU, fs};
+
+  #[test]
+  fn finds_the_emphasis_the_reference_was_made_with() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source.wav");
+    let samples: Vec<i16> = (0..18_900)
+      .map(|n| {
+        let t = n as f64 / 18_900.0;
+        ((t * 440.0 * TAU).sin() * 6000.0 + (t * 5000.0 * TAU).sin() * 1500.0) as i16
+      })
+      .collect();
+    write_wav(&samples, 18_900, &mut fs::File::create(&source).unwrap()).unwrap();
+
+    let made_with = EncoderConfig { pre_emphasis_db: 6.0, pre_emphasis_hz: 3000.0, ..Default::default() };
+    let reference_file = dir.path().join("reference.acm");
+    pipeline::encode_file_to_file(&source, &reference_file, &made_with).unwrap();
+    let reference = decode_apcm_aiff(&mut fs::File::open(&reference_file).unwrap(), true).unwrap().samples;
+
+    let grid = EmphasisGrid { gains_db: vec![0.0, 6.0, 12.0], corners_hz: vec![3000.0, 6000.0] };
+    let found = match_emphasis(&reference, &[source], &EncoderConfig::default(), &grid).unwrap();
+    assert_eq!(found.candidates.len(), 5);
+    assert_eq!((found.candidates[0].gain_db, found.candidates[0].corner_hz), (6.0, 3000.0));
+    assert_eq!(found.candidates[0].distance_db, 0.0);
+    assert_eq!((found.config.pre_emphasis_db, found.config.pre_emphasis_hz), (6.0, 3000.0));
+  }
+
+  #[test]
+  fn lines_up_a_reference_with_extra_leading_sectors() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source.wav");
+    let samples: Vec<i16> = (0..18_900)
+      .map(|n| {
+        let t = n as f64 / 18_900.0;
+        ((t * 440.0 * TAU).sin() * 6000.0 * (1.0 - t) + (t * 5000.0 * TAU).sin() * 1500.0 * t) as i16
+      })
+      .collect();
+    write_wav(&samples, 18_900, &mut fs::File::create(&source).unwrap()).unwrap();
+
+    // The reference has two and a bit sectors of near-silence ahead of the audio, which aren't
+    // blank, so they're still there once it's decoded
+    let padded: Vec<i16> = (0..2 * 4032 + 300).map(|n| if n % 2 == 0 { 2 } else { -2 }).chain(samples).collect();
+    let padded_source = dir.path().join("padded.wav");
+    write_wav(&padded, 18_900, &mut fs::File::create(&padded_source).unwrap()).unwrap();
+    let made_with = EncoderConfig { pre_emphasis_db: 6.0, pre_emphasis_hz: 3000.0, ..Default::default() };
+    let reference_file = dir.path().join("reference.acm");
+    pipeline::encode_file_to_file(&padded_source, &reference_file, &made_with).unwrap();
+    let reference = decode_apcm_aiff(&mut fs::File::open(&reference_file).unwrap(), true).unwrap().samples;
+    assert!(reference.len() > padded.len() - 300);
+
+    let grid = EmphasisGrid { gains_db: vec![0.0, 6.0, 12.0], corners_hz: vec![3000.0, 6000.0] };
+    let found = match_emphasis(&reference, &[source], &EncoderConfig::default(), &grid).unwrap();
+    assert_eq!((found.candidates[0].gain_db, found.candidates[0].corner_hz), (6.0, 3000.0));
+    assert!(found.candidates[0].distance_db < 3.0, "distance {}", found.candidates[0].distance_db);
+  }
+}
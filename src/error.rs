@@ -0,0 +1,85 @@
+use std::io;
+use thiserror::Error;
+
+/// Errors produced while parsing WAV and AIFF input.
+#[derive(Debug, Error)]
+pub enum XaError {
+  #[error("Not a RIFF file")]
+  NotRiff,
+
+  #[error("Not a WAVE file")]
+  NotWave,
+
+  #[error("Not an AIFF form type: {}", fourcc(.0))]
+  NotAiff([u8; 4]),
+
+  /// The chunk with this ID was expected but something else was found
+  #[error("Missing `{}` chunk, found `{}`", fourcc(.expected), fourcc(.found))]
+  MissingChunk { expected: [u8; 4], found: [u8; 4] },
+
+  #[error("WAV data chunk claims {declared} bytes, but only {available} bytes follow the headers")]
+  DataSizeExceedsFile { declared: u64, available: u64 },
+
+  #[error("WAV smpl loop {start}..={end} is outside the {samples_count} sample frames of data")]
+  LoopOutOfRange { start: usize, end: usize, samples_count: usize },
+
+  #[error("Unsupported audio format: {0}")]
+  UnsupportedAudioFormat(u16),
+
+  #[error("Unsupported number of audio channels: {0}")]
+  UnsupportedChannels(u16),
+
+  #[error("Unsupported sample rate: {0}")]
+  UnsupportedSampleRate(u32),
+
+  #[error("Unsupported number of bits per sample: {0}")]
+  UnsupportedBitDepth(u16),
+
+  #[error("Unexpected byte rate: {0}")]
+  UnexpectedByteRate(u32),
+
+  #[error("Unexpected block align: {0}")]
+  UnexpectedBlockAlign(u16),
+
+  #[error("Unexpected {} chunk size: expected {expected}, found {found}", fourcc(.chunk))]
+  ChunkSize { chunk: [u8; 4], expected: i64, found: i64 },
+
+  #[error("Unexpected APCM chunk size: expected 8 + a whole number of {sector_size}-byte sectors (nearest {} or {}), found {found}", .nearest.0, .nearest.1)]
+  PartialSector { sector_size: i32, nearest: (i64, i64), found: i32 },
+
+  #[error("APCM chunk size doesn't match FORM size: expected {expected}, found {found}")]
+  FormSizeMismatch { expected: i64, found: i64 },
+
+  #[error("Invalid APCM sector size: {0}")]
+  InvalidSectorSize(i32),
+
+  #[error("Output too large for AIFF (max ~2GB): {num_sectors} sectors, at most {max_sectors} fit")]
+  OutputTooLarge { num_sectors: usize, max_sectors: usize },
+
+  #[error(
+    "Output needs {num_sectors} sectors, {} more than the limit of {max_sectors}; trim at least {over_secs:.2}s",
+    .num_sectors - .max_sectors
+  )]
+  SectorLimitExceeded { num_sectors: usize, max_sectors: usize, over_secs: f64 },
+
+  #[error("APCM data ended after {read} of {expected} sectors")]
+  TruncatedSectors { read: usize, expected: usize },
+
+  #[error("XA data ended {read} bytes into a {sector_size}-byte sector")]
+  TruncatedSector { read: usize, sector_size: usize },
+
+  #[cfg(feature = "hound")]
+  #[error(transparent)]
+  Hound(#[from] hound::Error),
+
+  #[cfg(feature = "symphonia")]
+  #[error(transparent)]
+  Symphonia(#[from] symphonia::core::errors::Error),
+
+  #[error(transparent)]
+  Io(#[from] io::Error),
+}
+
+fn fourcc(id: &[u8; 4]) -> String {
+  String::from_utf8_lossy(id).into_owned()
+}
@@ -0,0 +1,972 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, trace};
+use std::{
+  cmp::{min, Ordering},
+  collections::VecDeque,
+  fmt,
+  fs,
+  io::{self, BufReader, Cursor, Read, Seek},
+  path::{Path, PathBuf},
+  sync::OnceLock,
+};
+#[cfg(feature = "hound")]
+use std::io::SeekFrom;
+
+use crate::{
+  adpcm_encoder::{LoopRegion, OutputFormat},
+  config::{EncoderConfig, StereoChannel, DEFAULT_SAMPLE_RATE},
+  preprocess::{
+    resample_reader,
+    resampled_len,
+    Fade,
+    FadeReader,
+    GainReader,
+    PreEmphasis,
+    PreEmphasisReader,
+    SilenceTrim,
+    SoftClip,
+    SoftClipReader,
+  },
+  wav::{ByteSwapReader, ChannelReader, DataChunks, Endian, FormatChunk, Pcm32Reader, RiffHeader},
+  XaError,
+};
+#[cfg(feature = "hound")]
+use crate::wav::HoundReader;
+#[cfg(feature = "symphonia")]
+use crate::symphonia_input;
+
+struct ZeroReader {
+  index: usize,
+  size:  usize,
+}
+
+impl ZeroReader {
+  fn new(size: usize) -> Self {
+    ZeroReader { index: 0, size }
+  }
+}
+impl Read for ZeroReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let limit = min(buf.len(), self.size - self.index);
+
+    buf[..limit].fill(0);
+    self.index += limit;
+    
+    Ok(limit)
+  }
+}
+
+pub fn ms_to_samples(ms: usize, sample_rate: u32) -> usize {
+  ms * sample_rate as usize / 1000
+}
+
+/// Compares paths the way people number files: runs of digits compare by their value, so `clip_2`
+/// sorts before `clip_10`, and everything else compares character by character. Equal numbers
+/// with different zero padding, as in `1` and `01`, put the shorter first.
+pub fn natural_cmp(a: &Path, b: &Path) -> Ordering {
+  let (a, b) = (a.to_string_lossy(), b.to_string_lossy());
+  let (mut a, mut b) = (a.as_ref(), b.as_ref());
+
+  loop {
+    let (Some(a_first), Some(b_first)) = (a.chars().next(), b.chars().next()) else {
+      return a.len().cmp(&b.len())
+    };
+    if a_first.is_ascii_digit() && b_first.is_ascii_digit() {
+      let a_end = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+      let b_end = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+      let (a_digits, b_digits) = (&a[..a_end], &b[..b_end]);
+      let (a_value, b_value) = (a_digits.trim_start_matches('0'), b_digits.trim_start_matches('0'));
+      let ordering = a_value.len().cmp(&b_value.len())
+        .then_with(|| a_value.cmp(b_value))
+        .then_with(|| a_digits.len().cmp(&b_digits.len()));
+      if ordering != Ordering::Equal {
+        return ordering
+      }
+      (a, b) = (&a[a_end..], &b[b_end..]);
+    } else {
+      if a_first != b_first {
+        return a_first.cmp(&b_first)
+      }
+      (a, b) = (&a[a_first.len_utf8()..], &b[b_first.len_utf8()..]);
+    }
+  }
+}
+
+/// Input or output path that stands for stdin or stdout.
+pub const STDIO_PATH: &str = "-";
+
+pub fn is_stdio(path: &Path) -> bool {
+  path == Path::new(STDIO_PATH)
+}
+
+static STDIN_BYTES: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Reads all of stdin the first time it's called, and returns the same bytes on every call.
+///
+/// Stdin can't be seeked, but the WAV parser walks every chunk header to find the data chunks and
+/// their sizes, and concatenation and trimming open each input more than once.
+fn stdin_bytes() -> Result<&'static [u8]> {
+  if let Some(bytes) = STDIN_BYTES.get() {
+    return Ok(bytes)
+  }
+
+  let mut bytes = Vec::new();
+  io::stdin().lock().read_to_end(&mut bytes).context("Failed to read stdin")?;
+  Ok(STDIN_BYTES.get_or_init(|| bytes))
+}
+
+/// Opens a WAV file, or stdin if `path` is `-`, and reads past its headers, picking out `channel`
+/// of a stereo file. See `read_wav`.
+///
+/// Paths that aren't regular files, such as named pipes or `/dev/stdin`, can't be seeked either,
+/// so they're read into memory first like stdin. Unlike stdin they aren't kept, so they can only be
+/// read once: as a single input without silence trimming.
+fn open_wav(path: &Path, sample_rate: Option<u32>, channel: Option<StereoChannel>) -> Result<(usize, u32, Box<dyn Read>)> {
+  if is_stdio(path) {
+    return read_wav(Cursor::new(stdin_bytes()?), path, sample_rate, channel)
+  }
+
+  let infile = fs::File::open(path)
+    .with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+  if !infile.metadata()?.is_file() {
+    return read_wav_buffered(infile, path, sample_rate, channel)
+  }
+  #[cfg(feature = "mmap")]
+  if let Some(mapped) = map_file(&infile) {
+    return read_wav(Cursor::new(mapped), path, sample_rate, channel)
+  }
+  read_wav(BufReader::new(infile), path, sample_rate, channel)
+}
+
+/// Reads past the headers of a WAV file already in memory, such as one made with `wav::write_wav`,
+/// with the same checks as an input file. The file must be mono, and at `sample_rate` unless that's
+/// `None`. Returns the number of samples, the file's sample rate and a reader over the samples,
+/// ready for `pipeline::encode`.
+pub fn read_wav_bytes(bytes: Vec<u8>, sample_rate: Option<u32>) -> Result<(usize, u32, Box<dyn Read>)> {
+  read_wav(Cursor::new(bytes), Path::new("in memory"), sample_rate, None)
+}
+
+/// Reads a whole WAV file from `rdr`, which needn't be seekable, and then reads past its headers
+/// from memory. See `read_wav`.
+fn read_wav_buffered<R: Read>(
+  mut rdr: R,
+  path: &Path,
+  sample_rate: Option<u32>,
+  channel: Option<StereoChannel>,
+) -> Result<(usize, u32, Box<dyn Read>)> {
+  let mut bytes = Vec::new();
+  rdr.read_to_end(&mut bytes)
+    .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+  read_wav(Cursor::new(bytes), path, sample_rate, channel)
+}
+
+/// Maps `file` into memory, or returns `None` if it can't be mapped (e.g. it's a pipe or empty), in
+/// which case it's read through a `BufReader` instead.
+///
+/// The chunk walk and sample reads then work on the mapped bytes, so finding the data chunks is the
+/// same code either way.
+#[cfg(feature = "mmap")]
+fn map_file(file: &fs::File) -> Option<memmap2::Mmap> {
+  // SAFETY: the map is only read, and is dropped when the input reader is. Another process
+  // truncating the file while it's being encoded is undefined behaviour, as it is for every tool
+  // that maps its input.
+  unsafe { memmap2::Mmap::map(file) }.ok()
+}
+
+/// Reads past the headers of a WAV file, which must be at `sample_rate` unless that's `None`.
+/// Returns the number of samples in the file, its sample rate, and a reader over just the sample
+/// data. `path` is only used in error messages.
+///
+/// The file must be mono, or stereo when `channel` is given, in which case only that channel's
+/// samples are read.
+///
+/// Sample data split across several `data` chunks is joined in file order. Each data chunk size is
+/// checked against the file size, so that a corrupt header can't make the encoder produce (or try
+/// to allocate) billions of samples.
+///
+/// With the `hound` feature, a file the built-in parser rejects is read with `hound` instead. When
+/// neither can read it, the parser's error is returned.
+fn read_wav<R: Read + Seek + 'static>(
+  mut rdr: R,
+  path: &Path,
+  sample_rate: Option<u32>,
+  channel: Option<StereoChannel>,
+) -> Result<(usize, u32, Box<dyn Read>)> {
+  let num_channels = if channel.is_some() { 2 } else { 1 };
+  let headers = read_wav_headers(&mut rdr, path, sample_rate, num_channels);
+  #[cfg(feature = "hound")]
+  let headers = match headers {
+    Err(err) => {
+      rdr.rewind()?;
+      return read_wav_hound(rdr, path, sample_rate, channel).map_err(|_| err)
+    },
+    headers => headers,
+  };
+  let (endian, format, data_chunks) = headers?;
+
+  let samples_count = data_chunks.samples_count();
+  let pcm = data_chunks.into_reader(rdr);
+  let pcm: Box<dyn Read> = match endian {
+    Endian::Little => Box::new(pcm),
+    Endian::Big => Box::new(ByteSwapReader::new(pcm, usize::from(format.bits_per_sample / 8))),
+  };
+  let pcm: Box<dyn Read> = match format.bits_per_sample {
+    32 => Box::new(Pcm32Reader::with_valid_bits(pcm, format.valid_bits_per_sample)),
+    _ => pcm,
+  };
+  let pcm: Box<dyn Read> = match channel {
+    Some(channel) => Box::new(ChannelReader::new(pcm, 2, channel.index())),
+    None => pcm,
+  };
+  Ok((samples_count, format.sample_rate, pcm))
+}
+
+/// The byte order, format and data chunks of a WAV file, leaving `rdr` at the first data chunk.
+fn read_wav_headers<R: Read + Seek>(
+  rdr: &mut R,
+  path: &Path,
+  sample_rate: Option<u32>,
+  num_channels: u16,
+) -> Result<(Endian, FormatChunk, DataChunks)> {
+  let endian = RiffHeader::from_reader(&mut *rdr)?.endian();
+  let format = FormatChunk::find(&mut *rdr, endian, sample_rate, num_channels)?;
+  let data_chunks = DataChunks::from_reader_endian(&mut *rdr, format.block_align, endian)
+    .with_context(|| format!("Invalid WAV file {}", path.to_string_lossy()))?;
+  Ok((endian, format, data_chunks))
+}
+
+/// Reads past the headers of a WAV file with `hound`, returning the same as `read_wav`.
+///
+/// The declared sample data size is checked against the file size too.
+#[cfg(feature = "hound")]
+fn read_wav_hound<R: Read + Seek + 'static>(
+  mut rdr: R,
+  path: &Path,
+  sample_rate: Option<u32>,
+  channel: Option<StereoChannel>,
+) -> Result<(usize, u32, Box<dyn Read>)> {
+  let file_size = rdr.seek(SeekFrom::End(0))?;
+  rdr.rewind()?;
+  let num_channels = if channel.is_some() { 2 } else { 1 };
+  let (samples_count, file_rate, rdr) = HoundReader::new_channels(rdr, num_channels)
+    .with_context(|| format!("Invalid WAV file {}", path.to_string_lossy()))?;
+  if sample_rate.is_some_and(|sample_rate| sample_rate != file_rate) {
+    return Err(XaError::UnsupportedSampleRate(file_rate))
+      .with_context(|| format!("Invalid WAV file {}", path.to_string_lossy()))
+  }
+
+  let declared = samples_count as u64 * rdr.sample_size();
+  if declared > file_size {
+    return Err(XaError::DataSizeExceedsFile { declared, available: file_size })
+      .with_context(|| format!("Invalid WAV file {}", path.to_string_lossy()))
+  }
+
+  let rdr: Box<dyn Read> = match channel {
+    Some(channel) => Box::new(ChannelReader::new(rdr, 2, channel.index())),
+    None => Box::new(rdr),
+  };
+  Ok((samples_count, file_rate, rdr))
+}
+
+/// Opens an input file, decoding it with `symphonia` unless it starts like a WAV file. Stdin must
+/// be a WAV file. The file must be at `sample_rate` unless that's `None`; returns the number of
+/// samples, the file's sample rate and a reader over the samples.
+///
+/// Compressed files are decoded in full when opened, so in a concatenation they're decoded once
+/// while counting samples and again while encoding.
+#[cfg(feature = "symphonia")]
+pub fn open_input(path: &Path, sample_rate: Option<u32>) -> Result<(usize, u32, Box<dyn Read>)> {
+  if is_stdio(path) {
+    return open_wav(path, sample_rate, None)
+  }
+
+  let mut magic = [0; 4];
+  let is_riff = fs::File::open(path)
+    .and_then(|mut file| file.read_exact(&mut magic))
+    .is_ok_and(|_| &magic == b"RIFF" || &magic == b"RIFX");
+  if is_riff {
+    return open_wav(path, sample_rate, None)
+  }
+
+  let (samples, file_rate) = match sample_rate {
+    Some(sample_rate) => symphonia_input::decode_file(path, sample_rate).map(|samples| (samples, sample_rate)),
+    None => symphonia_input::decode_file_any_rate(path),
+  }.with_context(|| format!("Failed to decode {}", path.to_string_lossy()))?;
+  let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+  Ok((samples.len(), file_rate, Box::new(Cursor::new(pcm))))
+}
+
+#[cfg(not(feature = "symphonia"))]
+pub fn open_input(path: &Path, sample_rate: Option<u32>) -> Result<(usize, u32, Box<dyn Read>)> {
+  open_wav(path, sample_rate, None)
+}
+
+enum InputSegment {
+  Wav(PathBuf),
+  Pcm(Vec<u8>),
+  Silence(usize),
+}
+
+/// Processing applied to each input file on its own, before files are joined. Every input must be
+/// at `sample_rate`.
+#[derive(Debug, Clone, Copy)]
+pub struct ClipProcessing {
+  sample_rate: u32,
+  resample: bool,
+  trim: Option<SilenceTrim>,
+  fade: Fade,
+  gain_db: Option<f64>,
+  pre_emphasis: Option<PreEmphasis>,
+  soft_clip: Option<SoftClip>,
+  stereo_channel: Option<StereoChannel>,
+}
+
+impl Default for ClipProcessing {
+  fn default() -> Self {
+    ClipProcessing { sample_rate: DEFAULT_SAMPLE_RATE, resample: false, trim: None, fade: Fade::default(), gain_db: None, pre_emphasis: None, soft_clip: None, stereo_channel: None }
+  }
+}
+
+impl ClipProcessing {
+  pub fn from_config(config: &EncoderConfig) -> Self {
+    let sample_rate = config.sample_rate;
+    let trim = config.trim_silence.then(|| SilenceTrim {
+      threshold_dbfs: config.trim_threshold_dbfs,
+      min_run_samples: ms_to_samples(config.trim_min_run_ms, sample_rate),
+      pre_roll_samples: ms_to_samples(config.trim_pre_roll_ms, sample_rate),
+    });
+    let fade = Fade {
+      in_samples: ms_to_samples(config.fade_in_ms, sample_rate),
+      out_samples: ms_to_samples(config.fade_out_ms, sample_rate),
+      curve: config.fade_curve,
+    };
+    let gain_db = (config.gain_db != 0.0).then_some(config.gain_db);
+    let pre_emphasis = (config.pre_emphasis_db > 0.0).then_some(PreEmphasis {
+      gain_db: config.pre_emphasis_db,
+      corner_hz: config.pre_emphasis_hz,
+    });
+
+    let soft_clip = config.soft_clip_dbfs.map(|threshold_dbfs| SoftClip { threshold_dbfs });
+
+    ClipProcessing {
+      sample_rate,
+      resample: config.resample,
+      trim,
+      fade,
+      gain_db,
+      pre_emphasis,
+      soft_clip,
+      stereo_channel: config.stereo_channel,
+    }
+  }
+}
+
+/// Opens an input file as `open_input` does, at `clip.sample_rate`, or just one channel of a stereo
+/// WAV file with `clip.stereo_channel`. With `clip.resample`, a file at
+/// any other rate is resampled to it; the returned sample count is then the resampled length.
+fn open_resampled(path: &Path, clip: ClipProcessing) -> Result<(usize, Box<dyn Read>)> {
+  let sample_rate = (!clip.resample).then_some(clip.sample_rate);
+  let (samples_count, file_rate, rdr) = match clip.stereo_channel {
+    Some(channel) => open_wav(path, sample_rate, Some(channel))?,
+    None => open_input(path, sample_rate)?,
+  };
+  if file_rate == clip.sample_rate {
+    return Ok((samples_count, rdr))
+  }
+
+  let resampled = resample_reader(rdr, samples_count, file_rate, clip.sample_rate);
+  Ok((resampled_len(samples_count, file_rate, clip.sample_rate), resampled))
+}
+
+/// Opens an input file as `open_resampled` does, trimming silence, fading the start and end of its
+/// samples and then applying gain, pre-emphasis and soft clipping. With pre-emphasis, overs are soft
+/// clipped before they're cut to the i16 range.
+///
+/// Trimming needs the position of the last loud sample, so a trimmed file is read through once to
+/// find it and then opened again.
+fn open_clip(path: &Path, clip: ClipProcessing) -> Result<(usize, Box<dyn Read>)> {
+  let (mut samples_count, mut rdr) = open_resampled(path, clip)?;
+
+  if let Some(trim) = clip.trim {
+    let keep = trim.keep_range(rdr)?;
+    trace!("{}: keeping samples {}..{}", path.to_string_lossy(), keep.start, keep.end);
+
+    let (_, mut untrimmed) = open_resampled(path, clip)?;
+    io::copy(&mut untrimmed.by_ref().take(keep.start as u64 * 2), &mut io::sink())?;
+    samples_count = keep.len();
+    rdr = Box::new(untrimmed.take(samples_count as u64 * 2));
+  }
+
+  if !clip.fade.is_none() {
+    rdr = Box::new(FadeReader::new(rdr, samples_count, clip.fade));
+  }
+  if let Some(gain_db) = clip.gain_db {
+    rdr = Box::new(GainReader::new(rdr, gain_db));
+  }
+  match (clip.pre_emphasis, clip.soft_clip) {
+    (Some(pre_emphasis), Some(soft_clip)) => {
+      rdr = Box::new(PreEmphasisReader::new(rdr, clip.sample_rate, pre_emphasis).with_soft_clip(soft_clip));
+    },
+    (Some(pre_emphasis), None) => rdr = Box::new(PreEmphasisReader::new(rdr, clip.sample_rate, pre_emphasis)),
+    (None, Some(soft_clip)) => rdr = Box::new(SoftClipReader::new(rdr, soft_clip)),
+    (None, None) => {},
+  }
+
+  Ok((samples_count, rdr))
+}
+
+/// Reads the sample data of several WAV files and sample buffers in sequence, with silence between
+/// them. Each file is only opened, and its headers skipped, once the previous segment has been read
+/// to the end.
+struct ChainReader {
+  segments: VecDeque<InputSegment>,
+  current: Option<Box<dyn Read>>,
+  clip: ClipProcessing,
+}
+
+impl ChainReader {
+  fn new(segments: VecDeque<InputSegment>, clip: ClipProcessing) -> Self {
+    ChainReader { segments, current: None, clip }
+  }
+}
+
+impl Read for ChainReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+      let current = match self.current.as_mut() {
+        Some(current) => current,
+        None => {
+          let next: Box<dyn Read> = match self.segments.pop_front() {
+            Some(InputSegment::Wav(path)) => {
+              debug!("reading file {}", path.to_string_lossy());
+              let (_, rdr) = open_clip(&path, self.clip).map_err(io::Error::other)?;
+              rdr
+            },
+            Some(InputSegment::Pcm(pcm)) => Box::new(Cursor::new(pcm)),
+            Some(InputSegment::Silence(size)) => {
+              trace!("reading {} bytes of zeroes", size);
+              Box::new(ZeroReader::new(size))
+            },
+            None => return Ok(0),
+          };
+          self.current.insert(next)
+        },
+      };
+
+      let count = current.read(buf)?;
+      if count > 0 || buf.is_empty() {
+        return Ok(count)
+      }
+      self.current = None;
+    }
+  }
+}
+
+/// Where one input file sits in a concatenated stream, in samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClipSpan {
+  pub start: usize,
+  pub samples: usize,
+}
+
+/// Opens `paths` as one stream of samples, joined by `gap_ms` of silence. `clip` processing is
+/// applied to each file separately.
+///
+/// Also returns where each file sits in the stream, in input order.
+pub fn prep_input_reader(paths: Vec<PathBuf>, gap_ms: usize, clip: ClipProcessing) -> Result<(usize, Box<dyn Read>, Vec<ClipSpan>)> {
+  if paths.is_empty() {
+    return Err(anyhow!("No input file paths provided"))
+  }
+
+  if paths.len() == 1 {
+    debug!("reading file {}", paths[0].to_string_lossy());
+    let (samples_count, rdr) = open_clip(&paths[0], clip)?;
+    return Ok((samples_count, rdr, vec![ClipSpan { start: 0, samples: samples_count }]))
+  }
+
+  let mut timeline = Timeline::new(clip);
+  for (n, path) in paths.into_iter().enumerate() {
+    if n > 0 {
+      timeline = timeline.silence_ms(gap_ms);
+    }
+    timeline = timeline.audio(path);
+  }
+  let input = timeline.build()?;
+
+  Ok((input.samples_count, input.reader, input.clips))
+}
+
+/// Audio placed on a `Timeline`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioSource {
+  /// Input file, opened with the timeline's `ClipProcessing` as `prep_input_reader` opens each input
+  Path(PathBuf),
+  /// Samples already at the timeline's sample rate, used as they are
+  Samples(Vec<i16>),
+}
+
+impl From<PathBuf> for AudioSource {
+  fn from(path: PathBuf) -> Self {
+    AudioSource::Path(path)
+  }
+}
+
+impl From<Vec<i16>> for AudioSource {
+  fn from(samples: Vec<i16>) -> Self {
+    AudioSource::Samples(samples)
+  }
+}
+
+/// One piece of a `Timeline`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+  Audio(AudioSource),
+  /// Silence in milliseconds, rounded down to whole samples at the timeline's sample rate
+  Silence(usize),
+  /// Silence in samples, for placing audio at an exact sample offset
+  SilenceSamples(usize),
+}
+
+/// Joins input files, sample buffers and silence, in order, into one stream of samples to encode.
+/// This is what `prep_input_reader` does with its fixed gap between files, but with any mix of
+/// segments.
+///
+/// Silence is true zeros, but the encoder carries its filter state into it from the audio before,
+/// so it only decodes as zeros when `TimelineInput::silence_starts` are added to
+/// `EncoderConfig::state_resets`.
+#[derive(Debug, Clone)]
+pub struct Timeline {
+  clip: ClipProcessing,
+  segments: Vec<Segment>,
+}
+
+impl Timeline {
+  /// Empty timeline at `clip`'s sample rate, applying `clip` to each input file on its own.
+  pub fn new(clip: ClipProcessing) -> Self {
+    Timeline { clip, segments: Vec::new() }
+  }
+
+  pub fn push(mut self, segment: Segment) -> Self {
+    self.segments.push(segment);
+    self
+  }
+
+  pub fn audio(self, source: impl Into<AudioSource>) -> Self {
+    self.push(Segment::Audio(source.into()))
+  }
+
+  pub fn silence_ms(self, ms: usize) -> Self {
+    self.push(Segment::Silence(ms))
+  }
+
+  pub fn silence_samples(self, samples: usize) -> Self {
+    self.push(Segment::SilenceSamples(samples))
+  }
+
+  /// Opens the timeline as one stream. Only the headers of input files are read here, to get the
+  /// total sample count; their sample data is streamed as the stream is read.
+  pub fn build(self) -> Result<TimelineInput> {
+    let mut segments = VecDeque::with_capacity(self.segments.len());
+    let mut samples_count = 0;
+    let mut clips = Vec::new();
+    let mut silence_starts = Vec::new();
+
+    for segment in self.segments {
+      let (samples, input_segment) = match segment {
+        Segment::Audio(AudioSource::Path(path)) => {
+          let (file_samples, _) = open_clip(&path, self.clip)?;
+          clips.push(ClipSpan { start: samples_count, samples: file_samples });
+          (file_samples, InputSegment::Wav(path))
+        },
+        Segment::Audio(AudioSource::Samples(samples)) => {
+          clips.push(ClipSpan { start: samples_count, samples: samples.len() });
+          let pcm = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+          (samples.len(), InputSegment::Pcm(pcm))
+        },
+        Segment::Silence(ms) => {
+          silence_starts.push(samples_count);
+          let samples = ms_to_samples(ms, self.clip.sample_rate);
+          (samples, InputSegment::Silence(samples * 2))
+        },
+        Segment::SilenceSamples(samples) => {
+          silence_starts.push(samples_count);
+          (samples, InputSegment::Silence(samples * 2))
+        },
+      };
+      samples_count += samples;
+      segments.push_back(input_segment);
+    }
+
+    Ok(TimelineInput {
+      samples_count,
+      reader: Box::new(ChainReader::new(segments, self.clip)),
+      clips,
+      silence_starts,
+    })
+  }
+}
+
+/// A built `Timeline`, ready to pass to `pipeline::encode` or `encode_xa_adpcm`.
+pub struct TimelineInput {
+  pub samples_count: usize,
+  pub reader: Box<dyn Read>,
+  /// Where each audio segment sits in the stream, in timeline order
+  pub clips: Vec<ClipSpan>,
+  /// Where each silence segment starts, in timeline order
+  pub silence_starts: Vec<usize>,
+}
+
+/// Loop region to encode `inputs` with: the config's `loop_region` if it has one, otherwise for SPU
+/// output the first loop of the input WAV's `smpl` chunk. The loop points count input sample
+/// frames, so they're only taken from a single input that isn't resampled or trimmed.
+pub fn loop_region_for(config: &EncoderConfig, inputs: &[PathBuf]) -> Result<Option<LoopRegion>> {
+  if config.loop_region.is_some()
+    || config.output_format != OutputFormat::Spu
+    || inputs.len() != 1
+    || config.resample
+    || config.trim_silence
+  {
+    return Ok(config.loop_region)
+  }
+
+  let path = &inputs[0];
+  let loop_region = if is_stdio(path) {
+    read_smpl_loop(Cursor::new(stdin_bytes()?))
+  } else {
+    let infile = fs::File::open(path)
+      .with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+    read_smpl_loop(BufReader::new(infile))
+  };
+
+  loop_region.with_context(|| format!("Invalid WAV file {}", path.to_string_lossy()))
+}
+
+/// Walks the chunks of a WAV file for its `smpl` loop. Inputs the built-in parser can't read, such
+/// as other formats decoded by symphonia, have no loop.
+fn read_smpl_loop<R: Read + Seek>(mut rdr: R) -> Result<Option<LoopRegion>, XaError> {
+  let endian = match RiffHeader::from_reader(&mut rdr) {
+    Ok(header) => header.endian(),
+    Err(XaError::NotRiff | XaError::NotWave) => return Ok(None),
+    Err(e) => return Err(e),
+  };
+  let Ok(format) = FormatChunk::find(&mut rdr, endian, None, 1) else {
+    return Ok(None)
+  };
+
+  Ok(DataChunks::from_reader_endian(&mut rdr, format.block_align, endian)?.loop_region())
+}
+
+/// Samples read from the start of the input by `peek_samples` for `suspect_samples` to look at.
+pub const SANITY_CHECK_SAMPLES: usize = 4096;
+
+/// Why the start of the input looks like it isn't audio read the right way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuspectSamples {
+  /// Every sample is the same loud value
+  Constant(i16),
+  /// Samples are mostly near full scale and jump wildly from one to the next, as audio read with
+  /// the wrong byte order does
+  ByteSwapped,
+}
+
+impl fmt::Display for SuspectSamples {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SuspectSamples::Constant(value) => write!(f, "the first samples are all {}, which isn't audio", value),
+      SuspectSamples::ByteSwapped => write!(f, "the first samples are loud noise, as if read with the wrong byte order"),
+    }
+  }
+}
+
+/// Cheap check of the first few thousand samples of an input for signs that it isn't audio, or was
+/// read with the wrong byte order. Too few samples to judge, and silence, pass.
+///
+/// Audio is mostly low frequencies, so each sample is usually close to the one before it, even when
+/// it's loud. Swapping the bytes of each sample turns its low byte, which is close to random, into
+/// the high byte: the samples come out spread over the whole range, with jumps between neighbours
+/// as large as the samples themselves.
+pub fn suspect_samples(samples: &[i16]) -> Option<SuspectSamples> {
+  if samples.len() < 256 {
+    return None
+  }
+
+  let first = samples[0];
+  if i32::from(first).abs() >= 1024 && samples.iter().all(|&sample| sample == first) {
+    return Some(SuspectSamples::Constant(first))
+  }
+
+  let mean_level = samples.iter().map(|&sample| f64::from(sample).abs()).sum::<f64>() / samples.len() as f64;
+  let mean_step = samples.windows(2)
+    .map(|pair| (f64::from(pair[1]) - f64::from(pair[0])).abs())
+    .sum::<f64>() / (samples.len() - 1) as f64;
+  (mean_level >= 8192.0 && mean_step >= mean_level).then_some(SuspectSamples::ByteSwapped)
+}
+
+/// Reads up to `count` samples from the start of `rdr` and returns them, along with a reader that
+/// gives the same samples as `rdr` would have, from the start.
+pub fn peek_samples(mut rdr: Box<dyn Read>, count: usize) -> io::Result<(Vec<i16>, Box<dyn Read>)> {
+  let mut head = Vec::with_capacity(count * 2);
+  rdr.by_ref().take(count as u64 * 2).read_to_end(&mut head)?;
+  let samples = head.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+
+  Ok((samples, Box::new(Cursor::new(head).chain(rdr))))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::wav::write_wav;
+
+  fn test_wav_bytes(samples: &[i16]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_wav(samples, 18900, &mut bytes).unwrap();
+    bytes
+  }
+
+  fn read_all(mut rdr: Box<dyn Read>) -> Vec<u8> {
+    let mut pcm = Vec::new();
+    rdr.read_to_end(&mut pcm).unwrap();
+    pcm
+  }
+
+  #[test]
+  fn in_memory_wav_counts_samples_from_data_header() {
+    let samples: Vec<i16> = (0..5000).map(|n| (n * 7 % 2000) as i16).collect();
+    let bytes = test_wav_bytes(&samples);
+
+    let (samples_count, sample_rate, rdr) = read_wav(Cursor::new(bytes), Path::new(STDIO_PATH), Some(18900), None).unwrap();
+    assert_eq!(samples_count, 5000);
+    assert_eq!(sample_rate, 18900);
+
+    let expected: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    assert_eq!(read_all(rdr), expected);
+  }
+
+  #[test]
+  fn data_chunk_may_come_before_fmt() {
+    let samples: Vec<i16> = (0..500).map(|n| (n * 13 % 700) as i16).collect();
+    let bytes = test_wav_bytes(&samples);
+    let (header, rest) = bytes.split_at(12);
+    let (fmt_chunk, data_chunk) = rest.split_at(8 + 16);
+    let reordered = [header, data_chunk, fmt_chunk].concat();
+
+    let (samples_count, _, rdr) = read_wav(Cursor::new(reordered), Path::new(STDIO_PATH), Some(18900), None).unwrap();
+    assert_eq!(samples_count, 500);
+    assert_eq!(read_all(rdr), data_chunk[8..]);
+
+    let no_fmt = [header, data_chunk].concat();
+    let err = read_wav(Cursor::new(no_fmt), Path::new(STDIO_PATH), Some(18900), None).err().unwrap();
+    assert!(matches!(
+      err.downcast_ref::<XaError>(),
+      Some(XaError::MissingChunk { expected: [b'f', b'm', b't', b' '], found: [b'd', b'a', b't', b'a'] })
+    ));
+  }
+
+  #[test]
+  fn pcm32_wav_reads_as_16_bit() {
+    let samples = [0_i32, 0x8000, -0x8001, 0x1234_5678, i32::MAX, i32::MIN];
+    let mut bytes = test_wav_bytes(&[]);
+    bytes[28..32].copy_from_slice(&(18900_u32 * 4).to_le_bytes());
+    bytes[32..34].copy_from_slice(&4_u16.to_le_bytes());
+    bytes[34..36].copy_from_slice(&32_u16.to_le_bytes());
+    bytes[40..44].copy_from_slice(&(samples.len() as u32 * 4).to_le_bytes());
+    bytes.extend(samples.iter().flat_map(|sample| sample.to_le_bytes()));
+
+    let (samples_count, _, rdr) = read_wav(Cursor::new(bytes), Path::new(STDIO_PATH), Some(18900), None).unwrap();
+    assert_eq!(samples_count, samples.len());
+
+    let expected: Vec<u8> = [0_i16, 1, -1, 0x1234, i16::MAX, i16::MIN].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    assert_eq!(read_all(rdr), expected);
+  }
+
+  /// Rewrites a plain 44-byte-header RIFF file as its big-endian RIFX twin
+  fn to_rifx(riff: &[u8], sample_width: usize) -> Vec<u8> {
+    let mut rifx = riff.to_vec();
+    rifx[..4].copy_from_slice(b"RIFX");
+    for (offset, width) in [(4, 4), (16, 4), (20, 2), (22, 2), (24, 4), (28, 4), (32, 2), (34, 2), (40, 4)] {
+      rifx[offset..offset + width].reverse();
+    }
+    for sample in rifx[44..].chunks_exact_mut(sample_width) {
+      sample.reverse();
+    }
+    rifx
+  }
+
+  #[test]
+  fn rifx_reads_like_its_riff_twin() {
+    let samples: Vec<i16> = (0..1001).map(|n| (n * 97 % 4000 - 2000) as i16 * 8).collect();
+    let riff = test_wav_bytes(&samples);
+    let (riff_count, _, riff_pcm) = read_wav(Cursor::new(riff.clone()), Path::new(STDIO_PATH), Some(18900), None).unwrap();
+    let (rifx_count, _, rifx_pcm) = read_wav(Cursor::new(to_rifx(&riff, 2)), Path::new(STDIO_PATH), Some(18900), None).unwrap();
+    assert_eq!(rifx_count, riff_count);
+    assert_eq!(read_all(rifx_pcm), read_all(riff_pcm));
+
+    // 32-bit samples are swapped as a whole before rounding to 16 bits
+    let mut riff32 = riff[..44].to_vec();
+    riff32[28..32].copy_from_slice(&(18900_u32 * 4).to_le_bytes());
+    riff32[32..34].copy_from_slice(&4_u16.to_le_bytes());
+    riff32[34..36].copy_from_slice(&32_u16.to_le_bytes());
+    riff32[40..44].copy_from_slice(&(samples.len() as u32 * 4).to_le_bytes());
+    riff32.extend(samples.iter().flat_map(|&sample| (i32::from(sample) << 16 | 0x1234).to_le_bytes()));
+    let (_, _, rifx32_pcm) = read_wav(Cursor::new(to_rifx(&riff32, 4)), Path::new(STDIO_PATH), Some(18900), None).unwrap();
+    let expected: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    assert_eq!(read_all(rifx32_pcm), expected);
+  }
+
+  #[test]
+  fn fmt_chunk_with_extension_size_is_skipped() {
+    let samples: Vec<i16> = (0..300).map(|n| (n * 11) as i16).collect();
+    let mut bytes = test_wav_bytes(&samples);
+    // An 18-byte `fmt ` chunk, ending in a zero cbSize
+    bytes[16..20].copy_from_slice(&18_u32.to_le_bytes());
+    bytes.splice(36..36, [0, 0]);
+    let riff_size = bytes.len() as u32 - 8;
+    bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    let (samples_count, _, rdr) = read_wav(Cursor::new(bytes), Path::new(STDIO_PATH), Some(18900), None).unwrap();
+    assert_eq!(samples_count, samples.len());
+    let expected: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    assert_eq!(read_all(rdr), expected);
+  }
+
+  #[test]
+  fn file_input_finds_data_after_other_chunks() {
+    let samples: Vec<i16> = (0..7000).map(|n| ((n % 500) * 30 - 7000) as i16).collect();
+    let mut bytes = test_wav_bytes(&samples);
+    // Another chunk between `fmt ` and `data`
+    bytes.splice(36..36, *b"LIST\x04\x00\x00\x00INFO");
+    let riff_size = bytes.len() as u32 - 8;
+    bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    fs::write(&wav, &bytes).unwrap();
+
+    // Mapped with the `mmap` feature, buffered without it
+    let (samples_count, _, rdr) = open_wav(&wav, Some(18900), None).unwrap();
+    assert_eq!(samples_count, samples.len());
+    let expected: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    assert_eq!(read_all(rdr), expected);
+  }
+
+  /// Hides the `Seek` of the reader it wraps, like a pipe
+  struct Unseekable<R: Read>(R);
+
+  impl<R: Read> Read for Unseekable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.0.read(buf)
+    }
+  }
+
+  #[test]
+  fn unseekable_input_skips_chunks_after_buffering() {
+    let samples: Vec<i16> = (0..6000).map(|n| ((n % 400) * 25 - 5000) as i16).collect();
+    let mut bytes = test_wav_bytes(&samples);
+    // A `LIST` chunk ahead of `data`, which the chunk walk seeks past
+    bytes.splice(36..36, *b"LIST\x06\x00\x00\x00INFOab");
+    let riff_size = bytes.len() as u32 - 8;
+    bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    let (samples_count, _, rdr) = read_wav_buffered(Unseekable(&bytes[..]), Path::new("pipe"), Some(18900), None).unwrap();
+    assert_eq!(samples_count, samples.len());
+    let expected: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    assert_eq!(read_all(rdr), expected);
+  }
+
+  #[test]
+  fn stdin_input_reads_like_a_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let samples: Vec<i16> = (0..9000).map(|n| ((n % 300) * 40) as i16).collect();
+    fs::write(&wav, test_wav_bytes(&samples)).unwrap();
+
+    // Stands in for piped stdin; this is the only test that reads `-`
+    STDIN_BYTES.set(test_wav_bytes(&samples)).unwrap();
+
+    // Twice, so the buffered stdin is opened for counting and again for reading
+    let clip = ClipProcessing::default();
+    let (file_count, from_file, _) = prep_input_reader(vec![wav.clone(), wav], 500, clip).unwrap();
+    let (stdin_count, from_stdin, _) = prep_input_reader(vec![STDIO_PATH.into(), STDIO_PATH.into()], 500, clip).unwrap();
+
+    assert_eq!(stdin_count, file_count);
+    assert_eq!(read_all(from_stdin), read_all(from_file));
+  }
+
+  /// Vowel-like harmonics of a 120 Hz voice, rising and falling in syllables
+  fn speech_like(samples_count: usize) -> Vec<i16> {
+    (0..samples_count)
+      .map(|n| {
+        let t = n as f64 / 18900.0;
+        let envelope = (t * 4.0 * std::f64::consts::PI).sin().abs();
+        let voice: f64 = [(1.0, 0.5), (2.0, 1.0), (3.0, 0.6), (6.0, 0.3), (12.0, 0.1)].iter()
+          .map(|&(harmonic, weight)| (t * 120.0 * harmonic * std::f64::consts::TAU).sin() * weight)
+          .sum();
+        (voice * envelope * 9000.0) as i16
+      })
+      .collect()
+  }
+
+  #[test]
+  fn suspect_samples_flags_byte_swapped_and_constant_input() {
+    let speech = speech_like(SANITY_CHECK_SAMPLES);
+    assert_eq!(suspect_samples(&speech), None);
+    assert_eq!(suspect_samples(&[0; SANITY_CHECK_SAMPLES]), None);
+    assert_eq!(suspect_samples(&[-1; SANITY_CHECK_SAMPLES]), None);
+    assert_eq!(suspect_samples(&speech[..100].iter().map(|sample| sample.swap_bytes()).collect::<Vec<_>>()), None);
+
+    let swapped: Vec<i16> = speech.iter().map(|sample| sample.swap_bytes()).collect();
+    assert_eq!(suspect_samples(&swapped), Some(SuspectSamples::ByteSwapped));
+    assert_eq!(suspect_samples(&[20_000; SANITY_CHECK_SAMPLES]), Some(SuspectSamples::Constant(20_000)));
+  }
+
+  #[test]
+  fn peeked_samples_are_read_again() {
+    let pcm: Vec<u8> = (0..100_i16).flat_map(|sample| sample.to_le_bytes()).collect();
+    let (head, mut rdr) = peek_samples(Box::new(Cursor::new(pcm.clone())), 10).unwrap();
+    assert_eq!(head, (0..10).collect::<Vec<i16>>());
+
+    let mut all = Vec::new();
+    rdr.read_to_end(&mut all).unwrap();
+    assert_eq!(all, pcm);
+  }
+
+  #[test]
+  fn timeline_length_is_the_sum_of_its_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("clip.wav");
+    let file_samples: Vec<i16> = (0..3000).map(|n| (n % 700) as i16 + 1).collect();
+    fs::write(&wav, test_wav_bytes(&file_samples)).unwrap();
+    let buffer: Vec<i16> = vec![-5; 1234];
+
+    let input = Timeline::new(ClipProcessing::default())
+      .audio(wav)
+      .silence_ms(300)
+      .audio(buffer.clone())
+      .silence_samples(17)
+      .build()
+      .unwrap();
+    let gap = ms_to_samples(300, DEFAULT_SAMPLE_RATE);
+    assert_eq!(input.samples_count, 3000 + gap + 1234 + 17);
+    assert_eq!(input.clips, [ClipSpan { start: 0, samples: 3000 }, ClipSpan { start: 3000 + gap, samples: 1234 }]);
+    assert_eq!(input.silence_starts, [3000, 3000 + gap + 1234]);
+
+    let pcm = read_all(input.reader);
+    let samples: Vec<i16> = pcm.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+    assert_eq!(samples.len(), input.samples_count);
+    assert_eq!(samples[..3000], file_samples);
+    assert!(samples[3000..3000 + gap].iter().all(|&sample| sample == 0));
+    assert_eq!(samples[3000 + gap..3000 + gap + 1234], buffer);
+    assert!(samples[3000 + gap + 1234..].iter().all(|&sample| sample == 0));
+  }
+
+  #[test]
+  fn natural_order_compares_numbers_by_value() {
+    let mut paths: Vec<PathBuf> = ["clip_10.wav", "clip_2.wav", "clip_1.wav", "clip_01.wav", "clip.wav", "a/clip_3.wav"]
+      .into_iter()
+      .map(PathBuf::from)
+      .collect();
+    paths.sort_by(|a, b| natural_cmp(a, b));
+    assert_eq!(paths, ["a/clip_3.wav", "clip.wav", "clip_1.wav", "clip_01.wav", "clip_2.wav", "clip_10.wav"].map(PathBuf::from));
+  }
+}
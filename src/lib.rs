@@ -0,0 +1,94 @@
+//! Encodes 16-bit PCM to PlayStation XA ADPCM, wrapped in an APCM AIFF file or as raw sectors, and
+//! decodes it back.
+//!
+//! `prelude` re-exports the types and functions most programs need. Everything else stays public
+//! for tools that work on sectors, headers or sound units directly, but may change more freely.
+//!
+//! Encoding a WAV file held in memory and writing the result out:
+//!
+//! ```
+//! use adpcm2aiff::prelude::*;
+//! use std::f64::consts::TAU;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! // One second of a 440 Hz tone, as a WAV file
+//! let tone: Vec<i16> = (0..18_900)
+//!   .map(|n| ((f64::from(n) * 440.0 / 18_900.0 * TAU).sin() * 8000.0) as i16)
+//!   .collect();
+//! let mut wav = Vec::new();
+//! write_wav(&tone, 18_900, &mut wav)?;
+//!
+//! let (samples_count, sample_rate, mut pcm) = read_wav_bytes(wav, None)?;
+//! let config = EncoderConfig::builder()
+//!   .sample_rate(sample_rate)
+//!   .stats(true)
+//!   .build()?;
+//! let mut acm = Vec::new();
+//! let stats = encode(&config, samples_count, &mut pcm, &mut acm)?;
+//! assert_eq!(stats.sectors_written, 5);
+//! assert!(stats.sector_snr_db.iter().all(|&snr_db| snr_db > 20.0));
+//!
+//! let decoded = decode_apcm_aiff(&mut acm.as_slice(), false)?;
+//! assert!(decoded.samples.len() >= tone.len());
+//!
+//! let path = std::env::temp_dir().join("tone.acm");
+//! std::fs::write(&path, &acm)?;
+//! # std::fs::remove_file(&path)?;
+//! # Ok(())
+//! # }
+//! ```
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod adpcm_core;
+pub mod adpcm_decoder;
+#[cfg(feature = "std")]
+pub mod adpcm_encoder;
+#[cfg(feature = "std")]
+pub mod aiff;
+#[cfg(feature = "std")]
+pub mod checksum;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod emphasis_match;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "std")]
+pub mod input;
+#[cfg(feature = "std")]
+pub mod offsets;
+#[cfg(feature = "std")]
+pub mod pipeline;
+#[cfg(feature = "std")]
+pub mod preprocess;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "symphonia")]
+pub mod symphonia_input;
+#[cfg(feature = "std")]
+pub mod timing;
+#[cfg(feature = "std")]
+pub mod wav;
+
+#[cfg(feature = "std")]
+pub use error::XaError;
+
+/// The stable surface of the library: configuring and running an encode, streaming encoders and
+/// decoders, and reading and writing the files on either side.
+#[cfg(feature = "std")]
+pub mod prelude {
+  pub use crate::{
+    adpcm_decoder::XaDecoder,
+    adpcm_encoder::{OutputFormat, XaEncoder},
+    aiff::{decode_apcm_aiff, DecodedAPCM},
+    config::{EncoderConfig, EncoderConfigBuilder, XaRate},
+    error::XaError,
+    input::read_wav_bytes,
+    pipeline::{encode, encode_file_to_file},
+    stats::EncodeStats,
+    wav::write_wav,
+  };
+}
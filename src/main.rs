@@ -1,219 +1,1903 @@
+use adpcm2aiff::{
+  adpcm_encoder::{FilterSet, LoopRegion, OutputFormat, RoundingMode, SectorPadding, SoundGroupLayout, TailMode},
+  config::{
+    EncoderConfig,
+    InputOrder,
+    StereoChannel,
+    StereoMode,
+    XaRate,
+    DEFAULT_INTERFILE_GAP_MS,
+    DEFAULT_PRE_EMPHASIS_HZ,
+    DEFAULT_SAMPLE_RATE,
+    DEFAULT_TRIM_MIN_RUN_MS,
+    DEFAULT_TRIM_PRE_ROLL_MS,
+    DEFAULT_TRIM_THRESHOLD_DBFS,
+  },
+  aiff::{
+    concat_apcm_aiff,
+    decode_apcm_aiff,
+    read_apcm_aiff_headers,
+    APCMChunk,
+    AIFF,
+    CommonChunk,
+    HeaderReport,
+    PcmAiffWriter,
+    ReferenceHeader,
+    APCM_AIFF_HEADER_SIZE,
+    APCM_SAMPLE_SIZE,
+  },
+  checksum::{write_checksums, SectorChecksumWriter},
+  input::{
+    is_stdio,
+    loop_region_for,
+    natural_cmp,
+    open_input,
+    peek_samples,
+    prep_input_reader,
+    suspect_samples,
+    ClipProcessing,
+    SANITY_CHECK_SAMPLES,
+  },
+  offsets::sector_to_sample,
+  pipeline,
+  preprocess::FadeCurve,
+  emphasis_match::{match_emphasis, EmphasisGrid},
+  stats::{aligned_snr_db, find_lag, EncodeStats, SnrWeighting, DEFAULT_ALIGN_WINDOW_SECTORS},
+  timing::TimingSidecar,
+  wav::WavWriter,
+};
 use anyhow::{anyhow, Context, Result};
-use wav::{RiffHeader, FormatChunk, DataChunk};
+use clap::{Parser, Subcommand};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{info, log_enabled, warn, Level, LevelFilter};
+use rayon::prelude::*;
 use std::{
-  cmp::min, fs::{self, File}, io::{BufReader, BufWriter, Chain, Cursor, Read}, path::{Path, PathBuf}
+  collections::HashMap,
+  fs,
+  io::{self, BufReader, BufWriter, Read, Seek, Write},
+  path::{Path, PathBuf},
+  sync::OnceLock,
 };
 
-mod adpcm_encoder;
-mod aiff;
-mod wav;
+mod manifest;
+mod naming;
+
+use manifest::Manifest;
+use naming::{NameFields, NameTemplate, DEFAULT_NAME_TEMPLATE};
+
+/// Encode 16-bit mono 18900 or 37800 Hz WAV files to XA ADPCM sectors in an AIFF container
+#[derive(Parser, Debug)]
+#[command(version, about, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+  #[command(subcommand)]
+  command: Option<Command>,
+
+  /// Input WAV files, or `-` for stdin. Multiple files are concatenated in `--input-order` with a
+  /// short silence between them
+  #[arg(required_unless_present = "manifest", conflicts_with = "manifest")]
+  inputs: Vec<PathBuf>,
+
+  /// Output file path, `-` for stdout, or the output directory when `--index` or `--manifest` is
+  /// given
+  #[arg(short, long)]
+  output: Option<PathBuf>,
+
+  /// Sample rate of the input files and the output: 18900 or 37800 Hz
+  #[arg(long, default_value_t = DEFAULT_SAMPLE_RATE)]
+  sample_rate: u32,
+
+  /// XA mode, instead of `--sample-rate`: `normal` for 18900 Hz or `high` for 37800 Hz
+  #[arg(long, conflicts_with = "sample_rate")]
+  rate: Option<XaRate>,
+
+  /// Accept input files at any sample rate, resampling each one to `--sample-rate` before they're
+  /// joined
+  #[arg(long)]
+  resample: bool,
+
+  /// `mono` for mono inputs, or `split` to encode each channel of stereo WAV inputs to its own
+  /// output, named with `_L` or `_R` added
+  #[arg(long, default_value = "mono")]
+  stereo: StereoMode,
+
+  /// Order each output's inputs are joined in: `natural` sorts them by path, with numbers compared
+  /// by value so `_2` comes before `_10`; `listed` keeps them as given here or in the manifest
+  #[arg(long, default_value = "natural")]
+  input_order: InputOrder,
+
+  /// Length of the silence inserted between concatenated input files, in milliseconds
+  #[arg(long, default_value_t = DEFAULT_INTERFILE_GAP_MS)]
+  gap_ms: usize,
+
+  /// Start the encoder's filters over at each gap between input files, so the gaps decode to true
+  /// digital silence rather than a decaying tail of the file before. The first sound unit of each
+  /// gap then uses filter 0, even if `--filters` leaves it out
+  #[arg(long)]
+  reset_at_gaps: bool,
+
+  /// Fade each input file in over this many milliseconds
+  #[arg(long, default_value_t = 0)]
+  fade_in_ms: usize,
+
+  /// Fade each input file out over this many milliseconds
+  #[arg(long, default_value_t = 0)]
+  fade_out_ms: usize,
+
+  /// Shape of the fades: `linear` or `cosine` (raised cosine)
+  #[arg(long, default_value = "linear")]
+  fade_curve: FadeCurve,
+
+  /// Change the level of each input file by this many dB, e.g. -2 for a clip that's already hot.
+  /// Samples pushed past full scale are clamped. 0 leaves the input alone
+  #[arg(long, default_value_t = 0.0, allow_hyphen_values = true)]
+  gain_db: f64,
+
+  /// Boost the highs of each input file with a first-order high shelf reaching this many dB at
+  /// Nyquist, so they survive ADPCM better. 0 leaves the input alone
+  #[arg(long, default_value_t = 0.0)]
+  pre_emphasis_db: f64,
+
+  /// Midpoint of the `--pre-emphasis-db` shelf, in Hz
+  #[arg(long, default_value_t = DEFAULT_PRE_EMPHASIS_HZ)]
+  pre_emphasis_hz: f64,
+
+  /// Soft clip samples above this level, in dBFS, so overs from pre-emphasis bend over smoothly
+  /// instead of being cut flat at full scale
+  #[arg(long, allow_hyphen_values = true)]
+  soft_clip_dbfs: Option<f64>,
+
+  /// Trim silence from the start and end of each input file
+  #[arg(long)]
+  trim_silence: bool,
+
+  /// Level below which samples count as silence for `--trim-silence`, in dBFS
+  #[arg(long, default_value_t = DEFAULT_TRIM_THRESHOLD_DBFS, allow_negative_numbers = true, requires = "trim_silence")]
+  trim_threshold_dbfs: f64,
+
+  /// Leading or trailing silence shorter than this isn't trimmed, in milliseconds
+  #[arg(long, default_value_t = DEFAULT_TRIM_MIN_RUN_MS, requires = "trim_silence")]
+  trim_min_run_ms: usize,
+
+  /// Silence kept before the first loud sample when trimming, in milliseconds
+  #[arg(long, default_value_t = DEFAULT_TRIM_PRE_ROLL_MS, requires = "trim_silence")]
+  trim_pre_roll_ms: usize,
+
+  /// Encode sectors on all CPU cores. Encoder state restarts at every sector, which adds a tiny
+  /// discontinuity at each sector boundary
+  #[arg(long)]
+  parallel: bool,
+
+  /// Decode each sector after encoding it and print reconstruction SNR statistics
+  #[arg(long, conflicts_with = "parallel")]
+  stats: bool,
+
+  /// Frequency weighting for `--stats` SNR figures: `flat`, or `a-weighted` to discount low
+  /// frequencies the ear barely hears. An approximation, and only changes what's reported
+  #[arg(long, default_value = "flat", requires = "stats")]
+  snr_weighting: SnrWeighting,
+
+  /// Encode the XA stream twice, starting the kept pass from the state the first pass ended in, so
+  /// that it loops back to its start without a click. Doubles encode time
+  #[arg(long, conflicts_with_all = ["parallel", "stats"])]
+  seamless_loop: bool,
+
+  /// Add triangular dither ahead of the 4-bit quantizer, which smooths out stair-stepping on quiet
+  /// fades at the cost of a little noise
+  #[arg(long, conflicts_with = "parallel")]
+  dither: bool,
+
+  /// Seed for `--dither`; the same seed always produces the same output
+  #[arg(long, default_value_t = 0, requires = "dither")]
+  dither_seed: u64,
+
+  /// Output format: `xa` for XA ADPCM sectors in an AIFF container, or `spu` for a headerless
+  /// stream of 16-byte SPU ADPCM blocks
+  #[arg(long, default_value = "xa")]
+  format: OutputFormat,
+
+  /// Padding after the last input sample: `zero`, or `hold` to repeat the last sample so the
+  /// encoder sees no step down to silence
+  #[arg(long, default_value = "zero")]
+  tail_mode: TailMode,
+
+  /// Rounding in the encoder's fixed-point filters and quantizer: `nearest`, or `truncate` to floor
+  /// like some other PSX encoders
+  #[arg(long, default_value = "nearest")]
+  rounding: RoundingMode,
+
+  /// Margin added to each sound unit's predicted peak when picking its range, in percent. Loud
+  /// transients clip less often, at the cost of a little resolution
+  #[arg(long, default_value_t = 0)]
+  peak_headroom_pct: u32,
+
+  /// Filters the encoder may pick from, as comma-separated indices. `0,1` suits simple decoders
+  /// that only handle those two reliably
+  #[arg(long, default_value = "0,1,2,3")]
+  filters: FilterSet,
+
+  /// Fail if the quantizer has to clip more than `--max-clipped-samples` samples, which happens
+  /// when full-scale input combines badly with the predictor
+  #[arg(long)]
+  strict_clip: bool,
+
+  /// Clipped samples tolerated by `--strict-clip`
+  #[arg(long, default_value_t = 0, requires = "strict_clip")]
+  max_clipped_samples: usize,
+
+  /// Fail instead of warning when the start of an input looks byte-swapped or isn't audio
+  #[arg(long)]
+  strict: bool,
+
+  /// Decode each XA output once it's written and fail if its SNR against the input, as encoded, is
+  /// below this many dB. Blank sectors ahead of the audio are found by cross-correlation
+  #[arg(long, value_name = "MIN_SNR_DB", allow_negative_numbers = true)]
+  verify_snr_db: Option<f64>,
+
+  /// Debugging only: write `FILTER:RANGE` as every sound unit's parameters instead of picking the
+  /// best fit, to see how a decoder handles them. The output will usually sound bad
+  #[arg(long, value_parser = parse_force_params, value_name = "FILTER:RANGE")]
+  force_params: Option<(u8, u8)>,
+
+  /// What follows each XA sector's sound groups: `padded` for the usual 0x14 zero bytes (0x914-byte
+  /// sectors), or `bare` for 0x900-byte sectors
+  #[arg(long, default_value = "padded")]
+  sector_padding: SectorPadding,
+
+  /// Layout of the sound parameters in each sound group's 16-byte header: `cd-xa` for the usual
+  /// redundant layout, `repeated` for all 8 parameters twice in order, or `single` for the 8
+  /// parameters once followed by zeros. Only `cd-xa` plays on a PlayStation
+  #[arg(long, default_value = "cd-xa")]
+  sound_group_layout: SoundGroupLayout,
+
+  /// Loop region for SPU output as `START:END`, inclusive sample indices
+  #[arg(long = "loop", value_parser = parse_loop_region)]
+  loop_region: Option<LoopRegion>,
+
+  /// Value for the reserved field in the APCM chunk header, to match existing files byte for byte
+  #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+  apcm_unknown: i32,
+
+  /// Bits per sample written to the COMM chunk. The game's files say 4, for the ADPCM nibbles;
+  /// standard AIFF readers expect the uncompressed sample size, e.g. 16
+  #[arg(long, default_value_t = APCM_SAMPLE_SIZE)]
+  comm_sample_size: i16,
+
+  /// Sample frame count written to the COMM chunk instead of the number of input samples, to match
+  /// existing files byte for byte
+  #[arg(long)]
+  comm_sample_frames: Option<u32>,
+
+  /// Copy the headers of this `.ACM` file byte for byte instead of writing new ones; the input must
+  /// fill exactly the sectors it declares. The other header options are ignored
+  #[arg(long, value_name = "FILE")]
+  reference_header: Option<PathBuf>,
+
+  /// Fail any XA output longer than this many sectors, before writing it, for players that can only
+  /// stream files up to some size
+  #[arg(long)]
+  max_sectors: Option<usize>,
+
+  /// Print a hash of each whole output file as it's written: CRC32, or BLAKE3 when built with the
+  /// `blake3` feature
+  #[arg(long)]
+  hash: bool,
+
+  /// Write a `.crc` file next to each XA output, listing the CRC32 of every sector
+  #[arg(long)]
+  checksum: bool,
+
+  /// Write a `.json` file next to each XA output, giving the start sample, sector and time of each
+  /// input file within it
+  #[arg(long)]
+  timings: bool,
+
+  /// Sectors another tool will add ahead of the audio, counted into the positions in `--timings`
+  #[arg(long, default_value_t = 0, requires = "timings")]
+  leading_sectors: usize,
+
+  /// Also write the samples going into the encoder, after resampling, trimming, fades, pre-emphasis
+  /// and joining, to this file: a standard 16-bit PCM AIFF file if it ends in `.aif` or `.aiff`,
+  /// otherwise a WAV file
+  #[arg(long, conflicts_with = "manifest")]
+  preview: Option<PathBuf>,
+
+  /// TOML manifest describing a batch of outputs to encode
+  #[arg(short, long, conflicts_with = "index")]
+  manifest: Option<PathBuf>,
+
+  /// Manifest entries to encode at the same time; 0 uses one thread per CPU core
+  #[arg(long, default_value_t = 0)]
+  threads: usize,
+
+  /// Stop at the first manifest entry that fails, instead of encoding the rest and listing the
+  /// failures at the end
+  #[arg(long)]
+  fail_fast: bool,
+
+  /// Name the output from `--name-template` with this index, e.g. `--index 0x0F` writes
+  /// `CP1_0015.ACM` with the default template
+  #[arg(long, value_parser = parse_index)]
+  index: Option<u8>,
+
+  /// File name for outputs named by `--index`, or manifest entries without an `output`. `{index}`
+  /// is the index in decimal, `{stem}` the first input's name without its extension and `{n}` the
+  /// manifest entry number from 0; numbers take a zero-padded width, as in `{index:04}`
+  #[arg(long, default_value = DEFAULT_NAME_TEMPLATE)]
+  name_template: NameTemplate,
+
+  /// Print each output's length and file size, worked out from the input headers, without encoding
+  #[arg(long)]
+  dry_run: bool,
+
+  /// Log the FORM, COMM and APCM headers of each XA output, and a one-line summary of each output.
+  /// `RUST_LOG` overrides this and `--quiet`, e.g. `RUST_LOG=debug` to also log each input read
+  #[arg(short, long, global = true, conflicts_with = "quiet")]
+  verbose: bool,
+
+  /// Only log errors, leaving out warnings about the input
+  #[arg(short, long, global = true)]
+  quiet: bool,
+
+  /// Overwrite output files that already exist
+  #[arg(short, long)]
+  force: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Print the headers of an APCM AIFF (`.ACM`) file, with the sizes they imply and whether they
+  /// agree with each other
+  Inspect {
+    file: PathBuf,
+  },
+
+  /// Decode an APCM AIFF file and check its SNR against the WAV it was encoded from. Exits with an
+  /// error if the SNR is below `--min-snr-db`
+  Verify {
+    original: PathBuf,
+    encoded: PathBuf,
+
+    /// Lowest acceptable reconstruction SNR, in dB
+    #[arg(long, default_value_t = DEFAULT_MIN_SNR_DB, allow_negative_numbers = true)]
+    min_snr_db: f64,
+
+    /// Number of sectors in the encoded file ahead of the original's first sample, e.g. blank
+    /// sectors added by another tool
+    #[arg(long, default_value_t = 0)]
+    leading_sectors: usize,
+
+    /// Line the original up with the decoded audio by cross-correlating them, searching
+    /// `--align-window-sectors` either side of `--leading-sectors`, instead of trusting the offset
+    /// as given. Copes with blank sectors or trimming that moved the audio by any number of samples
+    #[arg(long)]
+    compare_reference: bool,
+
+    /// How far `--compare-reference` searches, in sectors either way
+    #[arg(long, default_value_t = DEFAULT_ALIGN_WINDOW_SECTORS, requires = "compare_reference")]
+    align_window_sectors: usize,
+  },
+
+  /// Join APCM AIFF files into one by copying their sectors, without decoding and re-encoding them
+  Concat {
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Blank sectors inserted between each pair of files. At least one keeps each file from
+    /// decoding against the end of the one before it
+    #[arg(long, default_value_t = 1)]
+    gap_sectors: usize,
+
+    /// Leave out the blank sectors each file starts with
+    #[arg(long)]
+    drop_blank_sectors: bool,
+
+    /// Overwrite the output file if it already exists
+    #[arg(short, long)]
+    force: bool,
+  },
+
+  /// Encode WAV files with a range of pre-emphasis settings and print which one decodes closest in
+  /// spectrum to an existing APCM AIFF file of the same audio. Encodes once per setting, so it can
+  /// take a while
+  EmphasisMatch {
+    reference: PathBuf,
+
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Pre-emphasis gains to try, in dB, separated by commas
+    #[arg(long, value_delimiter = ',', default_values_t = EmphasisGrid::default().gains_db)]
+    gains_db: Vec<f64>,
+
+    /// Pre-emphasis corners to try, in Hz, separated by commas
+    #[arg(long, value_delimiter = ',', default_values_t = EmphasisGrid::default().corners_hz)]
+    corners_hz: Vec<f64>,
+  },
+}
+
+const DEFAULT_MIN_SNR_DB: f64 = 20.0;
+
+fn parse_index(s: &str) -> Result<u8> {
+  let index = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+    Some(hex) => u8::from_str_radix(hex, 16)?,
+    None => s.parse()?,
+  };
+
+  Ok(index)
+}
+
+fn parse_loop_region(s: &str) -> Result<LoopRegion> {
+  let (start, end) = s.split_once(':')
+    .ok_or_else(|| anyhow!("Expected `START:END`, got `{}`", s))?;
+
+  Ok(LoopRegion { start: start.parse()?, end: end.parse()? })
+}
+
+fn parse_force_params(s: &str) -> Result<(u8, u8)> {
+  let (filter, range) = s.split_once(':')
+    .ok_or_else(|| anyhow!("Expected `FILTER:RANGE`, got `{}`", s))?;
+
+  Ok((filter.parse()?, range.parse()?))
+}
+
+fn output_path(cli: &Cli) -> Result<PathBuf> {
+  match (&cli.output, cli.index) {
+    (Some(output), None) => Ok(output.clone()),
+    (output, Some(index)) => {
+      let dir = output.clone().unwrap_or_default();
+      let fields = NameFields { index: Some(index), first_input: &cli.inputs[0], n: 0 };
+      Ok(dir.join(cli.name_template.render(fields)?))
+    },
+    (None, None) => Err(anyhow!("No output path provided, use `--output` and/or `--index`")),
+  }
+}
+
+fn print_stats(out_path: &Path, stats: &EncodeStats, wtr: &mut dyn Write) -> io::Result<()> {
+  match stats.snr_weighting {
+    SnrWeighting::Flat => writeln!(wtr, "{}:", out_path.to_string_lossy())?,
+    SnrWeighting::AWeighted => writeln!(wtr, "{} (A-weighted SNR):", out_path.to_string_lossy())?,
+  }
+  for (n, snr) in stats.sector_snr_db.iter().enumerate() {
+    writeln!(wtr, "  sector {:>5}: {:6.2} dB", n, snr)?;
+  }
+  if let Some((worst, snr)) = stats.worst_sector() {
+    writeln!(wtr, "  min SNR {:.2} dB (sector {}), mean SNR {:.2} dB", snr, worst, stats.mean_snr_db())?;
+  }
+  writeln!(wtr, "  {} clipped samples", stats.clipped_samples)
+}
+
+fn encoder_config(cli: &Cli) -> Result<EncoderConfig> {
+  let reference_header = match &cli.reference_header {
+    Some(path) => {
+      let file = fs::File::open(path)
+        .with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+      let reference = ReferenceHeader::from_reader(&mut BufReader::new(file))
+        .with_context(|| format!("Can't take the headers of {}", path.to_string_lossy()))?;
+      Some(reference)
+    },
+    None => None,
+  };
+
+  EncoderConfig::builder()
+    .output_format(cli.format)
+    .sample_rate(cli.rate.map_or(cli.sample_rate, XaRate::sample_rate))
+    .resample(cli.resample)
+    .interfile_gap_ms(cli.gap_ms)
+    .reset_at_gaps(cli.reset_at_gaps)
+    .fade_in_ms(cli.fade_in_ms)
+    .fade_out_ms(cli.fade_out_ms)
+    .fade_curve(cli.fade_curve)
+    .gain_db(cli.gain_db)
+    .pre_emphasis_db(cli.pre_emphasis_db)
+    .pre_emphasis_hz(cli.pre_emphasis_hz)
+    .soft_clip_dbfs(cli.soft_clip_dbfs)
+    .trim_silence(cli.trim_silence)
+    .trim_threshold_dbfs(cli.trim_threshold_dbfs)
+    .trim_min_run_ms(cli.trim_min_run_ms)
+    .trim_pre_roll_ms(cli.trim_pre_roll_ms)
+    .parallel(cli.parallel)
+    .stats(cli.stats)
+    .snr_weighting(cli.snr_weighting)
+    .loop_region(cli.loop_region)
+    .seamless_loop(cli.seamless_loop)
+    .dither_seed(cli.dither.then_some(cli.dither_seed))
+    .apcm_unknown(cli.apcm_unknown)
+    .comm_sample_size(cli.comm_sample_size)
+    .comm_sample_frames(cli.comm_sample_frames)
+    .reference_header(reference_header)
+    .max_sectors(cli.max_sectors)
+    .hash_output(cli.hash)
+    .tail_mode(cli.tail_mode)
+    .rounding(cli.rounding)
+    .peak_headroom_pct(cli.peak_headroom_pct)
+    .sector_padding(cli.sector_padding)
+    .sound_group_layout(cli.sound_group_layout)
+    .allowed_filters(cli.filters)
+    .max_clipped_samples(cli.strict_clip.then_some(cli.max_clipped_samples))
+    .force_params(cli.force_params)
+    .build()
+}
+
+/// Extra files written, and extra reports printed, along with each output.
+#[derive(Debug, Clone, Default)]
+struct Sidecars {
+  checksum: bool,
+  timings: bool,
+  leading_sectors: usize,
+  /// WAV or AIFF file to write the preprocessed samples to, exactly as the encoder reads them
+  preview: Option<PathBuf>,
+  /// Fail when the input looks byte-swapped or isn't audio, rather than warning
+  strict: bool,
+  /// Check each output against its input once it's written, failing below this SNR
+  verify_snr_db: Option<f64>,
+}
+
+impl Sidecars {
+  fn from_cli(cli: &Cli) -> Self {
+    Sidecars {
+      checksum: cli.checksum,
+      timings: cli.timings,
+      leading_sectors: cli.leading_sectors,
+      preview: cli.preview.clone(),
+      strict: cli.strict,
+      verify_snr_db: cli.verify_snr_db,
+    }
+  }
+}
+
+/// Uncompressed copy of the encoder's input, in either of the formats `--preview` writes.
+enum PreviewWriter<W: Write> {
+  Wav(WavWriter<W>),
+  Aiff(PcmAiffWriter<W>),
+}
+
+impl PreviewWriter<BufWriter<fs::File>> {
+  /// Creates `path`, as AIFF if its extension is `aif` or `aiff` and as WAV otherwise.
+  fn create(path: &Path, samples_count: usize, sample_rate: u32) -> Result<Self> {
+    let file = fs::File::create(path)
+      .with_context(|| format!("Failed to create {}", path.to_string_lossy()))?;
+    let wtr = BufWriter::new(file);
 
-use aiff::{AIFF, CommonChunk, APCMChunk};
+    let is_aiff = path.extension()
+      .and_then(|extension| extension.to_str())
+      .is_some_and(|extension| extension.eq_ignore_ascii_case("aif") || extension.eq_ignore_ascii_case("aiff"));
+    if is_aiff {
+      Ok(PreviewWriter::Aiff(PcmAiffWriter::new(samples_count, sample_rate, wtr)?))
+    } else {
+      Ok(PreviewWriter::Wav(WavWriter::new(samples_count, sample_rate, wtr)?))
+    }
+  }
+}
 
-struct ZeroReader {
-  index: usize,
-  size:  usize,
+impl<W: Write> PreviewWriter<W> {
+  fn finish(self) -> Result<W> {
+    match self {
+      PreviewWriter::Wav(wtr) => wtr.finish(),
+      PreviewWriter::Aiff(wtr) => wtr.finish(),
+    }
+  }
 }
 
-impl ZeroReader {
-  fn new(size: usize) -> Self {
-    ZeroReader { index: 0, size }
+impl<W: Write> Write for PreviewWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      PreviewWriter::Wav(wtr) => wtr.write(buf),
+      PreviewWriter::Aiff(wtr) => wtr.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      PreviewWriter::Wav(wtr) => wtr.flush(),
+      PreviewWriter::Aiff(wtr) => wtr.flush(),
+    }
   }
 }
-impl Read for ZeroReader {
-  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-    let limit = min(buf.len(), self.size - self.index);
 
-    for n in 0..limit { buf[n] = 0 ; }
-    self.index += limit;
-    
-    Ok(limit)
+/// Reads from `inner`, copying everything read to `copy`.
+struct TeeReader<'a, R: Read> {
+  inner: R,
+  copy: &'a mut dyn Write,
+}
+
+impl<R: Read> Read for TeeReader<'_, R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let count = self.inner.read(buf)?;
+    self.copy.write_all(&buf[..count])?;
+    Ok(count)
+  }
+}
+
+/// Path of a sidecar for `out_path`: the same name with `extension` appended
+fn sidecar_path(out_path: &Path, extension: &str) -> PathBuf {
+  let mut path = out_path.as_os_str().to_owned();
+  path.push(".");
+  path.push(extension);
+  PathBuf::from(path)
+}
+
+/// Every progress bar is drawn through this, so that outputs encoded at the same time each get
+/// their own line.
+fn progress_bars() -> &'static MultiProgress {
+  static PROGRESS_BARS: OnceLock<MultiProgress> = OnceLock::new();
+  PROGRESS_BARS.get_or_init(MultiProgress::new)
+}
+
+/// Encodes `inputs` to `out_path`. The output is written under a temporary name next to it and only
+/// renamed into place once the encode and any checks on it have passed, so a rejected encode never
+/// leaves a file that looks finished.
+fn encode_file(config: &EncoderConfig, inputs: Vec<PathBuf>, out_path: &Path, sidecars: Sidecars) -> Result<()> {
+  if is_stdio(out_path) {
+    return write_output(config, inputs, out_path, out_path, sidecars)
+  }
+
+  let part_path = sidecar_path(out_path, "part");
+  let result = write_output(config, inputs, out_path, &part_path, sidecars)
+    .and_then(|()| {
+      fs::rename(&part_path, out_path)
+        .with_context(|| format!("Failed to move {} into place", out_path.to_string_lossy()))
+    });
+  if result.is_err() {
+    // Not there if the encode failed before creating it
+    let _ = fs::remove_file(&part_path);
+  }
+  result
+}
+
+/// `encode_file`, writing the encoded data to `write_path`; everything else is named after `out_path`
+fn write_output(config: &EncoderConfig, inputs: Vec<PathBuf>, out_path: &Path, write_path: &Path, sidecars: Sidecars) -> Result<()> {
+  if sidecars.checksum && config.output_format != OutputFormat::Xa {
+    return Err(anyhow!("Sector checksums are only written for XA output"))
+  }
+  if sidecars.timings && config.output_format != OutputFormat::Xa {
+    return Err(anyhow!("Clip timings are only written for XA output"))
+  }
+  if sidecars.verify_snr_db.is_some() && config.output_format != OutputFormat::Xa {
+    return Err(anyhow!("Only XA output can be verified"))
+  }
+  let to_stdout = is_stdio(out_path);
+  if to_stdout && (sidecars.checksum || sidecars.timings || sidecars.verify_snr_db.is_some()) {
+    return Err(anyhow!("Sidecar files and verification need an output file path, not stdout"))
+  }
+
+  let clip = ClipProcessing::from_config(config);
+  let (num_samples, rdr, clips) = prep_input_reader(inputs.clone(), config.interfile_gap_ms, clip)?;
+
+  // The report is written in one go, so reports from outputs encoded at the same time don't mix
+  let mut report = Vec::new();
+  let (head, rdr) = peek_samples(rdr, SANITY_CHECK_SAMPLES)?;
+  if let Some(suspect) = suspect_samples(&head) {
+    if sidecars.strict {
+      return Err(anyhow!("{}; check the input's format", suspect))
+    }
+    warn!("{}: {}; check the input's format", out_path.to_string_lossy(), suspect);
+  }
+
+  let mut config = config.clone();
+  config.loop_region = loop_region_for(&config, &inputs)?;
+  if config.reset_at_gaps {
+    // Each gap starts where the file before it ends
+    config.state_resets.extend(clips.iter().rev().skip(1).map(|span| span.start + span.samples));
+  }
+  let config = &config;
+  // An output that's too long fails here, before any file is created
+  pipeline::output_size(config, num_samples)?;
+
+  // The input as encoded, kept to check the decoded output against
+  let mut verify_input = Vec::new();
+
+  // The preview is written as the encoder reads its input, so it holds exactly what was encoded
+  let mut preview = match &sidecars.preview {
+    Some(preview_path) => Some(PreviewWriter::create(preview_path, num_samples, config.sample_rate)?),
+    None => None,
+  };
+  let mut rdr: Box<dyn Read + '_> = match preview.as_mut() {
+    Some(preview) => Box::new(TeeReader { inner: rdr, copy: preview }),
+    None => rdr,
+  };
+  if sidecars.verify_snr_db.is_some() {
+    rdr = Box::new(TeeReader { inner: rdr, copy: &mut verify_input });
+  }
+
+  if sidecars.timings {
+    let timing = TimingSidecar::new(
+      config.sample_rate,
+      sidecars.leading_sectors,
+      inputs.into_iter().zip(clips).map(|(path, span)| (path, span.start, span.samples)),
+    );
+    let timing_path = sidecar_path(out_path, "json");
+    let timing_file = fs::File::create(&timing_path)
+      .with_context(|| format!("Failed to create {}", timing_path.to_string_lossy()))?;
+    let mut timing_wtr = BufWriter::new(timing_file);
+    timing.to_writer(&mut timing_wtr)?;
+    timing_wtr.flush()?;
+  }
+
+  let outfile: Box<dyn Write> = if to_stdout {
+    Box::new(io::stdout().lock())
+  } else {
+    let file = fs::File::create(write_path)
+      .with_context(|| format!("Failed to create {}", write_path.to_string_lossy()))?;
+    Box::new(file)
+  };
+  let mut wtr = BufWriter::new(outfile);
+
+  let unit = match config.output_format {
+    OutputFormat::Xa => "sectors",
+    OutputFormat::Spu => "blocks",
+  };
+  let bar = progress_bars().add(ProgressBar::new(0).with_message(out_path.to_string_lossy().into_owned()));
+  bar.set_style(
+    ProgressStyle::with_template(&format!("{{msg}} [{{bar:40}}] {{pos}}/{{len}} {}", unit))?
+      .progress_chars("=> ")
+  );
+
+  let mut encode = |mut wtr: &mut dyn Write| {
+    pipeline::encode_with_progress(config, num_samples, &mut rdr, &mut wtr, &mut |done, total| {
+      bar.set_length(total as u64);
+      bar.set_position(done as u64);
+    })
+  };
+
+  let stats = if sidecars.checksum {
+    let mut crc_wtr = SectorChecksumWriter::new(&mut wtr, APCM_AIFF_HEADER_SIZE, config.sector_padding.sector_size());
+    let stats = encode(&mut crc_wtr)?;
+    let (_, checksums) = crc_wtr.finish()?;
+
+    let crc_path = sidecar_path(out_path, "crc");
+    let crc_file = fs::File::create(&crc_path)
+      .with_context(|| format!("Failed to create {}", crc_path.to_string_lossy()))?;
+    write_checksums(&checksums, &mut BufWriter::new(crc_file))?;
+    stats
+  } else {
+    encode(&mut wtr)?
+  };
+  wtr.flush()?;
+  drop(rdr);
+  if let Some(preview) = preview {
+    preview.finish()?;
+  }
+  bar.finish_and_clear();
+  progress_bars().remove(&bar);
+
+  // Checked against the file as written. A failure is returned once the rest of the report is out
+  let verification = sidecars.verify_snr_db.map(|min_snr_db| {
+    let original: Vec<i16> = verify_input.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+    let encoded = fs::read(write_path)
+      .with_context(|| format!("Failed to read back {}", write_path.to_string_lossy()))?;
+    pipeline::verify_encoded(&original, &encoded, min_snr_db)
+      .with_context(|| format!("{} failed verification", out_path.to_string_lossy()))
+  });
+
+  if log_enabled!(Level::Info) && config.output_format == OutputFormat::Xa {
+    let (aiff, comm, apcm) = pipeline::xa_headers(config, num_samples)?;
+    info!("{}:\n{}\n{}\n{}", out_path.to_string_lossy(), aiff, comm, apcm);
+  }
+  info!("{}: {}", out_path.to_string_lossy(), stats);
+  // The summary logged above already ends with the hash
+  if let (Some(output_hash), false) = (stats.output_hash, log_enabled!(Level::Info)) {
+    writeln!(report, "{}  {}", output_hash, out_path.to_string_lossy())?;
+  }
+  if config.stats {
+    print_stats(out_path, &stats, &mut report)?;
+  }
+  if let Some(Ok(verification)) = &verification {
+    writeln!(report, "{}: {}", out_path.to_string_lossy(), verification)?;
+  }
+  if stats.missing_samples > 0 {
+    warn!(
+      "{}: input ended {} samples early, {} padding samples added",
+      out_path.to_string_lossy(), stats.missing_samples, stats.padding_samples
+    );
+  }
+  // Reports go to stderr when stdout carries the encoded output
+  if to_stdout {
+    io::stderr().write_all(&report)?;
+  } else {
+    io::stdout().write_all(&report)?;
+  }
+
+  verification.transpose()?;
+  Ok(())
+}
+
+/// Encodes each `(inputs, output)` job with `encode_file`, up to `threads` at a time, or one per
+/// CPU core for 0. Each output has its own encoder state, so the results match a serial encode.
+///
+/// With `fail_fast`, the first error is returned as is and no more jobs are started. Otherwise every
+/// job is tried; when there's more than one, a summary listing each failure goes to `summary`, and
+/// the error returned only counts them.
+fn encode_jobs(
+  jobs: Vec<Job>,
+  sidecars: &Sidecars,
+  threads: usize,
+  fail_fast: bool,
+  summary: &mut dyn Write,
+) -> Result<()> {
+  let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build()?;
+  if fail_fast || jobs.len() == 1 {
+    return pool.install(|| {
+      jobs.into_par_iter().try_for_each(|(config, inputs, out_path)| encode_file(&config, inputs, &out_path, sidecars.clone()))
+    })
+  }
+
+  let results: Vec<(PathBuf, Result<()>)> = pool.install(|| {
+    jobs.into_par_iter()
+      .map(|(config, inputs, out_path)| {
+        let result = encode_file(&config, inputs, &out_path, sidecars.clone());
+        (out_path, result)
+      })
+      .collect()
+  });
+
+  let failures: Vec<_> = results.iter().enumerate()
+    .filter_map(|(n, (out_path, result))| result.as_ref().err().map(|e| (n, out_path, e)))
+    .collect();
+  writeln!(summary, "{} of {} outputs encoded", results.len() - failures.len(), results.len())?;
+  for (n, out_path, e) in &failures {
+    writeln!(summary, "  entry {}: {} failed: {:#}", n, out_path.to_string_lossy(), e)?;
+  }
+
+  if !failures.is_empty() {
+    return Err(anyhow!("{} of {} outputs failed", failures.len(), results.len()))
+  }
+  Ok(())
+}
+
+/// Config to encode with, input files and the output they're encoded to.
+type Job = (EncoderConfig, Vec<PathBuf>, PathBuf);
+
+/// Sorts the inputs of each job as `order` says. The jobs themselves stay in order, since entry
+/// numbers name outputs and each output is encoded on its own.
+fn order_inputs(jobs: &mut [Job], order: InputOrder) {
+  if order == InputOrder::Natural {
+    for (_, inputs, _) in jobs.iter_mut() {
+      inputs.sort_by(|a, b| natural_cmp(a, b));
+    }
+  }
+}
+
+/// Turns each job into one for each channel of its stereo inputs, writing `_L` and `_R` outputs.
+/// Each frame of interleaved PCM holds a sample of both channels, so there's never a shorter
+/// channel to pad: a file cut off partway through its last frame has that frame dropped from both.
+/// Each pair of outputs therefore has the same number of sectors.
+fn split_stereo_jobs(jobs: Vec<Job>, sidecars: &Sidecars) -> Result<Vec<Job>> {
+  if sidecars.preview.is_some() {
+    return Err(anyhow!("--preview can't be used with --stereo split"))
+  }
+  if jobs.iter().any(|(_, _, out_path)| is_stdio(out_path)) {
+    return Err(anyhow!("--stereo split needs output file paths, not stdout"))
+  }
+
+  let mut split = Vec::with_capacity(jobs.len() * 2);
+  for (config, inputs, out_path) in jobs {
+    for channel in [StereoChannel::Left, StereoChannel::Right] {
+      let config = EncoderConfig { stereo_channel: Some(channel), ..config.clone() };
+      config.validate()?;
+      split.push((config, inputs.clone(), channel.output_path(&out_path)));
+    }
+  }
+  Ok(split)
+}
+
+/// Checks, before anything is encoded, that no two jobs write the same output and, unless `force`
+/// is set, that no output already exists. Every offending job is listed, by manifest entry number
+/// when there's more than one.
+fn check_outputs(jobs: &[Job], force: bool) -> Result<()> {
+  let mut problems = Vec::new();
+  let mut seen = HashMap::new();
+  for (n, (_, _, out_path)) in jobs.iter().enumerate() {
+    if is_stdio(out_path) {
+      continue
+    }
+    let entry = if jobs.len() > 1 { format!("entry {}: ", n) } else { String::new() };
+
+    if let Some(first) = seen.insert(out_path, n) {
+      problems.push(format!("{}{} is also written by entry {}", entry, out_path.to_string_lossy(), first));
+    } else if !force && out_path.exists() {
+      problems.push(format!("{}{} already exists; pass --force to overwrite it", entry, out_path.to_string_lossy()));
+    }
+  }
+
+  if problems.is_empty() {
+    return Ok(())
   }
+  Err(anyhow!("Not encoding anything:\n  {}", problems.join("\n  ")))
 }
 
-const WAV_SAMPLE_SIZE_BYTES: usize = 2;
-const WAV_SAMPLE_RATE: usize = 18_900;
+/// Sample count and file size of the output `encode_file` would write for `inputs`. Only the input
+/// headers are read, except that trimmed inputs are read through to find their silence.
+fn plan_output(config: &EncoderConfig, inputs: Vec<PathBuf>) -> Result<(usize, usize)> {
+  let (num_samples, _, _) = prep_input_reader(inputs, config.interfile_gap_ms, ClipProcessing::from_config(config))?;
+  let size = pipeline::output_size(config, num_samples)?;
 
-const INTERFILE_DELAY_DIVISOR: usize = 2;
+  Ok((num_samples, size))
+}
 
-const INTERFILE_SAMPLES: usize = WAV_SAMPLE_RATE / INTERFILE_DELAY_DIVISOR;
-const INTERFILE_BYTES: usize = INTERFILE_SAMPLES * 2;
+/// Prints a table of the length and size of each output, and the totals, for `--dry-run`.
+fn dry_run(jobs: Vec<Job>, wtr: &mut dyn Write) -> Result<()> {
+  // Entries can be at different sample rates, so the total time is summed rather than worked out
+  // from the total samples
+  let (mut total_samples, mut total_seconds, mut total_size) = (0, 0.0, 0);
 
-fn prep_input_reader(paths: Vec<PathBuf>) -> Result<(usize, Box<dyn Read>)> {
-  if paths.len() == 0 {
-    return Err(anyhow!("No input file paths provided"))
+  writeln!(wtr, "{:>12} {:>10} {:>12}  output", "samples", "seconds", "bytes")?;
+  for (config, inputs, out_path) in jobs {
+    let (num_samples, size) = plan_output(&config, inputs)
+      .with_context(|| format!("Can't size {}", out_path.to_string_lossy()))?;
+    let seconds = num_samples as f64 / f64::from(config.sample_rate);
+    writeln!(wtr, "{:>12} {:>10.2} {:>12}  {}", num_samples, seconds, size, out_path.to_string_lossy())?;
+    total_samples += num_samples;
+    total_seconds += seconds;
+    total_size += size;
   }
+  writeln!(wtr, "{:>12} {:>10.2} {:>12}  total", total_samples, total_seconds, total_size)?;
+
+  Ok(())
+}
+
+fn inspect(path: &Path) -> Result<()> {
+  let file = fs::File::open(path)
+    .with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+  let file_size = file.metadata()?.len();
+  let mut rdr = BufReader::new(file);
+
+  // The headers are read one by one, rather than with `read_apcm_aiff_headers`, so that size
+  // mismatches between them are reported instead of rejected
+  let not_apcm = || format!("{} is not a FORM/AIFF/APCM or FORM/AIFC/APCM file", path.to_string_lossy());
+  let aiff = AIFF::from_reader(&mut rdr).with_context(not_apcm)?;
+  let comm = CommonChunk::from_reader(&mut rdr, &aiff).with_context(not_apcm)?;
+  let apcm = APCMChunk::from_reader(&mut rdr).with_context(not_apcm)?;
+  let report = HeaderReport::new(&aiff, &comm, &apcm, Some(file_size))?;
+
+  println!("{}", aiff);
+  println!("{}", comm);
+  println!("{}", apcm);
+  println!("{}", report);
+
+  Ok(())
+}
 
-  if paths.len() == 1 {
-    let infile = fs::File::open(&paths[0])?;
-    let mut rdr = BufReader::new(infile);
-    let riff_header = RiffHeader::from_reader(&mut rdr)?;
-    let format_chunk = FormatChunk::from_reader(&mut rdr)?;
-    let data_chunk = DataChunk::from_reader(&mut rdr)?;
+/// Decodes `encoded` and compares it with `original`, which must be at the sample rate declared in
+/// `encoded`'s COMM chunk.
+///
+/// The decoded samples are cut to the COMM chunk's sample frame count, which drops the zero padding
+/// of the final sector. Sample `n` of the original then lines up with decoded sample
+/// `leading_sectors * ADPCM_SECTOR_SAMPLES + n`, or with `align_window_sectors`, with decoded sample
+/// `n + lag` for the lag within that many sectors of there that `find_lag` finds.
+fn verify(
+  original: &Path,
+  encoded: &Path,
+  min_snr_db: f64,
+  leading_sectors: usize,
+  align_window_sectors: Option<usize>,
+) -> Result<()> {
+  let file = fs::File::open(encoded)
+    .with_context(|| format!("Failed to open {}", encoded.to_string_lossy()))?;
+  let mut encoded_rdr = BufReader::new(file);
+  let (_, comm, _) = read_apcm_aiff_headers(&mut encoded_rdr)
+    .with_context(|| format!("Failed to decode {}", encoded.to_string_lossy()))?;
+  encoded_rdr.rewind()?;
+  let decoded = decode_apcm_aiff(&mut encoded_rdr, false)
+    .with_context(|| format!("Failed to decode {}", encoded.to_string_lossy()))?;
 
-    return Ok((data_chunk.samples_count(), Box::new(rdr)))
+  let (_, _, mut rdr) = open_input(original, Some(comm.sample_rate().round() as u32))?;
+  let mut pcm = Vec::new();
+  rdr.read_to_end(&mut pcm)?;
+  let original_samples: Vec<i16> = pcm.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+  if decoded.leading_blank_sectors < leading_sectors {
+    warn!("expected {} leading blank sectors, found {}", leading_sectors, decoded.leading_blank_sectors);
   }
 
-  // Make the buffer big to minimize reallocations
-  let mut buf = Vec::with_capacity(8 * 1024 * 1024);
-  let mut samples_count = 0;
-  
-  for (n, path) in paths.iter().enumerate() {
-    println!("reading file {}", path.to_string_lossy());
-    let infile = fs::File::open(path)?;
-    let mut rdr = BufReader::new(infile);
-    let riff_header = RiffHeader::from_reader(&mut rdr)?;
-    let format_chunk = FormatChunk::from_reader(&mut rdr)?;
-    let data_chunk = DataChunk::from_reader(&mut rdr)?;
+  let expected_lag = sector_to_sample(leading_sectors) as isize;
+  let lag = match align_window_sectors {
+    Some(window_sectors) => {
+      let window = sector_to_sample(window_sectors) as isize;
+      find_lag(&original_samples, &decoded.samples, expected_lag - window, expected_lag + window)
+    },
+    None => expected_lag,
+  };
+  // A negative lag means the decoded audio is missing the start of the original
+  let original_samples = original_samples.get(lag.min(0).unsigned_abs()..).unwrap_or_default();
+  let leading_samples = lag.max(0) as usize;
 
-    samples_count += data_chunk.samples_count();
+  let (snr, compared) = aligned_snr_db(original_samples, &decoded.samples, leading_samples);
+  let decoded_len = decoded.samples.len().saturating_sub(leading_samples);
+  if decoded_len != original_samples.len() {
+    warn!(
+      "original has {} samples, decoded has {} after the leading sectors",
+      original_samples.len(), decoded_len
+    );
+  }
+  if align_window_sectors.is_some() {
+    println!("{}: {:.2} dB SNR over {} samples, aligned at a lag of {} samples", encoded.to_string_lossy(), snr, compared, lag);
+  } else {
+    println!("{}: {:.2} dB SNR over {} samples", encoded.to_string_lossy(), snr, compared);
+  }
 
-    rdr.read_to_end(&mut buf)?;
+  if snr < min_snr_db {
+    return Err(anyhow!("SNR {:.2} dB is below the minimum of {} dB", snr, min_snr_db))
+  }
 
-    if n != (paths.len() - 1) {
-      println!("reading zeroes");
-      let mut zeroes = ZeroReader::new(INTERFILE_BYTES);
-      zeroes.read_to_end(&mut buf)?;
+  Ok(())
+}
 
-      samples_count += INTERFILE_SAMPLES;
+fn concat(files: &[PathBuf], output: &Path, gap_sectors: usize, drop_blank_sectors: bool, force: bool) -> Result<()> {
+  if !force && output.exists() {
+    return Err(anyhow!("{} already exists; pass --force to overwrite it", output.to_string_lossy()))
+  }
+  // Creating the output truncates it, which would empty an input that's the same file
+  if let Ok(output_path) = output.canonicalize() {
+    if files.iter().any(|path| path.canonicalize().is_ok_and(|path| path == output_path)) {
+      return Err(anyhow!("{} is one of the files being joined", output.to_string_lossy()))
     }
   }
 
-  let rdr = Cursor::new(buf);
-  Ok((samples_count, Box::new(rdr)))
+  let inputs = files.iter()
+    .map(|path| fs::File::open(path)
+      .map(BufReader::new)
+      .with_context(|| format!("Failed to open {}", path.to_string_lossy())))
+    .collect::<Result<Vec<_>>>()?;
+  let mut wtr = BufWriter::new(fs::File::create(output)?);
+  let report = concat_apcm_aiff(inputs, gap_sectors, drop_blank_sectors, &mut wtr)
+    .with_context(|| format!("Failed to join into {}", output.to_string_lossy()))?;
+  wtr.flush()?;
+
+  println!(
+    "{}: {} sectors, {:.3} s from {} files",
+    output.to_string_lossy(), report.num_sectors, report.duration_secs(), files.len()
+  );
+
+  Ok(())
+}
+
+/// Runs `match_emphasis` at the reference's sample rate with otherwise default settings, and prints
+/// every candidate along with the flags that reproduce the best one.
+fn emphasis_match(reference: &Path, inputs: &[PathBuf], grid: EmphasisGrid) -> Result<()> {
+  let mut rdr = BufReader::new(fs::File::open(reference)
+    .with_context(|| format!("Failed to open {}", reference.to_string_lossy()))?);
+  let (_, comm, _) = read_apcm_aiff_headers(&mut rdr)
+    .with_context(|| format!("Failed to decode {}", reference.to_string_lossy()))?;
+  rdr.rewind()?;
+  let decoded = decode_apcm_aiff(&mut rdr, true)
+    .with_context(|| format!("Failed to decode {}", reference.to_string_lossy()))?;
+
+  let config = EncoderConfig::builder().sample_rate(comm.sample_rate().round() as u32).build()?;
+  let found = match_emphasis(&decoded.samples, inputs, &config, &grid)?;
+
+  println!("{:>8} {:>8} {:>10}", "gain dB", "corner", "distance");
+  for candidate in &found.candidates {
+    println!("{:>8.1} {:>8.0} {:>10.3}", candidate.gain_db, candidate.corner_hz, candidate.distance_db);
+  }
+  if found.config.pre_emphasis_db > 0.0 {
+    println!("best: --pre-emphasis-db {} --pre-emphasis-hz {}", found.config.pre_emphasis_db, found.config.pre_emphasis_hz);
+  } else {
+    println!("best: no pre-emphasis");
+  }
+
+  Ok(())
+}
+
+/// Sends log records to stderr, at warning level unless `--verbose` or `--quiet` says otherwise.
+/// A `RUST_LOG` setting is applied on top.
+fn init_logger(cli: &Cli) {
+  let level = if cli.verbose {
+    LevelFilter::Info
+  } else if cli.quiet {
+    LevelFilter::Error
+  } else {
+    LevelFilter::Warn
+  };
+  env_logger::Builder::new()
+    .filter_level(level)
+    .parse_default_env()
+    .format_target(false)
+    .init();
 }
 
 fn main() -> Result<()> {
-  // let mut infile = fs::File::open(
-  //   PathBuf::from("/mnt/e/Temp/Tactics Ogre/CP1_0001.ACM")
-  // )?;
-
-  // let aiff = AIFF::from_reader(&mut infile)?;
-  // let common = CommonChunk::from_reader(&mut infile)?;
-  // let adpcm = APCMChunk::from_reader(&mut infile)?;
-
-  // println!("{}", aiff);
-  // println!("{}", common);
-  // println!("{}", adpcm);
-
-  // let mut infile = fs::File::open(
-  //   PathBuf::from("/mnt/e/Temp/Tactics Ogre/test_adpcm_out.aiff")
-  // )?;
-
-  // let aiff = AIFF::from_reader(&mut infile)?;
-  // let common = CommonChunk::from_reader(&mut infile)?;
-  // let adpcm = APCMChunk::from_reader(&mut infile)?;
-
-  // println!("{}", aiff);
-  // println!("{}", common);
-  // println!("{}", adpcm);
-
-
-
-  // let infile = fs::File::open(
-  //   PathBuf::from("/mnt/e/Temp/Tactics Ogre/test_input_1.wav")
-  // )?;
-  // let mut rdr = BufReader::new(infile);
-  // let riff_header = RiffHeader::from_reader(&mut rdr)?;
-  // let format_chunk = FormatChunk::from_reader(&mut rdr)?;
-  // let data_chunk = DataChunk::from_reader(&mut rdr)?;
-
-  // println!("{:?}", riff_header);
-  // println!("{:?}", format_chunk);
-  // println!("{:?}", data_chunk);
-
-  // let num_samples = data_chunk.samples_count();
-
-  let infiles = [
-    (0x01, vec!["SCENARIO_C1_001_001_00.wav"]),
-    (0x02, vec!["SCENARIO_C1_001_002_00.wav"]),
-    (0x03, vec!["SCENARIO_C1_001_003_00.wav"]),
-    (0x04, vec!["SCENARIO_C1_001_004_00.wav"]),
-    (0x05, vec!["SCENARIO_C1_001_005_00.wav"]),
-    (0x06, vec!["SCENARIO_C1_001_006_00.wav"]),
-    (0x07, vec!["SCENARIO_C1_001_007_00.wav"]),
-    (0x08, vec!["SCENARIO_C1_001_008_00.wav"]),
-    (0x09, vec!["SCENARIO_C1_002_001_00.wav"]),
-    
-    (0x0A, vec!["SCENARIO_C1_002_002_00.wav"]),
-    (0x0B, vec!["SCENARIO_C1_002_002_01.wav"]),
-    (0x0C, vec!["SCENARIO_C1_002_002_02.wav"]),
-    // (0x0C, vec!["SCENARIO_C1_002_002_00.wav", "SCENARIO_C1_002_002_01.wav", "SCENARIO_C1_002_002_02.wav"]),
-    
-    (0x0D, vec!["SCENARIO_C1_002_003_00.wav"]),
-    (0x0E, vec!["SCENARIO_C1_002_004_00.wav"]),
-    (0x0F, vec!["SCENARIO_C1_002_004_01.wav", "SCENARIO_C1_002_004_02.wav"]),
-    // (0x0F, vec!["SCENARIO_C1_002_004_00.wav", "SCENARIO_C1_002_004_01.wav", "SCENARIO_C1_002_004_02.wav"]),
-    (0x10, vec!["SCENARIO_C1_002_005_00.wav"]),
-    (0x11, vec!["SCENARIO_C1_002_006_00.wav"]),
-    (0x12, vec!["SCENARIO_C1_003_001_00.wav"]),
-    (0x13, vec!["SCENARIO_C1_003_002_00.wav"]),
-    (0x14, vec!["SCENARIO_C1_003_003_00.wav"]),
-    (0x15, vec!["SCENARIO_C1_003_004_00.wav"]),
-    (0x16, vec!["SCENARIO_C1_003_005_00.wav"]),
-    (0x17, vec!["SCENARIO_C1_003_006_00.wav"]),
-
-    (0x18, vec!["SCENARIO_C1_003_007_00.wav"]),
-    (0x19, vec!["SCENARIO_C1_003_008_00.wav"]),
-    (0x1A, vec!["SCENARIO_C1_003_009_00.wav"]),
-    (0x1B, vec!["SCENARIO_C1_003_010_00.wav"]),
-    (0x1C, vec!["SCENARIO_C1_003_011_00.wav"]),
-    (0x1D, vec!["SCENARIO_C1_003_012_00.wav"]),
-    (0x1E, vec!["SCENARIO_C1_003_013_00.wav"]),
-    (0x1F, vec!["SCENARIO_C1_003_014_00.wav"]),
-    (0x20, vec!["SCENARIO_C1_003_015_00.wav"]),
-    (0x21, vec!["SCENARIO_C1_003_016_00.wav"]),
-
-    (0x22, vec!["SCENARIO_C1_003_017_00.wav"]),
-    (0x23, vec!["SCENARIO_C1_003_018_00.wav"]),
-    (0x24, vec!["SCENARIO_C1_003_019_00.wav"]),
-    (0x25, vec!["SCENARIO_C1_003_020_00.wav"]),
-    (0x26, vec!["SCENARIO_C1_003_021_00.wav"]),
-    (0x27, vec!["SCENARIO_C1_003_022_00.wav"]),
-    (0x28, vec!["SCENARIO_C1_004_001_00.wav"]),
-    (0x29, vec!["SCENARIO_C1_004_002_00.wav"]),
-    (0x2A, vec!["SCENARIO_C1_004_002_01.wav"]),
-    (0x2B, vec!["SCENARIO_C1_004_003_00.wav"]),
-    
-    // (0x27, vec![""]),
-    // (0x28, vec![""]),
-    // (0x29, vec![""]),
-  ];
-
-  // let infiles = vec![
-  //   PathBuf::from("/mnt/e/Temp/Tactics Ogre/SCENARIO_C1_012_001_00.wav"),
-  //   PathBuf::from("/mnt/e/Temp/Tactics Ogre/SCENARIO_C1_012_001_01.wav"),
-  // ];
-
-  for (n, base_paths) in infiles {
-    let paths: Vec<PathBuf> = base_paths.iter().map(|filename| ["/mnt/e/Temp/Tactics Ogre/", filename].iter().collect()).collect();
-
-    let (num_samples, mut rdr) = prep_input_reader(paths)?;
-
-    let outfile = fs::File::create(format!("/mnt/e/Temp/Tactics Ogre/CP1_{:0>4}.ACM", n))?;
-    let mut wtr = BufWriter::new(outfile);
-
-    aiff::write_apcm_aiff_header(num_samples, &mut wtr)?;
-    adpcm_encoder::encode_xa_adpcm(num_samples, &mut rdr, &mut wtr)?;
-  }
-
-
-  // let sectors = (adpcm.chunkSize - 8) / adpcm.sectorSize;
-  // let raw_samples = sectors * 0x7E0 * 2;
-  // let extra_samples = (raw_samples as u32) - common.numSampleFrames;
-  // let extra_sample_bytes = extra_samples / 2;
-  // let extra_sectors = extra_sample_bytes / 0x7E0;
-  
-  // let overflow_samples = extra_sample_bytes - (extra_sectors * 0x7E0);
-  // let overflow_portions = overflow_samples / 112;
-  // let overflow_overflow_samples = overflow_samples - (overflow_portions * 112);
-
-  // println!("overflow_portions: {}", overflow_portions);
-  // println!("overflow_samples: {}", overflow_overflow_samples);
-
-  // let tail = extra_sectors * 0x7E0 + overflow_portions * 128 + overflow_overflow_samples;
-
-  // println!("tail {:X}", tail);
+  let cli = Cli::parse();
+  init_logger(&cli);
+
+  match &cli.command {
+    Some(Command::Inspect { file }) => return inspect(file),
+    Some(Command::Verify { original, encoded, min_snr_db, leading_sectors, compare_reference, align_window_sectors }) => {
+      let align_window_sectors = compare_reference.then_some(*align_window_sectors);
+      return verify(original, encoded, *min_snr_db, *leading_sectors, align_window_sectors)
+    },
+    Some(Command::Concat { files, output, gap_sectors, drop_blank_sectors, force }) => {
+      return concat(files, output, *gap_sectors, *drop_blank_sectors, *force)
+    },
+    Some(Command::EmphasisMatch { reference, inputs, gains_db, corners_hz }) => {
+      let grid = EmphasisGrid { gains_db: gains_db.clone(), corners_hz: corners_hz.clone() };
+      return emphasis_match(reference, inputs, grid)
+    },
+    None => {},
+  }
+
+  let config = encoder_config(&cli)?;
+  let sidecars = Sidecars::from_cli(&cli);
+
+  let mut jobs = if let Some(manifest_path) = &cli.manifest {
+    let manifest = Manifest::from_path(manifest_path)?;
+    let out_dir = cli.output.clone().unwrap_or_default();
+
+    // Every output is named and every entry's settings are checked before anything is encoded, so
+    // a template that doesn't fit an entry or an invalid setting fails up front
+    manifest.entries.into_iter().enumerate()
+      .map(|(n, entry)| {
+        let out_path = entry.output_path(&out_dir, &cli.name_template, n)?;
+        let config = entry.config.apply(&config)
+          .with_context(|| format!("Invalid settings in manifest entry {}", n))?;
+        Ok((config, entry.inputs, out_path))
+      })
+      .collect::<Result<Vec<_>>>()?
+  } else {
+    let out_path = output_path(&cli)?;
+    vec![(config, cli.inputs, out_path)]
+  };
+  order_inputs(&mut jobs, cli.input_order);
+
+  let jobs = match cli.stereo {
+    StereoMode::Mono => jobs,
+    StereoMode::Split => split_stereo_jobs(jobs, &sidecars)?,
+  };
+
+  if cli.dry_run {
+    return dry_run(jobs, &mut io::stdout())
+  }
+  check_outputs(&jobs, cli.force)?;
+  encode_jobs(jobs, &sidecars, cli.threads, cli.fail_fast, &mut io::stdout())?;
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use adpcm2aiff::{
+    adpcm_encoder::{ADPCM_SECTOR_SAMPLES, SOUND_UNIT_SAMPLES},
+    aiff::read_pcm_aiff,
+    input::{ms_to_samples, ClipSpan, STDIO_PATH},
+    preprocess::resampled_len,
+    XaError,
+  };
+
+  fn write_test_wav(path: &Path, samples: &[i16]) {
+    write_test_wav_at(path, samples, 18900);
+  }
+
+  fn write_test_wav_at(path: &Path, samples: &[i16], sample_rate: u32) {
+    fs::write(path, test_wav_bytes(samples, sample_rate, 1)).unwrap();
+  }
+
+  /// 16-bit PCM WAV file of `samples`, interleaved when there's more than one channel
+  fn test_wav_bytes(samples: &[i16], sample_rate: u32, num_channels: u16) -> Vec<u8> {
+    let data: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16_u32.to_le_bytes());
+    bytes.extend_from_slice(&1_u16.to_le_bytes());
+    bytes.extend_from_slice(&num_channels.to_le_bytes());
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * 2 * u32::from(num_channels)).to_le_bytes());
+    bytes.extend_from_slice(&(2 * num_channels).to_le_bytes());
+    bytes.extend_from_slice(&16_u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&data);
+    bytes
+  }
+
+  #[test]
+  fn sidecars_need_an_output_file() {
+    let sidecars = Sidecars { checksum: true, ..Sidecars::default() };
+    let err = encode_file(&EncoderConfig::default(), vec![], Path::new(STDIO_PATH), sidecars).unwrap_err();
+    assert!(err.to_string().contains("stdout"));
+  }
+
+  #[test]
+  fn double_rate_wav_sets_comm_sample_rate() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let acm = dir.path().join("out.acm");
+    write_test_wav_at(&wav, &[1000; 5000], 37800);
+
+    // An 18900 Hz encode doesn't accept a 37800 Hz file
+    assert!(encode_file(&EncoderConfig::default(), vec![wav.clone()], &acm, Sidecars::default()).is_err());
+
+    let config = EncoderConfig::builder().sample_rate(37800).build().unwrap();
+    encode_file(&config, vec![wav], &acm, Sidecars::default()).unwrap();
+
+    // COMM's sample rate is an 80-bit extended float: 37800 is 0x93A8, which has its top bit at
+    // 2^15, so the exponent is 16383 + 15 = 0x400E and the mantissa starts 0x93A8
+    let acm_bytes = fs::read(&acm).unwrap();
+    assert_eq!(&acm_bytes[28..38], [0x40, 0x0E, 0x93, 0xA8, 0, 0, 0, 0, 0, 0]);
+
+    let (_, comm, _) = read_apcm_aiff_headers(&mut acm_bytes.as_slice()).unwrap();
+    assert_eq!(comm.sample_rate(), 37800.0);
+    assert_eq!(comm.num_sample_frames(), 5000);
+  }
+
+  #[test]
+  fn both_rates_give_consistent_headers() {
+    let dir = tempfile::tempdir().unwrap();
+    let acm = dir.path().join("out.acm");
+
+    for rate in [XaRate::Normal, XaRate::High] {
+      let sample_rate = rate.sample_rate();
+      let a = dir.path().join("a.wav");
+      let b = dir.path().join("b.wav");
+      write_test_wav_at(&a, &[1000; 5000], sample_rate);
+      write_test_wav_at(&b, &[-1000; 3000], sample_rate);
+
+      let config = EncoderConfig::builder().rate(rate).interfile_gap_ms(250).build().unwrap();
+      encode_file(&config, vec![a.clone(), b.clone()], &acm, Sidecars::default()).unwrap();
+      let (planned_samples, planned_size) = plan_output(&config, vec![a, b]).unwrap();
+
+      let acm_bytes = fs::read(&acm).unwrap();
+      let (aiff, comm, apcm) = read_apcm_aiff_headers(&mut acm_bytes.as_slice()).unwrap();
+      assert_eq!(comm.sample_rate(), f64::from(sample_rate));
+      // The gap is a quarter second at either rate
+      assert_eq!(comm.num_sample_frames() as usize, 5000 + sample_rate as usize / 4 + 3000);
+      assert_eq!(planned_samples, comm.num_sample_frames() as usize);
+      assert_eq!(planned_size, acm_bytes.len());
+      assert!(HeaderReport::new(&aiff, &comm, &apcm, Some(acm_bytes.len() as u64)).unwrap().is_consistent());
+    }
+
+    let cli = Cli::parse_from(["adpcm2aiff", "in.wav", "-o", "out.acm", "--rate", "high"]);
+    assert_eq!(encoder_config(&cli).unwrap().sample_rate, 37800);
+    assert!(Cli::try_parse_from(["adpcm2aiff", "in.wav", "--rate", "high", "--sample-rate", "18900"]).is_err());
+  }
+
+  #[test]
+  fn resample_joins_clips_at_different_rates() {
+    let dir = tempfile::tempdir().unwrap();
+    let low = dir.path().join("low.wav");
+    let high = dir.path().join("high.wav");
+    let acm = dir.path().join("out.acm");
+    // One second of 1 kHz tone at each rate
+    let tone = |sample_rate: u32| -> Vec<i16> {
+      (0..sample_rate)
+        .map(|n| ((2.0 * std::f64::consts::PI * 1000.0 * f64::from(n) / f64::from(sample_rate)).sin() * 8000.0) as i16)
+        .collect()
+    };
+    write_test_wav_at(&low, &tone(8000), 8000);
+    write_test_wav_at(&high, &tone(44_100), 44_100);
+
+    // Without `resample` the rates have to match
+    assert!(encode_file(&EncoderConfig::default(), vec![low.clone()], &acm, Sidecars::default()).is_err());
+
+    let config = EncoderConfig::builder().resample(true).build().unwrap();
+    let clip = ClipProcessing::from_config(&config);
+    let (samples_count, mut rdr, clips) = prep_input_reader(vec![low.clone(), high.clone()], config.interfile_gap_ms, clip).unwrap();
+    assert_eq!(clips, [ClipSpan { start: 0, samples: 18_900 }, ClipSpan { start: 18_900 + 9450, samples: 18_900 }]);
+    assert_eq!(samples_count, 18_900 + 9450 + 18_900);
+    let mut pcm = Vec::new();
+    rdr.read_to_end(&mut pcm).unwrap();
+    assert_eq!(pcm.len(), samples_count * 2);
+
+    encode_file(&config, vec![low, high], &acm, Sidecars::default()).unwrap();
+    let (_, comm, _) = read_apcm_aiff_headers(&mut fs::read(&acm).unwrap().as_slice()).unwrap();
+    assert_eq!(comm.num_sample_frames() as usize, samples_count);
+    assert_eq!(comm.sample_rate(), 18900.0);
+  }
+
+  #[test]
+  fn preview_holds_exactly_the_encoder_input() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.wav");
+    let b = dir.path().join("b.wav");
+    let acm = dir.path().join("out.acm");
+    let preview = dir.path().join("preview.wav");
+    write_test_wav(&a, &[5000; 3000]);
+    write_test_wav(&b, &[-5000; 2500]);
+
+    let config = EncoderConfig::builder().fade_in_ms(10).pre_emphasis_db(3.0).build().unwrap();
+    let sidecars = Sidecars { preview: Some(preview.clone()), ..Sidecars::default() };
+    encode_file(&config, vec![a.clone(), b.clone()], &acm, sidecars).unwrap();
+
+    let (_, comm, _) = read_apcm_aiff_headers(&mut fs::read(&acm).unwrap().as_slice()).unwrap();
+    let (preview_samples, _, mut preview_rdr) = open_input(&preview, Some(18900)).unwrap();
+    assert_eq!(preview_samples, comm.num_sample_frames() as usize);
+    assert_eq!(preview_samples, 3000 + 9450 + 2500);
+
+    let (_, mut rdr, _) = prep_input_reader(vec![a.clone(), b.clone()], config.interfile_gap_ms, ClipProcessing::from_config(&config)).unwrap();
+    let (mut expected, mut previewed) = (Vec::new(), Vec::new());
+    rdr.read_to_end(&mut expected).unwrap();
+    preview_rdr.read_to_end(&mut previewed).unwrap();
+    assert_eq!(previewed, expected);
+
+    // The same samples as a standard AIFF file
+    let aiff_preview = dir.path().join("preview.AIFF");
+    let sidecars = Sidecars { preview: Some(aiff_preview.clone()), ..Sidecars::default() };
+    encode_file(&config, vec![a, b], &dir.path().join("again.acm"), sidecars).unwrap();
+    let (_, aiff_samples) = read_pcm_aiff(&mut fs::read(&aiff_preview).unwrap().as_slice()).unwrap();
+    let aiff_pcm: Vec<u8> = aiff_samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    assert_eq!(aiff_pcm, expected);
+  }
+
+  #[test]
+  fn empty_wav_encodes_to_headers_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("empty.wav");
+    let acm = dir.path().join("out.acm");
+    write_test_wav(&wav, &[]);
+
+    encode_file(&EncoderConfig::default(), vec![wav], &acm, Sidecars::default()).unwrap();
+
+    let acm_bytes = fs::read(&acm).unwrap();
+    assert_eq!(acm_bytes.len(), APCM_AIFF_HEADER_SIZE);
+    let (aiff, comm, apcm) = read_apcm_aiff_headers(&mut acm_bytes.as_slice()).unwrap();
+    assert!(HeaderReport::new(&aiff, &comm, &apcm, Some(acm_bytes.len() as u64)).unwrap().is_consistent());
+  }
+
+  #[test]
+  fn batch_continues_past_a_failed_entry() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    write_test_wav(&wav, &[1000; 3000]);
+    let out = |n: usize| dir.path().join(format!("out{}.acm", n));
+    let config = EncoderConfig::default();
+    let jobs = vec![
+      (config.clone(), vec![wav.clone()], out(0)),
+      (config.clone(), vec![dir.path().join("missing.wav")], out(1)),
+      (config, vec![wav], out(2)),
+    ];
+
+    let mut summary = Vec::new();
+    let err = encode_jobs(jobs.clone(), &Sidecars::default(), 1, false, &mut summary).unwrap_err();
+    assert_eq!(err.to_string(), "1 of 3 outputs failed");
+    assert!(out(0).exists() && out(2).exists());
+    assert!(!out(1).exists());
+
+    let summary = String::from_utf8(summary).unwrap();
+    let lines: Vec<&str> = summary.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], "2 of 3 outputs encoded");
+    assert!(lines[1].starts_with(&format!("  entry 1: {} failed: ", out(1).to_string_lossy())), "{}", lines[1]);
+    assert!(lines[1].contains("missing.wav"));
+
+    // Fail-fast gives back the entry's own error, with no summary
+    let mut summary = Vec::new();
+    let err = encode_jobs(jobs, &Sidecars::default(), 1, true, &mut summary).unwrap_err();
+    assert!(err.to_string().contains("missing.wav"), "{}", err);
+    assert!(summary.is_empty());
+  }
+
+  #[test]
+  fn existing_outputs_need_force() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let kept = dir.path().join("kept.acm");
+    let fresh = dir.path().join("fresh.acm");
+    write_test_wav(&wav, &[1000; 3000]);
+    fs::write(&kept, b"hand-tweaked").unwrap();
+    let config = EncoderConfig::default();
+    let jobs = vec![(config.clone(), vec![wav.clone()], fresh.clone()), (config.clone(), vec![wav.clone()], kept.clone())];
+
+    // The whole batch is refused, including the entry that wouldn't overwrite anything
+    let err = check_outputs(&jobs, false).unwrap_err().to_string();
+    assert!(err.contains(&format!("entry 1: {} already exists", kept.to_string_lossy())), "{}", err);
+    assert!(!err.contains("entry 0"));
+    assert!(!fresh.exists());
+    assert_eq!(fs::read(&kept).unwrap(), b"hand-tweaked");
+
+    check_outputs(&jobs, true).unwrap();
+    encode_jobs(jobs, &Sidecars::default(), 1, true, &mut io::sink()).unwrap();
+    assert!(fs::read(&kept).unwrap().starts_with(b"FORM"));
+    assert!(fresh.exists());
+
+    // Stdout is never an existing file, and two entries can't share an output even with `--force`
+    check_outputs(&[(config.clone(), vec![wav.clone()], PathBuf::from(STDIO_PATH))], false).unwrap();
+    let err = check_outputs(&[(config.clone(), vec![wav.clone()], fresh.clone()), (config, vec![wav], fresh)], true).unwrap_err();
+    assert!(err.to_string().contains("is also written by entry 0"));
+  }
+
+  #[test]
+  fn dry_run_sizes_match_the_encode() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.wav");
+    let b = dir.path().join("b.wav");
+    let acm = dir.path().join("out.acm");
+    write_test_wav_at(&a, &[1000; 5000], 8000);
+    write_test_wav(&b, &[-1000; 3000]);
+
+    let configs = [
+      EncoderConfig::builder().resample(true).build().unwrap(),
+      EncoderConfig::builder().resample(true).interfile_gap_ms(0).sector_padding(SectorPadding::Bare).build().unwrap(),
+      EncoderConfig::builder().resample(true).output_format(OutputFormat::Spu).build().unwrap(),
+    ];
+    for config in configs {
+      let (num_samples, size) = plan_output(&config, vec![a.clone(), b.clone()]).unwrap();
+      encode_file(&config, vec![a.clone(), b.clone()], &acm, Sidecars::default()).unwrap();
+      assert_eq!(size as u64, fs::metadata(&acm).unwrap().len());
+      assert_eq!(num_samples, resampled_len(5000, 8000, 18_900) + ms_to_samples(config.interfile_gap_ms, 18_900) + 3000);
+    }
+
+    let mut table = Vec::new();
+    let config = EncoderConfig::default();
+    dry_run(vec![(config.clone(), vec![b.clone()], acm.clone()), (config, vec![b], acm)], &mut table).unwrap();
+    let table = String::from_utf8(table).unwrap();
+    assert_eq!(table.lines().count(), 4);
+    assert!(table.lines().last().unwrap().trim_start().starts_with("6000 "));
+  }
+
+  #[test]
+  fn zero_gap_concatenates_samples_exactly() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.wav");
+    let b = dir.path().join("b.wav");
+    write_test_wav(&a, &[1, 2, 3]);
+    write_test_wav(&b, &[4, 5]);
+
+    let (samples_count, mut rdr, _) = prep_input_reader(vec![a.clone(), b.clone()], 0, ClipProcessing::default()).unwrap();
+    let mut pcm = Vec::new();
+    rdr.read_to_end(&mut pcm).unwrap();
+
+    assert_eq!(samples_count, 5);
+    assert_eq!(pcm, [1, 0, 2, 0, 3, 0, 4, 0, 5, 0]);
+
+    let (samples_count, _, _) = prep_input_reader(vec![a, b], 1000, ClipProcessing::default()).unwrap();
+    assert_eq!(samples_count, 5 + 18900);
+  }
+
+  #[test]
+  fn natural_input_order_sorts_numbered_clips_by_value() {
+    let dir = tempfile::tempdir().unwrap();
+    let inputs: Vec<PathBuf> = ["clip_2.wav", "clip_10.wav", "clip_1.wav"].iter().map(|name| dir.path().join(name)).collect();
+    write_test_wav(&inputs[0], &[2]);
+    write_test_wav(&inputs[1], &[10]);
+    write_test_wav(&inputs[2], &[1]);
+    let job = (EncoderConfig::default(), inputs, dir.path().join("out.acm"));
+
+    let mut jobs = vec![job.clone()];
+    order_inputs(&mut jobs, InputOrder::Natural);
+    let (_, inputs, _) = jobs.pop().unwrap();
+    let (_, mut rdr, _) = prep_input_reader(inputs, 0, ClipProcessing::default()).unwrap();
+    let mut pcm = Vec::new();
+    rdr.read_to_end(&mut pcm).unwrap();
+    assert_eq!(pcm, [1, 0, 2, 0, 10, 0]);
+
+    let mut jobs = vec![job];
+    order_inputs(&mut jobs, InputOrder::Listed);
+    let (_, inputs, _) = jobs.pop().unwrap();
+    let (_, mut rdr, _) = prep_input_reader(inputs, 0, ClipProcessing::default()).unwrap();
+    let mut pcm = Vec::new();
+    rdr.read_to_end(&mut pcm).unwrap();
+    assert_eq!(pcm, [2, 0, 10, 0, 1, 0]);
+  }
+
+  #[test]
+  fn reset_at_gaps_decodes_gaps_to_silence() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.wav");
+    let b = dir.path().join("b.wav");
+    let acm = dir.path().join("out.acm");
+    // Loud tone cut off away from zero. With only filters 2 and 3, the decoder's prediction carries
+    // the tone's end into the gap
+    let tone: Vec<i16> = (0..5000).map(|n| ((f64::from(n) * 0.07).sin() * 30_000.0) as i16).collect();
+    write_test_wav(&a, &tone);
+    write_test_wav(&b, &tone);
+
+    let gap = |config: EncoderConfig| -> Vec<i16> {
+      encode_file(&config, vec![a.clone(), b.clone()], &acm, Sidecars::default()).unwrap();
+      let decoded = decode_apcm_aiff(&mut BufReader::new(fs::File::open(&acm).unwrap()), false).unwrap();
+      // From the first sound unit boundary in the gap to the start of the next file
+      decoded.samples[5000_usize.next_multiple_of(SOUND_UNIT_SAMPLES)..5000 + 1890].to_vec()
+    };
+
+    let config = EncoderConfig::builder().interfile_gap_ms(100).allowed_filters(FilterSet::from_bits(0b1100));
+    assert!(gap(config.clone().build().unwrap()).iter().any(|&sample| sample != 0));
+    assert!(gap(config.reset_at_gaps(true).build().unwrap()).iter().all(|&sample| sample == 0));
+  }
+
+  #[test]
+  fn smpl_loop_sets_spu_loop_flags() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let spu = dir.path().join("out.spu");
+    let write_looped_wav = |start: u32, end: u32| {
+      let mut bytes = test_wav_bytes(&[1000; 280], 18900, 1);
+      bytes.extend_from_slice(b"smpl");
+      bytes.extend_from_slice(&60_u32.to_le_bytes());
+      bytes.extend_from_slice(&[0; 28]);
+      for field in [1, 0, 0, 0, start, end, 0, 0] {
+        bytes.extend_from_slice(&field.to_le_bytes());
+      }
+      let riff_size = bytes.len() as u32 - 8;
+      bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+      fs::write(&wav, bytes).unwrap();
+    };
+    let block_flags = |config: EncoderConfig| -> Vec<u8> {
+      encode_file(&config, vec![wav.clone()], &spu, Sidecars::default()).unwrap();
+      fs::read(&spu).unwrap().chunks(16).map(|block| block[1]).collect()
+    };
+    let config = EncoderConfig::builder().output_format(OutputFormat::Spu).build().unwrap();
+
+    write_looped_wav(56, 139);
+    assert_eq!(block_flags(config.clone()), [0, 0, 0x06, 0, 0x03, 0, 0, 0, 0, 0]);
+
+    // An explicit loop region wins
+    let explicit = EncoderConfig { loop_region: Some(LoopRegion { start: 0, end: 279 }), ..config.clone() };
+    assert_eq!(block_flags(explicit), [0x06, 0, 0, 0, 0, 0, 0, 0, 0, 0x03]);
+
+    write_looped_wav(56, 280);
+    let err = encode_file(&config, vec![wav.clone()], &spu, Sidecars::default()).unwrap_err();
+    assert!(matches!(err.root_cause().downcast_ref(), Some(XaError::LoopOutOfRange { end: 280, .. })));
+  }
+
+  #[test]
+  fn chained_files_stream_with_gaps() {
+    let dir = tempfile::tempdir().unwrap();
+    let paths: Vec<PathBuf> = ["a.wav", "b.wav", "c.wav"].iter().map(|name| dir.path().join(name)).collect();
+    write_test_wav(&paths[0], &[1, 2]);
+    write_test_wav(&paths[1], &[3]);
+    write_test_wav(&paths[2], &[4, 5, 6]);
+
+    // 1 ms at 18900 Hz is 18 samples of silence between files
+    let (samples_count, mut rdr, _) = prep_input_reader(paths.clone(), 1, ClipProcessing::default()).unwrap();
+    assert_eq!(samples_count, 6 + 2 * 18);
+
+    // A file that goes away before it's reached only fails once the reader gets to it
+    fs::remove_file(&paths[2]).unwrap();
+    let mut pcm = vec![0; (2 + 18 + 1 + 18) * 2];
+    rdr.read_exact(&mut pcm).unwrap();
+    assert!(rdr.read_to_end(&mut Vec::new()).is_err());
+
+    let mut expected = vec![1, 0, 2, 0];
+    expected.extend_from_slice(&[0; 36]);
+    expected.extend_from_slice(&[3, 0]);
+    expected.extend_from_slice(&[0; 36]);
+    assert_eq!(pcm, expected);
+  }
+
+  #[test]
+  fn trimmed_files_size_the_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.wav");
+    let b = dir.path().join("b.wav");
+    let mut samples = vec![0_i16; 5000];
+    samples.extend([1000, -1000, 1000]);
+    samples.extend([0; 5000]);
+    write_test_wav(&a, &samples);
+    write_test_wav(&b, &[500, 0, 0, 500]);
+
+    let config = EncoderConfig::builder()
+      .trim_silence(true)
+      .trim_pre_roll_ms(1)
+      .build()
+      .unwrap();
+    let clip = ClipProcessing::from_config(&config);
+
+    // 18 samples of pre-roll ahead of a's loud samples, and b is too short to trim
+    let (samples_count, mut rdr, _) = prep_input_reader(vec![a, b], 0, clip).unwrap();
+    let mut pcm = Vec::new();
+    rdr.read_to_end(&mut pcm).unwrap();
+
+    assert_eq!(samples_count, 18 + 3 + 4);
+    assert_eq!(pcm.len(), samples_count * 2);
+    assert_eq!(&pcm[36..42], [0xE8, 0x03, 0x18, 0xFC, 0xE8, 0x03]);
+  }
+
+  #[test]
+  fn reject_data_chunk_larger_than_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.wav");
+    write_test_wav(&path, &[1, 2, 3]);
+
+    let mut bytes = fs::read(&path).unwrap();
+    bytes[40..44].copy_from_slice(&0xFFFF_FFF0_u32.to_le_bytes());
+    fs::write(&path, bytes).unwrap();
+
+    for paths in [vec![path.clone()], vec![path.clone(), path.clone()]] {
+      let err = prep_input_reader(paths, 0, ClipProcessing::default()).err().unwrap();
+      assert!(matches!(
+        err.downcast_ref::<XaError>(),
+        Some(XaError::DataSizeExceedsFile { declared: 0xFFFF_FFF0, .. })
+      ));
+    }
+  }
+
+  #[cfg(feature = "hound")]
+  #[test]
+  fn hound_reads_layouts_the_parser_rejects() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("list.wav");
+    write_test_wav(&path, &[1, 2, 3]);
+
+    // Slip a LIST chunk in between `fmt ` and `data`
+    let mut bytes = fs::read(&path).unwrap();
+    let list = b"LIST\x04\x00\x00\x00INFO";
+    bytes.splice(36..36, list.iter().copied());
+    let riff_size = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) + list.len() as u32;
+    bytes[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    fs::write(&path, bytes).unwrap();
+
+    let (samples_count, mut rdr, _) = prep_input_reader(vec![path.clone()], 0, ClipProcessing::default()).unwrap();
+    let mut pcm = Vec::new();
+    rdr.read_to_end(&mut pcm).unwrap();
+    assert_eq!(samples_count, 3);
+    assert_eq!(pcm, [1, 0, 2, 0, 3, 0]);
+
+    let out_path = dir.path().join("out.acm");
+    encode_file(&EncoderConfig::default(), vec![path], &out_path, Sidecars::default()).unwrap();
+    assert!(fs::metadata(&out_path).unwrap().len() > 0);
+  }
+
+  #[test]
+  fn stereo_split_writes_equal_length_channels() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("stereo.wav");
+    let left: Vec<i16> = (0..5000).map(|n| ((n % 100) * 100) as i16).collect();
+    let right: Vec<i16> = left.iter().map(|&sample| -sample / 2).collect();
+    let mut interleaved: Vec<i16> = left.iter().zip(&right).flat_map(|(&l, &r)| [l, r]).collect();
+    // Cut off after the left sample of a last frame, so the left channel is a sample longer in the
+    // file. The partial frame is dropped from both channels rather than padding the right one
+    interleaved.push(1234);
+    let bytes = test_wav_bytes(&interleaved, 18900, 2);
+    fs::write(&path, bytes).unwrap();
+
+    let out_path = dir.path().join("MUSIC.ACM");
+    let jobs = vec![(EncoderConfig::default(), vec![path.clone()], out_path)];
+    for job in split_stereo_jobs(jobs, &Sidecars::default()).unwrap() {
+      let (config, inputs, _) = &job;
+      let (samples_count, mut rdr, _) = prep_input_reader(inputs.clone(), 0, ClipProcessing::from_config(config)).unwrap();
+      let mut pcm = Vec::new();
+      rdr.read_to_end(&mut pcm).unwrap();
+      let samples: Vec<i16> = pcm.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+      assert_eq!(samples_count, 5000);
+      assert_eq!(&samples, if config.stereo_channel == Some(StereoChannel::Left) { &left } else { &right });
+
+      encode_jobs(vec![job], &Sidecars::default(), 1, true, &mut io::sink()).unwrap();
+    }
+
+    let left_file = fs::read(dir.path().join("MUSIC_L.ACM")).unwrap();
+    let right_file = fs::read(dir.path().join("MUSIC_R.ACM")).unwrap();
+    assert_eq!(left_file.len(), APCM_AIFF_HEADER_SIZE + 2 * 0x914);
+    assert_eq!(left_file.len(), right_file.len());
+    assert_eq!(left_file[..APCM_AIFF_HEADER_SIZE], right_file[..APCM_AIFF_HEADER_SIZE]);
+
+    // Mono encoding still refuses the file
+    let err = prep_input_reader(vec![path], 0, ClipProcessing::default()).err().unwrap();
+    assert!(matches!(err.downcast_ref::<XaError>(), Some(XaError::UnsupportedChannels(2))));
+  }
+
+  #[cfg(feature = "hound")]
+  #[test]
+  fn hound_decodes_stereo_48k_end_to_end() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("stereo.wav");
+    let acm = dir.path().join("out.acm");
+    let spec = hound::WavSpec {
+      channels: 2,
+      sample_rate: 48_000,
+      bits_per_sample: 16,
+      sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+    for n in 0..4800 {
+      let sample = ((f64::from(n) * 0.02).sin() * 10_000.0) as i16;
+      writer.write_sample(sample).unwrap();
+      writer.write_sample(-sample).unwrap();
+    }
+    writer.finalize().unwrap();
+
+    let config = EncoderConfig::builder().resample(true).stereo_channel(Some(StereoChannel::Left)).build().unwrap();
+    let (samples_count, _, _) = prep_input_reader(vec![path.clone()], 0, ClipProcessing::from_config(&config)).unwrap();
+    assert_eq!(samples_count, resampled_len(4800, 48_000, 18_900));
+
+    encode_file(&config, vec![path], &acm, Sidecars::default()).unwrap();
+    let (_, comm, apcm) = read_apcm_aiff_headers(&mut fs::File::open(&acm).unwrap()).unwrap();
+    assert_eq!(comm.num_sample_frames() as usize, samples_count);
+    assert_eq!(apcm.num_sectors().unwrap(), samples_count.div_ceil(ADPCM_SECTOR_SAMPLES));
+    let decoded = decode_apcm_aiff(&mut fs::File::open(&acm).unwrap(), false).unwrap();
+    assert!(decoded.samples.iter().any(|&sample| sample.abs() > 5000));
+  }
+
+  #[test]
+  fn strict_refuses_input_that_looks_byte_swapped() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let acm = dir.path().join("out.acm");
+    let samples: Vec<i16> = (0..5000).map(|n| if n % 2 == 0 { 32_000 } else { -32_000 }).collect();
+    write_test_wav(&wav, &samples);
+
+    let strict = Sidecars { strict: true, ..Default::default() };
+    let err = encode_file(&EncoderConfig::default(), vec![wav.clone()], &acm, strict).unwrap_err();
+    assert!(err.to_string().contains("wrong byte order"), "{}", err);
+    assert!(encode_file(&EncoderConfig::default(), vec![wav], &acm, Sidecars::default()).is_ok());
+  }
+
+  #[test]
+  fn verify_checks_the_written_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let acm = dir.path().join("out.acm");
+    let samples: Vec<i16> = (0..8000).map(|n| (((n as f64) * 0.05).sin() * 12_000.0) as i16).collect();
+    write_test_wav(&wav, &samples);
+
+    let verify = Sidecars { verify_snr_db: Some(20.0), ..Default::default() };
+    encode_file(&EncoderConfig::default(), vec![wav.clone()], &acm, verify).unwrap();
+
+    let impossible = Sidecars { verify_snr_db: Some(f64::INFINITY), ..Default::default() };
+    let rejected = dir.path().join("rejected.acm");
+    let err = encode_file(&EncoderConfig::default(), vec![wav], &rejected, impossible).unwrap_err();
+    assert!(format!("{:#}", err).contains("failed verification: SNR"), "{:#}", err);
+    assert!(!rejected.exists());
+  }
+
+  #[test]
+  fn strict_clip_failure_leaves_no_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let acm = dir.path().join("out.acm");
+    let samples: Vec<i16> = (0..8000).map(|n| if (n / 37) % 2 == 0 { -32_000 } else { 32_000 }).collect();
+    write_test_wav(&wav, &samples);
+
+    let config = EncoderConfig::builder().max_clipped_samples(Some(0)).build().unwrap();
+    let err = encode_file(&config, vec![wav], &acm, Sidecars::default()).unwrap_err();
+    assert!(err.to_string().contains("samples clipped"), "{}", err);
+    assert!(!acm.exists());
+    assert!(!sidecar_path(&acm, "part").exists());
+  }
+
+  #[test]
+  fn comm_sample_size_reaches_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let acm = dir.path().join("out.acm");
+    write_test_wav(&wav, &[100; 1000]);
+
+    let config = EncoderConfig::builder().comm_sample_size(16).build().unwrap();
+    encode_file(&config, vec![wav], &acm, Sidecars::default()).unwrap();
+    let (_, comm, _) = read_apcm_aiff_headers(&mut BufReader::new(fs::File::open(&acm).unwrap())).unwrap();
+    assert_eq!(comm.sample_size(), 16);
+    assert!(inspect(&acm).is_ok());
+  }
+
+  #[test]
+  fn inspect_is_a_subcommand() {
+    let cli = Cli::parse_from(["adpcm2aiff", "inspect", "in.acm"]);
+    assert!(matches!(cli.command, Some(Command::Inspect { file }) if file == Path::new("in.acm")));
+
+    let cli = Cli::parse_from(["adpcm2aiff", "in.wav", "-o", "out.acm"]);
+    assert!(cli.command.is_none());
+  }
+
+  #[test]
+  fn verify_aligns_leading_sectors() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let acm = dir.path().join("out.acm");
+    let samples: Vec<i16> = (0..5000).map(|n| (((n as f64) * 0.05).sin() * 12_000.0) as i16).collect();
+    write_test_wav(&wav, &samples);
+    encode_file(&EncoderConfig::default(), vec![wav.clone()], &acm, Sidecars::default()).unwrap();
+
+    verify(&wav, &acm, 20.0, 0, None).unwrap();
+    assert!(verify(&wav, &acm, 200.0, 0, None).is_err());
+    // Misaligned by a sector, the comparison is against the wrong samples
+    assert!(verify(&wav, &acm, 20.0, 1, None).is_err());
+  }
+
+  #[test]
+  fn compare_reference_finds_an_injected_offset() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let shifted_wav = dir.path().join("shifted.wav");
+    let trimmed_wav = dir.path().join("trimmed.wav");
+    let shifted = dir.path().join("shifted.acm");
+    let trimmed = dir.path().join("trimmed.acm");
+    // A chirp, so that no shift other than the right one lines it up with itself
+    let samples: Vec<i16> = (0..12_000).map(|n| (((n as f64) * 0.02 + (n as f64).powi(2) * 2e-6).sin() * 12_000.0) as i16).collect();
+    write_test_wav(&wav, &samples);
+    // 300 samples of silence ahead of the audio, and then the first 137 samples cut off
+    let delayed: Vec<i16> = [0; 300].iter().chain(&samples).copied().collect();
+    write_test_wav(&shifted_wav, &delayed);
+    write_test_wav(&trimmed_wav, &samples[137..]);
+    encode_file(&EncoderConfig::default(), vec![shifted_wav], &shifted, Sidecars::default()).unwrap();
+    encode_file(&EncoderConfig::default(), vec![trimmed_wav], &trimmed, Sidecars::default()).unwrap();
+
+    assert!(verify(&wav, &shifted, 20.0, 0, None).is_err());
+    assert!(verify(&wav, &trimmed, 20.0, 0, None).is_err());
+    verify(&wav, &shifted, 20.0, 0, Some(DEFAULT_ALIGN_WINDOW_SECTORS)).unwrap();
+    verify(&wav, &trimmed, 20.0, 0, Some(DEFAULT_ALIGN_WINDOW_SECTORS)).unwrap();
+  }
+
+  #[test]
+  fn checksum_sidecar_lists_every_sector() {
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    let acm = dir.path().join("out.acm");
+    write_test_wav(&wav, &[1000; 5000]);
+
+    encode_file(&EncoderConfig::default(), vec![wav], &acm, Sidecars { checksum: true, ..Default::default() }).unwrap();
+
+    let sidecar = fs::read_to_string(dir.path().join("out.acm.crc")).unwrap();
+    let acm_bytes = fs::read(&acm).unwrap();
+    let expected: String = acm_bytes[APCM_AIFF_HEADER_SIZE..]
+      .chunks(0x914)
+      .map(|sector| format!("{:08x}\n", crc32fast::hash(sector)))
+      .collect();
+    assert_eq!(sidecar, expected);
+    assert_eq!(sidecar.lines().count(), 2);
+  }
+
+  #[test]
+  fn timings_sidecar_locates_each_clip() {
+    let dir = tempfile::tempdir().unwrap();
+    let paths: Vec<PathBuf> = ["a.wav", "b.wav", "c.wav"].iter().map(|name| dir.path().join(name)).collect();
+    write_test_wav(&paths[0], &[100; 5000]);
+    write_test_wav(&paths[1], &[200; 3]);
+    write_test_wav(&paths[2], &[300; 10]);
+    let acm = dir.path().join("out.acm");
+
+    let config = EncoderConfig::builder().interfile_gap_ms(100).build().unwrap();
+    let sidecars = Sidecars { timings: true, leading_sectors: 3, ..Default::default() };
+    encode_file(&config, paths.clone(), &acm, sidecars).unwrap();
+
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(dir.path().join("out.acm.json")).unwrap()).unwrap();
+    let clips = json["clips"].as_array().unwrap();
+    assert_eq!(clips.len(), 3);
+
+    // 100 ms at 18900 Hz is 1890 samples of silence between clips
+    let leading = 3 * ADPCM_SECTOR_SAMPLES;
+    let starts = [leading, leading + 5000 + 1890, leading + 5000 + 1890 + 3 + 1890];
+    for (clip, (start, path)) in clips.iter().zip(starts.iter().zip(&paths)) {
+      assert_eq!(clip["start_sample"], *start);
+      assert_eq!(clip["start_sector"], start / ADPCM_SECTOR_SAMPLES);
+      assert_eq!(clip["path"], path.to_str().unwrap());
+    }
+    assert_eq!(clips[1]["samples"], 3);
+    assert_eq!(clips[2]["start_secs"].as_f64().unwrap(), starts[2] as f64 / 18900.0);
+  }
+
+  #[test]
+  fn index_accepts_hex_and_decimal() {
+    assert_eq!(parse_index("0x0F").unwrap(), 15);
+    assert_eq!(parse_index("15").unwrap(), 15);
+    assert!(parse_index("0x100").is_err());
+  }
+
+  #[test]
+  fn index_names_output_in_directory() {
+    let cli = Cli::parse_from(["adpcm2aiff", "in.wav", "-o", "out", "--index", "0x0F"]);
+
+    assert_eq!(output_path(&cli).unwrap(), PathBuf::from("out/CP1_0015.ACM"));
+  }
+
+  #[test]
+  fn threaded_jobs_match_serial() {
+    let dir = tempfile::tempdir().unwrap();
+    let inputs: Vec<PathBuf> = (0..6)
+      .map(|n| {
+        let path = dir.path().join(format!("{}.wav", n));
+        let samples: Vec<i16> = (0..3000 + n * 2500).map(|i| ((i * (n + 3)) % 400) as i16 * 40 - 8000).collect();
+        write_test_wav(&path, &samples);
+        path
+      })
+      .collect();
+    let config = EncoderConfig::builder().dither_seed(Some(7)).build().unwrap();
+    let jobs = |out_dir: &str| -> Vec<Job> {
+      fs::create_dir_all(dir.path().join(out_dir)).unwrap();
+      inputs.iter().enumerate()
+        .map(|(n, input)| {
+          let out_path = dir.path().join(out_dir).join(format!("{}.acm", n));
+          (config.clone(), vec![input.clone(), inputs[0].clone()], out_path)
+        })
+        .collect()
+    };
+
+    encode_jobs(jobs("serial"), &Sidecars::default(), 1, false, &mut io::sink()).unwrap();
+    encode_jobs(jobs("threaded"), &Sidecars::default(), 4, false, &mut io::sink()).unwrap();
+    for (_, _, out_path) in jobs("serial") {
+      let threaded = dir.path().join("threaded").join(out_path.file_name().unwrap());
+      assert_eq!(fs::read(&out_path).unwrap(), fs::read(threaded).unwrap());
+    }
+  }
+}
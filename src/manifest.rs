@@ -0,0 +1,379 @@
+use adpcm2aiff::{
+  adpcm_encoder::{FilterSet, OutputFormat, RoundingMode, SectorPadding, SoundGroupLayout, TailMode},
+  config::EncoderConfig,
+  preprocess::FadeCurve,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::{de, Deserialize, Deserializer};
+use std::{
+  fmt,
+  fs,
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use crate::naming::{NameFields, NameTemplate};
+
+/// Batch encoding description, one output file per entry.
+///
+/// ```toml
+/// [[entry]]
+/// index = 0x0F
+/// inputs = ["a.wav", "b.wav"]
+/// output = "CP1_0015.ACM"
+/// config = { gain_db = -2.0, fade_out_ms = 500 }
+/// ```
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Manifest {
+  #[serde(rename = "entry", default)]
+  pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ManifestEntry {
+  pub index: Option<u8>,
+  pub inputs: Vec<PathBuf>,
+  pub output: Option<PathBuf>,
+  #[serde(default)]
+  pub config: ConfigOverrides,
+}
+
+/// Encoder settings a manifest entry changes for its own output. Each setting is taken from the
+/// entry if it gives one, otherwise from the command line, otherwise from `EncoderConfig`'s
+/// default. Names are the `EncoderConfig` field names; choices such as `fade_curve` and
+/// `tail_mode` are spelled as on the command line, and a setting that can be left unset, such as
+/// `soft_clip_dbfs`, is turned off with `"none"`.
+///
+/// Every `EncoderConfig` field is here except those that only come from the command line:
+/// `stereo_channel`, set on each job by `--stereo split`; `stats`, `snr_weighting` and
+/// `hash_output`, which only change what's reported; `loop_region`, from `--loop` or each input's
+/// `smpl` chunk; `reference_header`, read once for the run; `force_params`, for debugging;
+/// `filter_tables`, which the CLI never changes; and `state_resets`, worked out from the gaps.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ConfigOverrides {
+  #[serde(default, deserialize_with = "from_str")]
+  pub output_format: Option<OutputFormat>,
+  pub sample_rate: Option<u32>,
+  pub resample: Option<bool>,
+  pub interfile_gap_ms: Option<usize>,
+  pub reset_at_gaps: Option<bool>,
+  pub fade_in_ms: Option<usize>,
+  pub fade_out_ms: Option<usize>,
+  #[serde(default, deserialize_with = "from_str")]
+  pub fade_curve: Option<FadeCurve>,
+  pub trim_silence: Option<bool>,
+  pub trim_threshold_dbfs: Option<f64>,
+  pub trim_min_run_ms: Option<usize>,
+  pub trim_pre_roll_ms: Option<usize>,
+  pub parallel: Option<bool>,
+  pub seamless_loop: Option<bool>,
+  #[serde(default, deserialize_with = "or_none")]
+  pub dither_seed: Option<Option<u64>>,
+  pub apcm_unknown: Option<i32>,
+  pub comm_sample_size: Option<i16>,
+  #[serde(default, deserialize_with = "or_none")]
+  pub comm_sample_frames: Option<Option<u32>>,
+  #[serde(default, deserialize_with = "or_none")]
+  pub max_sectors: Option<Option<usize>>,
+  #[serde(default, deserialize_with = "from_str")]
+  pub tail_mode: Option<TailMode>,
+  pub peak_headroom_pct: Option<u32>,
+  #[serde(default, deserialize_with = "from_str")]
+  pub sector_padding: Option<SectorPadding>,
+  #[serde(default, deserialize_with = "from_str")]
+  pub sound_group_layout: Option<SoundGroupLayout>,
+  pub gain_db: Option<f64>,
+  pub pre_emphasis_db: Option<f64>,
+  pub pre_emphasis_hz: Option<f64>,
+  #[serde(default, deserialize_with = "or_none")]
+  pub soft_clip_dbfs: Option<Option<f64>>,
+  #[serde(default, deserialize_with = "from_str")]
+  pub allowed_filters: Option<FilterSet>,
+  #[serde(default, deserialize_with = "or_none")]
+  pub max_clipped_samples: Option<Option<usize>>,
+  #[serde(default, deserialize_with = "from_str")]
+  pub rounding: Option<RoundingMode>,
+}
+
+/// Reads a setting from a string with its `FromStr` implementation.
+fn from_str<'de, D: Deserializer<'de>, T: FromStr<Err: fmt::Display>>(deserializer: D) -> Result<Option<T>, D::Error> {
+  let s = String::deserialize(deserializer)?;
+  s.parse().map(Some).map_err(de::Error::custom)
+}
+
+/// Reads a setting that can be left unset, where `"none"` unsets it.
+fn or_none<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum ValueOrNone<T> {
+    Value(T),
+    Word(String),
+  }
+
+  match ValueOrNone::deserialize(deserializer)? {
+    ValueOrNone::Value(value) => Ok(Some(Some(value))),
+    ValueOrNone::Word(word) if word == "none" => Ok(Some(None)),
+    ValueOrNone::Word(word) => Err(de::Error::custom(format!("Unknown value `{}`, expected a value or `none`", word))),
+  }
+}
+
+impl ConfigOverrides {
+  /// `global` with every setting this entry gives replaced, checked as a whole.
+  pub fn apply(&self, global: &EncoderConfig) -> Result<EncoderConfig> {
+    let mut config = global.clone();
+    macro_rules! apply {
+      ($($field:ident),*) => {
+        $(if let Some(value) = self.$field { config.$field = value; })*
+      };
+    }
+    apply!(
+      output_format, sample_rate, resample, interfile_gap_ms, reset_at_gaps, fade_in_ms, fade_out_ms,
+      fade_curve, trim_silence, trim_threshold_dbfs, trim_min_run_ms, trim_pre_roll_ms, parallel,
+      seamless_loop, dither_seed, apcm_unknown, comm_sample_size, comm_sample_frames, max_sectors,
+      tail_mode, peak_headroom_pct, sector_padding, sound_group_layout, gain_db,
+      pre_emphasis_db, pre_emphasis_hz, soft_clip_dbfs, allowed_filters, max_clipped_samples, rounding
+    );
+
+    config.validate()?;
+    Ok(config)
+  }
+}
+
+impl Manifest {
+  pub fn parse(text: &str) -> Result<Self> {
+    let manifest: Manifest = toml::from_str(text)?;
+
+    for (n, entry) in manifest.entries.iter().enumerate() {
+      if entry.inputs.is_empty() {
+        return Err(anyhow!("Manifest entry {} has no inputs", n))
+      }
+    }
+
+    Ok(manifest)
+  }
+
+  /// Reads a manifest file, resolving relative input and output paths against its directory.
+  pub fn from_path(path: &Path) -> Result<Self> {
+    let text = fs::read_to_string(path)
+      .with_context(|| format!("Failed to read manifest {}", path.to_string_lossy()))?;
+    let mut manifest = Manifest::parse(&text)
+      .with_context(|| format!("Invalid manifest {}", path.to_string_lossy()))?;
+
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+    for entry in manifest.entries.iter_mut() {
+      for input in entry.inputs.iter_mut() {
+        *input = base_dir.join(&*input);
+      }
+      if let Some(output) = entry.output.as_mut() {
+        *output = base_dir.join(&*output);
+      }
+    }
+
+    Ok(manifest)
+  }
+}
+
+impl ManifestEntry {
+  /// Output path for entry `n` of the manifest, falling back to `template` in `base_dir`.
+  pub fn output_path(&self, base_dir: &Path, template: &NameTemplate, n: usize) -> Result<PathBuf> {
+    if let Some(output) = &self.output {
+      return Ok(output.clone())
+    }
+
+    let fields = NameFields { index: self.index, first_input: &self.inputs[0], n };
+    let name = template.render(fields)
+      .with_context(|| format!("Can't name the output of manifest entry {}", n))?;
+    Ok(base_dir.join(name))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::naming::DEFAULT_NAME_TEMPLATE;
+  use adpcm2aiff::{
+    input::{prep_input_reader, ClipProcessing},
+    wav::write_wav,
+  };
+  use std::io::Read;
+
+  fn default_template() -> NameTemplate {
+    DEFAULT_NAME_TEMPLATE.parse().unwrap()
+  }
+
+  #[test]
+  fn parse_entries() {
+    let manifest = Manifest::parse(r#"
+      [[entry]]
+      index = 0x0F
+      inputs = ["a.wav", "b.wav"]
+      output = "CP1_0015.ACM"
+
+      [[entry]]
+      index = 0x10
+      inputs = ["c.wav"]
+    "#).unwrap();
+
+    assert_eq!(manifest, Manifest {
+      entries: vec![
+        ManifestEntry {
+          index: Some(0x0F),
+          inputs: vec![PathBuf::from("a.wav"), PathBuf::from("b.wav")],
+          output: Some(PathBuf::from("CP1_0015.ACM")),
+          config: ConfigOverrides::default(),
+        },
+        ManifestEntry {
+          index: Some(0x10),
+          inputs: vec![PathBuf::from("c.wav")],
+          output: None,
+          config: ConfigOverrides::default(),
+        },
+      ],
+    });
+    assert_eq!(manifest.entries[1].output_path(Path::new("out"), &default_template(), 1).unwrap(), PathBuf::from("out/CP1_0016.ACM"));
+  }
+
+  #[test]
+  fn parse_sample_manifest() {
+    let manifest = Manifest::parse(include_str!("../manifests/tactics_ogre_cp1.toml")).unwrap();
+
+    assert_eq!(manifest.entries.len(), 43);
+    let entry = &manifest.entries[0x0E];
+    assert_eq!(entry.index, Some(0x0F));
+    assert_eq!(entry.inputs.len(), 2);
+    assert_eq!(entry.output_path(Path::new(""), &default_template(), 0x0E).unwrap(), PathBuf::from("CP1_0015.ACM"));
+  }
+
+  #[test]
+  fn template_names_entries_without_an_output() {
+    let manifest = Manifest::parse(r#"
+      [[entry]]
+      inputs = ["music/intro.wav"]
+
+      [[entry]]
+      inputs = ["music/battle.wav"]
+    "#).unwrap();
+
+    let template: NameTemplate = "{n:02}_{stem}.ACM".parse().unwrap();
+    assert_eq!(manifest.entries[1].output_path(Path::new("out"), &template, 1).unwrap(), PathBuf::from("out/01_battle.ACM"));
+    assert!(manifest.entries[0].output_path(Path::new("out"), &default_template(), 0).is_err());
+  }
+
+  #[test]
+  fn reject_entry_without_inputs() {
+    assert!(Manifest::parse("[[entry]]\nindex = 1\ninputs = []").is_err());
+  }
+
+  #[test]
+  fn entry_settings_override_the_global_config() {
+    let manifest = Manifest::parse(r#"
+      [[entry]]
+      inputs = ["hot.wav"]
+      config = { gain_db = -2.0, fade_curve = "cosine" }
+
+      [[entry]]
+      inputs = ["quiet.wav"]
+    "#).unwrap();
+
+    let global = EncoderConfig::builder().gain_db(3.0).fade_out_ms(250).build().unwrap();
+    let hot = manifest.entries[0].config.apply(&global).unwrap();
+    assert_eq!((hot.gain_db, hot.fade_curve, hot.fade_out_ms), (-2.0, FadeCurve::RaisedCosine, 250));
+    assert_eq!(manifest.entries[1].config.apply(&global).unwrap(), global);
+
+    // Each entry's own gain reaches its audio
+    let dir = tempfile::tempdir().unwrap();
+    let wav = dir.path().join("in.wav");
+    write_wav(&[10_000; 1000], 18900, &mut fs::File::create(&wav).unwrap()).unwrap();
+    let early_sample = |config: &EncoderConfig| {
+      let (_, mut rdr, _) = prep_input_reader(vec![wav.clone()], 0, ClipProcessing::from_config(config)).unwrap();
+      let mut pcm = Vec::new();
+      rdr.read_to_end(&mut pcm).unwrap();
+      i16::from_le_bytes([pcm[200], pcm[201]])
+    };
+    assert_eq!(early_sample(&hot), 7943);
+    assert_eq!(early_sample(&global), 14_125);
+  }
+
+  #[test]
+  fn entry_can_override_every_setting_not_set_by_the_command_line() {
+    let manifest = Manifest::parse(r#"
+      [[entry]]
+      inputs = ["a.wav"]
+
+      [entry.config]
+      output_format = "xa"
+      sample_rate = 18900
+      resample = true
+      interfile_gap_ms = 300
+      reset_at_gaps = false
+      fade_in_ms = 10
+      fade_out_ms = 20
+      fade_curve = "cosine"
+      trim_silence = true
+      trim_threshold_dbfs = -60.0
+      trim_min_run_ms = 50
+      trim_pre_roll_ms = 5
+      parallel = false
+      seamless_loop = true
+      dither_seed = "none"
+      apcm_unknown = 7
+      comm_sample_size = 16
+      comm_sample_frames = 1000
+      max_sectors = 12
+      tail_mode = "hold"
+      peak_headroom_pct = 10
+      sector_padding = "bare"
+      sound_group_layout = "repeated"
+      gain_db = -1.0
+      pre_emphasis_db = 2.0
+      pre_emphasis_hz = 3000.0
+      soft_clip_dbfs = -3.0
+      allowed_filters = "0,1"
+      max_clipped_samples = 5
+      rounding = "truncate"
+    "#).unwrap();
+
+    let global = EncoderConfig {
+      output_format: OutputFormat::Spu,
+      reset_at_gaps: true,
+      parallel: true,
+      dither_seed: Some(1),
+      ..EncoderConfig::default()
+    };
+    // No `..` here: a new `EncoderConfig` field doesn't compile until it's either an entry setting
+    // checked below or listed on `ConfigOverrides` as command line only
+    let EncoderConfig {
+      output_format, sample_rate, resample, interfile_gap_ms, stereo_channel: _, reset_at_gaps,
+      fade_in_ms, fade_out_ms, fade_curve, trim_silence, trim_threshold_dbfs, trim_min_run_ms,
+      trim_pre_roll_ms, parallel, stats: _, snr_weighting: _, loop_region: _, seamless_loop,
+      dither_seed, apcm_unknown, comm_sample_size, comm_sample_frames, reference_header: _,
+      max_sectors, hash_output: _, tail_mode, peak_headroom_pct, sector_padding, sound_group_layout,
+      gain_db, pre_emphasis_db, pre_emphasis_hz, soft_clip_dbfs, allowed_filters, max_clipped_samples,
+      force_params: _, rounding, filter_tables: _, state_resets: _,
+    } = manifest.entries[0].config.apply(&global).unwrap();
+
+    assert_eq!(output_format, OutputFormat::Xa);
+    assert_eq!((sample_rate, resample, interfile_gap_ms, reset_at_gaps), (18900, true, 300, false));
+    assert_eq!((fade_in_ms, fade_out_ms, fade_curve), (10, 20, FadeCurve::RaisedCosine));
+    assert_eq!((trim_silence, trim_threshold_dbfs, trim_min_run_ms, trim_pre_roll_ms), (true, -60.0, 50, 5));
+    assert_eq!((parallel, seamless_loop, dither_seed), (false, true, None));
+    assert_eq!((apcm_unknown, comm_sample_size, comm_sample_frames, max_sectors), (7, 16, Some(1000), Some(12)));
+    assert_eq!((tail_mode, peak_headroom_pct), (TailMode::Hold, 10));
+    assert_eq!((sector_padding, sound_group_layout), (SectorPadding::Bare, SoundGroupLayout::Repeated));
+    assert_eq!((gain_db, pre_emphasis_db, pre_emphasis_hz, soft_clip_dbfs), (-1.0, 2.0, 3000.0, Some(-3.0)));
+    assert_eq!((allowed_filters, max_clipped_samples, rounding), ("0,1".parse().unwrap(), Some(5), RoundingMode::Truncate));
+  }
+
+  #[test]
+  fn reject_bad_entry_settings() {
+    assert!(Manifest::parse("[[entry]]\ninputs = [\"a.wav\"]\nconfig = { gain = 2.0 }").is_err());
+    assert!(Manifest::parse("[[entry]]\ninputs = [\"a.wav\"]\nconfig = { tail_mode = \"loud\" }").is_err());
+    assert!(Manifest::parse("[[entry]]\ninputs = [\"a.wav\"]\nconfig = { max_sectors = \"all\" }").is_err());
+
+    let manifest = Manifest::parse("[[entry]]\ninputs = [\"a.wav\"]\nconfig = { sample_rate = 44100 }").unwrap();
+    assert!(manifest.entries[0].config.apply(&EncoderConfig::default()).is_err());
+  }
+}
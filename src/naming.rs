@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Result};
+use std::{path::Path, str::FromStr};
+
+/// Default `--name-template`: the game's `CP1_{index:04}.ACM` naming, with the index printed in
+/// decimal.
+pub(crate) const DEFAULT_NAME_TEMPLATE: &str = "CP1_{index:04}.ACM";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+  Literal(String),
+  Index { width: usize },
+  Stem,
+  N { width: usize },
+}
+
+/// Output file name with placeholders, filled in for each output of a batch.
+///
+/// `{index}` is the output's index, `{stem}` the file name of its first input without the
+/// extension, and `{n}` its position in the batch, counting from 0. The numbers take a zero-padded
+/// width as in `format!`, e.g. `{index:04}`. `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NameTemplate {
+  parts: Vec<Part>,
+}
+
+/// Values substituted into a `NameTemplate`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct NameFields<'a> {
+  pub index: Option<u8>,
+  pub first_input: &'a Path,
+  pub n: usize,
+}
+
+/// Parses the width in a `{name:0W}` placeholder.
+fn parse_width(name: &str, spec: Option<&str>) -> Result<usize> {
+  let Some(spec) = spec else {
+    return Ok(0)
+  };
+
+  spec.strip_prefix('0')
+    .filter(|digits| !digits.is_empty())
+    .and_then(|digits| digits.parse().ok())
+    .ok_or_else(|| anyhow!("Unsupported format `{}` for `{{{}}}`, expected a zero-padded width like `04`", spec, name))
+}
+
+impl FromStr for NameTemplate {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+      match c {
+        '{' if chars.as_str().starts_with('{') => {
+          chars.next();
+          literal.push('{');
+        },
+        '}' if chars.as_str().starts_with('}') => {
+          chars.next();
+          literal.push('}');
+        },
+        '{' => {
+          let (placeholder, rest) = chars.as_str().split_once('}')
+            .ok_or_else(|| anyhow!("Unclosed `{{` in name template `{}`", s))?;
+          let (name, spec) = match placeholder.split_once(':') {
+            Some((name, spec)) => (name, Some(spec)),
+            None => (placeholder, None),
+          };
+          let part = match name {
+            "index" => Part::Index { width: parse_width(name, spec)? },
+            "n" => Part::N { width: parse_width(name, spec)? },
+            "stem" if spec.is_none() => Part::Stem,
+            "stem" => return Err(anyhow!("`{{stem}}` doesn't take a format")),
+            _ => return Err(anyhow!(
+              "Unknown placeholder `{{{}}}` in name template `{}`, expected `{{index}}`, `{{stem}}` or `{{n}}`",
+              placeholder, s
+            )),
+          };
+
+          if !literal.is_empty() {
+            parts.push(Part::Literal(std::mem::take(&mut literal)));
+          }
+          parts.push(part);
+          chars = rest.chars();
+        },
+        '}' => return Err(anyhow!("Unmatched `}}` in name template `{}`, use `}}}}` for a literal brace", s)),
+        c => literal.push(c),
+      }
+    }
+    if !literal.is_empty() {
+      parts.push(Part::Literal(literal));
+    }
+
+    Ok(NameTemplate { parts })
+  }
+}
+
+impl NameTemplate {
+  pub fn render(&self, fields: NameFields) -> Result<String> {
+    let mut name = String::new();
+
+    for part in &self.parts {
+      match part {
+        Part::Literal(text) => name.push_str(text),
+        Part::Index { width } => {
+          let index = fields.index
+            .ok_or_else(|| anyhow!("The name template uses `{{index}}`, but no index was given"))?;
+          name.push_str(&format!("{:0width$}", index, width = width));
+        },
+        Part::Stem => {
+          let stem = fields.first_input.file_stem()
+            .ok_or_else(|| anyhow!("{} has no file name for `{{stem}}`", fields.first_input.to_string_lossy()))?;
+          name.push_str(&stem.to_string_lossy());
+        },
+        Part::N { width } => name.push_str(&format!("{:0width$}", fields.n, width = width)),
+      }
+    }
+
+    Ok(name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn render(template: &str, index: Option<u8>, first_input: &str, n: usize) -> Result<String> {
+    template.parse::<NameTemplate>()?.render(NameFields { index, first_input: Path::new(first_input), n })
+  }
+
+  #[test]
+  fn default_template_matches_game_naming() {
+    assert_eq!(render(DEFAULT_NAME_TEMPLATE, Some(0x0F), "a.wav", 3).unwrap(), "CP1_0015.ACM");
+    assert_eq!(render(DEFAULT_NAME_TEMPLATE, Some(0xFF), "a.wav", 3).unwrap(), "CP1_0255.ACM");
+  }
+
+  #[test]
+  fn substitutes_every_placeholder() {
+    assert_eq!(render("{index}", Some(7), "a.wav", 0).unwrap(), "7");
+    assert_eq!(render("{stem}.acm", None, "music/intro.loop.wav", 0).unwrap(), "intro.loop.acm");
+    assert_eq!(render("{n:03}_{stem}_{index:02}", Some(5), "x/y.wav", 12).unwrap(), "012_y_05");
+    assert_eq!(render("{{{n}}}", None, "a.wav", 4).unwrap(), "{4}");
+  }
+
+  #[test]
+  fn index_placeholder_needs_an_index() {
+    assert!(render("{index}.ACM", None, "a.wav", 0).is_err());
+    assert_eq!(render("{stem}.ACM", None, "a.wav", 0).unwrap(), "a.ACM");
+  }
+
+  #[test]
+  fn rejects_bad_templates() {
+    for template in ["{name}.ACM", "{index:4}", "{index:x}", "{stem:04}", "CP1_{index", "CP1}", "{}"] {
+      assert!(template.parse::<NameTemplate>().is_err(), "{}", template);
+    }
+  }
+}
@@ -0,0 +1,78 @@
+//! Conversions between sample positions, sector numbers and byte offsets in an APCM AIFF file.
+//!
+//! Sample and sector numbers count from the first sample of the original audio. Byte offsets count
+//! from the start of the file, so they take in the AIFF header and any blank sectors ahead of the
+//! audio.
+
+use crate::{
+  adpcm_core::{ADPCM_SECTOR_SAMPLES, XA_ADPCM_SECTOR_SIZE},
+  aiff::APCM_AIFF_HEADER_SIZE,
+};
+
+/// Sector holding sample `n`.
+pub fn sample_to_sector(n: usize) -> usize {
+  n / ADPCM_SECTOR_SAMPLES
+}
+
+/// First sample held by sector `s`.
+pub fn sector_to_sample(s: usize) -> usize {
+  s * ADPCM_SECTOR_SAMPLES
+}
+
+/// Byte offset of sector `s` in a file of `sector_size`-byte sectors that starts with
+/// `leading_blank_sectors` blank sectors.
+pub fn sector_to_byte_offset(s: usize, sector_size: usize, leading_blank_sectors: usize) -> usize {
+  APCM_AIFF_HEADER_SIZE + (leading_blank_sectors + s) * sector_size
+}
+
+/// Sector holding the byte at `offset` in a file laid out as for `sector_to_byte_offset`, or `None`
+/// if the byte is part of the header or a leading blank sector.
+pub fn byte_offset_to_sector(offset: usize, sector_size: usize, leading_blank_sectors: usize) -> Option<usize> {
+  let file_sector = offset.checked_sub(APCM_AIFF_HEADER_SIZE)? / sector_size;
+  file_sector.checked_sub(leading_blank_sectors)
+}
+
+/// Byte offset of the sector holding sample `n`, in a file of the usual 0x914-byte sectors.
+pub fn sample_to_byte_offset(n: usize, leading_blank_sectors: usize) -> usize {
+  sector_to_byte_offset(sample_to_sector(n), XA_ADPCM_SECTOR_SIZE, leading_blank_sectors)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::adpcm_core::XA_ADPCM_SECTOR_DATA_SIZE;
+
+  #[test]
+  fn first_samples_of_sectors() {
+    for s in 0..4 {
+      let first = s * 4032;
+      assert_eq!(sector_to_sample(s), first);
+      assert_eq!(sample_to_sector(first), s);
+      assert_eq!(sample_to_sector(first + 4031), s);
+      if s > 0 {
+        assert_eq!(sample_to_sector(first - 1), s - 1);
+      }
+    }
+  }
+
+  #[test]
+  fn byte_offsets_start_after_the_header() {
+    assert_eq!(APCM_AIFF_HEADER_SIZE, 54);
+    assert_eq!(sector_to_byte_offset(0, XA_ADPCM_SECTOR_SIZE, 0), 54);
+    assert_eq!(sector_to_byte_offset(2, XA_ADPCM_SECTOR_SIZE, 0), 54 + 2 * 0x914);
+    assert_eq!(sector_to_byte_offset(2, XA_ADPCM_SECTOR_DATA_SIZE, 3), 54 + 5 * 0x900);
+    assert_eq!(sample_to_byte_offset(4032, 1), 54 + 2 * 0x914);
+  }
+
+  #[test]
+  fn byte_offsets_map_back_to_sectors() {
+    for s in 0..4 {
+      let offset = sector_to_byte_offset(s, XA_ADPCM_SECTOR_SIZE, 2);
+      assert_eq!(byte_offset_to_sector(offset, XA_ADPCM_SECTOR_SIZE, 2), Some(s));
+      assert_eq!(byte_offset_to_sector(offset + XA_ADPCM_SECTOR_SIZE - 1, XA_ADPCM_SECTOR_SIZE, 2), Some(s));
+    }
+    assert_eq!(byte_offset_to_sector(53, XA_ADPCM_SECTOR_SIZE, 0), None);
+    assert_eq!(byte_offset_to_sector(54, XA_ADPCM_SECTOR_SIZE, 1), None);
+    assert_eq!(byte_offset_to_sector(54 + 0x914, XA_ADPCM_SECTOR_SIZE, 1), Some(0));
+  }
+}
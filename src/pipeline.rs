@@ -0,0 +1,556 @@
+use anyhow::{anyhow, Context, Result};
+use log::{debug, trace};
+use std::{
+  fmt,
+  fs,
+  io::{BufWriter, Read, Write},
+  path::Path,
+};
+
+use crate::{
+  adpcm_encoder::{
+    encode_spu_adpcm,
+    encode_xa_adpcm_looped,
+    encode_xa_adpcm_parallel,
+    encode_xa_adpcm_with_progress,
+    encode_xa_adpcm_with_weighted_stats,
+    sectors_for_samples,
+    OutputFormat,
+    ADPCM_SECTOR_SAMPLES,
+    SOUND_UNIT_SAMPLES,
+    SPU_ADPCM_BLOCK_SIZE,
+  },
+  aiff::{apcm_aiff_headers, decode_apcm_aiff, max_apcm_sectors, write_apcm_aiff_headers, APCMChunk, CommonChunk, ReferenceHeader, AIFF, APCM_AIFF_HEADER_SIZE},
+  checksum::HashingWriter,
+  config::EncoderConfig,
+  error::XaError,
+  input::{loop_region_for, prep_input_reader, ClipProcessing},
+  offsets::{sector_to_byte_offset, sector_to_sample},
+  stats::{aligned_snr_db, find_offset, EncodeStats},
+};
+
+/// Encodes `samples_count` samples from `input` to a complete output file as described by
+/// `config`: an APCM AIFF header followed by XA sectors, or a flat stream of SPU blocks.
+///
+/// `sector_snr_db` in the returned stats is only filled in when `config.stats` is set. With
+/// `config.max_clipped_samples` set, clipping more samples than that is an error, reported once the
+/// whole output has been written.
+///
+/// Zero samples encode to a valid, empty output: XA output is just the headers, declaring no sample
+/// frames and an APCM chunk with no sectors, and SPU output has no blocks at all.
+pub fn encode<R: Read, W: Write>(config: &EncoderConfig, samples_count: usize, input: &mut R, output: &mut W) -> Result<EncodeStats> {
+  encode_with_progress(config, samples_count, input, output, &mut |_, _| {})
+}
+
+/// Encodes the audio file `input` to the file `output` in one call, with the same input handling
+/// as the command-line tool: a WAV file, or any format `symphonia` can decode when built with it,
+/// resampled, trimmed, faded and pre-emphasized as `config` says.
+///
+/// `output`'s extension picks the format when it names one (see `OutputFormat::from_extension`),
+/// overriding `config.output_format`.
+pub fn encode_file_to_file(input: impl AsRef<Path>, output: impl AsRef<Path>, config: &EncoderConfig) -> Result<EncodeStats> {
+  let (input, output) = (input.as_ref(), output.as_ref());
+  let mut config = config.clone();
+  if let Some(output_format) = OutputFormat::from_extension(output) {
+    config.output_format = output_format;
+  }
+
+  let inputs = vec![input.to_path_buf()];
+  let clip = ClipProcessing::from_config(&config);
+  let (samples_count, mut rdr, _) = prep_input_reader(inputs.clone(), config.interfile_gap_ms, clip)?;
+  config.loop_region = loop_region_for(&config, &inputs)?;
+
+  let file = fs::File::create(output)
+    .with_context(|| format!("Failed to create {}", output.to_string_lossy()))?;
+  let mut wtr = BufWriter::new(file);
+  let stats = encode(&config, samples_count, &mut rdr, &mut wtr)?;
+  wtr.flush()?;
+
+  Ok(stats)
+}
+
+/// APCM AIFF headers `encode` writes ahead of `samples_count` samples of XA sectors, with COMM
+/// declaring `config.comm_sample_frames` frames when that's set. With `config.reference_header`,
+/// they're the reference's headers.
+pub fn xa_headers(config: &EncoderConfig, samples_count: usize) -> Result<(AIFF, CommonChunk, APCMChunk)> {
+  check_sector_limit(config, samples_count)?;
+  if let Some(reference) = &config.reference_header {
+    check_reference_sectors(reference, samples_count)?;
+    return Ok(reference.headers()?)
+  }
+  let (aiff, mut comm, apcm) = apcm_aiff_headers(
+    samples_count, config.sample_rate, config.comm_sample_size, config.sector_padding.sector_size(), config.apcm_unknown,
+  )?;
+  if let Some(num_sample_frames) = config.comm_sample_frames {
+    comm.set_num_sample_frames(num_sample_frames);
+  }
+
+  Ok((aiff, comm, apcm))
+}
+
+/// Fails when `samples_count` samples need more sectors than `config.max_sectors`, with how far over
+/// the limit they are.
+fn check_sector_limit(config: &EncoderConfig, samples_count: usize) -> Result<(), XaError> {
+  let Some(max_sectors) = config.max_sectors else {
+    return Ok(())
+  };
+  let num_sectors = sectors_for_samples(samples_count);
+  if num_sectors > max_sectors {
+    // Only the samples past the last whole sector allowed need to go
+    let over_samples = samples_count - sector_to_sample(max_sectors);
+    let over_secs = over_samples as f64 / f64::from(config.sample_rate);
+    return Err(XaError::SectorLimitExceeded { num_sectors, max_sectors, over_secs })
+  }
+
+  Ok(())
+}
+
+/// Fails unless `samples_count` samples fill exactly the sectors `reference` declares.
+fn check_reference_sectors(reference: &ReferenceHeader, samples_count: usize) -> Result<()> {
+  let num_sectors = sectors_for_samples(samples_count);
+  if num_sectors != reference.num_sectors() {
+    return Err(anyhow!(
+      "The reference header declares {} sectors, but the input makes {}", reference.num_sectors(), num_sectors
+    ))
+  }
+
+  Ok(())
+}
+
+/// Size in bytes of the file `encode` writes for `samples_count` samples, without encoding
+/// anything. Fails, as `encode` would, when XA output needs more sectors than an APCM AIFF file can
+/// hold, or than `config.max_sectors`, or doesn't fit `config.reference_header`.
+pub fn output_size(config: &EncoderConfig, samples_count: usize) -> Result<usize> {
+  match config.output_format {
+    OutputFormat::Spu => Ok(samples_count.div_ceil(SOUND_UNIT_SAMPLES) * SPU_ADPCM_BLOCK_SIZE),
+    OutputFormat::Xa => {
+      let sector_size = config.sector_padding.sector_size();
+      let num_sectors = sectors_for_samples(samples_count);
+      let max_sectors = max_apcm_sectors(sector_size);
+      if num_sectors > max_sectors {
+        return Err(XaError::OutputTooLarge { num_sectors, max_sectors }.into())
+      }
+      check_sector_limit(config, samples_count)?;
+      if let Some(reference) = &config.reference_header {
+        check_reference_sectors(reference, samples_count)?;
+      }
+
+      Ok(sector_to_byte_offset(num_sectors, sector_size, 0))
+    },
+  }
+}
+
+/// Encodes like `encode`, calling `progress(done, total)` as output is written. Progress is counted
+/// in sectors for XA output and in blocks for SPU output, and is reported at least once.
+pub fn encode_with_progress<R: Read, W: Write>(
+  config: &EncoderConfig,
+  samples_count: usize,
+  input: &mut R,
+  output: &mut W,
+  progress: &mut dyn FnMut(usize, usize),
+) -> Result<EncodeStats> {
+  config.validate()?;
+
+  if config.hash_output {
+    let mut wtr = HashingWriter::new(output);
+    let mut stats = encode_unhashed(config, samples_count, input, &mut wtr, progress)?;
+    stats.output_hash = Some(wtr.finish().1);
+    return Ok(stats)
+  }
+  encode_unhashed(config, samples_count, input, output, progress)
+}
+
+/// Body of `encode_with_progress`, once the config is checked and the output wrapped for hashing.
+fn encode_unhashed<R: Read, W: Write>(
+  config: &EncoderConfig,
+  samples_count: usize,
+  input: &mut R,
+  output: &mut W,
+  progress: &mut dyn FnMut(usize, usize),
+) -> Result<EncodeStats> {
+  debug!("encoding {} samples as {:?} at {} Hz", samples_count, config.output_format, config.sample_rate);
+  let (mut stats, unit_samples) = match config.output_format {
+    OutputFormat::Spu => {
+      let stats = encode_spu_adpcm(samples_count, config.loop_region, config.encode_options(), input, output, progress)?;
+      (stats, SOUND_UNIT_SAMPLES)
+    },
+    OutputFormat::Xa => {
+      let (aiff, comm, apcm) = xa_headers(config, samples_count)?;
+      trace!("writing headers\n{}\n{}\n{}", aiff, comm, apcm);
+      match &config.reference_header {
+        Some(reference) => output.write_all(reference.as_bytes())?,
+        None => write_apcm_aiff_headers(&aiff, &comm, &apcm, output)?,
+      }
+      let stats = if config.parallel {
+        encode_xa_adpcm_parallel(samples_count, config.encode_options(), input, output, progress)?
+      } else if config.seamless_loop {
+        encode_xa_adpcm_looped(samples_count, config.encode_options(), input, output, progress)?
+      } else if config.stats {
+        encode_xa_adpcm_with_weighted_stats(samples_count, config.encode_options(), config.snr_weighting, config.sample_rate, input, output, progress)?
+      } else {
+        encode_xa_adpcm_with_progress(samples_count, config.encode_options(), input, output, progress)?
+      };
+      (stats, ADPCM_SECTOR_SAMPLES)
+    },
+  };
+
+  // The final sector or block is always padded out; anything beyond that is input that was missing
+  let expected_padding = samples_count.div_ceil(unit_samples) * unit_samples - samples_count;
+  stats.missing_samples = stats.padding_samples.saturating_sub(expected_padding);
+  stats.output_format = config.output_format;
+  stats.sectors_written = samples_count.div_ceil(unit_samples);
+  stats.samples_count = samples_count;
+  stats.sample_rate = config.sample_rate;
+
+  // The header was written from the sample count, so the data that followed has to agree with it
+  let header_size = if config.output_format == OutputFormat::Xa { APCM_AIFF_HEADER_SIZE } else { 0 };
+  let expected_bytes = output_size(config, samples_count)? - header_size;
+  if stats.bytes_written != expected_bytes {
+    return Err(anyhow!("Wrote {} bytes of encoded data, but the output size says {}", stats.bytes_written, expected_bytes))
+  }
+
+  if let Some(max_clipped_samples) = config.max_clipped_samples {
+    if stats.clipped_samples > max_clipped_samples {
+      return Err(anyhow!("{} samples clipped, more than the {} allowed", stats.clipped_samples, max_clipped_samples))
+    }
+  }
+
+  Ok(stats)
+}
+
+/// How a decoded encode lines up with the input it was encoded from, and how close it came.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Verification {
+  /// Decoded samples ahead of the input's first sample, such as blank sectors another tool added
+  pub offset_samples: usize,
+  /// Decoded samples past the end of the input
+  pub padding_samples: usize,
+  pub snr_db: f64,
+  /// Samples the SNR was measured over
+  pub compared: usize,
+}
+
+impl fmt::Display for Verification {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.offset_samples.is_multiple_of(ADPCM_SECTOR_SAMPLES) {
+      write!(f, "offset {} sectors", self.offset_samples / ADPCM_SECTOR_SAMPLES)?;
+    } else {
+      write!(f, "offset {} samples", self.offset_samples)?;
+    }
+    write!(
+      f, ", {} padding samples, {:.2} dB SNR over {} samples",
+      self.padding_samples, self.snr_db, self.compared
+    )
+  }
+}
+
+/// Decodes `encoded`, a complete APCM AIFF file, finds where `original` starts in it with
+/// `find_offset`, and measures the SNR from there. Fails if the SNR is below `min_snr_db`.
+///
+/// `original` should be the samples as the encoder read them, after any preprocessing.
+pub fn verify_encoded(original: &[i16], encoded: &[u8], min_snr_db: f64) -> Result<Verification> {
+  let decoded = decode_apcm_aiff(&mut &encoded[..], false)?.samples;
+
+  let offset_samples = find_offset(original, &decoded, decoded.len().saturating_sub(original.len()));
+  let (snr_db, compared) = aligned_snr_db(original, &decoded, offset_samples);
+  let verification = Verification {
+    offset_samples,
+    padding_samples: decoded.len().saturating_sub(offset_samples + original.len()),
+    snr_db,
+    compared,
+  };
+  if snr_db < min_snr_db {
+    return Err(anyhow!("SNR {:.2} dB is below the minimum of {} dB ({})", snr_db, min_snr_db, verification))
+  }
+
+  Ok(verification)
+}
+
+/// Encodes like `encode`, but to memory first, then decodes the result and checks it against the
+/// input with `verify_encoded`. `output` is only written once the check passes. XA output only.
+pub fn encode_and_verify<R: Read, W: Write>(
+  config: &EncoderConfig,
+  samples_count: usize,
+  input: &mut R,
+  output: &mut W,
+  min_snr_db: f64,
+) -> Result<(EncodeStats, Verification)> {
+  if config.output_format != OutputFormat::Xa {
+    return Err(anyhow!("Only XA output can be verified"))
+  }
+
+  let mut pcm = Vec::with_capacity(samples_count * 2);
+  input.take(samples_count as u64 * 2).read_to_end(&mut pcm)?;
+  let original: Vec<i16> = pcm.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+
+  let mut encoded = Vec::new();
+  let stats = encode(config, samples_count, &mut pcm.as_slice(), &mut encoded)?;
+  let verification = verify_encoded(&original, &encoded, min_snr_db)?;
+  output.write_all(&encoded)?;
+
+  Ok((stats, verification))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io;
+  use crate::{
+    adpcm_encoder::{encode_xa_adpcm, SectorPadding, XA_ADPCM_SECTOR_DATA_SIZE, XA_ADPCM_SECTOR_SIZE},
+    aiff::{decode_apcm_aiff, read_apcm_aiff_headers, write_apcm_aiff_header, HeaderReport, APCM_SAMPLE_SIZE},
+    wav::write_wav,
+  };
+
+  #[test]
+  fn comm_sample_frames_override_is_written_verbatim() {
+    let pcm = test_pcm(5000);
+    let mut computed = Vec::new();
+    encode(&EncoderConfig::default(), 5000, &mut pcm.as_slice(), &mut computed).unwrap();
+
+    let config = EncoderConfig::builder().comm_sample_frames(Some(0x0001_2345)).build().unwrap();
+    let mut overridden = Vec::new();
+    encode(&config, 5000, &mut pcm.as_slice(), &mut overridden).unwrap();
+
+    // numSampleFrames follows COMM's header and channel count
+    let frames_at = 12 + 8 + 2;
+    assert_eq!(overridden[frames_at..frames_at + 4], [0x00, 0x01, 0x23, 0x45]);
+    assert_eq!(overridden[..frames_at], computed[..frames_at]);
+    assert_eq!(overridden[frames_at + 4..], computed[frames_at + 4..]);
+
+    let (_, comm, _) = read_apcm_aiff_headers(&mut overridden.as_slice()).unwrap();
+    assert_eq!(comm.num_sample_frames(), 0x0001_2345);
+    let (_, comm, _) = xa_headers(&config, 5000).unwrap();
+    assert_eq!(comm.num_sample_frames(), 0x0001_2345);
+  }
+
+  fn test_pcm(count: usize) -> Vec<u8> {
+    (0..count)
+      .map(|n| (((n as f64) * 0.05).sin() * 12_000.0) as i16)
+      .flat_map(|sample| sample.to_le_bytes())
+      .collect()
+  }
+
+  #[test]
+  fn default_config_matches_plain_encode() {
+    let pcm = test_pcm(5000);
+
+    let mut out = Vec::new();
+    let stats = encode(&EncoderConfig::default(), 5000, &mut pcm.as_slice(), &mut out).unwrap();
+
+    let mut expected = Vec::new();
+    write_apcm_aiff_header(5000, 18900, APCM_SAMPLE_SIZE, XA_ADPCM_SECTOR_SIZE, 0, &mut expected).unwrap();
+    encode_xa_adpcm(5000, &mut pcm.as_slice(), &mut expected).unwrap();
+
+    assert_eq!(out, expected);
+    assert_eq!(stats.padding_samples, 2 * ADPCM_SECTOR_SAMPLES - 5000);
+    assert_eq!(stats.missing_samples, 0);
+    assert!(stats.sector_snr_db.is_empty());
+  }
+
+  #[test]
+  fn output_size_matches_encode() {
+    let configs = [
+      EncoderConfig::default(),
+      EncoderConfig::builder().sector_padding(SectorPadding::Bare).build().unwrap(),
+      EncoderConfig::builder().output_format(OutputFormat::Spu).build().unwrap(),
+    ];
+    for config in configs {
+      for count in [0, 1, ADPCM_SECTOR_SAMPLES, ADPCM_SECTOR_SAMPLES + 1, 10_000] {
+        let mut out = Vec::new();
+        encode(&config, count, &mut test_pcm(count).as_slice(), &mut out).unwrap();
+        assert_eq!(output_size(&config, count).unwrap(), out.len(), "{:?}, {} samples", config.output_format, count);
+      }
+    }
+
+    let too_many = (max_apcm_sectors(XA_ADPCM_SECTOR_SIZE) + 1) * ADPCM_SECTOR_SAMPLES;
+    assert!(output_size(&EncoderConfig::default(), too_many).is_err());
+  }
+
+  #[test]
+  fn bare_sectors_shrink_output_by_the_pad() {
+    let pcm = test_pcm(10_000);
+
+    let mut padded = Vec::new();
+    encode(&EncoderConfig::default(), 10_000, &mut pcm.as_slice(), &mut padded).unwrap();
+    let mut bare = Vec::new();
+    let config = EncoderConfig::builder().sector_padding(SectorPadding::Bare).build().unwrap();
+    encode(&config, 10_000, &mut pcm.as_slice(), &mut bare).unwrap();
+
+    assert_eq!(padded.len() - bare.len(), 0x14 * 3);
+    let (_, _, apcm) = read_apcm_aiff_headers(&mut bare.as_slice()).unwrap();
+    assert_eq!(apcm.sector_size().unwrap(), XA_ADPCM_SECTOR_DATA_SIZE);
+    assert_eq!(apcm.num_sectors().unwrap(), 3);
+  }
+
+  #[test]
+  fn strict_clip_fails_past_the_limit() {
+    let pcm: Vec<u8> = (0..ADPCM_SECTOR_SAMPLES)
+      .map(|n| (((n as f64) * std::f64::consts::TAU * 50.0 / 18900.0).sin() * 32767.0) as i16)
+      .flat_map(|sample| sample.to_le_bytes())
+      .collect();
+
+    let stats = encode(&EncoderConfig::default(), ADPCM_SECTOR_SAMPLES, &mut pcm.as_slice(), &mut Vec::new()).unwrap();
+    assert!(stats.clipped_samples > 0);
+
+    let strict = |max| EncoderConfig::builder().max_clipped_samples(Some(max)).build().unwrap();
+    assert!(encode(&strict(stats.clipped_samples), ADPCM_SECTOR_SAMPLES, &mut pcm.as_slice(), &mut Vec::new()).is_ok());
+    assert!(encode(&strict(stats.clipped_samples - 1), ADPCM_SECTOR_SAMPLES, &mut pcm.as_slice(), &mut Vec::new()).is_err());
+  }
+
+  #[test]
+  fn zero_samples_give_headers_only() {
+    let configs = [
+      EncoderConfig::default(),
+      EncoderConfig::builder().stats(true).build().unwrap(),
+      EncoderConfig::builder().seamless_loop(true).build().unwrap(),
+      EncoderConfig::builder().parallel(true).build().unwrap(),
+      EncoderConfig::builder().sector_padding(SectorPadding::Bare).build().unwrap(),
+    ];
+    for config in configs {
+      let mut out = Vec::new();
+      let stats = encode(&config, 0, &mut io::empty(), &mut out).unwrap();
+      assert_eq!(out.len(), APCM_AIFF_HEADER_SIZE);
+      assert_eq!((stats.padding_samples, stats.missing_samples), (0, 0));
+
+      let (aiff, comm, apcm) = read_apcm_aiff_headers(&mut out.as_slice()).unwrap();
+      let report = HeaderReport::new(&aiff, &comm, &apcm, Some(out.len() as u64)).unwrap();
+      assert!(report.is_consistent(), "{:?}", report);
+      assert_eq!(report.num_sectors, 0);
+      assert_eq!(report.declared_sample_frames, 0);
+      assert!(decode_apcm_aiff(&mut out.as_slice(), false).unwrap().samples.is_empty());
+    }
+
+    let spu = EncoderConfig::builder().output_format(OutputFormat::Spu).build().unwrap();
+    let mut out = Vec::new();
+    encode(&spu, 0, &mut io::empty(), &mut out).unwrap();
+    assert!(out.is_empty());
+  }
+
+  #[test]
+  fn missing_samples_reported() {
+    let pcm = test_pcm(100);
+
+    let config = EncoderConfig::builder().output_format(OutputFormat::Spu).build().unwrap();
+    let stats = encode(&config, 150, &mut pcm.as_slice(), &mut Vec::new()).unwrap();
+
+    assert_eq!(stats.missing_samples, 50);
+  }
+
+  #[test]
+  fn encode_prints_nothing() {
+    // As in `aiff::tests::header_writer_prints_nothing`, the encode runs in a child run of this
+    // test with capturing off. No logger is installed there, so log records go nowhere
+    if std::env::var_os("ENCODE_CHILD").is_some() {
+      let dir = tempfile::tempdir().unwrap();
+      let wav = dir.path().join("in.wav");
+      write_wav(&vec![1000; 5000], 18900, &mut fs::File::create(&wav).unwrap()).unwrap();
+      log::set_max_level(log::LevelFilter::Trace);
+
+      println!("<<");
+      eprintln!("<<");
+      let (samples_count, mut rdr, _) = prep_input_reader(vec![wav.clone(), wav], 100, ClipProcessing::default()).unwrap();
+      encode(&EncoderConfig::default(), samples_count, &mut rdr, &mut Vec::new()).unwrap();
+      println!(">>");
+      eprintln!(">>");
+      return
+    }
+
+    let child = std::process::Command::new(std::env::current_exe().unwrap())
+      .args(["--exact", "pipeline::tests::encode_prints_nothing", "--nocapture", "--test-threads=1"])
+      .env("ENCODE_CHILD", "1")
+      .output()
+      .unwrap();
+    assert!(child.status.success());
+    assert!(String::from_utf8_lossy(&child.stdout).contains("<<\n>>"));
+    assert!(String::from_utf8_lossy(&child.stderr).contains("<<\n>>"));
+  }
+
+  #[test]
+  fn sector_limit_fails_long_inputs_before_writing() {
+    let config = EncoderConfig::builder().max_sectors(Some(2)).build().unwrap();
+    let pcm = test_pcm(2 * ADPCM_SECTOR_SAMPLES);
+    let mut out = Vec::new();
+    encode(&config, 2 * ADPCM_SECTOR_SAMPLES, &mut pcm.as_slice(), &mut out).unwrap();
+    assert_eq!(out.len(), output_size(&config, 2 * ADPCM_SECTOR_SAMPLES).unwrap());
+
+    // 2.5 sectors: one sector over, and half a sector of samples to trim
+    let too_long = 2 * ADPCM_SECTOR_SAMPLES + ADPCM_SECTOR_SAMPLES / 2;
+    let pcm = test_pcm(too_long);
+    let mut out = Vec::new();
+    let err = encode(&config, too_long, &mut pcm.as_slice(), &mut out).unwrap_err();
+    assert!(out.is_empty());
+    assert!(output_size(&config, too_long).is_err());
+    match err.downcast_ref::<XaError>() {
+      Some(&XaError::SectorLimitExceeded { num_sectors, max_sectors, over_secs }) => {
+        assert_eq!((num_sectors, max_sectors), (3, 2));
+        assert!((over_secs - 2016.0 / 18900.0).abs() < 1e-9);
+      },
+      other => panic!("unexpected error {:?}", other),
+    }
+    assert!(err.to_string().contains("1 more than the limit of 2; trim at least 0.11s"), "{}", err);
+  }
+
+  #[test]
+  fn verify_finds_leading_blank_sectors() {
+    let original: Vec<i16> = (0..10_000).map(|n| (((n as f64) * 0.05).sin() * 12_000.0) as i16).collect();
+    // Encoding silence from a fresh state gives all-zero sectors, as blank lead-in sectors are
+    let leading = 3 * ADPCM_SECTOR_SAMPLES;
+    let pcm: Vec<u8> = std::iter::repeat_n(0, leading).chain(original.iter().copied())
+      .flat_map(|sample: i16| sample.to_le_bytes())
+      .collect();
+    let mut encoded = Vec::new();
+    encode(&EncoderConfig::default(), leading + original.len(), &mut pcm.as_slice(), &mut encoded).unwrap();
+
+    let verification = verify_encoded(&original, &encoded, 20.0).unwrap();
+    assert_eq!(verification.offset_samples, leading);
+    assert_eq!((verification.padding_samples, verification.compared), (0, original.len()));
+    assert!(verification.to_string().starts_with("offset 3 sectors, 0 padding samples"), "{}", verification);
+
+    let err = verify_encoded(&original, &encoded, f64::INFINITY).unwrap_err();
+    assert!(err.to_string().contains("offset 3 sectors"), "{}", err);
+  }
+
+  #[test]
+  fn encode_and_verify_writes_the_checked_output() {
+    let pcm = test_pcm(5000);
+    let mut plain = Vec::new();
+    encode(&EncoderConfig::default(), 5000, &mut pcm.as_slice(), &mut plain).unwrap();
+
+    let mut out = Vec::new();
+    let (_, verification) = encode_and_verify(&EncoderConfig::default(), 5000, &mut pcm.as_slice(), &mut out, 20.0).unwrap();
+    assert_eq!(out, plain);
+    assert_eq!(verification.offset_samples, 0);
+    assert!(verification.snr_db > 20.0);
+
+    let mut out = Vec::new();
+    assert!(encode_and_verify(&EncoderConfig::default(), 5000, &mut pcm.as_slice(), &mut out, f64::INFINITY).is_err());
+    assert!(out.is_empty());
+  }
+
+  #[test]
+  fn reference_header_is_copied_byte_for_byte() {
+    // A reference whose reserved field and frame count differ from what would be written
+    let mut reference_file = Vec::new();
+    write_apcm_aiff_header(2 * ADPCM_SECTOR_SAMPLES - 7, 18900, APCM_SAMPLE_SIZE, XA_ADPCM_SECTOR_SIZE, 0x1234, &mut reference_file).unwrap();
+    reference_file.extend([0; 2 * XA_ADPCM_SECTOR_SIZE]);
+    let reference = ReferenceHeader::from_reader(&mut reference_file.as_slice()).unwrap();
+    assert_eq!(reference.num_sectors(), 2);
+
+    let config = EncoderConfig::builder().reference_header(Some(reference)).build().unwrap();
+    let pcm = test_pcm(5000);
+    let mut out = Vec::new();
+    encode(&config, 5000, &mut pcm.as_slice(), &mut out).unwrap();
+    assert_eq!(out[..APCM_AIFF_HEADER_SIZE], reference_file[..APCM_AIFF_HEADER_SIZE]);
+
+    let mut plain = Vec::new();
+    encode(&EncoderConfig::default(), 5000, &mut pcm.as_slice(), &mut plain).unwrap();
+    assert_eq!(out[APCM_AIFF_HEADER_SIZE..], plain[APCM_AIFF_HEADER_SIZE..]);
+
+    // One sector's worth of input doesn't fit the two sectors the reference declares
+    let mut out = Vec::new();
+    let err = encode(&config, 1000, &mut pcm.as_slice(), &mut out).unwrap_err();
+    assert!(err.to_string().contains("declares 2 sectors, but the input makes 1"), "{}", err);
+    assert!(out.is_empty());
+    assert!(output_size(&config, 1000).is_err());
+  }
+}
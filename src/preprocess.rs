@@ -0,0 +1,839 @@
+use anyhow::{anyhow, Result};
+use byteorder::{LE, ReadBytesExt};
+use std::{
+  collections::VecDeque,
+  f64::consts::PI,
+  io::{self, Read},
+  ops::Range,
+  str::FromStr,
+};
+
+/// Shape of a fade ramp.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FadeCurve {
+  /// Gain rises in a straight line
+  #[default]
+  Linear,
+  /// Gain follows half a cosine period, easing in and out of the ramp
+  RaisedCosine,
+}
+
+impl FromStr for FadeCurve {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "linear" => Ok(FadeCurve::Linear),
+      "cosine" | "raised-cosine" => Ok(FadeCurve::RaisedCosine),
+      _ => Err(anyhow!("Unknown fade curve `{}`, expected `linear` or `cosine`", s)),
+    }
+  }
+}
+
+impl FadeCurve {
+  /// Gain at position `t` through the ramp, from 0.0 at `t == 0.0` to 1.0 at `t == 1.0`
+  fn gain(self, t: f64) -> f64 {
+    match self {
+      FadeCurve::Linear => t,
+      FadeCurve::RaisedCosine => (1.0 - (PI * t).cos()) / 2.0,
+    }
+  }
+}
+
+/// A processing stage over 16-bit samples, producing its output one sample at a time.
+/// `SampleReader` reads a stage as little-endian bytes.
+pub trait SampleStage {
+  /// The next output sample, or `None` once the stage has no more
+  fn next_sample(&mut self) -> io::Result<Option<i16>>;
+}
+
+/// Reads the samples a `SampleStage` produces as a stream of little-endian 16-bit bytes.
+pub struct SampleReader<S: SampleStage> {
+  stage: S,
+  // High byte of a sample whose low byte ended the last read
+  pending: Option<u8>,
+}
+
+impl<S: SampleStage> SampleReader<S> {
+  pub fn from_stage(stage: S) -> Self {
+    SampleReader { stage, pending: None }
+  }
+}
+
+impl<S: SampleStage> Read for SampleReader<S> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let mut count = 0;
+    while count < buf.len() {
+      let byte = match self.pending.take() {
+        Some(byte) => byte,
+        None => {
+          let Some(sample) = self.stage.next_sample()? else { break };
+          let [low, high] = sample.to_le_bytes();
+          self.pending = Some(high);
+          low
+        },
+      };
+      buf[count] = byte;
+      count += 1;
+    }
+
+    Ok(count)
+  }
+}
+
+/// Reads one little-endian 16-bit sample from `rdr`, or `None` at the end of the stream.
+fn read_sample<R: Read>(rdr: &mut R) -> io::Result<Option<i16>> {
+  match rdr.read_i16::<LE>() {
+    Ok(sample) => Ok(Some(sample)),
+    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+    Err(e) => Err(e),
+  }
+}
+
+/// Fade-in and fade-out lengths, in samples.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fade {
+  pub in_samples: usize,
+  pub out_samples: usize,
+  pub curve: FadeCurve,
+}
+
+impl Fade {
+  pub fn is_none(&self) -> bool {
+    self.in_samples == 0 && self.out_samples == 0
+  }
+
+  /// Shortens both ramps to at most half of a `samples_count`-sample clip, so they don't overlap.
+  fn clamped(self, samples_count: usize) -> Self {
+    Fade {
+      in_samples: self.in_samples.min(samples_count / 2),
+      out_samples: self.out_samples.min(samples_count / 2),
+      curve: self.curve,
+    }
+  }
+
+  /// Gain for sample `n` of a `samples_count`-sample clip. The first and last samples of a faded
+  /// clip get a gain of 0.
+  fn gain(&self, n: usize, samples_count: usize) -> f64 {
+    let mut gain = 1.0;
+    if n < self.in_samples {
+      gain *= self.curve.gain(n as f64 / self.in_samples as f64);
+    }
+    let from_end = samples_count.saturating_sub(n + 1);
+    if from_end < self.out_samples {
+      gain *= self.curve.gain(from_end as f64 / self.out_samples as f64);
+    }
+    gain
+  }
+}
+
+/// Applies a `Fade` to a stream of little-endian 16-bit samples that's `samples_count` samples long.
+pub type FadeReader<R> = SampleReader<FadeStage<R>>;
+
+/// `SampleStage` behind `FadeReader`.
+pub struct FadeStage<R: Read> {
+  inner: R,
+  fade: Fade,
+  samples_count: usize,
+  position: usize,
+}
+
+impl<R: Read> FadeReader<R> {
+  pub fn new(inner: R, samples_count: usize, fade: Fade) -> Self {
+    SampleReader::from_stage(FadeStage {
+      inner,
+      fade: fade.clamped(samples_count),
+      samples_count,
+      position: 0,
+    })
+  }
+}
+
+impl<R: Read> SampleStage for FadeStage<R> {
+  fn next_sample(&mut self) -> io::Result<Option<i16>> {
+    let Some(sample) = read_sample(&mut self.inner)? else { return Ok(None) };
+    let gain = self.fade.gain(self.position, self.samples_count);
+    self.position += 1;
+
+    Ok(Some((f64::from(sample) * gain).round() as i16))
+  }
+}
+
+/// Scales a stream of little-endian 16-bit samples by `gain_db`, clamping the result to the i16
+/// range.
+pub type GainReader<R> = SampleReader<GainStage<R>>;
+
+/// `SampleStage` behind `GainReader`.
+pub struct GainStage<R: Read> {
+  inner: R,
+  gain: f64,
+}
+
+impl<R: Read> GainReader<R> {
+  pub fn new(inner: R, gain_db: f64) -> Self {
+    SampleReader::from_stage(GainStage { inner, gain: 10_f64.powf(gain_db / 20.0) })
+  }
+}
+
+impl<R: Read> SampleStage for GainStage<R> {
+  fn next_sample(&mut self) -> io::Result<Option<i16>> {
+    let gained = read_sample(&mut self.inner)?.map(|sample| (f64::from(sample) * self.gain).round());
+    Ok(gained.map(|sample| sample.clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16))
+  }
+}
+
+// Pre-emphasis filter coefficients are fixed-point with this many fractional bits. The allpass
+// output can reach three times full scale, so this leaves room in an i32 for a coefficient times
+// the difference between it and the input, up to the largest shelf `MAX_PRE_EMPHASIS_DB` allows.
+const PRE_EMPHASIS_FRACTION_BITS: u32 = 13;
+
+/// First-order high shelf: flat at DC, rising to `gain_db` at Nyquist, with its midpoint at
+/// `corner_hz`.
+///
+/// Built as `H(z) = 1 + (V - 1) / 2 * (1 - A(z))`, with `A(z)` a first-order allpass and `V` the
+/// linear gain. Decoded output can be flattened again with the matching cut: the same filter with
+/// `V` replaced by `1 / V` and the allpass coefficient computed from `tan(pi * corner / rate) * V`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreEmphasis {
+  pub gain_db: f64,
+  pub corner_hz: f64,
+}
+
+impl PreEmphasis {
+  /// Allpass coefficient and shelf gain `(V - 1) / 2`, both fixed-point
+  fn coefficients(&self, sample_rate: u32) -> (i32, i32) {
+    let scale = f64::from(1 << PRE_EMPHASIS_FRACTION_BITS);
+    let t = (PI * self.corner_hz / f64::from(sample_rate)).tan();
+    let allpass = (t - 1.0) / (t + 1.0);
+    let shelf = (10_f64.powf(self.gain_db / 20.0) - 1.0) / 2.0;
+    ((allpass * scale).round() as i32, (shelf * scale).round() as i32)
+  }
+}
+
+/// Applies a `PreEmphasis` shelf to a stream of little-endian 16-bit samples, clamping the boosted
+/// output to the i16 range, or soft clipping it with `with_soft_clip`.
+pub type PreEmphasisReader<R> = SampleReader<PreEmphasisStage<R>>;
+
+/// `SampleStage` behind `PreEmphasisReader`.
+pub struct PreEmphasisStage<R: Read> {
+  inner: R,
+  allpass: i32,
+  shelf: i32,
+  clipper: Option<SoftClipper>,
+  // Previous input sample and allpass output
+  delayed: Option<(i32, i32)>,
+}
+
+impl<R: Read> PreEmphasisReader<R> {
+  pub fn new(inner: R, sample_rate: u32, pre_emphasis: PreEmphasis) -> Self {
+    let (allpass, shelf) = pre_emphasis.coefficients(sample_rate);
+    SampleReader::from_stage(PreEmphasisStage { inner, allpass, shelf, clipper: None, delayed: None })
+  }
+
+  /// Soft clips the boosted output before it's cut to the i16 range, so overs bend over smoothly.
+  pub fn with_soft_clip(mut self, soft_clip: SoftClip) -> Self {
+    self.stage.clipper = Some(SoftClipper::new(soft_clip));
+    self
+  }
+}
+
+impl<R: Read> PreEmphasisStage<R> {
+  fn filter(&mut self, sample: i16) -> i16 {
+    let input = i32::from(sample);
+    // The history starts out as if the clip had always sat at its first sample, which the shelf
+    // passes unchanged, so a clip that starts away from zero doesn't open with a boosted step
+    let (delayed_input, delayed_allpass) = self.delayed.unwrap_or((input, input));
+
+    let round = 1 << (PRE_EMPHASIS_FRACTION_BITS - 1);
+    let allpass = ((self.allpass * (input - delayed_allpass) + round) >> PRE_EMPHASIS_FRACTION_BITS) + delayed_input;
+    self.delayed = Some((input, allpass));
+
+    let boost = (self.shelf * (input - allpass) + round) >> PRE_EMPHASIS_FRACTION_BITS;
+    match &self.clipper {
+      Some(clipper) => clipper.clip(input + boost),
+      None => (input + boost).clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16,
+    }
+  }
+}
+
+impl<R: Read> SampleStage for PreEmphasisStage<R> {
+  fn next_sample(&mut self) -> io::Result<Option<i16>> {
+    Ok(read_sample(&mut self.inner)?.map(|sample| self.filter(sample)))
+  }
+}
+
+// The soft clip curve is tabulated at this many points per knee width above the threshold, out to
+// `SOFT_CLIP_TABLE_KNEES` knee widths, past which it's within an LSB of full scale.
+const SOFT_CLIP_TABLE_STEPS: i64 = 64;
+const SOFT_CLIP_TABLE_KNEES: i64 = 8;
+const SOFT_CLIP_FRACTION_BITS: u32 = 15;
+
+/// Soft clipper for samples beyond `threshold_dbfs`. Below it samples pass unchanged; above it they
+/// follow a tanh curve that bends over smoothly towards full scale, rather than being cut flat at
+/// it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoftClip {
+  pub threshold_dbfs: f64,
+}
+
+impl SoftClip {
+  fn threshold(&self) -> i32 {
+    (32767.0 * 10_f64.powf(self.threshold_dbfs / 20.0)).round() as i32
+  }
+}
+
+/// `SoftClip` curve as a table of fixed-point tanh values, so clipping a sample is a lookup and a
+/// linear interpolation.
+struct SoftClipper {
+  threshold: i32,
+  knee: i32,
+  curve: Vec<i32>,
+}
+
+impl SoftClipper {
+  fn new(soft_clip: SoftClip) -> Self {
+    let threshold = soft_clip.threshold().clamp(0, i32::from(i16::MAX) - 1);
+    let scale = f64::from(1 << SOFT_CLIP_FRACTION_BITS);
+    let curve = (0..=SOFT_CLIP_TABLE_STEPS * SOFT_CLIP_TABLE_KNEES)
+      .map(|n| ((n as f64 / SOFT_CLIP_TABLE_STEPS as f64).tanh() * scale).round() as i32)
+      .collect();
+    SoftClipper { threshold, knee: i32::from(i16::MAX) - threshold, curve }
+  }
+
+  /// Clips a sample that may lie outside the i16 range, such as a boosted one.
+  fn clip(&self, sample: i32) -> i16 {
+    let magnitude = sample.unsigned_abs().min(i32::MAX as u32) as i32;
+    if magnitude <= self.threshold {
+      return sample as i16
+    }
+
+    // Position above the threshold in table steps, with 8 fraction bits for interpolation
+    let position = ((i64::from(magnitude - self.threshold) * SOFT_CLIP_TABLE_STEPS) << 8) / i64::from(self.knee);
+    let index = (position >> 8) as usize;
+    let tanh = match self.curve.get(index + 1) {
+      Some(&next) => {
+        let fraction = position & 0xFF;
+        let this = i64::from(self.curve[index]);
+        this + (((i64::from(next) - this) * fraction) >> 8)
+      },
+      None => 1 << SOFT_CLIP_FRACTION_BITS,
+    };
+
+    let clipped = self.threshold + ((i64::from(self.knee) * tanh) >> SOFT_CLIP_FRACTION_BITS) as i32;
+    (if sample < 0 { -clipped } else { clipped }) as i16
+  }
+}
+
+/// Applies a `SoftClip` to a stream of little-endian 16-bit samples.
+pub type SoftClipReader<R> = SampleReader<SoftClipStage<R>>;
+
+/// `SampleStage` behind `SoftClipReader`.
+pub struct SoftClipStage<R: Read> {
+  inner: R,
+  clipper: SoftClipper,
+}
+
+impl<R: Read> SoftClipReader<R> {
+  pub fn new(inner: R, soft_clip: SoftClip) -> Self {
+    SampleReader::from_stage(SoftClipStage { inner, clipper: SoftClipper::new(soft_clip) })
+  }
+}
+
+impl<R: Read> SampleStage for SoftClipStage<R> {
+  fn next_sample(&mut self) -> io::Result<Option<i16>> {
+    Ok(read_sample(&mut self.inner)?.map(|sample| self.clipper.clip(i32::from(sample))))
+  }
+}
+
+// The resampling kernel is a Blackman-windowed sinc reaching this many zero crossings either side
+// of its center, with its cutoff this far up to the lower of the two Nyquist frequencies, leaving
+// room for the window's transition band.
+const RESAMPLE_ZERO_CROSSINGS: f64 = 16.0;
+const RESAMPLE_CUTOFF: f64 = 0.9;
+
+/// Blackman-windowed sinc with its cutoff at `cutoff` of the input's Nyquist frequency, reaching
+/// `half_width` input samples either side of its center
+fn resample_kernel(offset: f64, cutoff: f64, half_width: f64) -> f64 {
+  let x = cutoff * offset;
+  let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+  let u = offset / half_width;
+  let window = 0.42 + 0.5 * (PI * u).cos() + 0.08 * (2.0 * PI * u).cos();
+  cutoff * sinc * window
+}
+
+/// Resamples a stream of little-endian 16-bit samples from `from_rate` to `to_rate`, with a
+/// `DecimateReader` when `from_rate` is a whole multiple of `to_rate` and a `ResampleReader`
+/// otherwise. Either way the output is `resampled_len` samples long.
+pub fn resample_reader<'a, R: Read + 'a>(inner: R, input_samples: usize, from_rate: u32, to_rate: u32) -> Box<dyn Read + 'a> {
+  if from_rate > to_rate && from_rate.is_multiple_of(to_rate) {
+    Box::new(DecimateReader::new(inner, input_samples, from_rate / to_rate))
+  } else {
+    Box::new(ResampleReader::new(inner, input_samples, from_rate, to_rate))
+  }
+}
+
+/// Number of samples `ResampleReader` makes of `samples_count` samples at `from_rate`: one for each
+/// output sample period that starts within the input.
+pub fn resampled_len(samples_count: usize, from_rate: u32, to_rate: u32) -> usize {
+  (samples_count as u64 * u64::from(to_rate)).div_ceil(u64::from(from_rate)) as usize
+}
+
+/// Resamples a stream of little-endian 16-bit samples from `from_rate` to `to_rate` with a windowed
+/// sinc filter, which also low-passes the input below the output's Nyquist frequency when
+/// downsampling.
+///
+/// Output sample `k` is taken at input time `k * from_rate / to_rate`, so both streams start
+/// together. Input outside the clip counts as silence, and the output is `resampled_len` samples
+/// long.
+pub type ResampleReader<R> = SampleReader<ResampleStage<R>>;
+
+/// `SampleStage` behind `ResampleReader`.
+pub struct ResampleStage<R: Read> {
+  inner: R,
+  from_rate: u64,
+  to_rate: u64,
+  // Kernel cutoff as a fraction of the input's Nyquist frequency, and the kernel's half-width in
+  // input samples
+  cutoff: f64,
+  half_width: f64,
+  samples_count: usize,
+  position: usize,
+  // Input samples from `buffer_start` on, while the kernel can still reach them
+  buffer: VecDeque<f64>,
+  buffer_start: usize,
+}
+
+impl<R: Read> ResampleReader<R> {
+  /// `inner` holds `input_samples` samples at `from_rate`.
+  pub fn new(inner: R, input_samples: usize, from_rate: u32, to_rate: u32) -> Self {
+    let cutoff = RESAMPLE_CUTOFF * (f64::from(to_rate) / f64::from(from_rate)).min(1.0);
+    SampleReader::from_stage(ResampleStage {
+      inner,
+      from_rate: u64::from(from_rate),
+      to_rate: u64::from(to_rate),
+      cutoff,
+      half_width: RESAMPLE_ZERO_CROSSINGS / cutoff,
+      samples_count: resampled_len(input_samples, from_rate, to_rate),
+      position: 0,
+      buffer: VecDeque::new(),
+      buffer_start: 0,
+    })
+  }
+}
+
+impl<R: Read> ResampleStage<R> {
+  fn kernel(&self, offset: f64) -> f64 {
+    resample_kernel(offset, self.cutoff, self.half_width)
+  }
+
+  fn filtered_sample(&mut self) -> io::Result<i16> {
+    let numerator = self.position as u64 * self.from_rate;
+    let center = (numerator / self.to_rate) as usize;
+    let time = center as f64 + (numerator % self.to_rate) as f64 / self.to_rate as f64;
+    let reach = self.half_width.ceil() as usize;
+    let first = center.saturating_sub(reach);
+    let last = center + reach;
+
+    while self.buffer_start + self.buffer.len() <= last {
+      // Past the end of the input counts as silence
+      let sample = read_sample(&mut self.inner)?.map_or(0.0, f64::from);
+      self.buffer.push_back(sample);
+    }
+    while self.buffer_start < first {
+      self.buffer.pop_front();
+      self.buffer_start += 1;
+    }
+
+    let mut sum = 0.0;
+    for (n, &sample) in self.buffer.iter().enumerate().take(last + 1 - self.buffer_start) {
+      let offset = time - (self.buffer_start + n) as f64;
+      if offset.abs() < self.half_width {
+        sum += sample * self.kernel(offset);
+      }
+    }
+    self.position += 1;
+
+    Ok(sum.round().clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16)
+  }
+}
+
+impl<R: Read> SampleStage for ResampleStage<R> {
+  fn next_sample(&mut self) -> io::Result<Option<i16>> {
+    if self.position == self.samples_count {
+      return Ok(None)
+    }
+    self.filtered_sample().map(Some)
+  }
+}
+
+/// Downsamples a stream of little-endian 16-bit samples by a whole `factor`, e.g. 37800 Hz to
+/// 18900 Hz by 2, low-passing it and keeping every `factor`th sample.
+///
+/// The low-pass is the same windowed sinc `ResampleReader` uses, and output sample `k` is still
+/// taken at input sample `k * factor`, so the output is the same as `ResampleReader`'s for the same
+/// rates. Because every output sample lands on an input sample, the kernel is only ever needed at
+/// whole-sample offsets, and is worked out once up front rather than for every sample.
+pub type DecimateReader<R> = SampleReader<DecimateStage<R>>;
+
+/// `SampleStage` behind `DecimateReader`.
+pub struct DecimateStage<R: Read> {
+  inner: R,
+  factor: usize,
+  // Kernel values at input offsets `-reach..=reach` from each output sample
+  taps: Vec<f64>,
+  reach: usize,
+  samples_count: usize,
+  position: usize,
+  // Input samples from `buffer_start` on, while the kernel can still reach them
+  buffer: VecDeque<f64>,
+  buffer_start: usize,
+}
+
+impl<R: Read> DecimateReader<R> {
+  /// `inner` holds `input_samples` samples at `factor` times the output rate.
+  pub fn new(inner: R, input_samples: usize, factor: u32) -> Self {
+    let cutoff = RESAMPLE_CUTOFF / f64::from(factor);
+    let half_width = RESAMPLE_ZERO_CROSSINGS / cutoff;
+    let reach = half_width.ceil() as usize;
+    let taps = (0..=2 * reach)
+      .map(|n| {
+        let offset = n as f64 - reach as f64;
+        if offset.abs() < half_width { resample_kernel(offset, cutoff, half_width) } else { 0.0 }
+      })
+      .collect();
+
+    SampleReader::from_stage(DecimateStage {
+      inner,
+      factor: factor as usize,
+      taps,
+      reach,
+      samples_count: input_samples.div_ceil(factor as usize),
+      position: 0,
+      buffer: VecDeque::new(),
+      buffer_start: 0,
+    })
+  }
+}
+
+impl<R: Read> DecimateStage<R> {
+  fn filtered_sample(&mut self) -> io::Result<i16> {
+    let center = self.position * self.factor;
+    let first = center.saturating_sub(self.reach);
+    let last = center + self.reach;
+
+    while self.buffer_start + self.buffer.len() <= last {
+      // Past the end of the input counts as silence
+      let sample = read_sample(&mut self.inner)?.map_or(0.0, f64::from);
+      self.buffer.push_back(sample);
+    }
+    while self.buffer_start < first {
+      self.buffer.pop_front();
+      self.buffer_start += 1;
+    }
+
+    // Taps ahead of the first input sample fall on silence
+    let skipped = self.reach - (center - first);
+    let sum: f64 = self.buffer.iter()
+      .zip(&self.taps[skipped..])
+      .map(|(&sample, &tap)| sample * tap)
+      .sum();
+    self.position += 1;
+
+    Ok(sum.round().clamp(f64::from(i16::MIN), f64::from(i16::MAX)) as i16)
+  }
+}
+
+impl<R: Read> SampleStage for DecimateStage<R> {
+  fn next_sample(&mut self) -> io::Result<Option<i16>> {
+    if self.position == self.samples_count {
+      return Ok(None)
+    }
+    self.filtered_sample().map(Some)
+  }
+}
+
+/// Settings for trimming silence from the start and end of a clip. Lengths are in samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SilenceTrim {
+  /// Samples quieter than this level, in dBFS, count as silence
+  pub threshold_dbfs: f64,
+  /// Leading or trailing silence shorter than this is left alone
+  pub min_run_samples: usize,
+  /// Silence kept ahead of the first loud sample, so soft onsets aren't clipped
+  pub pre_roll_samples: usize,
+}
+
+impl SilenceTrim {
+  fn threshold(&self) -> i32 {
+    (32768.0 * 10_f64.powf(self.threshold_dbfs / 20.0)).round() as i32
+  }
+
+  /// Reads a stream of little-endian 16-bit samples to the end, and returns the range of samples to
+  /// keep. Silence between the first and last loud samples is always kept; a clip that's silent
+  /// throughout is trimmed to nothing.
+  pub fn keep_range<R: Read>(&self, mut rdr: R) -> io::Result<Range<usize>> {
+    let threshold = self.threshold();
+
+    let mut samples_count = 0;
+    let mut loud: Option<Range<usize>> = None;
+    while let Some(sample) = read_sample(&mut rdr)? {
+      if i32::from(sample).abs() >= threshold {
+        let first = loud.map_or(samples_count, |loud| loud.start);
+        loud = Some(first..samples_count + 1);
+      }
+      samples_count += 1;
+    }
+
+    let Some(loud) = loud else {
+      return Ok(0..0)
+    };
+
+    let start = if loud.start >= self.min_run_samples {
+      loud.start.saturating_sub(self.pre_roll_samples)
+    } else {
+      0
+    };
+    let end = if samples_count - loud.end >= self.min_run_samples { loud.end } else { samples_count };
+
+    Ok(start..end)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn faded(samples_count: usize, fade: Fade) -> Vec<i16> {
+    let pcm: Vec<u8> = std::iter::repeat_n(i16::MAX, samples_count).flat_map(|sample| sample.to_le_bytes()).collect();
+
+    let mut out = Vec::new();
+    FadeReader::new(&pcm[..], samples_count, fade).read_to_end(&mut out).unwrap();
+    out.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+  }
+
+  #[test]
+  fn constant_input_ramps_at_both_ends() {
+    for curve in [FadeCurve::Linear, FadeCurve::RaisedCosine] {
+      let samples = faded(1000, Fade { in_samples: 100, out_samples: 200, curve });
+
+      assert_eq!(samples.len(), 1000);
+      assert_eq!(samples[0], 0);
+      assert_eq!(samples[999], 0);
+      assert!(samples[100..800].iter().all(|&sample| sample == i16::MAX));
+      assert!(samples[..=100].windows(2).all(|w| w[0] <= w[1]), "{:?} fade-in isn't rising", curve);
+      assert!(samples[799..].windows(2).all(|w| w[0] >= w[1]), "{:?} fade-out isn't falling", curve);
+
+      // No step is much bigger than a linear ramp's, so the ramps don't click themselves
+      let max_step = samples.windows(2).map(|w| (i32::from(w[0]) - i32::from(w[1])).abs()).max().unwrap();
+      assert!(max_step < i32::from(i16::MAX) / 100 * 2, "{:?} step of {}", curve, max_step);
+    }
+  }
+
+  #[test]
+  fn gain_scales_and_clamps() {
+    let pcm: Vec<u8> = [1000_i16, -1000, 20_000, -20_000].iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    let gained = |gain_db| {
+      let mut out = Vec::new();
+      GainReader::new(&pcm[..], gain_db).read_to_end(&mut out).unwrap();
+      out.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect::<Vec<_>>()
+    };
+
+    assert_eq!(gained(0.0), [1000, -1000, 20_000, -20_000]);
+    assert_eq!(gained(-6.0), [501, -501, 10_024, -10_024]);
+    assert_eq!(gained(6.0), [1995, -1995, i16::MAX, i16::MIN]);
+  }
+
+  fn trim_range(samples: &[i16], pre_roll_samples: usize) -> Range<usize> {
+    let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    let trim = SilenceTrim { threshold_dbfs: -40.0, min_run_samples: 100, pre_roll_samples };
+    trim.keep_range(&pcm[..]).unwrap()
+  }
+
+  #[test]
+  fn trim_keeps_pre_roll_and_interior_silence() {
+    // -40 dBFS is an amplitude of 328. A soft onset below that leads into the first loud sample.
+    let mut samples = vec![0_i16; 500];
+    samples.extend((0..50).map(|n| n * 4));
+    samples.extend([1000; 100]);
+    samples.extend([0; 300]);
+    samples.extend([-1000; 100]);
+    samples.extend([10; 500]);
+
+    assert_eq!(trim_range(&samples, 0), 550..1050);
+    assert_eq!(trim_range(&samples, 50), 500..1050);
+    assert_eq!(trim_range(&samples, 1000), 0..1050);
+  }
+
+  #[test]
+  fn trim_ignores_short_silence() {
+    let mut samples = vec![0_i16; 99];
+    samples.extend([1000; 100]);
+    samples.extend([0; 99]);
+
+    assert_eq!(trim_range(&samples, 0), 0..298);
+    assert_eq!(trim_range(&[0; 1000], 0), 0..0);
+  }
+
+  fn emphasized(samples: &[i16], pre_emphasis: PreEmphasis) -> Vec<i16> {
+    let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+    let mut out = Vec::new();
+    PreEmphasisReader::new(&pcm[..], 18900, pre_emphasis).read_to_end(&mut out).unwrap();
+    out.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+  }
+
+  #[test]
+  fn pre_emphasis_tilts_up_to_nyquist() {
+    let pre_emphasis = PreEmphasis { gain_db: 6.0, corner_hz: 3000.0 };
+
+    // DC passes unchanged, from the first sample on
+    assert!(emphasized(&[5000; 200], pre_emphasis).iter().all(|&sample| sample == 5000));
+
+    // A tone at Nyquist, the top of a flat spectrum, comes out `gain_db` louder once the filter
+    // settles
+    let nyquist: Vec<i16> = (0..400).map(|n| if n % 2 == 0 { 4000 } else { -4000 }).collect();
+    let out = emphasized(&nyquist, pre_emphasis);
+    let amplitude = out[200..].iter().map(|&sample| f64::from(sample).abs()).sum::<f64>() / 200.0;
+    let gain_db = 20.0 * (amplitude / 4000.0).log10();
+    assert!((gain_db - 6.0).abs() < 0.05, "{} dB", gain_db);
+
+    // Halfway up, at the corner, the boost is partway there
+    let corner: Vec<i16> = (0..18900).map(|n| ((n as f64) * 2.0 * PI * 3000.0 / 18900.0).sin() * 4000.0).map(|s| s as i16).collect();
+    let out = emphasized(&corner, pre_emphasis);
+    let peak = out[1000..].iter().map(|&sample| i32::from(sample).abs()).max().unwrap();
+    let gain_db = 20.0 * (f64::from(peak) / 4000.0).log10();
+    assert!(gain_db > 1.0 && gain_db < 5.0, "{} dB", gain_db);
+  }
+
+  #[test]
+  fn pre_emphasis_clamps_full_scale() {
+    let nyquist: Vec<i16> = (0..100).map(|n| if n % 2 == 0 { i16::MAX } else { i16::MIN }).collect();
+    let out = emphasized(&nyquist, PreEmphasis { gain_db: 12.0, corner_hz: 3000.0 });
+    assert_eq!(out[50..52], [i16::MAX, i16::MIN]);
+  }
+
+  #[test]
+  fn soft_clip_bends_overs_smoothly() {
+    let clipper = SoftClipper::new(SoftClip { threshold_dbfs: -6.0 });
+    assert_eq!(clipper.clip(10_000), 10_000);
+    assert_eq!(clipper.clip(-10_000), -10_000);
+    assert_eq!(clipper.clip(i32::MAX), i16::MAX);
+    assert_eq!(clipper.clip(-i32::MAX), -i16::MAX);
+
+    // A sine driven 20% past full scale stays within range, still rising all the way to its peak
+    // and falling away after it, where a hard clamp would sit flat at full scale
+    let overdriven: Vec<i32> = (0..200).map(|n| ((n as f64) * 2.0 * PI / 200.0).sin() * 1.2 * 32767.0).map(|s| s as i32).collect();
+    let clipped: Vec<i16> = overdriven.iter().map(|&sample| clipper.clip(sample)).collect();
+    assert!(clipped[..=50].windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(clipped[50..=150].windows(2).all(|pair| pair[0] > pair[1]));
+    assert!(clipped[50] < i16::MAX && clipped[50] > 30_000, "{}", clipped[50]);
+    assert!(overdriven.iter().filter(|&&sample| sample >= 32767).count() > 20);
+  }
+
+  #[test]
+  fn pre_emphasis_soft_clips_when_asked() {
+    let nyquist: Vec<i16> = (0..100).map(|n| if n % 2 == 0 { 20_000 } else { -20_000 }).collect();
+    let pcm: Vec<u8> = nyquist.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    let mut out = Vec::new();
+    PreEmphasisReader::new(&pcm[..], 18900, PreEmphasis { gain_db: 12.0, corner_hz: 3000.0 })
+      .with_soft_clip(SoftClip { threshold_dbfs: -3.0 })
+      .read_to_end(&mut out)
+      .unwrap();
+    let out: Vec<i16> = out.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+    assert!(out[50] > 23_197 && out[50] < i16::MAX, "{}", out[50]);
+    assert_eq!(out[51], -out[50]);
+  }
+
+  #[test]
+  fn fades_clamped_to_half_a_short_clip() {
+    let samples = faded(10, Fade { in_samples: 100, out_samples: 100, curve: FadeCurve::Linear });
+
+    assert_eq!(samples, [0, 6553, 13107, 19660, 26214, 26214, 19660, 13107, 6553, 0]);
+  }
+
+  fn resampled(samples: &[i16], from_rate: u32, to_rate: u32) -> Vec<i16> {
+    let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+    let mut out = Vec::new();
+    ResampleReader::new(&pcm[..], samples.len(), from_rate, to_rate).read_to_end(&mut out).unwrap();
+    out.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect()
+  }
+
+  fn tone(frequency: f64, sample_rate: u32, samples_count: usize) -> Vec<i16> {
+    (0..samples_count)
+      .map(|n| ((2.0 * PI * frequency * n as f64 / f64::from(sample_rate)).sin() * 10_000.0) as i16)
+      .collect()
+  }
+
+  fn peak(samples: &[i16]) -> i16 {
+    samples.iter().map(|sample| sample.saturating_abs()).max().unwrap()
+  }
+
+  #[test]
+  fn resampled_length_covers_the_input() {
+    assert_eq!(resampled_len(44_100, 44_100, 18_900), 18_900);
+    assert_eq!(resampled_len(8_000, 8_000, 18_900), 18_900);
+    assert_eq!(resampled_len(3, 44_100, 18_900), 2);
+    assert_eq!(resampled_len(0, 8_000, 18_900), 0);
+    assert_eq!(resampled(&[1000; 441], 44_100, 18_900).len(), 189);
+  }
+
+  #[test]
+  fn resampling_keeps_a_passband_tone() {
+    for from_rate in [8_000, 44_100] {
+      let samples = resampled(&tone(1000.0, from_rate, from_rate as usize), from_rate, 18_900);
+      assert_eq!(samples.len(), 18_900);
+
+      // Away from the edges, the tone keeps its level and matches a tone made at 18900 Hz
+      let expected = tone(1000.0, 18_900, 18_900);
+      let middle = 1000..17_900;
+      let max_error = samples[middle.clone()].iter()
+        .zip(&expected[middle])
+        .map(|(&a, &b)| (i32::from(a) - i32::from(b)).abs())
+        .max()
+        .unwrap();
+      assert!(max_error < 100, "{} Hz: max error {}", from_rate, max_error);
+    }
+  }
+
+  #[test]
+  fn whole_ratio_decimation_matches_the_resampler() {
+    // Two seconds of a passband tone plus one that has to be filtered out
+    let samples: Vec<i16> = tone(1000.0, 37_800, 75_600).iter()
+      .zip(tone(15_000.0, 37_800, 75_600))
+      .map(|(&a, b)| a / 2 + b / 2)
+      .collect();
+    let pcm: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+    let mut general = Vec::new();
+    ResampleReader::new(&pcm[..], samples.len(), 37_800, 18_900).read_to_end(&mut general).unwrap();
+    let mut decimated = Vec::new();
+    resample_reader(&pcm[..], samples.len(), 37_800, 18_900).read_to_end(&mut decimated).unwrap();
+
+    assert_eq!(decimated.len(), general.len());
+    let max_error = decimated.chunks(2)
+      .zip(general.chunks(2))
+      .map(|(a, b)| (i16::from_le_bytes([a[0], a[1]]) - i16::from_le_bytes([b[0], b[1]])).abs())
+      .max()
+      .unwrap();
+    assert!(max_error <= 1, "max error {}", max_error);
+  }
+
+  #[test]
+  fn decimated_length_covers_the_input() {
+    for samples_count in [0, 1, 566, 567, 568] {
+      let pcm = vec![0_u8; samples_count * 2];
+      let mut out = Vec::new();
+      DecimateReader::new(&pcm[..], samples_count, 3).read_to_end(&mut out).unwrap();
+      assert_eq!(out.len() / 2, resampled_len(samples_count, 56_700, 18_900));
+    }
+  }
+
+  #[test]
+  fn downsampling_filters_out_tones_above_nyquist() {
+    // 15 kHz would alias to 3900 Hz at 18900 Hz
+    let samples = resampled(&tone(15_000.0, 44_100, 44_100), 44_100, 18_900);
+
+    assert!(peak(&samples[1000..17_900]) < 100, "peak {}", peak(&samples[1000..17_900]));
+  }
+}
@@ -0,0 +1,493 @@
+use anyhow::{anyhow, Result};
+use std::{f64::consts::TAU, fmt, ops::RangeInclusive, str::FromStr};
+
+use crate::{adpcm_encoder::OutputFormat, checksum::OutputHash};
+
+/// Statistics gathered while encoding. SNR figures are only gathered by
+/// `encode_xa_adpcm_with_stats`, and the output's length and layout are only filled in by
+/// `pipeline::encode` and its variants.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EncodeStats {
+  /// Reconstruction SNR of each sector in dB, in output order
+  pub sector_snr_db: Vec<f64>,
+  /// Zero samples added after the input ran out, including those filling out the final sector
+  pub padding_samples: usize,
+  /// How many samples short of the requested sample count the input was
+  pub missing_samples: usize,
+  /// Samples the quantizer had to clamp to the 4-bit range, across the whole output
+  pub clipped_samples: usize,
+  /// Encoded bytes written, not counting any container header: whole sectors for XA output, or
+  /// blocks for SPU output
+  pub bytes_written: usize,
+  /// Layout of the output
+  pub output_format: OutputFormat,
+  /// Sectors written for XA output, or blocks for SPU output
+  pub sectors_written: usize,
+  /// Samples the output was sized for, not counting padding
+  pub samples_count: usize,
+  /// Sample rate of the output, or 0 if not known
+  pub sample_rate: u32,
+  /// Frequency weighting the SNR figures were measured with
+  pub snr_weighting: SnrWeighting,
+  /// Hash of the whole output, header included, when `EncoderConfig::hash_output` is set
+  pub output_hash: Option<OutputHash>,
+}
+
+/// Frequency weighting applied to both the source and the decoded samples before measuring SNR.
+/// Only affects what's reported, never the encode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnrWeighting {
+  /// Every frequency counts the same
+  #[default]
+  Flat,
+  /// An approximation of the A-weighting curve, which discounts low frequencies the ear barely
+  /// hears: one-pole high-pass filters at the curve's pole frequencies (20.6 Hz twice, 107.7 Hz and
+  /// 737.9 Hz) and low-pass filters at 12194 Hz. Close to the standard curve below a few kHz, but
+  /// not a calibrated meter.
+  AWeighted,
+}
+
+impl FromStr for SnrWeighting {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_ascii_lowercase().as_str() {
+      "flat" => Ok(SnrWeighting::Flat),
+      "a-weighted" => Ok(SnrWeighting::AWeighted),
+      _ => Err(anyhow!("Unknown SNR weighting `{}`, expected `flat` or `a-weighted`", s)),
+    }
+  }
+}
+
+/// One-pole filter section, either high-pass or low-pass
+#[derive(Debug, Clone)]
+struct OnePole {
+  high_pass: bool,
+  coefficient: f64,
+  last_input: f64,
+  last_output: f64,
+}
+
+impl OnePole {
+  fn new(high_pass: bool, corner_hz: f64, sample_rate: u32) -> Self {
+    let rc = 1.0 / (TAU * corner_hz);
+    let dt = 1.0 / f64::from(sample_rate);
+    let coefficient = if high_pass { rc / (rc + dt) } else { dt / (rc + dt) };
+    OnePole { high_pass, coefficient, last_input: 0.0, last_output: 0.0 }
+  }
+
+  fn process(&mut self, input: f64) -> f64 {
+    let output = if self.high_pass {
+      self.coefficient * (self.last_output + input - self.last_input)
+    } else {
+      self.last_output + self.coefficient * (input - self.last_output)
+    };
+    self.last_input = input;
+    self.last_output = output;
+    output
+  }
+}
+
+/// Applies an `SnrWeighting` to a stream of samples, keeping its state from one call to the next so
+/// that a stream can be weighted a sector at a time.
+#[derive(Debug, Clone)]
+pub struct WeightingFilter {
+  sections: Vec<OnePole>,
+}
+
+impl WeightingFilter {
+  pub fn new(weighting: SnrWeighting, sample_rate: u32) -> Self {
+    let sections = match weighting {
+      SnrWeighting::Flat => Vec::new(),
+      SnrWeighting::AWeighted => vec![
+        OnePole::new(true, 20.6, sample_rate),
+        OnePole::new(true, 20.6, sample_rate),
+        OnePole::new(true, 107.7, sample_rate),
+        OnePole::new(true, 737.9, sample_rate),
+        OnePole::new(false, 12_194.0, sample_rate),
+        OnePole::new(false, 12_194.0, sample_rate),
+      ],
+    };
+    WeightingFilter { sections }
+  }
+
+  /// Weights `samples`, continuing from the end of the previous call.
+  pub fn process(&mut self, samples: &[i16]) -> Vec<f64> {
+    samples.iter()
+      .map(|&sample| self.sections.iter_mut().fold(f64::from(sample), |value, section| section.process(value)))
+      .collect()
+  }
+}
+
+impl EncodeStats {
+  /// SNR of the worst sector, which is where audible artifacts live
+  pub fn min_snr_db(&self) -> f64 {
+    self.sector_snr_db.iter().copied().fold(f64::INFINITY, f64::min)
+  }
+
+  /// Index and SNR of the worst sector
+  pub fn worst_sector(&self) -> Option<(usize, f64)> {
+    self.sector_snr_db.iter()
+      .copied()
+      .enumerate()
+      .min_by(|(_, a), (_, b)| a.total_cmp(b))
+  }
+
+  /// Mean of the finite per-sector SNRs. Sectors that decode perfectly (infinite SNR) or that are
+  /// silent in the source (negative infinite SNR) are left out.
+  pub fn mean_snr_db(&self) -> f64 {
+    let finite: Vec<f64> = self.sector_snr_db.iter().copied().filter(|snr| snr.is_finite()).collect();
+    if finite.is_empty() {
+      return self.min_snr_db()
+    }
+
+    finite.iter().sum::<f64>() / finite.len() as f64
+  }
+}
+
+/// One-line summary for logs and scripts, e.g.
+/// `412 sectors, 21.8s, min SNR 38.2 dB, 0 clipped, 14 pad samples`. The length is left out when
+/// the sample rate isn't known, and the SNR when it wasn't measured.
+impl fmt::Display for EncodeStats {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let unit = match self.output_format {
+      OutputFormat::Xa => "sectors",
+      OutputFormat::Spu => "blocks",
+    };
+    write!(f, "{} {}", self.sectors_written, unit)?;
+    if self.sample_rate > 0 {
+      write!(f, ", {:.1}s", self.samples_count as f64 / f64::from(self.sample_rate))?;
+    }
+    if !self.sector_snr_db.is_empty() {
+      write!(f, ", min SNR {:.1} dB", self.min_snr_db())?;
+    }
+    write!(f, ", {} clipped, {} pad samples", self.clipped_samples, self.padding_samples)?;
+    if let Some(output_hash) = self.output_hash {
+      write!(f, ", hash {}", output_hash)?;
+    }
+    Ok(())
+  }
+}
+
+/// Signal-to-noise ratio in dB of `decoded` against `original`.
+///
+/// Returns infinity when the two are identical, and negative infinity when `original` is silent
+/// but `decoded` isn't.
+pub fn snr_db(original: &[i16], decoded: &[i16]) -> f64 {
+  snr_db_of(original.iter().map(|&a| f64::from(a)).zip(decoded.iter().map(|&b| f64::from(b))))
+}
+
+/// SNR in dB of `decoded` against `original` after both are run through `weighting`, starting from
+/// silence. Flat weighting gives the same as `snr_db`.
+pub fn weighted_snr_db(original: &[i16], decoded: &[i16], weighting: SnrWeighting, sample_rate: u32) -> f64 {
+  let original = WeightingFilter::new(weighting, sample_rate).process(original);
+  let decoded = WeightingFilter::new(weighting, sample_rate).process(decoded);
+  snr_db_of(original.into_iter().zip(decoded))
+}
+
+/// SNR in dB of each pair's second value against its first
+pub(crate) fn snr_db_of(pairs: impl Iterator<Item = (f64, f64)>) -> f64 {
+  let mut signal = 0.0;
+  let mut noise = 0.0;
+  for (a, b) in pairs {
+    let error = a - b;
+    signal += a * a;
+    noise += error * error;
+  }
+
+  if noise == 0.0 {
+    return f64::INFINITY
+  }
+
+  10.0 * (signal / noise).log10()
+}
+
+/// SNR in dB of a decoded stream against the original samples it was encoded from, along with how
+/// many samples were compared.
+///
+/// Sample `n` of `original` lines up with sample `leading_samples + n` of `decoded`, where
+/// `leading_samples` covers anything placed ahead of the audio, such as blank sectors
+/// (`ADPCM_SECTOR_SAMPLES` each). The tail of `decoded` past the end of `original`, such as the
+/// zero padding of the final sector, isn't compared; nor is any of `original` that `decoded` is too
+/// short to cover.
+pub fn aligned_snr_db(original: &[i16], decoded: &[i16], leading_samples: usize) -> (f64, usize) {
+  let decoded = decoded.get(leading_samples..).unwrap_or_default();
+  let compared = original.len().min(decoded.len());
+
+  (snr_db(&original[..compared], &decoded[..compared]), compared)
+}
+
+/// Samples of the original's audio `find_offset` looks for in the decoded stream
+pub const ALIGNMENT_WINDOW_SAMPLES: usize = 1000;
+/// Sectors either side of the expected offset that `find_lag` searches by default, in
+/// `verify --compare-reference` and `emphasis_match`
+pub const DEFAULT_ALIGN_WINDOW_SECTORS: usize = 4;
+
+/// Where `original` starts in `decoded`, as the `leading_samples` for `aligned_snr_db`: the offset,
+/// from 0 to `max_offset`, found by `find_lag`.
+pub fn find_offset(original: &[i16], decoded: &[i16], max_offset: usize) -> usize {
+  find_lag(original, decoded, 0, max_offset as isize) as usize
+}
+
+/// The lag, from `min_lag` to `max_lag`, at which `original` and `decoded` cross-correlate best,
+/// so that sample `n` of `original` lines up with sample `n + lag` of `decoded`. A negative lag
+/// means `decoded` is missing the start of `original`, e.g. because it was trimmed.
+///
+/// Positive lags look for `ALIGNMENT_WINDOW_SAMPLES` of `original`, from its first non-zero
+/// sample, in `decoded`; negative lags look for the same stretch of `decoded` in `original`. The
+/// correlation is normalized by the level of both stretches, so loud passages don't win on level
+/// alone and the two directions can be compared. Silent inputs, which line up anywhere, give the
+/// lag closest to 0.
+pub fn find_lag(original: &[i16], decoded: &[i16], min_lag: isize, max_lag: isize) -> isize {
+  let later = (max_lag >= 0)
+    .then(|| best_offset(original, decoded, min_lag.max(0) as usize..=max_lag as usize))
+    .flatten()
+    .map(|(offset, score)| (offset as isize, score));
+  let earlier = (min_lag < 0)
+    .then(|| best_offset(decoded, original, max_lag.min(-1).unsigned_abs()..=min_lag.unsigned_abs()))
+    .flatten()
+    .map(|(offset, score)| (-(offset as isize), score));
+
+  match (later, earlier) {
+    (Some(later), Some(earlier)) => if earlier.1 > later.1 { earlier.0 } else { later.0 },
+    (Some((lag, _)), None) | (None, Some((lag, _))) => lag,
+    (None, None) => 0.clamp(min_lag, max_lag),
+  }
+}
+
+/// Offset within `offsets` at which `haystack` correlates best with `ALIGNMENT_WINDOW_SAMPLES` of
+/// `needle` from its first non-zero sample, and the normalized correlation there. `None` if
+/// `needle` is silent or no offset has any signal to compare.
+fn best_offset(needle: &[i16], haystack: &[i16], offsets: RangeInclusive<usize>) -> Option<(usize, f64)> {
+  let start = needle.iter().position(|&sample| sample != 0)?;
+  let window = &needle[start..needle.len().min(start + ALIGNMENT_WINDOW_SAMPLES)];
+  let window_energy: f64 = window.iter().map(|&sample| f64::from(sample).powi(2)).sum();
+
+  let mut best = None;
+  for offset in offsets {
+    let Some(candidate) = haystack.get(start + offset..start + offset + window.len()) else {
+      break
+    };
+    let (dot, energy) = window.iter().zip(candidate).fold((0.0, 0.0), |(dot, energy), (&a, &b)| {
+      let b = f64::from(b);
+      (dot + f64::from(a) * b, energy + b * b)
+    });
+    if energy == 0.0 {
+      continue
+    }
+    let score = dot / (window_energy * energy).sqrt();
+    if best.is_none_or(|(_, best_score)| score > best_score) {
+      best = Some((offset, score));
+    }
+  }
+
+  best
+}
+
+/// Frame length `spectral_distance_db` measures band levels over; a power of two for `fft`
+const SPECTRUM_FRAME_SAMPLES: usize = 1024;
+/// Number of log-spaced bands `spectral_distance_db` compares, from 100 Hz to 90% of Nyquist
+const SPECTRUM_BANDS: usize = 16;
+
+/// In-place radix-2 FFT of `re` and `im`, whose length must be a power of two
+fn fft(re: &mut [f64], im: &mut [f64]) {
+  let len = re.len();
+  let mut j = 0;
+  for i in 1..len {
+    let mut bit = len >> 1;
+    while j & bit != 0 {
+      j ^= bit;
+      bit >>= 1;
+    }
+    j |= bit;
+    if i < j {
+      re.swap(i, j);
+      im.swap(i, j);
+    }
+  }
+
+  let mut size = 2;
+  while size <= len {
+    let angle = -TAU / size as f64;
+    for start in (0..len).step_by(size) {
+      for k in 0..size / 2 {
+        let (w_im, w_re) = (angle * k as f64).sin_cos();
+        let (a, b) = (start + k, start + k + size / 2);
+        let t_re = re[b] * w_re - im[b] * w_im;
+        let t_im = re[b] * w_im + im[b] * w_re;
+        (re[b], im[b]) = (re[a] - t_re, im[a] - t_im);
+        re[a] += t_re;
+        im[a] += t_im;
+      }
+    }
+    size *= 2;
+  }
+}
+
+/// Level in dB of each band of one Hann-windowed frame, where band `n` covers the FFT bins from
+/// `band_edges[n]` up to `band_edges[n + 1]`. A floor of one frame length's worth of power keeps
+/// silent bands finite and hides rounding noise.
+fn band_levels_db(frame: &[i16], band_edges: &[usize]) -> Vec<f64> {
+  let len = frame.len() as f64;
+  let mut re: Vec<f64> = frame.iter()
+    .enumerate()
+    .map(|(n, &sample)| f64::from(sample) * (1.0 - (TAU * n as f64 / len).cos()) / 2.0)
+    .collect();
+  let mut im = vec![0.0; frame.len()];
+  fft(&mut re, &mut im);
+
+  band_edges.windows(2)
+    .map(|edges| {
+      let power: f64 = (edges[0]..edges[1]).map(|bin| re[bin] * re[bin] + im[bin] * im[bin]).sum();
+      10.0 * (power + len).log10()
+    })
+    .collect()
+}
+
+/// RMS difference in dB between the spectra of `a` and `b`, compared band by band a frame at a
+/// time over their common length. 0.0 means the two have the same spectral shape and level; it
+/// ignores phase, so unlike SNR it doesn't punish a slightly different waveform that sounds alike.
+/// Inputs shorter than one frame give 0.0.
+pub fn spectral_distance_db(a: &[i16], b: &[i16], sample_rate: u32) -> f64 {
+  let bin_hz = f64::from(sample_rate) / SPECTRUM_FRAME_SAMPLES as f64;
+  let top_hz = f64::from(sample_rate) * 0.45;
+  let mut band_edges: Vec<usize> = (0..=SPECTRUM_BANDS)
+    .map(|edge| (100.0 * (top_hz / 100.0).powf(edge as f64 / SPECTRUM_BANDS as f64) / bin_hz).round() as usize)
+    .collect();
+  // The lowest bands are narrower than a bin; each band gets at least one
+  for edge in 1..band_edges.len() {
+    band_edges[edge] = band_edges[edge].max(band_edges[edge - 1] + 1);
+  }
+
+  let mut squared_sum = 0.0;
+  let mut count = 0;
+  for (frame_a, frame_b) in a.chunks_exact(SPECTRUM_FRAME_SAMPLES).zip(b.chunks_exact(SPECTRUM_FRAME_SAMPLES)) {
+    let levels_a = band_levels_db(frame_a, &band_edges);
+    let levels_b = band_levels_db(frame_b, &band_edges);
+    for (level_a, level_b) in levels_a.iter().zip(levels_b) {
+      squared_sum += (level_a - level_b).powi(2);
+      count += 1;
+    }
+  }
+  if count == 0 {
+    return 0.0
+  }
+
+  (squared_sum / count as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lag_recovers_a_shift_either_way() {
+    let tone: Vec<i16> = (0..20_000).map(|n| ((f64::from(n) * 0.031).sin() * (f64::from(n) * 0.0007).cos() * 9000.0) as i16).collect();
+    let delayed: Vec<i16> = [0; 700].iter().chain(&tone).copied().collect();
+
+    assert_eq!(find_lag(&tone, &delayed, -4032, 4032), 700);
+    assert_eq!(find_lag(&tone, &tone[250..], -4032, 4032), -250);
+    // Out of the window, the closest lag inside it is no better than any other
+    assert_ne!(find_lag(&tone, &delayed, -100, 100), 700);
+  }
+
+  #[test]
+  fn snr_of_known_error() {
+    // Signal power 100^2, noise power 1^2 => 40 dB
+    let original = [100_i16, -100, 100, -100];
+    let decoded = [101_i16, -99, 99, -101];
+
+    assert!((snr_db(&original, &decoded) - 40.0).abs() < 1e-9);
+    assert_eq!(snr_db(&original, &original), f64::INFINITY);
+    assert_eq!(snr_db(&[0, 0], &[1, 0]), f64::NEG_INFINITY);
+  }
+
+  #[test]
+  fn a_weighting_discounts_low_frequency_error() {
+    let sample_rate = 18_900;
+    let tone = |hz: f64, amplitude: f64| -> Vec<f64> {
+      (0..sample_rate as usize).map(|n| (n as f64 * TAU * hz / f64::from(sample_rate)).sin() * amplitude).collect()
+    };
+    let original: Vec<i16> = tone(1000.0, 8000.0).iter().map(|&x| x as i16).collect();
+    let with_error = |error: Vec<f64>| -> Vec<i16> {
+      original.iter().zip(error).map(|(&x, e)| (f64::from(x) + e) as i16).collect()
+    };
+
+    // The same error power, as a 30 Hz hum or as a 3 kHz whine
+    let hum = with_error(tone(30.0, 200.0));
+    let whine = with_error(tone(3000.0, 200.0));
+    let flat_hum = weighted_snr_db(&original, &hum, SnrWeighting::Flat, sample_rate);
+    let flat_whine = weighted_snr_db(&original, &whine, SnrWeighting::Flat, sample_rate);
+    assert!((flat_hum - flat_whine).abs() < 0.5, "{} vs {}", flat_hum, flat_whine);
+    assert_eq!(flat_hum, snr_db(&original, &hum));
+
+    // Weighted, the hum hardly counts, while the whine counts about as much as before
+    let weighted_hum = weighted_snr_db(&original, &hum, SnrWeighting::AWeighted, sample_rate);
+    let weighted_whine = weighted_snr_db(&original, &whine, SnrWeighting::AWeighted, sample_rate);
+    assert!(weighted_hum > flat_hum + 20.0, "{} vs {}", weighted_hum, flat_hum);
+    assert!((weighted_whine - flat_whine).abs() < 6.0, "{} vs {}", weighted_whine, flat_whine);
+
+    assert_eq!("A-Weighted".parse::<SnrWeighting>().unwrap(), SnrWeighting::AWeighted);
+    assert!("loudness".parse::<SnrWeighting>().is_err());
+  }
+
+  #[test]
+  fn aligned_snr_skips_leading_samples() {
+    let original = [100_i16, -100, 100, -100];
+    let decoded = [0_i16, 0, 0, 101, -99, 99, -101, 0, 0];
+
+    let (snr, compared) = aligned_snr_db(&original, &decoded, 3);
+    assert!((snr - 40.0).abs() < 1e-9);
+    assert_eq!(compared, 4);
+    assert_eq!(aligned_snr_db(&original, &decoded, 7).1, 2);
+    assert_eq!(aligned_snr_db(&original, &decoded, 20).1, 0);
+  }
+
+  #[test]
+  fn spectral_distance_follows_spectral_shape() {
+    let sample_rate = 18_900;
+    let tones = |amplitudes: &[(f64, f64)]| -> Vec<i16> {
+      (0..8192)
+        .map(|n| {
+          let t = n as f64 / f64::from(sample_rate);
+          amplitudes.iter().map(|&(hz, amplitude)| (t * TAU * hz).sin() * amplitude).sum::<f64>() as i16
+        })
+        .collect()
+    };
+    let reference = tones(&[(300.0, 6000.0), (4000.0, 2000.0)]);
+
+    assert_eq!(spectral_distance_db(&reference, &reference, sample_rate), 0.0);
+    assert_eq!(spectral_distance_db(&reference[..100], &reference[..100], sample_rate), 0.0);
+    // A phase shift changes every sample but not the spectrum
+    let shifted: Vec<i16> = reference[5..].to_vec();
+    let near = spectral_distance_db(&reference, &tones(&[(300.0, 6000.0), (4000.0, 2400.0)]), sample_rate);
+    let far = spectral_distance_db(&reference, &tones(&[(300.0, 6000.0), (4000.0, 500.0)]), sample_rate);
+    assert!(near < far, "{} vs {}", near, far);
+    assert!(spectral_distance_db(&reference, &shifted, sample_rate) < near);
+  }
+
+  #[test]
+  fn summary_values() {
+    let stats = EncodeStats { sector_snr_db: vec![30.0, 20.0, f64::INFINITY, 40.0], ..Default::default() };
+
+    assert_eq!(stats.min_snr_db(), 20.0);
+    assert_eq!(stats.worst_sector(), Some((1, 20.0)));
+    assert_eq!(stats.mean_snr_db(), 30.0);
+  }
+
+  #[test]
+  fn display_summary_is_stable() {
+    let stats = EncodeStats {
+      sector_snr_db: vec![41.0, 38.25],
+      padding_samples: 14,
+      sectors_written: 412,
+      samples_count: 412_020,
+      sample_rate: 18_900,
+      ..Default::default()
+    };
+    assert_eq!(stats.to_string(), "412 sectors, 21.8s, min SNR 38.2 dB, 0 clipped, 14 pad samples");
+
+    let stats = EncodeStats { output_format: OutputFormat::Spu, sectors_written: 3, clipped_samples: 2, ..Default::default() };
+    assert_eq!(stats.to_string(), "3 blocks, 2 clipped, 0 pad samples");
+  }
+}
@@ -0,0 +1,132 @@
+use std::{fs, path::Path};
+
+use symphonia::core::{
+  audio::SampleBuffer,
+  codecs::{DecoderOptions, CODEC_TYPE_NULL},
+  errors::Error,
+  formats::FormatOptions,
+  io::MediaSourceStream,
+  meta::MetadataOptions,
+  probe::Hint,
+};
+
+use crate::error::XaError;
+
+/// Decodes the first audio track of any file `symphonia` can probe (MP3, FLAC, Ogg Vorbis, ...)
+/// to 16-bit mono samples, averaging the channels of multichannel audio.
+///
+/// The whole track is decoded into memory. Timestamps and gapless metadata (encoder delay and
+/// padding) are ignored, so lossy formats may carry a few extra samples at either end. The track
+/// must already be at `expected_sample_rate`.
+pub fn decode_file(path: &Path, expected_sample_rate: u32) -> Result<Vec<i16>, XaError> {
+  decode(path, Some(expected_sample_rate)).map(|(samples, _)| samples)
+}
+
+/// Decodes a file as `decode_file` does, at whatever sample rate it was recorded at. Returns the
+/// samples and their sample rate.
+pub fn decode_file_any_rate(path: &Path) -> Result<(Vec<i16>, u32), XaError> {
+  decode(path, None)
+}
+
+fn decode(path: &Path, expected_sample_rate: Option<u32>) -> Result<(Vec<i16>, u32), XaError> {
+  let file = fs::File::open(path)?;
+  let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+  let mut hint = Hint::new();
+  if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+    hint.with_extension(extension);
+  }
+
+  let probed = symphonia::default::get_probe()
+    .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())?;
+  let mut format = probed.format;
+
+  let track = format.tracks()
+    .iter()
+    .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+    .ok_or(Error::Unsupported("no audio track"))?;
+  let track_id = track.id;
+
+  let sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+  if sample_rate == 0 || expected_sample_rate.is_some_and(|expected| sample_rate != expected) {
+    return Err(XaError::UnsupportedSampleRate(sample_rate))
+  }
+
+  let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+  let mut samples = Vec::new();
+  loop {
+    let packet = match format.next_packet() {
+      Ok(packet) => packet,
+      Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+      Err(e) => return Err(e.into()),
+    };
+    if packet.track_id() != track_id {
+      continue
+    }
+
+    let decoded = match decoder.decode(&packet) {
+      Ok(decoded) => decoded,
+      // A corrupt packet only loses that packet's audio
+      Err(Error::DecodeError(_)) => continue,
+      Err(e) => return Err(e.into()),
+    };
+
+    let spec = *decoded.spec();
+    let channels = spec.channels.count();
+    let mut buf = SampleBuffer::<i16>::new(decoded.capacity() as u64, spec);
+    buf.copy_interleaved_ref(decoded);
+
+    for frame in buf.samples().chunks(channels) {
+      let sum: i32 = frame.iter().map(|&sample| i32::from(sample)).sum();
+      samples.push((sum / channels as i32) as i16);
+    }
+  }
+
+  Ok((samples, sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn decode_and_downmix_stereo() {
+    // symphonia also reads WAV, which is the easiest container to build by hand
+    let frames = [(100_i16, 300_i16), (-100, -301), (i16::MAX, i16::MAX)];
+    let data: Vec<u8> = frames.iter()
+      .flat_map(|&(left, right)| [left.to_le_bytes(), right.to_le_bytes()])
+      .flatten()
+      .collect();
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16_u32.to_le_bytes());
+    bytes.extend_from_slice(&1_u16.to_le_bytes());
+    bytes.extend_from_slice(&2_u16.to_le_bytes());
+    bytes.extend_from_slice(&18900_u32.to_le_bytes());
+    bytes.extend_from_slice(&(18900_u32 * 4).to_le_bytes());
+    bytes.extend_from_slice(&4_u16.to_le_bytes());
+    bytes.extend_from_slice(&16_u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&data);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("stereo.wav");
+    fs::write(&path, bytes).unwrap();
+
+    assert_eq!(decode_file(&path, 18900).unwrap(), [200, -200, i16::MAX]);
+  }
+
+  #[test]
+  fn reject_unknown_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("noise.bin");
+    fs::write(&path, [0x5A_u8; 64]).unwrap();
+
+    assert!(matches!(decode_file(&path, 18900), Err(XaError::Symphonia(_))));
+  }
+}
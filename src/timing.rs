@@ -0,0 +1,80 @@
+use serde::Serialize;
+use std::{io::Write, path::PathBuf};
+
+use crate::offsets::{sample_to_sector, sector_to_sample};
+
+/// Where each input file of a concatenation starts in the encoded output, for engines that play
+/// sub-clips of one stream.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimingSidecar {
+  pub sample_rate: u32,
+  /// Sectors ahead of the first input sample, e.g. blank sectors added by another tool. Already
+  /// included in every clip position.
+  pub leading_sectors: usize,
+  pub clips: Vec<ClipTiming>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClipTiming {
+  pub path: PathBuf,
+  pub start_sample: usize,
+  /// Sector holding the clip's first sample
+  pub start_sector: usize,
+  pub start_secs: f64,
+  pub samples: usize,
+}
+
+impl TimingSidecar {
+  /// Builds the sidecar from each clip's path, its first sample within the encoded audio and its
+  /// length in samples.
+  pub fn new(sample_rate: u32, leading_sectors: usize, clips: impl IntoIterator<Item = (PathBuf, usize, usize)>) -> Self {
+    let leading_samples = sector_to_sample(leading_sectors);
+    let clips = clips.into_iter()
+      .map(|(path, start, samples)| {
+        let start_sample = leading_samples + start;
+        ClipTiming {
+          path,
+          start_sample,
+          start_sector: sample_to_sector(start_sample),
+          start_secs: start_sample as f64 / f64::from(sample_rate),
+          samples,
+        }
+      })
+      .collect();
+
+    TimingSidecar { sample_rate, leading_sectors, clips }
+  }
+
+  pub fn to_writer<W: Write>(&self, wtr: &mut W) -> serde_json::Result<()> {
+    serde_json::to_writer_pretty(&mut *wtr, self)?;
+    writeln!(wtr).map_err(serde_json::Error::io)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::adpcm_core::ADPCM_SECTOR_SAMPLES;
+
+  #[test]
+  fn positions_include_leading_sectors() {
+    let clips = [
+      (PathBuf::from("a.wav"), 0, 5000),
+      (PathBuf::from("b.wav"), 5000 + 9450, 100),
+    ];
+    let timing = TimingSidecar::new(18900, 2, clips);
+
+    assert_eq!(timing.clips[0].start_sample, 2 * ADPCM_SECTOR_SAMPLES);
+    assert_eq!(timing.clips[0].start_sector, 2);
+    assert_eq!(timing.clips[1].start_sample, 2 * ADPCM_SECTOR_SAMPLES + 14450);
+    assert_eq!(timing.clips[1].start_sector, 5);
+    assert!((timing.clips[1].start_secs - (8064.0 + 14450.0) / 18900.0).abs() < 1e-9);
+
+    let mut json = Vec::new();
+    timing.to_writer(&mut json).unwrap();
+    let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(value["clips"][1]["path"], "b.wav");
+    assert_eq!(value["clips"][1]["samples"], 100);
+    assert_eq!(value["leading_sectors"], 2);
+  }
+}
@@ -1,28 +1,65 @@
-use anyhow::{anyhow, Result}; 
-use byteorder::{LE, ReadBytesExt};
-use std::io::Read;
+use anyhow::{anyhow, Result};
+use byteorder::{BE, LE, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::{adpcm_encoder::LoopRegion, error::XaError};
+
+/// Byte order of a WAV file's header fields and samples: little-endian in a `RIFF` file, big-endian
+/// in a `RIFX` one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endian {
+  #[default]
+  Little,
+  Big,
+}
+
+impl Endian {
+  fn read_u16<R: Read>(self, rdr: &mut R) -> io::Result<u16> {
+    match self {
+      Endian::Little => rdr.read_u16::<LE>(),
+      Endian::Big => rdr.read_u16::<BE>(),
+    }
+  }
+
+  fn read_u32<R: Read>(self, rdr: &mut R) -> io::Result<u32> {
+    match self {
+      Endian::Little => rdr.read_u32::<LE>(),
+      Endian::Big => rdr.read_u32::<BE>(),
+    }
+  }
+}
 
 #[derive(Debug)]
-pub(crate) struct RiffHeader {
-  chunk_id: [u8; 4], // 'RIFF'
-  chunk_size: u32,   // 32 + sample data size
-  format: [u8; 4],   // 'WAVE'
+pub struct RiffHeader {
+  pub chunk_id: [u8; 4], // 'RIFF', or 'RIFX' for big-endian
+  pub chunk_size: u32,   // 36 + sample data size
+  pub format: [u8; 4],   // 'WAVE'
 }
 
 impl RiffHeader {
-  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self> {
+  fn new(data_size: u32) -> Self {
+    RiffHeader {
+      chunk_id: [0x52, 0x49, 0x46, 0x46],
+      chunk_size: 4 + (8 + 16) + (8 + data_size),
+      format: [0x57, 0x41, 0x56, 0x45],
+    }
+  }
+
+  /// Reads a `RIFF` or `RIFX` header. See `endian` for which.
+  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self, XaError> {
     let mut chunk_id = [0_u8; 4];
     rdr.read_exact(&mut chunk_id)?;
-    if chunk_id != [0x52, 0x49, 0x46, 0x46] {
-      return Err(anyhow!("Not a RIFF file"))
+    if chunk_id != [0x52, 0x49, 0x46, 0x46] && chunk_id != *b"RIFX" {
+      return Err(XaError::NotRiff)
     }
 
-    let chunk_size = rdr.read_u32::<LE>()?;
+    let endian = if chunk_id == *b"RIFX" { Endian::Big } else { Endian::Little };
+    let chunk_size = endian.read_u32(rdr)?;
 
     let mut format = [0_u8; 4];
     rdr.read_exact(&mut format)?;
     if format != [0x57, 0x41, 0x56, 0x45] {
-      return Err(anyhow!("Not a WAVE file"))
+      return Err(XaError::NotWave)
     }
 
     Ok(RiffHeader {
@@ -31,58 +68,156 @@ impl RiffHeader {
       format,
     })
   }
+
+  /// Byte order of everything after the header
+  pub fn endian(&self) -> Endian {
+    if self.chunk_id == *b"RIFX" { Endian::Big } else { Endian::Little }
+  }
+
+  fn to_writer<W: Write>(&self, wtr: &mut W) -> Result<()> {
+    wtr.write_all(&self.chunk_id)?;
+    wtr.write_u32::<LE>(self.chunk_size)?;
+    wtr.write_all(&self.format)?;
+
+    Ok(())
+  }
 }
 
+/// `audio_format` of a `fmt ` chunk whose real format is in the SubFormat GUID of its extension
+pub const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+// KSDATAFORMAT_SUBTYPE_PCM and _IEEE_FLOAT are the GUIDs {0000xxxx-0000-0010-8000-00AA00389B71},
+// with their format tag (1 or 3) as xxxx. This is the last field, which is stored as bytes.
+const KSDATAFORMAT_SUBTYPE_DATA4: [u8; 8] = [0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71];
+
 #[derive(Debug)]
-pub(crate) struct FormatChunk {
-  chunk_id: [u8; 4],    // 'fmt '
-  chunk_size: u32,      // 16
-  audio_format: u16,    // 1 (PCM)
-  num_channels: u16,    // 1 (Mono)
-  sample_rate: u32,     // 18900
-  byte_rate: u32,       // sample_rate * num_channels * 16/8
-  block_align: u16,     // 2 * 16/8
-  bits_per_sample: u16, // 16
+pub struct FormatChunk {
+  pub chunk_id: [u8; 4],    // 'fmt '
+  pub chunk_size: u32,      // 16, or 18 or 40 with an extension
+  pub audio_format: u16,    // 1 (PCM), or WAVE_FORMAT_EXTENSIBLE with a PCM SubFormat
+  pub num_channels: u16,    // 1 (Mono)
+  pub sample_rate: u32,     // 18900 or 37800
+  pub byte_rate: u32,       // sample_rate * num_channels * bits_per_sample/8
+  pub block_align: u16,     // num_channels * bits_per_sample/8
+  pub bits_per_sample: u16, // 16 or 32
+  /// Bits of each sample that hold audio, from the top; the same as `bits_per_sample` unless the
+  /// chunk is extensible
+  pub valid_bits_per_sample: u16,
 }
 
 impl FormatChunk {
-  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self> {
+  fn new(sample_rate: u32) -> Self {
+    FormatChunk {
+      chunk_id: [0x66, 0x6D, 0x74, 0x20],
+      chunk_size: 16,
+      audio_format: 1,
+      num_channels: 1,
+      sample_rate,
+      byte_rate: sample_rate * 2,
+      block_align: 2,
+      bits_per_sample: 16,
+      valid_bits_per_sample: 16,
+    }
+  }
+
+  /// Reads a `fmt ` chunk, which must describe 16- or 32-bit mono PCM at `expected_sample_rate`.
+  ///
+  /// A `WAVE_FORMAT_EXTENSIBLE` chunk is accepted when its SubFormat is PCM. Any bytes past the
+  /// fields read here are skipped, so `rdr` ends up at the next chunk.
+  pub fn from_reader<R: Read>(rdr: &mut R, expected_sample_rate: u32) -> Result<Self, XaError> {
+    Self::from_reader_endian(rdr, Endian::Little, Some(expected_sample_rate))
+  }
+
+  /// Reads a `fmt ` chunk, which must describe 16- or 32-bit mono PCM at any non-zero sample rate.
+  pub fn from_reader_any_rate<R: Read>(rdr: &mut R) -> Result<Self, XaError> {
+    Self::from_reader_endian(rdr, Endian::Little, None)
+  }
+
+  /// Reads a `fmt ` chunk like the above from a file of the given byte order, at
+  /// `expected_sample_rate` or at any rate for `None`.
+  pub fn from_reader_endian<R: Read>(rdr: &mut R, endian: Endian, expected_sample_rate: Option<u32>) -> Result<Self, XaError> {
+    Self::from_reader_channels(rdr, endian, expected_sample_rate, 1)
+  }
+
+  /// Reads a `fmt ` chunk like `from_reader_endian`, but of `expected_channels` interleaved channels
+  /// rather than mono.
+  pub fn from_reader_channels<R: Read>(
+    rdr: &mut R,
+    endian: Endian,
+    expected_sample_rate: Option<u32>,
+    expected_channels: u16,
+  ) -> Result<Self, XaError> {
     let mut chunk_id = [0_u8; 4];
     rdr.read_exact(&mut chunk_id)?;
     if chunk_id != [0x66, 0x6D, 0x74, 0x20] {
-      return Err(anyhow!("Not a `fmt ` chunk"))
+      return Err(XaError::MissingChunk { expected: *b"fmt ", found: chunk_id })
     }
 
-    let chunk_size = rdr.read_u32::<LE>()?;
+    let chunk_size = endian.read_u32(rdr)?;
     
-    let audio_format = rdr.read_u16::<LE>()?;
-    if audio_format != 1 {
-      return Err(anyhow!("Unsupported audio format: {}", audio_format))
+    let audio_format = endian.read_u16(rdr)?;
+    if audio_format != 1 && audio_format != WAVE_FORMAT_EXTENSIBLE {
+      return Err(XaError::UnsupportedAudioFormat(audio_format))
     }
     
-    let num_channels = rdr.read_u16::<LE>()?;
-    if num_channels != 1 {
-      return Err(anyhow!("Unsupported number of audio channels: {}", num_channels))
+    let num_channels = endian.read_u16(rdr)?;
+    if num_channels != expected_channels {
+      return Err(XaError::UnsupportedChannels(num_channels))
+    }
+
+    let sample_rate = endian.read_u32(rdr)?;
+    if sample_rate == 0 || expected_sample_rate.is_some_and(|expected| sample_rate != expected) {
+      return Err(XaError::UnsupportedSampleRate(sample_rate))
     }
 
-    let sample_rate = rdr.read_u32::<LE>()?;
-    if sample_rate != 18900 {
-      return Err(anyhow!("Unsupported sample rate: {}", sample_rate))
+    let byte_rate = endian.read_u32(rdr)?;
+    let block_align = endian.read_u16(rdr)?;
+    let bits_per_sample = endian.read_u16(rdr)?;
+    if bits_per_sample != 16 && bits_per_sample != 32 {
+      return Err(XaError::UnsupportedBitDepth(bits_per_sample))
     }
 
-    let byte_rate = rdr.read_u32::<LE>()?;
-    if byte_rate != (18900 * 2) {
-      return Err(anyhow!("Unexpected byte rate: {}", byte_rate))
+    let mut valid_bits_per_sample = bits_per_sample;
+    let mut read_size = 16;
+    if audio_format == WAVE_FORMAT_EXTENSIBLE {
+      const EXTENSIBLE_SIZE: u32 = 40;
+      if chunk_size < EXTENSIBLE_SIZE {
+        return Err(XaError::ChunkSize { chunk: chunk_id, expected: i64::from(EXTENSIBLE_SIZE), found: i64::from(chunk_size) })
+      }
+      let _extension_size = endian.read_u16(rdr)?;
+      let valid_bits = endian.read_u16(rdr)?;
+      let _channel_mask = endian.read_u32(rdr)?;
+      // The GUID's first three fields are integers in the file's byte order
+      let sub_format_data1 = endian.read_u32(rdr)?;
+      let sub_format_data2 = endian.read_u16(rdr)?;
+      let sub_format_data3 = endian.read_u16(rdr)?;
+      let mut sub_format_data4 = [0_u8; 8];
+      rdr.read_exact(&mut sub_format_data4)?;
+      read_size = EXTENSIBLE_SIZE;
+
+      if (sub_format_data1 >> 16, sub_format_data2, sub_format_data3, sub_format_data4) != (0, 0, 0x0010, KSDATAFORMAT_SUBTYPE_DATA4) {
+        return Err(XaError::UnsupportedAudioFormat(WAVE_FORMAT_EXTENSIBLE))
+      }
+      let format_tag = sub_format_data1 as u16;
+      if format_tag != 1 {
+        return Err(XaError::UnsupportedAudioFormat(format_tag))
+      }
+      // Zero means the writer didn't say, so every bit is taken to be valid
+      if valid_bits > bits_per_sample {
+        return Err(XaError::UnsupportedBitDepth(valid_bits))
+      }
+      if valid_bits != 0 {
+        valid_bits_per_sample = valid_bits;
+      }
     }
+    let padded_size = u64::from(chunk_size) + u64::from(chunk_size & 1);
+    io::copy(&mut rdr.take(padded_size.saturating_sub(u64::from(read_size))), &mut io::sink())?;
 
-    let block_align = rdr.read_u16::<LE>()?;
-    if block_align != 2 {
-      return Err(anyhow!("Unexpected block align: {}", block_align))
+    if block_align != num_channels * bits_per_sample / 8 {
+      return Err(XaError::UnexpectedBlockAlign(block_align))
     }
-    
-    let bits_per_sample = rdr.read_u16::<LE>()?;
-    if bits_per_sample != 16 {
-      return Err(anyhow!("Unsupported number of bits per sample: {}", bits_per_sample))
+    if u64::from(byte_rate) != u64::from(sample_rate) * u64::from(block_align) {
+      return Err(XaError::UnexpectedByteRate(byte_rate))
     }
 
     Ok(FormatChunk {
@@ -94,22 +229,77 @@ impl FormatChunk {
       byte_rate,
       block_align,
       bits_per_sample,
+      valid_bits_per_sample,
     })
   }
+
+  /// Reads a `fmt ` chunk like `from_reader_channels`, wherever it is among the chunks from
+  /// `rdr`'s position on: the chunks before it, even a `data` chunk some writers put first, are
+  /// skipped. `rdr` is left where it started, so the data chunks can be walked from there.
+  pub fn find<R: Read + Seek>(
+    rdr: &mut R,
+    endian: Endian,
+    expected_sample_rate: Option<u32>,
+    expected_channels: u16,
+  ) -> Result<Self, XaError> {
+    let start = rdr.stream_position()?;
+    let mut last_chunk_id = [0_u8; 4];
+    loop {
+      let mut chunk_id = [0_u8; 4];
+      let chunk_size = match rdr.read_exact(&mut chunk_id).and_then(|_| endian.read_u32(rdr)) {
+        Ok(chunk_size) => chunk_size,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+          return Err(XaError::MissingChunk { expected: *b"fmt ", found: last_chunk_id })
+        },
+        Err(e) => return Err(e.into()),
+      };
+
+      if chunk_id == *b"fmt " {
+        rdr.seek(SeekFrom::Current(-8))?;
+        let format = Self::from_reader_channels(rdr, endian, expected_sample_rate, expected_channels)?;
+        rdr.seek(SeekFrom::Start(start))?;
+        return Ok(format)
+      }
+
+      last_chunk_id = chunk_id;
+      let padded_size = i64::from(chunk_size) + i64::from(chunk_size & 1);
+      rdr.seek(SeekFrom::Current(padded_size))?;
+    }
+  }
+
+  fn to_writer<W: Write>(&self, wtr: &mut W) -> Result<()> {
+    wtr.write_all(&self.chunk_id)?;
+    wtr.write_u32::<LE>(self.chunk_size)?;
+    wtr.write_u16::<LE>(self.audio_format)?;
+    wtr.write_u16::<LE>(self.num_channels)?;
+    wtr.write_u32::<LE>(self.sample_rate)?;
+    wtr.write_u32::<LE>(self.byte_rate)?;
+    wtr.write_u16::<LE>(self.block_align)?;
+    wtr.write_u16::<LE>(self.bits_per_sample)?;
+
+    Ok(())
+  }
 }
 
 #[derive(Debug)]
-pub(crate) struct DataChunk {
-  chunk_id: [u8; 4],     // 'data'
-  chunk_size: u32,       // sample data size
+pub struct DataChunk {
+  pub chunk_id: [u8; 4],     // 'data'
+  pub chunk_size: u32,       // sample data size
 }
 
 impl DataChunk {
-  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self> {
+  fn new(data_size: u32) -> Self {
+    DataChunk {
+      chunk_id: [0x64, 0x61, 0x74, 0x61],
+      chunk_size: data_size,
+    }
+  }
+
+  pub fn from_reader<R: Read>(rdr: &mut R) -> Result<Self, XaError> {
     let mut chunk_id = [0_u8; 4];
     rdr.read_exact(&mut chunk_id)?;
     if chunk_id != [0x64, 0x61, 0x74, 0x61] {
-      return Err(anyhow!("Not a `data` chunk: {:?}", chunk_id))
+      return Err(XaError::MissingChunk { expected: *b"data", found: chunk_id })
     }
 
     let chunk_size = rdr.read_u32::<LE>()?;
@@ -120,8 +310,757 @@ impl DataChunk {
     })
   }
 
-  // TODO temp function for testing
+  fn to_writer<W: Write>(&self, wtr: &mut W) -> Result<()> {
+    wtr.write_all(&self.chunk_id)?;
+    wtr.write_u32::<LE>(self.chunk_size)?;
+
+    Ok(())
+  }
+
+  /// Number of sample frames this chunk holds in the layout `format` describes, from the headers
+  /// alone. A trailing partial frame isn't counted.
+  pub fn frames_count(&self, format: &FormatChunk) -> usize {
+    (self.chunk_size / u32::from(format.block_align.max(1))) as usize
+  }
+}
+
+/// The sample data of a WAV file, which some editors split across several `data` chunks.
+#[derive(Debug, PartialEq)]
+pub struct DataChunks {
+  // Offset and length of each non-empty `data` chunk's whole samples, in file order
+  spans: Vec<(u64, u64)>,
+  // Bytes per sample
+  sample_size: u64,
+  // First loop of the `smpl` chunk, if there is one
+  loop_region: Option<LoopRegion>,
+}
+
+impl DataChunks {
+  /// Walks the chunks from `rdr`'s position to the end of the file, noting every `data` chunk and
+  /// skipping everything else, including the pad byte after odd-sized chunks. Errors if a `data`
+  /// chunk claims more bytes than the file has left, or if there's no `data` chunk at all.
+  ///
+  /// `sample_size` is the `fmt ` chunk's block align, i.e. bytes per sample.
+  ///
+  /// The first loop of a `smpl` chunk is kept too, and must lie within the sample data.
+  pub fn from_reader<R: Read + Seek>(rdr: &mut R, sample_size: u16) -> Result<Self, XaError> {
+    Self::from_reader_endian(rdr, sample_size, Endian::Little)
+  }
+
+  /// Walks the chunks like `from_reader`, in a file of the given byte order.
+  pub fn from_reader_endian<R: Read + Seek>(rdr: &mut R, sample_size: u16, endian: Endian) -> Result<Self, XaError> {
+    let sample_size = u64::from(sample_size.max(1));
+    let start = rdr.stream_position()?;
+    let file_size = rdr.seek(SeekFrom::End(0))?;
+    rdr.seek(SeekFrom::Start(start))?;
+
+    let mut data_chunks = DataChunks { spans: Vec::new(), sample_size, loop_region: None };
+    let mut found_data = false;
+    let mut last_chunk_id = [0_u8; 4];
+    loop {
+      let mut chunk_id = [0_u8; 4];
+      let chunk_size = match rdr.read_exact(&mut chunk_id).and_then(|_| endian.read_u32(rdr)) {
+        Ok(chunk_size) => chunk_size,
+        // Trailing bytes too short for a chunk header end the walk
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+        Err(e) => return Err(e.into()),
+      };
+      let offset = rdr.stream_position()?;
+
+      if chunk_id == *b"data" {
+        found_data = true;
+        let available = file_size.saturating_sub(offset);
+        if u64::from(chunk_size) > available {
+          return Err(XaError::DataSizeExceedsFile { declared: u64::from(chunk_size), available })
+        }
+        // A stray partial sample would misalign every later one, so only whole samples are kept
+        let whole_samples = u64::from(chunk_size) / sample_size * sample_size;
+        if whole_samples > 0 {
+          data_chunks.spans.push((offset, whole_samples));
+        }
+      } else {
+        if chunk_id == *b"smpl" {
+          data_chunks.loop_region = read_smpl_loop(rdr, chunk_size, endian)?;
+        }
+        last_chunk_id = chunk_id;
+      }
+
+      let padded_size = u64::from(chunk_size) + u64::from(chunk_size & 1);
+      if offset + padded_size >= file_size {
+        break
+      }
+      rdr.seek(SeekFrom::Start(offset + padded_size))?;
+    }
+
+    if !found_data {
+      return Err(XaError::MissingChunk { expected: *b"data", found: last_chunk_id })
+    }
+    if let Some(LoopRegion { start, end }) = data_chunks.loop_region {
+      let samples_count = data_chunks.samples_count();
+      if start > end || end >= samples_count {
+        return Err(XaError::LoopOutOfRange { start, end, samples_count })
+      }
+    }
+
+    Ok(data_chunks)
+  }
+
+  /// The first loop of the file's `smpl` chunk, in sample frames.
+  pub fn loop_region(&self) -> Option<LoopRegion> {
+    self.loop_region
+  }
+
   pub fn samples_count(&self) -> usize {
-    (self.chunk_size / 2) as usize
+    self.spans.iter().map(|&(_, len)| (len / self.sample_size) as usize).sum()
+  }
+
+  /// Reads the sample data of every `data` chunk in turn, seeking `rdr` to each.
+  pub fn into_reader<R: Read + Seek>(self, rdr: R) -> DataChunksReader<R> {
+    DataChunksReader { inner: rdr, spans: self.spans, current: 0, remaining: None }
+  }
+}
+
+/// Reads the body of a `smpl` chunk of `chunk_size` bytes, returning its first loop, or `None` if it
+/// has no loops.
+fn read_smpl_loop<R: Read>(rdr: &mut R, chunk_size: u32, endian: Endian) -> Result<Option<LoopRegion>, XaError> {
+  // Manufacturer, product, sample period, MIDI unity note and pitch fraction, SMPTE format and offset
+  const SMPL_HEADER_SIZE: u32 = 36;
+  // Cue point ID, type, start, end, fraction and play count
+  const SMPL_LOOP_SIZE: u32 = 24;
+
+  if chunk_size < SMPL_HEADER_SIZE {
+    return Err(XaError::ChunkSize { chunk: *b"smpl", expected: i64::from(SMPL_HEADER_SIZE), found: i64::from(chunk_size) })
+  }
+  let mut header = [0_u8; 28];
+  rdr.read_exact(&mut header)?;
+  let num_loops = endian.read_u32(rdr)?;
+  let _sampler_data = endian.read_u32(rdr)?;
+  if num_loops == 0 {
+    return Ok(None)
+  }
+
+  let expected = SMPL_HEADER_SIZE + SMPL_LOOP_SIZE;
+  if chunk_size < expected {
+    return Err(XaError::ChunkSize { chunk: *b"smpl", expected: i64::from(expected), found: i64::from(chunk_size) })
+  }
+  let _cue_point_id = endian.read_u32(rdr)?;
+  let _loop_type = endian.read_u32(rdr)?;
+  let start = endian.read_u32(rdr)?;
+  let end = endian.read_u32(rdr)?;
+
+  Ok(Some(LoopRegion { start: start as usize, end: end as usize }))
+}
+
+/// Reader over the concatenated sample data of `DataChunks`.
+pub struct DataChunksReader<R: Read + Seek> {
+  inner: R,
+  spans: Vec<(u64, u64)>,
+  current: usize,
+  // Bytes left in the current span, once `inner` has been moved to it
+  remaining: Option<u64>,
+}
+
+impl<R: Read + Seek> Read for DataChunksReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    while self.current < self.spans.len() {
+      let remaining = match self.remaining {
+        Some(remaining) => remaining,
+        None => {
+          let (offset, len) = self.spans[self.current];
+          self.inner.seek(SeekFrom::Start(offset))?;
+          *self.remaining.insert(len)
+        },
+      };
+
+      if remaining == 0 {
+        self.current += 1;
+        self.remaining = None;
+        continue
+      }
+
+      let take = buf.len().min(usize::try_from(remaining).unwrap_or(usize::MAX));
+      let count = self.inner.read(&mut buf[..take])?;
+      if count == 0 && take > 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into())
+      }
+      self.remaining = Some(remaining - count as u64);
+      return Ok(count)
+    }
+
+    Ok(0)
+  }
+}
+
+/// Converts a 32-bit sample to 16 bits by an arithmetic shift right of 16, rounding to nearest.
+/// Values that would round up past `i16::MAX` saturate rather than wrap.
+pub fn i32_to_i16(sample: i32) -> i16 {
+  ((i64::from(sample) + 0x8000) >> 16).min(i64::from(i16::MAX)) as i16
+}
+
+/// Reverses the bytes of each `width`-byte sample, turning big-endian PCM into little-endian. A
+/// trailing partial sample is dropped.
+pub struct ByteSwapReader<R: Read> {
+  inner: R,
+  width: usize,
+  sample: [u8; 4],
+  // Bytes of `sample` already handed out
+  taken: usize,
+}
+
+impl<R: Read> ByteSwapReader<R> {
+  /// `width` is the sample size in bytes, 2 or 4.
+  pub fn new(inner: R, width: usize) -> Self {
+    let width = width.clamp(1, 4);
+    ByteSwapReader { inner, width, sample: [0; 4], taken: width }
+  }
+}
+
+impl<R: Read> Read for ByteSwapReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let mut count = 0;
+    while count < buf.len() {
+      if self.taken == self.width {
+        match self.inner.read_exact(&mut self.sample[..self.width]) {
+          Ok(()) => (),
+          Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+          Err(e) => return Err(e),
+        }
+        self.sample[..self.width].reverse();
+        self.taken = 0;
+      }
+
+      let n = (self.width - self.taken).min(buf.len() - count);
+      buf[count..count + n].copy_from_slice(&self.sample[self.taken..self.taken + n]);
+      self.taken += n;
+      count += n;
+    }
+
+    Ok(count)
+  }
+}
+
+/// Turns little-endian 32-bit PCM bytes into little-endian 16-bit PCM bytes, with `i32_to_i16`. A
+/// trailing partial sample is dropped.
+pub struct Pcm32Reader<R: Read> {
+  inner: R,
+  pending: Option<u8>,
+  // Clears the bits below the valid ones before rounding
+  valid_mask: i32,
+}
+
+impl<R: Read> Pcm32Reader<R> {
+  pub fn new(inner: R) -> Self {
+    Self::with_valid_bits(inner, 32)
+  }
+
+  /// Reads samples of which only the top `valid_bits` (1 to 32) hold audio, as in a 24-bit
+  /// extensible file with 32-bit containers. Whatever is in the bits below is ignored.
+  pub fn with_valid_bits(inner: R, valid_bits: u16) -> Self {
+    let valid_mask = !0_i32 << (32 - u32::from(valid_bits.clamp(1, 32)));
+    Pcm32Reader { inner, pending: None, valid_mask }
+  }
+}
+
+impl<R: Read> Read for Pcm32Reader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let mut count = 0;
+    while count < buf.len() {
+      let byte = match self.pending.take() {
+        Some(byte) => byte,
+        None => {
+          let mut sample = [0_u8; 4];
+          match self.inner.read_exact(&mut sample) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+          }
+          let [low, high] = i32_to_i16(i32::from_le_bytes(sample) & self.valid_mask).to_le_bytes();
+          self.pending = Some(high);
+          low
+        },
+      };
+      buf[count] = byte;
+      count += 1;
+    }
+
+    Ok(count)
+  }
+}
+
+/// Picks one channel out of interleaved little-endian 16-bit PCM bytes, giving mono 16-bit PCM
+/// bytes. A trailing partial frame is dropped.
+pub struct ChannelReader<R: Read> {
+  inner: R,
+  frame: Vec<u8>,
+  channel: usize,
+  pending: Option<u8>,
+}
+
+impl<R: Read> ChannelReader<R> {
+  /// `channel` counts from 0, and must be below `num_channels`.
+  pub fn new(inner: R, num_channels: usize, channel: usize) -> Self {
+    ChannelReader { inner, frame: vec![0; num_channels * 2], channel, pending: None }
+  }
+}
+
+impl<R: Read> Read for ChannelReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let mut count = 0;
+    while count < buf.len() {
+      let byte = match self.pending.take() {
+        Some(byte) => byte,
+        None => {
+          match self.inner.read_exact(&mut self.frame) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+          }
+          self.pending = Some(self.frame[self.channel * 2 + 1]);
+          self.frame[self.channel * 2]
+        },
+      };
+      buf[count] = byte;
+      count += 1;
+    }
+
+    Ok(count)
+  }
+}
+
+/// Writes `samples` as a 16-bit mono PCM WAV file.
+pub fn write_wav<W: Write>(samples: &[i16], sample_rate: u32, wtr: &mut W) -> Result<()> {
+  let mut wav_wtr = WavWriter::new(samples.len(), sample_rate, wtr)?;
+  for &sample in samples {
+    wav_wtr.write_i16::<LE>(sample)?;
+  }
+  wav_wtr.finish()?;
+
+  Ok(())
+}
+
+/// Writes a 16-bit mono PCM WAV file of `samples_count` samples, taking the little-endian sample
+/// bytes through `Write` as they arrive. The headers come first, so the length has to be known up
+/// front.
+pub struct WavWriter<W: Write> {
+  inner: W,
+  // Sample bytes still to come
+  remaining: u64,
+}
+
+impl<W: Write> WavWriter<W> {
+  pub fn new(samples_count: usize, sample_rate: u32, mut inner: W) -> Result<Self> {
+    let data_size = samples_count
+      .checked_mul(2)
+      .and_then(|size| u32::try_from(size).ok())
+      .filter(|&size| size <= u32::MAX - (4 + 8 + 16 + 8))
+      .ok_or_else(|| anyhow!("Too many samples for a WAV file: {}", samples_count))?;
+
+    RiffHeader::new(data_size).to_writer(&mut inner)?;
+    FormatChunk::new(sample_rate).to_writer(&mut inner)?;
+    DataChunk::new(data_size).to_writer(&mut inner)?;
+
+    Ok(WavWriter { inner, remaining: u64::from(data_size) })
+  }
+
+  /// Pads the data out to the declared length with silence if fewer samples were written, then
+  /// flushes and returns the wrapped writer.
+  pub fn finish(mut self) -> Result<W> {
+    io::copy(&mut io::repeat(0).take(self.remaining), &mut self.inner)?;
+    self.inner.flush()?;
+
+    Ok(self.inner)
+  }
+}
+
+impl<W: Write> Write for WavWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if self.remaining == 0 && !buf.is_empty() {
+      return Err(io::Error::other("More sample data than the WAV header declares"))
+    }
+
+    let take = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+    let count = self.inner.write(&buf[..take])?;
+    self.remaining -= count as u64;
+    Ok(count)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Streams the samples of a WAV file decoded by `hound`, as little-endian 16-bit PCM bytes.
+///
+/// `hound` copes with chunk layouts the parsers above reject (e.g. `LIST` chunks or an extensible
+/// `fmt ` chunk), but the input must still be 16- or 32-bit mono PCM at the expected sample rate.
+/// 32-bit samples are converted with `i32_to_i16`.
+#[cfg(feature = "hound")]
+pub struct HoundReader<R: Read> {
+  samples: hound::WavIntoSamples<R, i32>,
+  bits_per_sample: u16,
+  num_channels: u16,
+  pending: Option<u8>,
+}
+
+#[cfg(feature = "hound")]
+impl<R: Read> HoundReader<R> {
+  /// Reads the WAV headers from `rdr`. Returns the number of samples the file declares along with
+  /// the reader.
+  pub fn new(rdr: R, expected_sample_rate: u32) -> Result<(usize, Self), XaError> {
+    let (samples_count, sample_rate, reader) = Self::new_any_rate(rdr)?;
+    if sample_rate != expected_sample_rate {
+      return Err(XaError::UnsupportedSampleRate(sample_rate))
+    }
+
+    Ok((samples_count, reader))
+  }
+
+  /// Reads the WAV headers from `rdr`, accepting any non-zero sample rate. Returns the number of
+  /// samples the file declares and its sample rate along with the reader.
+  pub fn new_any_rate(rdr: R) -> Result<(usize, u32, Self), XaError> {
+    Self::new_channels(rdr, 1)
+  }
+
+  /// Reads the WAV headers like `new_any_rate`, but of a file of `num_channels` interleaved
+  /// channels, which the reader streams still interleaved. The count returned is of sample frames.
+  pub fn new_channels(rdr: R, num_channels: u16) -> Result<(usize, u32, Self), XaError> {
+    let reader = hound::WavReader::new(rdr)?;
+    let spec = reader.spec();
+
+    if spec.sample_format != hound::SampleFormat::Int {
+      return Err(XaError::UnsupportedAudioFormat(3))
+    }
+    if spec.channels != num_channels {
+      return Err(XaError::UnsupportedChannels(spec.channels))
+    }
+    if spec.sample_rate == 0 {
+      return Err(XaError::UnsupportedSampleRate(spec.sample_rate))
+    }
+    if spec.bits_per_sample != 16 && spec.bits_per_sample != 32 {
+      return Err(XaError::UnsupportedBitDepth(spec.bits_per_sample))
+    }
+
+    let samples_count = reader.len() as usize / usize::from(num_channels);
+    let bits_per_sample = spec.bits_per_sample;
+    let reader = HoundReader { samples: reader.into_samples(), bits_per_sample, num_channels, pending: None };
+    Ok((samples_count, spec.sample_rate, reader))
+  }
+
+  /// Bytes per sample frame in the file, before any conversion to 16 bits.
+  pub fn sample_size(&self) -> u64 {
+    u64::from(self.bits_per_sample / 8) * u64::from(self.num_channels)
+  }
+}
+
+#[cfg(feature = "hound")]
+impl<R: Read> Read for HoundReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut count = 0;
+    while count < buf.len() {
+      let byte = match self.pending.take() {
+        Some(byte) => byte,
+        None => match self.samples.next() {
+          Some(sample) => {
+            let sample = sample.map_err(std::io::Error::other)?;
+            let sample = match self.bits_per_sample {
+              32 => i32_to_i16(sample),
+              _ => sample as i16,
+            };
+            let [low, high] = sample.to_le_bytes();
+            self.pending = Some(high);
+            low
+          },
+          None => break,
+        },
+      };
+      buf[count] = byte;
+      count += 1;
+    }
+
+    Ok(count)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn write_wav_reads_back() {
+    let samples = [0_i16, 1, -1, i16::MAX, i16::MIN, 1234];
+
+    let mut out = Vec::new();
+    write_wav(&samples, 18900, &mut out).unwrap();
+    assert_eq!(out.len(), 44 + samples.len() * 2);
+
+    let mut rdr = out.as_slice();
+    let riff_header = RiffHeader::from_reader(&mut rdr).unwrap();
+    let format_chunk = FormatChunk::from_reader(&mut rdr, 18900).unwrap();
+    let data_chunk = DataChunk::from_reader(&mut rdr).unwrap();
+
+    assert_eq!(riff_header.chunk_size as usize, out.len() - 8);
+    assert_eq!(format_chunk.sample_rate, 18900);
+    assert_eq!(data_chunk.frames_count(&format_chunk), samples.len());
+
+    let read_back: Vec<i16> = rdr.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+    assert_eq!(read_back, samples);
+  }
+
+  #[test]
+  fn frames_count_uses_block_align() {
+    let format = |num_channels: u16, bits_per_sample: u16| {
+      let block_align = num_channels * bits_per_sample / 8;
+      FormatChunk {
+        chunk_id: *b"fmt ",
+        chunk_size: 16,
+        audio_format: 1,
+        num_channels,
+        sample_rate: 18900,
+        byte_rate: 18900 * u32::from(block_align),
+        block_align,
+        bits_per_sample,
+        valid_bits_per_sample: bits_per_sample,
+      }
+    };
+    let data = DataChunk::new(1002);
+
+    assert_eq!(data.frames_count(&format(1, 16)), 501);
+    assert_eq!(data.frames_count(&format(2, 16)), 250);
+    assert_eq!(data.frames_count(&format(1, 8)), 1002);
+    assert_eq!(data.frames_count(&format(1, 32)), 250);
+  }
+
+  #[test]
+  fn wav_writer_pads_short_data() {
+    let mut wtr = WavWriter::new(4, 18900, Vec::new()).unwrap();
+    wtr.write_all(&[1, 0, 2, 0]).unwrap();
+    let out = wtr.finish().unwrap();
+    assert_eq!(&out[44..], [1, 0, 2, 0, 0, 0, 0, 0]);
+
+    let mut wtr = WavWriter::new(1, 18900, Vec::new()).unwrap();
+    assert!(wtr.write_all(&[1, 0, 2, 0]).is_err());
+  }
+
+  fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut bytes = id.to_vec();
+    bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+      bytes.push(0);
+    }
+    bytes
+  }
+
+  fn sample_bytes(samples: impl IntoIterator<Item = i16>) -> Vec<u8> {
+    samples.into_iter().flat_map(|sample| sample.to_le_bytes()).collect()
+  }
+
+  #[test]
+  fn split_data_chunks_are_concatenated() {
+    let mut body = Vec::new();
+    body.extend(chunk(b"data", &sample_bytes(0..10)));
+    body.extend(chunk(b"fact", &[1, 2, 3]));
+    body.extend(chunk(b"data", &[]));
+    body.extend(chunk(b"data", &sample_bytes(10..16)));
+    body.extend(chunk(b"LIST", &[0; 4]));
+
+    let mut rdr = io::Cursor::new(body);
+    let data_chunks = DataChunks::from_reader(&mut rdr, 2).unwrap();
+    assert_eq!(data_chunks.samples_count(), 16);
+
+    let mut pcm = Vec::new();
+    data_chunks.into_reader(rdr).read_to_end(&mut pcm).unwrap();
+    assert_eq!(pcm, sample_bytes(0..16));
+  }
+
+  fn smpl_chunk(loops: &[(u32, u32)]) -> Vec<u8> {
+    let mut body = vec![0_u8; 28];
+    body.extend_from_slice(&(loops.len() as u32).to_le_bytes());
+    body.extend_from_slice(&0_u32.to_le_bytes());
+    for (n, &(start, end)) in loops.iter().enumerate() {
+      for field in [n as u32, 0, start, end, 0, 0] {
+        body.extend_from_slice(&field.to_le_bytes());
+      }
+    }
+    chunk(b"smpl", &body)
+  }
+
+  #[test]
+  fn smpl_chunk_gives_first_loop() {
+    let mut body = chunk(b"data", &sample_bytes(0..100));
+    body.extend(smpl_chunk(&[(10, 89), (0, 99)]));
+    let data_chunks = DataChunks::from_reader(&mut io::Cursor::new(body), 2).unwrap();
+    assert_eq!(data_chunks.samples_count(), 100);
+    assert_eq!(data_chunks.loop_region(), Some(LoopRegion { start: 10, end: 89 }));
+
+    // Ahead of the data, as some editors write it, and with no loops
+    let mut body = smpl_chunk(&[]);
+    body.extend(chunk(b"data", &sample_bytes(0..100)));
+    assert_eq!(DataChunks::from_reader(&mut io::Cursor::new(body), 2).unwrap().loop_region(), None);
+
+    let body = chunk(b"data", &sample_bytes(0..100));
+    assert_eq!(DataChunks::from_reader(&mut io::Cursor::new(body), 2).unwrap().loop_region(), None);
+  }
+
+  #[test]
+  fn smpl_loop_must_fit_the_data() {
+    let mut body = chunk(b"data", &sample_bytes(0..100));
+    body.extend(smpl_chunk(&[(10, 100)]));
+    assert!(matches!(
+      DataChunks::from_reader(&mut io::Cursor::new(body), 2),
+      Err(XaError::LoopOutOfRange { start: 10, end: 100, samples_count: 100 })
+    ));
+
+    let mut body = chunk(b"data", &sample_bytes(0..100));
+    body.extend(smpl_chunk(&[(50, 40)]));
+    assert!(matches!(DataChunks::from_reader(&mut io::Cursor::new(body), 2), Err(XaError::LoopOutOfRange { .. })));
+
+    // Claims a loop, but ends before it
+    let mut body = chunk(b"data", &sample_bytes(0..100));
+    body.extend(chunk(b"smpl", &smpl_chunk(&[(0, 99)])[8..8 + 40]));
+    assert!(matches!(
+      DataChunks::from_reader(&mut io::Cursor::new(body), 2),
+      Err(XaError::ChunkSize { expected: 60, found: 40, .. })
+    ));
+  }
+
+  #[test]
+  fn data_chunks_need_at_least_one_data_chunk() {
+    let mut rdr = io::Cursor::new(chunk(b"fact", &[0; 4]));
+    assert!(matches!(
+      DataChunks::from_reader(&mut rdr, 2),
+      Err(XaError::MissingChunk { expected: [b'd', b'a', b't', b'a'], found: [b'f', b'a', b'c', b't'] })
+    ));
+
+    let mut oversized = chunk(b"data", &sample_bytes(0..4));
+    oversized[4..8].copy_from_slice(&100_u32.to_le_bytes());
+    assert!(matches!(
+      DataChunks::from_reader(&mut io::Cursor::new(oversized), 2),
+      Err(XaError::DataSizeExceedsFile { declared: 100, available: 8 })
+    ));
+  }
+
+  #[test]
+  fn pcm32_rounds_to_16_bits() {
+    let cases = [
+      (0, 0),
+      (0x7FFF, 0),
+      (0x8000, 1),
+      (0x1_0000, 1),
+      (-0x8000, 0),
+      (-0x8001, -1),
+      (-0x1_0000, -1),
+      (0x7FFF_7FFF, i16::MAX),
+      (i32::MAX, i16::MAX),
+      (i32::MIN, i16::MIN),
+    ];
+    for (sample, expected) in cases {
+      assert_eq!(i32_to_i16(sample), expected, "{:#x}", sample);
+    }
+
+    let mut pcm32: Vec<u8> = cases.iter().flat_map(|&(sample, _)| sample.to_le_bytes()).collect();
+    pcm32.extend_from_slice(&[1, 2]);
+    let mut pcm = Vec::new();
+    Pcm32Reader::new(pcm32.as_slice()).read_to_end(&mut pcm).unwrap();
+    assert_eq!(pcm, sample_bytes(cases.iter().map(|&(_, expected)| expected)));
+  }
+
+  #[test]
+  fn format_chunk_accepts_32_bit_pcm() {
+    let mut out = Vec::new();
+    write_wav(&[0; 4], 18900, &mut out).unwrap();
+    out[28..32].copy_from_slice(&(18900_u32 * 4).to_le_bytes());
+    out[32..34].copy_from_slice(&4_u16.to_le_bytes());
+    out[34..36].copy_from_slice(&32_u16.to_le_bytes());
+
+    let mut rdr = &out[12..];
+    let format_chunk = FormatChunk::from_reader(&mut rdr, 18900).unwrap();
+    assert_eq!((format_chunk.bits_per_sample, format_chunk.block_align), (32, 4));
+
+    // A 16-bit block align doesn't fit 32-bit samples
+    out[32..34].copy_from_slice(&2_u16.to_le_bytes());
+    assert!(matches!(FormatChunk::from_reader(&mut &out[12..], 18900), Err(XaError::UnexpectedBlockAlign(2))));
+
+    out[34..36].copy_from_slice(&24_u16.to_le_bytes());
+    assert!(matches!(FormatChunk::from_reader(&mut &out[12..], 18900), Err(XaError::UnsupportedBitDepth(24))));
+  }
+
+  /// A mono 24-bit-in-32 `WAVE_FORMAT_EXTENSIBLE` file holding `samples`, with `sub_format_tag` as
+  /// the first two bytes of its SubFormat GUID
+  fn extensible_wav(samples: &[i32], sub_format_tag: u16) -> Vec<u8> {
+    let data: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(60 + data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&40_u32.to_le_bytes());
+    bytes.extend_from_slice(&WAVE_FORMAT_EXTENSIBLE.to_le_bytes());
+    bytes.extend_from_slice(&1_u16.to_le_bytes());
+    bytes.extend_from_slice(&18900_u32.to_le_bytes());
+    bytes.extend_from_slice(&(18900_u32 * 4).to_le_bytes());
+    bytes.extend_from_slice(&4_u16.to_le_bytes());
+    bytes.extend_from_slice(&32_u16.to_le_bytes());
+    bytes.extend_from_slice(&22_u16.to_le_bytes());
+    bytes.extend_from_slice(&24_u16.to_le_bytes());
+    bytes.extend_from_slice(&4_u32.to_le_bytes());
+    bytes.extend_from_slice(&u32::from(sub_format_tag).to_le_bytes());
+    bytes.extend_from_slice(&[0x00, 0x00, 0x10, 0x00]);
+    bytes.extend_from_slice(&KSDATAFORMAT_SUBTYPE_DATA4);
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&data);
+    bytes
+  }
+
+  #[test]
+  fn extensible_24_in_32_uses_valid_bits() {
+    // The low byte is outside the 24 valid bits; with it, 0x1234_7FFF would round up to 0x1235
+    let samples = [0x1234_7FFF, 0x1234_8000, -0x0001_00FF, i32::MAX];
+    let wav = extensible_wav(&samples, 1);
+
+    let mut rdr = io::Cursor::new(wav);
+    RiffHeader::from_reader(&mut rdr).unwrap();
+    let format = FormatChunk::from_reader(&mut rdr, 18900).unwrap();
+    assert_eq!((format.audio_format, format.bits_per_sample, format.valid_bits_per_sample), (WAVE_FORMAT_EXTENSIBLE, 32, 24));
+
+    // The rest of the 40-byte `fmt ` chunk was skipped, so the data chunk is found
+    let data_chunks = DataChunks::from_reader(&mut rdr, format.block_align).unwrap();
+    assert_eq!(data_chunks.samples_count(), samples.len());
+
+    let mut pcm = Vec::new();
+    Pcm32Reader::with_valid_bits(data_chunks.into_reader(rdr), format.valid_bits_per_sample).read_to_end(&mut pcm).unwrap();
+    assert_eq!(pcm, sample_bytes([0x1234, 0x1235, -1, i16::MAX]));
+  }
+
+  #[test]
+  fn extensible_float_is_rejected_as_float() {
+    let wav = extensible_wav(&[0; 4], 3);
+    assert!(matches!(FormatChunk::from_reader(&mut &wav[12..], 18900), Err(XaError::UnsupportedAudioFormat(3))));
+
+    // Extensible, but too short to hold the extension
+    let mut wav = extensible_wav(&[0; 4], 1);
+    wav[16..20].copy_from_slice(&18_u32.to_le_bytes());
+    assert!(matches!(FormatChunk::from_reader(&mut &wav[12..], 18900), Err(XaError::ChunkSize { expected: 40, found: 18, .. })));
+  }
+
+  #[test]
+  fn parse_errors_are_matchable() {
+    let mut out = Vec::new();
+    write_wav(&[0; 4], 18900, &mut out).unwrap();
+
+    // Patch the sample rate to 44100
+    out[24..28].copy_from_slice(&44100_u32.to_le_bytes());
+    let mut rdr = out.as_slice();
+    RiffHeader::from_reader(&mut rdr).unwrap();
+    assert!(matches!(FormatChunk::from_reader(&mut rdr, 18900), Err(XaError::UnsupportedSampleRate(44100))));
+
+    assert!(matches!(RiffHeader::from_reader(&mut &b"RF64\0\0\0\0WAVE"[..]), Err(XaError::NotRiff)));
+    assert_eq!(RiffHeader::from_reader(&mut &b"RIFX\0\0\0\0WAVE"[..]).unwrap().endian(), Endian::Big);
+    assert!(matches!(
+      DataChunk::from_reader(&mut &b"LIST\0\0\0\0"[..]),
+      Err(XaError::MissingChunk { expected: [b'd', b'a', b't', b'a'], .. })
+    ));
+    assert!(matches!(RiffHeader::from_reader(&mut &b"RIFF"[..]), Err(XaError::Io(_))));
   }
 }
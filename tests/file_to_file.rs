@@ -0,0 +1,51 @@
+// Round trip through the one-call library API: WAV file in, encoded file out, decoded again.
+
+use adpcm2aiff::{
+  adpcm_encoder::{OutputFormat, SOUND_UNIT_SAMPLES, SPU_ADPCM_BLOCK_SIZE},
+  aiff::decode_apcm_aiff,
+  config::EncoderConfig,
+  pipeline::encode_file_to_file,
+  stats::snr_db,
+  wav::write_wav,
+};
+use std::fs;
+
+fn test_samples() -> Vec<i16> {
+  (0..10_000).map(|n| ((f64::from(n) * 0.05).sin() * 12_000.0) as i16).collect()
+}
+
+#[test]
+fn wav_to_acm_round_trip() {
+  let dir = tempfile::tempdir().unwrap();
+  let wav = dir.path().join("in.wav");
+  let acm = dir.path().join("out.acm");
+  let samples = test_samples();
+  write_wav(&samples, 18900, &mut fs::File::create(&wav).unwrap()).unwrap();
+
+  let stats = encode_file_to_file(&wav, &acm, &EncoderConfig::default()).unwrap();
+  assert_eq!(stats.missing_samples, 0);
+
+  let decoded = decode_apcm_aiff(&mut fs::File::open(&acm).unwrap(), false).unwrap();
+  assert_eq!(decoded.samples.len(), samples.len());
+  let snr = snr_db(&samples, &decoded.samples);
+  assert!(snr > 20.0, "SNR {:.2} dB", snr);
+}
+
+#[test]
+fn output_extension_picks_the_format() {
+  let dir = tempfile::tempdir().unwrap();
+  let wav = dir.path().join("in.wav");
+  let samples = test_samples();
+  write_wav(&samples, 18900, &mut fs::File::create(&wav).unwrap()).unwrap();
+
+  // `.spu` overrides the configured XA output with headerless SPU blocks
+  let spu = dir.path().join("out.spu");
+  encode_file_to_file(&wav, &spu, &EncoderConfig::default()).unwrap();
+  assert_eq!(fs::read(&spu).unwrap().len(), samples.len().div_ceil(SOUND_UNIT_SAMPLES) * SPU_ADPCM_BLOCK_SIZE);
+
+  // An extension that names no format leaves the configured one
+  let bin = dir.path().join("out.bin");
+  let config = EncoderConfig::builder().output_format(OutputFormat::Spu).build().unwrap();
+  encode_file_to_file(&wav, &bin, &config).unwrap();
+  assert_eq!(fs::read(&bin).unwrap(), fs::read(&spu).unwrap());
+}
@@ -0,0 +1,79 @@
+// Bit-exact regression test for the encoder.
+//
+// `data/golden.wav` is a few sectors of 18900 Hz mono audio chosen to exercise every filter and a
+// spread of ranges: a decaying tone with some treble, a noise burst, full-scale square steps,
+// silence and a sweep. `data/golden.acm` is what the reviewed encoder made of it with default
+// settings. Any change to the output bytes fails this test.
+//
+// When a change to the output is intentional, regenerate the golden file and commit it along with
+// the change:
+//
+//   UPDATE_GOLDEN=1 cargo test --test golden
+
+use std::{
+  fs,
+  io::Write,
+  path::Path,
+  process::{Command, Stdio},
+};
+
+const GOLDEN_WAV: &str = "tests/data/golden.wav";
+const GOLDEN_ACM: &str = "tests/data/golden.acm";
+
+/// Encodes `wav` with the command-line tool and default settings, returning the output file.
+fn encode_with_cli(wav: &Path, out_dir: &Path) -> Vec<u8> {
+  let acm = out_dir.join("out.acm");
+  let status = Command::new(env!("CARGO_BIN_EXE_adpcm2aiff"))
+    .arg(wav)
+    .arg("-o")
+    .arg(&acm)
+    .status()
+    .unwrap();
+  assert!(status.success(), "encoder exited with {}", status);
+
+  fs::read(&acm).unwrap()
+}
+
+#[test]
+fn output_matches_golden_acm() {
+  let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+  let dir = tempfile::tempdir().unwrap();
+  let encoded = encode_with_cli(&root.join(GOLDEN_WAV), dir.path());
+
+  let golden_path = root.join(GOLDEN_ACM);
+  if std::env::var_os("UPDATE_GOLDEN").is_some() {
+    fs::write(&golden_path, &encoded).unwrap();
+    return
+  }
+
+  let golden = fs::read(&golden_path)
+    .unwrap_or_else(|e| panic!("Can't read {} ({}); run with UPDATE_GOLDEN=1 to create it", GOLDEN_ACM, e));
+  assert_eq!(encoded.len(), golden.len(), "output length differs from {}", GOLDEN_ACM);
+  if let Some(offset) = encoded.iter().zip(&golden).position(|(a, b)| a != b) {
+    panic!(
+      "output differs from {} from byte {:#x} on; if the change is intentional, rerun with \
+       UPDATE_GOLDEN=1 and commit the new file",
+      GOLDEN_ACM, offset,
+    );
+  }
+}
+
+#[test]
+fn stdin_to_stdout_matches_golden_acm() {
+  let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+  let wav = fs::read(root.join(GOLDEN_WAV)).unwrap();
+
+  let mut child = Command::new(env!("CARGO_BIN_EXE_adpcm2aiff"))
+    .args(["-", "-o", "-"])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::null())
+    .spawn()
+    .unwrap();
+  child.stdin.take().unwrap().write_all(&wav).unwrap();
+  let output = child.wait_with_output().unwrap();
+  assert!(output.status.success(), "encoder exited with {}", output.status);
+
+  let golden = fs::read(root.join(GOLDEN_ACM)).unwrap();
+  assert!(output.stdout == golden, "stdout differs from {}", GOLDEN_ACM);
+}